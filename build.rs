@@ -0,0 +1,59 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Records where a binary came from, for `version --verbose` and the server's
+// startup log. Anything that can't be found out (no git, a source tarball)
+// is left unset and shows up as "unknown".
+
+fn main() {
+    if let Some(commit) = run("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=RUST_PRACTICE_GIT_COMMIT={}", commit);
+    }
+    if let Some(branch) = run("git", &["rev-parse", "--abbrev-ref", "HEAD"]) {
+        println!("cargo:rustc-env=RUST_PRACTICE_GIT_BRANCH={}", branch);
+    }
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = run(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=RUST_PRACTICE_RUSTC_VERSION={}", version);
+    }
+    let built = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=RUST_PRACTICE_BUILD_UNIX={}", built);
+
+    // Cargo tells build scripts about enabled features as CARGO_FEATURE_<NAME>,
+    // `default` included, which says nothing about what's built in.
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|f| f.to_lowercase().replace('_', "-"))
+        })
+        .filter(|f| f != "default")
+        .collect();
+    features.sort();
+    println!(
+        "cargo:rustc-env=RUST_PRACTICE_FEATURES={}",
+        features.join(",")
+    );
+
+    // Rebuild when the checked-out commit changes, not on every build.
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Some(git_dir) = run("git", &["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(reference) = run("git", &["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, reference);
+        }
+    }
+}
+
+// The trimmed stdout of a command that succeeded, if it printed anything.
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
@@ -1,39 +1,237 @@
 use std::env;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::io::{self, BufRead, Error, ErrorKind, IsTerminal, Write};
 use std::time::Duration;
 
-fn main() -> std::io::Result<()> {
-    // Allow overriding address and message via CLI args
-    // Usage: cargo run --bin client -- [ADDR] [MESSAGE]
-    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:4000".to_string());
-    let message = env::args().nth(2).unwrap_or_else(|| "hello from client".to_string());
+use rust_practice::utils::circuit_breaker::{BreakerConfig, CallError, CircuitBreaker};
+use rust_practice::utils::client::RetryPolicy;
+use rust_practice::utils::command::{self, Command};
+#[cfg(feature = "e2e")]
+use rust_practice::utils::e2e::{self, Handshake};
+use rust_practice::utils::text_protocol::TextClient;
+use rust_practice::{Client, DEFAULT_ADDR, LogLevel, Logger, StderrLogger};
 
-    println!("connecting to {}...", addr);
-    let mut stream = TcpStream::connect(&addr)?;
+// Echo messages through `server`, one frame each.
+//
+// Connecting is retried --retries times with a growing, jittered wait in
+// between, or with --wait SECS until that many seconds have passed (for
+// scripts that start the server and the client together). If the
+// connection drops mid-way, the client reconnects the same way and sends
+// the message again; an echo is safe to repeat. If reconnecting fails, the
+// message is reported as not sent and the client moves on, but a circuit
+// breaker stops the messages after it from each waiting out a whole retry
+// schedule of their own: they fail at once until RECONNECT_COOLDOWN has
+// passed, and then one tries again.
+//
+// With --interactive, it reads commands (see `command`) from stdin until
+// `quit` or the end of input, sending `send`, `ping`, `time` and `auth` over
+// the server's line protocol (see `text_protocol`) and printing the
+// replies. With the `e2e` feature, `key` shows this session's public key
+// and its fingerprint, and `verify <KEY> <FINGERPRINT>` checks a peer's key
+// against the fingerprint they read out over another channel.
+//
+// Usage: cargo run --bin client -- [ADDR] [MESSAGE]... [--retries N] [--wait SECS]
+//        cargo run --bin client -- [ADDR] --interactive [--retries N] [--wait SECS]
+
+// How long after a failed reconnect the client waits before trying again.
+const RECONNECT_COOLDOWN: Duration = Duration::from_secs(30);
 
-    // Fail fast on timeout configuration issues
-    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+fn parse_flag<T: std::str::FromStr>(name: &str, value: Option<String>) -> std::io::Result<T> {
+    value
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} needs a number", name)))
+}
 
-    // Send full message
-    stream.write_all(message.as_bytes())?;
-    println!("sent: {:?}", message);
+// Errors after which the connection is gone and a new one might work.
+fn connection_lost(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::UnexpectedEof
+    )
+}
 
-    // Gracefully close the write half so the server can finish and we can read EOF
-    stream.shutdown(Shutdown::Write)?;
+// Echo `message`, reconnecting first if there's no connection or it has
+// dropped. A failed reconnect leaves `client` as `None`.
+fn send(
+    client: &mut Option<Client>,
+    message: &str,
+    addr: &str,
+    policy: &RetryPolicy,
+    breaker: &CircuitBreaker,
+) -> std::io::Result<String> {
+    if let Some(connected) = client {
+        match connected.echo(message) {
+            Err(e) if connection_lost(&e) => {
+                println!("connection lost ({}); reconnecting...", e);
+                *client = None;
+            }
+            result => return result,
+        }
+    }
+    let mut fresh = breaker
+        .call(|| Client::connect_with_retry(addr, policy, &StderrLogger))
+        .map_err(|e| match e {
+            CallError::Open => Error::new(
+                ErrorKind::NotConnected,
+                "server unreachable; not reconnecting yet",
+            ),
+            CallError::Failed(e) => e,
+        })?;
+    let reply = fresh.echo(message)?;
+    *client = Some(fresh);
+    Ok(reply)
+}
 
-    // Read response until EOF
-    let mut buf = [0u8; 1024];
-    let mut total = Vec::new();
+// Run the commands on stdin, printing what each one gets back.
+fn interactive(client: Client) -> std::io::Result<()> {
+    let prompt = io::stdin().is_terminal();
+    let mut client = TextClient::new(client.into_stream())?;
+    let mut lines = io::stdin().lock().lines();
+    #[cfg(feature = "e2e")]
+    let handshake = Handshake::new().map_err(Error::other)?;
     loop {
-        match stream.read(&mut buf) {
-            Ok(0) => break, // EOF
-            Ok(n) => total.extend_from_slice(&buf[..n]),
-            Err(e) => return Err(e),
+        if prompt {
+            print!("> ");
+            io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        let command = match Command::parse(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("{}", e.pretty(&line));
+                continue;
+            }
+        };
+        let Some(request) = command.request() else {
+            let output = match &command {
+                Command::Help => command::HELP.to_string(),
+                #[cfg(feature = "e2e")]
+                Command::Key => format!(
+                    "{}\nfingerprint {}",
+                    handshake.hello(),
+                    e2e::fingerprint(&handshake.public_key())
+                ),
+                #[cfg(feature = "e2e")]
+                Command::Verify { key, fingerprint } => verify(key, fingerprint),
+                _ => "key and verify need the e2e feature".to_string(),
+            };
+            println!("{}", output);
+            continue;
+        };
+        let times = match command {
+            Command::Send { times, .. } => times,
+            _ => 1,
+        };
+        for _ in 0..times {
+            match client.request(&request.line())? {
+                Some(reply) => println!("{}", reply),
+                None => {
+                    println!("server closed the connection");
+                    return Ok(());
+                }
+            }
         }
+        if command == Command::Quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Whether `claimed` is the fingerprint of the hello `key`, as a line to
+// show.
+#[cfg(feature = "e2e")]
+fn verify(key: &str, claimed: &str) -> String {
+    match e2e::parse_hello(key) {
+        Ok(key) if e2e::fingerprint_matches(&key, claimed) => "fingerprint matches".to_string(),
+        Ok(key) => format!(
+            "fingerprint DOES NOT MATCH: that key's is {}",
+            e2e::fingerprint(&key)
+        ),
+        Err(e) => e,
     }
-    println!("recv: {:?}", String::from_utf8_lossy(&total));
+}
 
+fn main() -> std::io::Result<()> {
+    let mut positional = Vec::new();
+    let mut policy = RetryPolicy::default();
+    let mut interactive_mode = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--retries" => {
+                policy.attempts = parse_flag::<u32>(&arg, args.next())?
+                    .checked_add(1)
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("--retries can be at most {}", u32::MAX - 1),
+                        )
+                    })?;
+            }
+            "--wait" => {
+                policy.deadline = Some(Duration::from_secs_f64(parse_flag(&arg, args.next())?));
+            }
+            "--interactive" => interactive_mode = true,
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let addr = positional
+        .next()
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let mut messages: Vec<String> = positional.collect();
+    if interactive_mode {
+        if !messages.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--interactive reads its messages from stdin",
+            ));
+        }
+        println!("connecting to {}...", addr);
+        return interactive(Client::connect_with_retry(&addr, &policy, &StderrLogger)?);
+    }
+    if messages.is_empty() {
+        messages.push("hello from client".to_string());
+    }
+
+    println!("connecting to {}...", addr);
+    let mut client = Some(Client::connect_with_retry(&addr, &policy, &StderrLogger)?);
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 1,
+        cooldown: RECONNECT_COOLDOWN,
+        success_threshold: 1,
+    })
+    .on_transition(|from, to| {
+        StderrLogger.log(
+            LogLevel::Warn,
+            &format!("reconnect circuit {} -> {}", from, to),
+        )
+    });
+
+    let mut unsent = 0;
+    for message in &messages {
+        println!("sent: {:?}", message);
+        match send(&mut client, message, &addr, &policy, &breaker) {
+            Ok(reply) => println!("recv: {:?}", reply),
+            Err(e) => {
+                println!("not echoed: {}", e);
+                unsent += 1;
+            }
+        }
+    }
+
+    if unsent > 0 {
+        return Err(Error::other(format!(
+            "{} of {} messages weren't echoed",
+            unsent,
+            messages.len()
+        )));
+    }
     Ok(())
-}
\ No newline at end of file
+}
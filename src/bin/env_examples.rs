@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use std::env;
 
+use rust_practice::utils::config::{ConfigError, ConfigLoader};
+
 fn main() {
     println!("=== Environment Variable Usage Examples ===\n");
 
@@ -32,13 +34,19 @@ fn main() {
     example_iterate_env_vars();
 }
 
+// args[0] is only there by convention; a process can be started without
+// it, so don't index blindly.
+fn program_name(args: &[String]) -> &str {
+    args.first().map(String::as_str).unwrap_or("<unknown>")
+}
+
 // Example 1: Command Line Arguments (like in your client.rs and server.rs)
 fn example_command_line_args() {
     println!("1. Command Line Arguments:");
     println!("----------------------------");
 
     let args: Vec<String> = env::args().collect();
-    println!("Program name: {}", args[0]);
+    println!("Program name: {}", program_name(&args));
 
     // Your pattern: using nth() with unwrap_or_else
     let server_addr = env::args()
@@ -162,27 +170,26 @@ fn example_config_management() {
     }
 
     impl AppConfig {
-        fn from_env() -> Self {
-            Self {
-                database_url: env::var("DATABASE_URL")
-                    .unwrap_or_else(|_| "postgres://localhost/myapp".to_string()),
-                port: env::var("PORT")
-                    .unwrap_or_else(|_| "3000".to_string())
-                    .parse()
-                    .unwrap_or(3000),
-                debug_mode: env::var("DEBUG")
-                    .map(|v| v == "true" || v == "1")
-                    .unwrap_or(false),
-                max_connections: env::var("MAX_CONNECTIONS")
-                    .unwrap_or_else(|_| "100".to_string())
-                    .parse()
-                    .unwrap_or(100),
-            }
+        // The same pattern through `utils::config::ConfigLoader`, which reports
+        // unparseable values instead of silently using the default.
+        fn from_env() -> Result<Self, ConfigError> {
+            let mut env = ConfigLoader::from_env();
+            let config = Self {
+                database_url: env
+                    .optional("DATABASE_URL", "postgres://localhost/myapp".to_string()),
+                port: env.optional("PORT", 3000),
+                debug_mode: env.flag("DEBUG"),
+                max_connections: env.optional("MAX_CONNECTIONS", 100),
+            };
+            env.finish()?;
+            Ok(config)
         }
     }
 
-    let config = AppConfig::from_env();
-    println!("App configuration: {:?}", config);
+    match AppConfig::from_env() {
+        Ok(config) => println!("App configuration: {:?}", config),
+        Err(e) => println!("Invalid configuration: {}", e),
+    }
     println!();
 }
 
@@ -203,10 +210,7 @@ fn example_database_config() {
         format!("postgres://{}:{}@{}:{}/{}", user, pass, host, port, name)
     });
 
-    println!(
-        "Database URL: {}",
-        database_url.replace(|c: char| c == ':' || c == '@', "*")
-    );
+    println!("Database URL: {}", database_url.replace([':', '@'], "*"));
 
     // Pattern 2: Connection pool configuration
     let pool_size = env::var("DB_POOL_SIZE")
@@ -231,20 +235,17 @@ fn example_api_keys() {
     }
 
     impl ApiClient {
-        fn from_env() -> Result<Self, String> {
-            let api_key =
-                env::var("API_KEY").map_err(|_| "API_KEY environment variable is required")?;
-
-            let api_secret = env::var("API_SECRET").ok();
-
-            let endpoint =
-                env::var("API_ENDPOINT").unwrap_or_else(|_| "https://api.example.com".to_string());
-
-            Ok(Self {
-                api_key,
-                api_secret,
-                endpoint,
-            })
+        fn from_env() -> Result<Self, ConfigError> {
+            let mut env = ConfigLoader::from_env();
+            let client = Self {
+                // `required` records API_KEY as missing instead of failing
+                // right away, so every missing key shows up in one error.
+                api_key: env.required("API_KEY"),
+                api_secret: env.maybe("API_SECRET"),
+                endpoint: env.optional("API_ENDPOINT", "https://api.example.com".to_string()),
+            };
+            env.finish()?;
+            Ok(client)
         }
     }
 
@@ -439,3 +440,15 @@ fn _example_build_info() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_name_survives_an_empty_argv() {
+        assert_eq!(program_name(&[]), "<unknown>");
+        assert_eq!(program_name(&["env_examples".to_string()]), "env_examples");
+        assert_eq!(program_name(&[String::new(), "x".to_string()]), "");
+    }
+}
@@ -0,0 +1,110 @@
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use rust_practice::utils::fuzz;
+use rust_practice::utils::sha256::{sha256, to_hex};
+
+// Fuzz the frame parser and everything behind it (see `utils::fuzz`).
+//
+// Every file in the corpus directory is checked first, as it is: those are
+// regression inputs, so `--replay` (checking only them) is the quick test
+// to run after touching the parsers. Then --iterations mutated inputs are
+// tried, derived from --seed. A failing input is saved to the corpus as
+// `crash-<hash>.bin`, so it's replayed from then on.
+//
+// Usage: cargo run --bin fuzz_frames -- [--seed N] [--iterations N] [--corpus DIR] [--replay]
+
+const DEFAULT_CORPUS: &str = "fuzz/corpus";
+const DEFAULT_ITERATIONS: u64 = 100_000;
+
+fn parse_flag<T: std::str::FromStr>(name: &str, value: Option<String>) -> std::io::Result<T> {
+    value
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} needs a number", name)))
+}
+
+fn load_corpus(dir: &Path) -> std::io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut paths = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| fs::read(&path).map(|bytes| (path, bytes)))
+        .collect()
+}
+
+fn run() -> std::io::Result<bool> {
+    let mut seed = 0;
+    let mut iterations = DEFAULT_ITERATIONS;
+    let mut corpus_dir = PathBuf::from(DEFAULT_CORPUS);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = parse_flag(&arg, args.next())?,
+            "--iterations" => iterations = parse_flag(&arg, args.next())?,
+            "--replay" => iterations = 0,
+            "--corpus" => {
+                corpus_dir = args.next().map(PathBuf::from).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--corpus needs a directory")
+                })?;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown argument '{}'", arg),
+                ));
+            }
+        }
+    }
+
+    // Panics are caught and reported with their input; don't print them
+    // as they happen too.
+    std::panic::set_hook(Box::new(|_| {}));
+    let corpus = load_corpus(&corpus_dir)?;
+    let inputs: Vec<Vec<u8>> = corpus.iter().map(|(_, bytes)| bytes.clone()).collect();
+    let report = fuzz::run(seed, iterations, &inputs);
+
+    for failure in &report.failures {
+        let known = corpus.iter().find(|(_, bytes)| *bytes == failure.input);
+        let path = match known {
+            Some((path, _)) => path.clone(),
+            None => {
+                let name = format!("crash-{}.bin", &to_hex(&sha256(&failure.input))[..16]);
+                fs::create_dir_all(&corpus_dir)?;
+                let path = corpus_dir.join(name);
+                fs::write(&path, &failure.input)?;
+                path
+            }
+        };
+        println!("FAIL {}: {}", path.display(), failure.reason);
+    }
+    println!(
+        "{} inputs ({} from {}, seed {}): {} failed",
+        report.executed,
+        corpus.len(),
+        corpus_dir.display(),
+        seed,
+        report.failures.len()
+    );
+    Ok(report.failures.is_empty())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
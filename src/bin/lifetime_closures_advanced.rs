@@ -1,8 +1,8 @@
-/// ADVANCED LIFETIMES WITH CLOSURES
-/// This file covers:
-/// 1. Returning closures with multiple input lifetimes
-/// 2. Capturing variables and returning references
-/// 3. Using Trait Objects (Box<dyn Fn>) with lifetimes
+//! ADVANCED LIFETIMES WITH CLOSURES
+//! This file covers:
+//! 1. Returning closures with multiple input lifetimes
+//! 2. Capturing variables and returning references
+//! 3. Using Trait Objects (Box<dyn Fn>) with lifetimes
 
 // ---------------------------------------------------------
 // CASE 1: Returning a closure that takes two references and returns one
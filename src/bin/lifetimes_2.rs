@@ -9,6 +9,8 @@ struct Broker<'a> {
 impl<'a> Broker<'a> {
     // CASE A: Broker-dependent Access ('b)
     // 'b is tied to &self. Meaning: The key is valid only as long as the Broker 'exists'.
+    // (clippy would elide 'b here; it is spelled out on purpose for the example)
+    #[allow(clippy::needless_lifetimes)]
     fn get_temporary_access<'b>(&'b self) -> &'b str {
         println!("Obtained a temporary key from the Broker...");
         self.building_address
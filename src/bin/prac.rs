@@ -26,7 +26,7 @@ fn main() -> Result<(), io::Error> {
     );
 
     let status = read_file(input.trim())?;
-    if status == true {
+    if status {
         print!("\nFile found and read its content successfully...\n");
     };
     print!("{status:?}");
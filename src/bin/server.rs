@@ -1,52 +1,925 @@
 use std::env;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-fn handle_client(mut stream: TcpStream) {
+use rust_practice::storage::audit::AuditLog;
+use rust_practice::storage::counters::PersistentCounters;
+use rust_practice::storage::now_ms;
+use rust_practice::storage::retention::{self, Policy, Target};
+use rust_practice::storage::tokens::{TokenGate, TokenStore};
+use rust_practice::storage::undo;
+use rust_practice::utils::alerts::{AlertRule, AlertState, Alerts};
+use rust_practice::utils::build_info::BUILD_INFO;
+use rust_practice::utils::config::ConfigLoader;
+use rust_practice::utils::echo::{EchoServer, transform_echo};
+use rust_practice::utils::file_handling::{JsonFileError, write_json};
+use rust_practice::utils::health::{Health, HealthConfig, HealthRegistry, Role};
+use rust_practice::utils::mutation::ApplyToDisk;
+use rust_practice::utils::protocol::{MAX_FRAME_LEN, read_frame};
+use rust_practice::utils::scope::{CancelToken, ScopeError, panic_message, scope};
+use rust_practice::utils::server_stats::ServerStats;
+use rust_practice::utils::startup::{Startup, StartupPlan, Strictness};
+use rust_practice::utils::supervisor::{Supervisor, SupervisorConfig};
+use rust_practice::utils::test_closure::FileLogger;
+use rust_practice::utils::text_protocol::{self, TextCommand};
+use rust_practice::utils::trace::{self, Span};
+use rust_practice::utils::transport::Handler;
+use rust_practice::utils::vm::Program;
+use rust_practice::{
+    ConsoleLogger, DEFAULT_ADDR, LogFormat, LogLevel, Logger, LoggerExt, StderrLogger,
+};
+
+// What every connection's handler shares.
+struct Services<'a> {
+    logger: &'a (dyn Logger + Send + Sync),
+    counters: Option<&'a PersistentCounters>,
+    gate: Option<&'a TokenGate>,
+    stats: &'a ServerStats,
+    // With --transform, what echoes go through.
+    transform: Option<&'a Arc<Program>>,
+    // With --trace, time each connection's steps with `trace` spans.
+    trace: bool,
+}
+
+// A `trace` span for `name` if tracing is on; it ends when dropped.
+fn span(on: bool, name: &'static str) -> Option<Span> {
+    on.then(|| Span::enter(name))
+}
+
+fn handle_client(mut stream: TcpStream, services: &Services, token: &CancelToken) {
+    let Services {
+        logger,
+        counters,
+        gate,
+        stats,
+        transform,
+        trace,
+    } = *services;
+    let _connection = span(trace, "connection");
     let peer = stream.peer_addr().ok();
-    let mut buf = [0u8; 1024];
+    let from = peer.map(|p| p.to_string()).unwrap_or_default();
+    let client = peer.map(|p| p.ip().to_string()).unwrap_or_default();
+    let _active = stats.connection_opened(&client);
+    // A client that starts with a letter is typing commands, not sending
+    // frames.
+    let mut first = [0; 1];
+    let peeked =
+        await_message(&stream, token, || stream.peek(&mut first).map(|_| ())).and_then(|ready| {
+            match ready {
+                true => stream.peek(&mut first),
+                // Shutting down: as good as closed.
+                false => Ok(0),
+            }
+        });
+    match peeked {
+        Ok(0) => return,
+        Ok(_) if text_protocol::is_text_start(first[0]) => {
+            return handle_text_client(stream, &client, services, token);
+        }
+        // Frames have nowhere to carry a token, so with --tokens only the
+        // line protocol is served.
+        Ok(_) if gate.is_some() => {
+            logger.log(
+                LogLevel::Warn,
+                &format!("{}: framed client refused: --tokens needs AUTH", from),
+            );
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            stats.record_error();
+            logger.log(LogLevel::Error, &format!("read error: {}", e));
+            return;
+        }
+    }
+    let mut server = match transform {
+        Some(transform) => EchoServer::with_transform(Arc::clone(transform)),
+        None => EchoServer::default(),
+    };
     loop {
-        match stream.read(&mut buf) {
-            Ok(0) => {
+        let ready = {
+            let _wait = span(trace, "wait");
+            await_message(&stream, token, || stream.peek(&mut [0]).map(|_| ()))
+        };
+        let message = ready.and_then(|ready| {
+            let _read = span(trace, "read");
+            match ready {
+                true => read_frame(&mut stream, MAX_FRAME_LEN),
+                false => Ok(None),
+            }
+        });
+        match message {
+            Ok(None) => {
                 // connection closed
                 break;
             }
-            Ok(n) => {
-                // echo back
-                let _ = stream.write_all(&buf[..n]);
-                println!(
-                    "echoed {} bytes {}",
-                    n,
-                    peer.map(|p| format!("to {}", p)).unwrap_or_default()
+            Ok(Some(message)) => {
+                stats.message_received(&client, message.len());
+                // The protocol logic is shared with simulations; over TCP
+                // a reply can only go back down this same stream.
+                let echoed = {
+                    let _echo = span(trace, "echo");
+                    server.on_message(&from, &message, &mut stream)
+                };
+                if let Err(e) = echoed {
+                    stats.record_error();
+                    logger.log(LogLevel::Error, &format!("write error: {}", e));
+                    break;
+                }
+                stats.add_bytes_out(message.len());
+                if let Some(counters) = counters {
+                    counters.add("bytes_echoed", message.len() as u64);
+                }
+                logger.log(
+                    LogLevel::Debug,
+                    &format!(
+                        "echoed {} bytes {}",
+                        message.len(),
+                        peer.map(|p| format!("to {}", p)).unwrap_or_default()
+                    ),
                 );
             }
             Err(e) => {
-                eprintln!("read error: {}", e);
+                // Also covers an oversized or truncated frame: there's no
+                // way to find the next frame after one, so hang up.
+                stats.record_error();
+                logger.log(LogLevel::Error, &format!("read error: {}", e));
                 break;
             }
         }
     }
 }
 
+// The same connection speaking `text_protocol`: one command per line, one
+// reply line each.
+fn handle_text_client(stream: TcpStream, client: &str, services: &Services, token: &CancelToken) {
+    let Services {
+        logger,
+        counters,
+        gate,
+        stats,
+        transform,
+        trace,
+    } = *services;
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            stats.record_error();
+            logger.log(LogLevel::Error, &format!("clone stream: {}", e));
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    // The token from the connection's last accepted AUTH.
+    let mut auth = None;
+    loop {
+        // The read timeout is the socket's, so it's set through the writer.
+        let ready = {
+            let _wait = span(trace, "wait");
+            await_message(&writer, token, || reader.fill_buf().map(|_| ()))
+        };
+        let line = ready.and_then(|ready| {
+            let _read = span(trace, "read");
+            match ready {
+                true => text_protocol::read_line(&mut reader),
+                false => Ok(None),
+            }
+        });
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                stats.record_error();
+                logger.log(LogLevel::Error, &format!("read error: {}", e));
+                break;
+            }
+        };
+        stats.message_received(client, line.len());
+        // A mistyped command is the client's problem, not a server error.
+        let command = {
+            let _parse = span(trace, "parse");
+            TextCommand::parse(&line)
+        };
+        let reply = {
+            let _reply = span(trace, "reply");
+            command
+                .as_ref()
+                .map_err(String::clone)
+                .and_then(|command| text_reply(command, gate, transform, &mut auth, client))
+        };
+        let allowed = reply.is_ok();
+        let reply = reply.unwrap_or_else(|e| format!("ERR {}", e));
+        let written = {
+            let _write = span(trace, "write");
+            writer.write_all(format!("{}\n", reply).as_bytes())
+        };
+        if let Err(e) = written {
+            stats.record_error();
+            logger.log(LogLevel::Error, &format!("write error: {}", e));
+            break;
+        }
+        stats.add_bytes_out(reply.len());
+        match &command {
+            Ok(TextCommand::Quit) => break,
+            Ok(TextCommand::Echo(text)) if allowed => {
+                if let Some(counters) = counters {
+                    counters.add("bytes_echoed", text.len() as u64);
+                }
+            }
+            _ => {}
+        }
+        // Logged as parsed, which leaves out AUTH's token.
+        let shown = command.map_or(line, |command| command.to_string());
+        logger.log(
+            LogLevel::Debug,
+            &format!("{}: {:?} -> {:?}", client, shown, reply),
+        );
+    }
+}
+
+// Wait for the start of a client's next message, checking `token` every
+// `CANCEL_POLL_INTERVAL`; `ready` is a read that fails with a timeout until
+// something arrives. Returns false once the token is cancelled. The rest
+// of the message then has `MESSAGE_TIMEOUT` to arrive.
+fn await_message(
+    stream: &TcpStream,
+    token: &CancelToken,
+    mut ready: impl FnMut() -> io::Result<()>,
+) -> io::Result<bool> {
+    stream.set_read_timeout(Some(CANCEL_POLL_INTERVAL))?;
+    loop {
+        match ready() {
+            Ok(()) => break,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if token.is_cancelled() {
+                    return Ok(false);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    stream.set_read_timeout(Some(MESSAGE_TIMEOUT))?;
+    Ok(true)
+}
+
+// The reply to one command, or the reason it's refused. With --tokens,
+// everything but AUTH and QUIT needs a token whose scopes allow it.
+fn text_reply(
+    command: &TextCommand,
+    gate: Option<&TokenGate>,
+    transform: Option<&Arc<Program>>,
+    auth: &mut Option<String>,
+    client: &str,
+) -> Result<String, String> {
+    let reply = || match (command, transform) {
+        (TextCommand::Echo(text), Some(transform)) => transform_echo(transform, text),
+        _ => command.reply(SystemTime::now()),
+    };
+    let gate = match gate {
+        Some(gate) if *command != TextCommand::Quit => gate,
+        _ => return Ok(reply()),
+    };
+    let denied = |denied| format!("denied: {}", denied);
+    match command {
+        TextCommand::Auth(presented) => {
+            let id = gate.check(Some(presented), None, client).map_err(denied)?;
+            *auth = Some(presented.clone());
+            Ok(format!("OK {}", id))
+        }
+        command => {
+            gate.check(auth.as_deref(), Some(command.method()), client)
+                .map_err(denied)?;
+            Ok(reply())
+        }
+    }
+}
+
+// With --log-file, the log rotates once it passes this size, keeping this
+// many old files next to it.
+const LOG_MAX_BYTES: u64 = 1024 * 1024;
+const LOG_KEEP: usize = 3;
+
+// With --counters, lifetime counts are written back this often.
+const COUNTERS_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often a stats summary is logged, unless --stats-interval says
+// otherwise (0 turns it off).
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// With --stats-file, the per-client stats are written out this often.
+const STATS_FILE_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often --alert rules are checked against the stats.
+const ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often --retention policies are enforced, unless --gc-interval says
+// otherwise.
+const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(3600);
+
+// How often the listener is checked for new connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How often a connection waiting for its client's next message checks for
+// shutdown.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Once a message has started arriving, how long the rest of it may take.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
 fn main() -> std::io::Result<()> {
-    // Allow overriding address via CLI args
-    // Usage: cargo run --bin server -- [ADDR]
-    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:4000".to_string());
-    let listener = TcpListener::bind(&addr)?;
+    // Allow overriding address and logging to a file via CLI args
+    // Usage: cargo run --bin server -- [ADDR] [--log-file PATH [--log-stderr]]
+    //        [--log-format bare|human|compact] [--counters PATH [--reset-counters]]
+    //        [--stats-interval SECS] [--alert RULE]... [--stats-file PATH]
+    //        [--tokens PATH [--audit PATH]]
+    //        [--retention STORE:POLICY]... [--gc-interval SECS]
+    //        [--transform SCRIPT] [--trace]
+    let mut addr = None;
+    let mut log_file = None;
+    // With --log-file, also keep logging to stderr.
+    let mut log_stderr = false;
+    // Without --log-format, lines look as they always have.
+    let mut log_format = None;
+    let mut counters_file = None;
+    let mut reset_counters = false;
+    let mut stats_interval = DEFAULT_STATS_INTERVAL;
+    let mut alert_rules = Vec::new();
+    let mut stats_file = None;
+    // With --tokens, text clients must AUTH and frame clients are refused.
+    let mut tokens_file = None;
+    let mut audit_file = None;
+    // Stores to hold to a retention policy: sessions (the --counters
+    // history), undo runs, rotated --log-file copies.
+    let mut retention = Vec::new();
+    let mut gc_interval = DEFAULT_GC_INTERVAL;
+    // With --transform, a `vm` script every echo goes through.
+    let mut transform = None;
+    // With --trace, each connection's timings are logged when it closes.
+    let mut trace = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--log-file" {
+            log_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "--log-file needs a path")
+            })?);
+        } else if arg == "--log-format" {
+            let format = args.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--log-format needs bare, human or compact",
+                )
+            })?;
+            log_format = Some(
+                format
+                    .parse::<LogFormat>()
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+            );
+        } else if arg == "--log-stderr" {
+            log_stderr = true;
+        } else if arg == "--counters" {
+            counters_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "--counters needs a path")
+            })?);
+        } else if arg == "--reset-counters" {
+            reset_counters = true;
+        } else if arg == "--alert" {
+            let rule = args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "--alert needs a rule")
+            })?;
+            let rule = AlertRule::parse(&rule)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            if !ServerStats::new()
+                .snapshot()
+                .metrics()
+                .iter()
+                .any(|(name, _)| *name == rule.metric)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("--alert: no metric named '{}'", rule.metric),
+                ));
+            }
+            alert_rules.push(rule);
+        } else if arg == "--stats-file" {
+            stats_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--stats-file needs a path",
+                )
+            })?);
+        } else if arg == "--tokens" {
+            tokens_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "--tokens needs a path")
+            })?);
+        } else if arg == "--audit" {
+            audit_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "--audit needs a path")
+            })?);
+        } else if arg == "--transform" {
+            let path = args.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--transform needs a script",
+                )
+            })?;
+            let source = std::fs::read_to_string(&path)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            let program = Program::assemble(&source).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {}", path, e))
+            })?;
+            transform = Some(Arc::new(program));
+        } else if arg == "--trace" {
+            trace = true;
+        } else if arg == "--retention" {
+            let spec = args.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--retention needs STORE:POLICY",
+                )
+            })?;
+            let invalid = |e: String| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("--retention: {}", e),
+                )
+            };
+            let (target, policy) = spec
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("expected STORE:POLICY, got '{}'", spec)))?;
+            retention.push((
+                target.parse::<Target>().map_err(invalid)?,
+                policy.parse::<Policy>().map_err(invalid)?,
+            ));
+        } else if arg == "--gc-interval" {
+            gc_interval = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "--gc-interval needs a number of seconds",
+                    )
+                })?;
+        } else if arg == "--stats-interval" {
+            stats_interval = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "--stats-interval needs a number of seconds",
+                    )
+                })?;
+        } else if arg.starts_with("--") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown option '{}'", arg),
+            ));
+        } else if let Some(first) = &addr {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("one address only: got '{}' after '{}'", arg, first),
+            ));
+        } else {
+            addr = Some(arg);
+        }
+    }
+    let addr = addr.unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    if reset_counters && counters_file.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--reset-counters needs --counters",
+        ));
+    }
+    if audit_file.is_some() && tokens_file.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--audit needs --tokens",
+        ));
+    }
+    for (target, _) in &retention {
+        let needs = match target {
+            Target::Sessions if counters_file.is_none() => "--counters",
+            Target::Logs if log_file.is_none() => "--log-file",
+            _ => continue,
+        };
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--retention {} needs {}", target, needs),
+        ));
+    }
+    // RUST_PRACTICE_STRICTNESS=strict makes an optional subsystem failing to
+    // start (the log file or the counters file) fatal; by default the server runs
+    // without it and says so.
+    let mut config = ConfigLoader::with_dotenv();
+    let strictness = config.optional("RUST_PRACTICE_STRICTNESS", Strictness::default());
+    config
+        .finish()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let startup = Startup::new(strictness);
+
+    // The log file and the listener don't depend on each other, so they're
+    // started in parallel; each step leaves what it built in its slot.
+    let logger_slot = OnceLock::new();
+    let listener_slot = OnceLock::new();
+    let counters_slot = OnceLock::new();
+    let gate_slot = OnceLock::new();
+    let timeline = StartupPlan::new()
+        .step("log file", &[], || {
+            let stderr = || -> Arc<dyn Logger + Send + Sync> {
+                match log_format {
+                    Some(format) => Arc::new(ConsoleLogger::new(format).with_target("server")),
+                    None => Arc::new(StderrLogger),
+                }
+            };
+            let logger: Arc<dyn Logger + Send + Sync> = match &log_file {
+                Some(path) => startup
+                    .optional(
+                        "log file",
+                        || {
+                            let mut file = FileLogger::new(path, LOG_MAX_BYTES, LOG_KEEP)
+                                .map_err(|e| format!("{}: {}", path, e))?;
+                            if let Some(format) = log_format {
+                                file = file.with_format(format).with_target("server");
+                            }
+                            Ok::<_, String>(if log_stderr {
+                                Arc::new(file.and(stderr())) as Arc<dyn Logger + Send + Sync>
+                            } else {
+                                Arc::new(file)
+                            })
+                        },
+                        stderr,
+                    )
+                    .map_err(|e| e.to_string())?,
+                None => stderr(),
+            };
+            let _ = logger_slot.set(logger);
+            Ok(())
+        })
+        .step("listener", &[], || {
+            let listener = startup
+                .critical("listener", || TcpListener::bind(&addr))
+                .map_err(|e| e.to_string())?;
+            let _ = listener_slot.set(listener);
+            Ok(())
+        })
+        .step("counters", &[], || {
+            let counters = match &counters_file {
+                Some(path) => startup
+                    .optional(
+                        "counters",
+                        || {
+                            let counters = PersistentCounters::open(path)?;
+                            if reset_counters {
+                                counters.reset()?;
+                            }
+                            Ok::<_, JsonFileError>(Some(Arc::new(counters)))
+                        },
+                        || None,
+                    )
+                    .map_err(|e| e.to_string())?,
+                None => None,
+            };
+            let _ = counters_slot.set(counters);
+            Ok(())
+        })
+        // Never optional: running without the tokens would let everyone in.
+        .step("tokens", &[], || {
+            let gate = match &tokens_file {
+                Some(path) => Some(
+                    startup
+                        .critical("tokens", || {
+                            let tokens =
+                                TokenStore::open(path).map_err(|e| format!("{}: {}", path, e))?;
+                            let audit = match &audit_file {
+                                Some(path) => Some(
+                                    AuditLog::open(path).map_err(|e| format!("{}: {}", path, e))?,
+                                ),
+                                None => None,
+                            };
+                            Ok::<_, String>(TokenGate::new(tokens, audit))
+                        })
+                        .map_err(|e| e.to_string())?,
+                ),
+                None => None,
+            };
+            let _ = gate_slot.set(gate);
+            Ok(())
+        })
+        .run()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let logger = logger_slot.into_inner().expect("set by its step");
+    let listener = listener_slot.into_inner().expect("set by its step");
+    let counters = counters_slot.into_inner().expect("set by its step");
+    let gate = gate_slot.into_inner().expect("set by its step");
+    logger.log(LogLevel::Debug, &timeline.to_string());
+
     println!("server listening on {}", addr);
+    logger.log(
+        LogLevel::Info,
+        &format!("{} listening on {}", BUILD_INFO, addr),
+    );
+    for degraded in startup.degraded() {
+        println!("warning: {}", degraded);
+        logger.log(LogLevel::Warn, &degraded.to_string());
+    }
 
-    // Accept connections and handle each in its own thread
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream_obj) => {
-                thread::spawn(|| handle_client(stream_obj));
-            }
-            Err(e) => eprintln!("accept error: {}", e),
+    // What the stats summary reports as live and ready. A subsystem that
+    // works is believed at once; one that fails has to keep failing before
+    // it counts as down, so one bad write doesn't flip readiness.
+    let health = Arc::new(HealthRegistry::new(HealthConfig {
+        rise: 1,
+        ..HealthConfig::default()
+    }));
+    health.register("listener", Role::Liveness);
+    report_health(&health, &*logger, "listener", Health::Up, "listening");
+    let optional = [
+        ("log file", log_file.is_some(), Role::Info),
+        ("counters", counters_file.is_some(), Role::Readiness),
+    ];
+    for (name, _, role) in optional.iter().filter(|(_, configured, _)| *configured) {
+        health.register(name, *role);
+        let degraded = startup
+            .degraded()
+            .into_iter()
+            .find(|d| d.component == *name);
+        match degraded {
+            Some(d) => report_health(&health, &*logger, name, Health::Degraded, &d.error),
+            None => report_health(&health, &*logger, name, Health::Up, "started"),
+        }
+    }
+
+    // Keep flushing the counters and logging stats even if a round panics;
+    // a failed write is only logged, and retried on the next round. Workers
+    // beat once a round, so they're given a few of the longest round.
+    let supervisor = Supervisor::with_logger(
+        SupervisorConfig {
+            heartbeat_timeout: COUNTERS_FLUSH_INTERVAL.max(stats_interval).max(
+                if retention.is_empty() {
+                    Duration::ZERO
+                } else {
+                    gc_interval
+                },
+            ) * 3,
+            ..SupervisorConfig::default()
+        },
+        Arc::clone(&logger),
+    );
+    if let Some(counters) = &counters {
+        for value in counters.snapshot() {
+            logger.log(
+                LogLevel::Info,
+                &format!("{}: {} lifetime", value.name, value.lifetime),
+            );
         }
+        let counters = Arc::clone(counters);
+        let logger = Arc::clone(&logger);
+        let health = Arc::clone(&health);
+        supervisor.spawn("counters flush", move |heartbeat| {
+            while !heartbeat.sleep(COUNTERS_FLUSH_INTERVAL) {
+                match counters.flush() {
+                    Ok(()) => report_health(&health, &*logger, "counters", Health::Up, "flushed"),
+                    Err(e) => {
+                        logger.log(LogLevel::Error, &format!("flushing counters: {}", e));
+                        let detail = format!("flushing: {}", e);
+                        report_health(&health, &*logger, "counters", Health::Down, &detail);
+                    }
+                }
+                heartbeat.beat();
+            }
+        });
     }
 
-    Ok(())
+    // Log what the server has been doing every so often, for operators
+    // watching the log.
+    let stats = Arc::new(ServerStats::new());
+    if !stats_interval.is_zero() {
+        let stats = Arc::clone(&stats);
+        let logger = Arc::clone(&logger);
+        let health = Arc::clone(&health);
+        supervisor.spawn("stats summary", move |heartbeat| {
+            while !heartbeat.sleep(stats_interval) {
+                logger.log(
+                    LogLevel::Info,
+                    &format!("stats: {}; {}", stats.snapshot(), health.report_now()),
+                );
+                heartbeat.beat();
+            }
+        });
+    }
+
+    // Keep --stats-file up to date for `rust-practice stats clients`.
+    if let Some(path) = stats_file {
+        let stats = Arc::clone(&stats);
+        let logger = Arc::clone(&logger);
+        supervisor.spawn("stats file", move |heartbeat| {
+            while !heartbeat.sleep(STATS_FILE_INTERVAL) {
+                if let Err(e) = write_json(Path::new(&path), &stats.report(), true) {
+                    logger.log(LogLevel::Error, &format!("writing stats: {}", e));
+                }
+                heartbeat.beat();
+            }
+        });
+    }
+
+    // Watch the stats for --alert rules: each one firing or resolving is
+    // logged once, at Warn and Info.
+    if !alert_rules.is_empty() {
+        let stats = Arc::clone(&stats);
+        let logger = Arc::clone(&logger);
+        supervisor.spawn("alerts", move |heartbeat| {
+            let mut alerts = Alerts::new(alert_rules.clone());
+            while !heartbeat.sleep(ALERT_CHECK_INTERVAL) {
+                let snapshot = stats.snapshot();
+                for event in alerts.evaluate(Instant::now(), &snapshot.metrics()) {
+                    let level = match event.state {
+                        AlertState::Firing => LogLevel::Warn,
+                        AlertState::Resolved => LogLevel::Info,
+                    };
+                    logger.log(level, &event.to_string());
+                }
+                heartbeat.beat();
+            }
+        });
+    }
+
+    // Hold the --retention stores to their policies, once at startup and
+    // then every --gc-interval. Sessions go through the open counters, so a
+    // collection can't undo a flush; what's removed is logged.
+    if !retention.is_empty() {
+        let counters = counters.clone();
+        let logger = Arc::clone(&logger);
+        supervisor.spawn("gc", move |heartbeat| {
+            loop {
+                for (target, policy) in &retention {
+                    let collected = match (target, &counters) {
+                        (Target::Sessions, Some(counters)) => {
+                            collect_sessions(counters, policy).map_err(|e| e.to_string())
+                        }
+                        // The counters file failed to open; that's been
+                        // said already.
+                        (Target::Sessions, None) => continue,
+                        (Target::Undo, _) => retention::collect(
+                            Target::Undo,
+                            &undo::undo_dir_from_env(),
+                            policy,
+                            now_ms(),
+                            &mut ApplyToDisk,
+                        )
+                        .map_err(|e| e.to_string()),
+                        (Target::Logs, _) => retention::collect(
+                            Target::Logs,
+                            Path::new(log_file.as_deref().unwrap_or_default()),
+                            policy,
+                            now_ms(),
+                            &mut ApplyToDisk,
+                        )
+                        .map_err(|e| e.to_string()),
+                    };
+                    match collected {
+                        Ok(report) if report.removed.is_empty() => {}
+                        Ok(report) => logger.log(LogLevel::Info, &format!("gc: {}", report)),
+                        Err(e) => logger.log(LogLevel::Error, &format!("gc {}: {}", target, e)),
+                    }
+                }
+                heartbeat.beat();
+                if heartbeat.sleep(gc_interval) {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Accept connections and handle each in its own thread. The threads are
+    // scoped, so none outlives the server: once the scope is cancelled (only
+    // a failure of the server itself does that), the listener stops and each
+    // connection notices within `CANCEL_POLL_INTERVAL`, even one whose
+    // client is idle. A handler that panics is caught in its own thread.
+    // std can't interrupt a blocking accept, so the listener is polled.
+    listener.set_nonblocking(true)?;
+    scope(|s| {
+        while !s.is_cancelled() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => {
+                    report_health(&health, &*logger, "listener", Health::Up, "accepting");
+                    stream
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    stats.record_error();
+                    logger.log(LogLevel::Error, &format!("accept error: {}", e));
+                    let detail = format!("accept: {}", e);
+                    report_health(&health, &*logger, "listener", Health::Down, &detail);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(false) {
+                stats.record_error();
+                logger.log(LogLevel::Error, &format!("accept error: {}", e));
+                continue;
+            }
+            if let Some(counters) = &counters {
+                counters.add("connections", 1);
+            }
+            let services = Services {
+                logger: &*logger,
+                counters: counters.as_deref(),
+                gate: gate.as_ref(),
+                stats: &stats,
+                transform: transform.as_ref(),
+                trace,
+            };
+            s.spawn(move |token| {
+                let peer = stream.peer_addr().ok();
+                // One connection's bug shouldn't take everyone else's down:
+                // its panic is logged and only its connection is dropped.
+                let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle_client(stream, &services, token)
+                }));
+                if let Err(payload) = handled {
+                    services.stats.record_error();
+                    services.logger.log(
+                        LogLevel::Error,
+                        &format!("connection handler panicked: {}", panic_message(&*payload)),
+                    );
+                }
+                // The spans were all entered on this thread, so what's
+                // recorded here is this connection's alone.
+                if services.trace {
+                    let report = trace::take_report();
+                    let peer = peer.map_or_else(|| "unknown peer".to_string(), |p| p.to_string());
+                    services.logger.log(
+                        LogLevel::Info,
+                        &format!("trace {}:\n{}", peer, report.render().trim_end()),
+                    );
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    })
+    .map_err(|e: ScopeError<std::io::Error>| std::io::Error::other(e.to_string()))
 }
 
+// Report `name`'s health, logging when the state in effect changes.
+fn report_health(
+    health: &HealthRegistry,
+    logger: &dyn Logger,
+    name: &str,
+    reported: Health,
+    detail: &str,
+) {
+    let before = health.health_of(name);
+    let now = health.report(name, reported, detail);
+    if before != Some(now) {
+        let level = match now {
+            Health::Up => LogLevel::Info,
+            Health::Degraded | Health::Down => LogLevel::Warn,
+        };
+        logger.log(level, &format!("health: {} is {} ({})", name, now, detail));
+    }
+}
 
+// `retention::collect` for the history in the server's own counters file.
+// This process's session is still being written, so it's never a
+// candidate.
+fn collect_sessions(
+    counters: &PersistentCounters,
+    policy: &Policy,
+) -> Result<retention::GcReport, JsonFileError> {
+    let mut history = counters.history();
+    history.retain(|session| session.id != counters.session_id());
+    let mut items = retention::session_items(&history);
+    let dropped = policy.expired(&items, now_ms());
+    let kept = items.len() - dropped.len();
+    let mut removed = Vec::new();
+    for i in dropped.into_iter().rev() {
+        removed.insert(0, items.remove(i));
+    }
+    if !removed.is_empty() {
+        let ids: Vec<String> = removed.iter().map(|item| item.name.clone()).collect();
+        counters.remove_sessions(&ids)?;
+    }
+    Ok(retention::GcReport {
+        target: Target::Sessions,
+        path: counters.path().to_path_buf(),
+        removed,
+        kept,
+    })
+}
@@ -0,0 +1,324 @@
+use std::env;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, watch};
+use tokio::task::JoinSet;
+
+use rust_practice::utils::async_log::{AsyncLogger, Background, Inline};
+use rust_practice::utils::build_info::BUILD_INFO;
+use rust_practice::utils::echo::EchoServer;
+use rust_practice::utils::file_handling::write_json;
+use rust_practice::utils::protocol::FrameDecoder;
+use rust_practice::utils::server_stats::ServerStats;
+use rust_practice::utils::test_closure::FileLogger;
+use rust_practice::utils::transport::Handler;
+use rust_practice::{ConsoleLogger, DEFAULT_ADDR, LogFormat, LogLevel, StderrLogger};
+
+// The echo server again, on tokio: a task per connection instead of an OS
+// thread, and at most `--max-connections` of them at once. Connections
+// beyond that wait in the listen backlog until a slot frees up.
+//
+// Ctrl-C stops accepting and tells every connection to finish; any still
+// open after SHUTDOWN_GRACE are aborted.
+//
+// Logs go to stderr, or with --log-file to a rotating file written from a
+// background thread, so a slow disk doesn't hold up the connection tasks.
+// --log-format picks a layout for them (see `log_format`); a file's lines
+// name the writer thread, not the task that logged.
+//
+// Like `server`, it logs a stats summary every --stats-interval seconds
+// (0 turns it off), and with --stats-file keeps per-client stats in a file
+// for `rust-practice stats clients`.
+//
+// Usage: cargo run --bin server_async -- [ADDR] [--max-connections N] [--log-file PATH]
+//        [--log-format bare|human|compact] [--stats-interval SECS] [--stats-file PATH]
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60);
+const STATS_FILE_INTERVAL: Duration = Duration::from_secs(5);
+
+// With --log-file, the same rotation as `server`, and room for this many
+// log lines waiting to be written.
+const LOG_MAX_BYTES: u64 = 1024 * 1024;
+const LOG_KEEP: usize = 3;
+const LOG_QUEUE: usize = 1024;
+
+async fn handle_client(
+    mut stream: TcpStream,
+    mut shutdown: watch::Receiver<bool>,
+    logger: &dyn AsyncLogger,
+    stats: &ServerStats,
+) {
+    let peer = stream.peer_addr().ok();
+    let from = peer.map(|p| p.to_string()).unwrap_or_default();
+    let client = peer.map(|p| p.ip().to_string()).unwrap_or_default();
+    let _active = stats.connection_opened(&client);
+    let mut server = EchoServer::default();
+    let mut frames = FrameDecoder::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = tokio::select! {
+            read = stream.read(&mut buf) => match read {
+                // connection closed
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    stats.record_error();
+                    logger.log(LogLevel::Error, &format!("read error: {}", e)).await;
+                    break;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        frames.push(&buf[..n]);
+        // The handler is synchronous, so collect its replies to every
+        // complete frame and write them here in one go.
+        let mut reply = Vec::new();
+        let mut echoed = 0;
+        let mut echoed_bytes = 0;
+        let mut bad_frame = false;
+        loop {
+            match frames.next_frame() {
+                Ok(Some(message)) => {
+                    stats.message_received(&client, message.len());
+                    let _ = server.on_message(&from, &message, &mut reply);
+                    echoed += 1;
+                    echoed_bytes += message.len();
+                }
+                Ok(None) => break,
+                // There's no finding the next frame after a bad one, so
+                // answer what came before it and hang up.
+                Err(e) => {
+                    stats.record_error();
+                    logger
+                        .log(LogLevel::Error, &format!("read error: {}", e))
+                        .await;
+                    bad_frame = true;
+                    break;
+                }
+            }
+        }
+        if let Err(e) = stream.write_all(&reply).await {
+            stats.record_error();
+            logger
+                .log(LogLevel::Error, &format!("write error: {}", e))
+                .await;
+            break;
+        }
+        stats.add_bytes_out(echoed_bytes);
+        if echoed > 0 {
+            logger
+                .log(
+                    LogLevel::Debug,
+                    &format!("echoed {} messages to {}", echoed, from),
+                )
+                .await;
+        }
+        if bad_frame {
+            break;
+        }
+    }
+}
+
+// Write `stats` to `path` off the runtime's threads; it syncs to disk.
+async fn write_stats(path: &str, stats: &ServerStats) -> Result<(), String> {
+    let path = path.to_string();
+    let report = stats.report();
+    tokio::task::spawn_blocking(move || write_json(Path::new(&path), &report, true))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("writing stats: {}", e))
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut max_connections = DEFAULT_MAX_CONNECTIONS;
+    let mut log_file = None;
+    let mut log_format = None;
+    let mut stats_interval = DEFAULT_STATS_INTERVAL;
+    let mut stats_file = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--max-connections" {
+            max_connections = args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .filter(|&n| n > 0)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "--max-connections needs a positive number",
+                    )
+                })?;
+        } else if arg == "--log-file" {
+            log_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "--log-file needs a path")
+            })?);
+        } else if arg == "--log-format" {
+            let format = args.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--log-format needs bare, human or compact",
+                )
+            })?;
+            log_format = Some(
+                format
+                    .parse::<LogFormat>()
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+            );
+        } else if arg == "--stats-file" {
+            stats_file = Some(args.next().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--stats-file needs a path",
+                )
+            })?);
+        } else if arg == "--stats-interval" {
+            stats_interval = args
+                .next()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "--stats-interval needs a number of seconds",
+                    )
+                })?;
+        } else {
+            addr = arg;
+        }
+    }
+
+    let logger: Arc<dyn AsyncLogger> = match &log_file {
+        Some(path) => {
+            let mut file = FileLogger::new(path, LOG_MAX_BYTES, LOG_KEEP)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            if let Some(format) = log_format {
+                file = file.with_format(format).with_target("server_async");
+            }
+            Arc::new(Background::spawn(file, LOG_QUEUE))
+        }
+        None => match log_format {
+            Some(format) => Arc::new(Inline(
+                ConsoleLogger::new(format).with_target("server_async"),
+            )),
+            None => Arc::new(Inline(StderrLogger)),
+        },
+    };
+
+    let listener = TcpListener::bind(&addr).await?;
+    println!(
+        "server listening on {} (at most {} connections)",
+        addr, max_connections
+    );
+    logger.log(LogLevel::Info, &BUILD_INFO.to_string()).await;
+
+    let stats = Arc::new(ServerStats::new());
+    let summary = (!stats_interval.is_zero()).then(|| {
+        let stats = Arc::clone(&stats);
+        let logger = Arc::clone(&logger);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(stats_interval);
+            // The first tick is immediate, and there's nothing to say yet.
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                logger
+                    .log(LogLevel::Info, &format!("stats: {}", stats.snapshot()))
+                    .await;
+            }
+        })
+    });
+
+    let writer = stats_file.clone().map(|path| {
+        let stats = Arc::clone(&stats);
+        let logger = Arc::clone(&logger);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(STATS_FILE_INTERVAL);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = write_stats(&path, &stats).await {
+                    logger.log(LogLevel::Error, &e).await;
+                }
+            }
+        })
+    });
+
+    let slots = Arc::new(Semaphore::new(max_connections));
+    let (stop, stopped) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    loop {
+        // Take a slot before accepting, so connections over the limit stay
+        // in the backlog instead of being accepted and left waiting.
+        let slot = tokio::select! {
+            slot = slots.clone().acquire_owned() => slot.expect("the semaphore is never closed"),
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let stopped = stopped.clone();
+                    let logger = Arc::clone(&logger);
+                    let stats = Arc::clone(&stats);
+                    connections.spawn(async move {
+                        handle_client(stream, stopped, &*logger, &stats).await;
+                        drop(slot);
+                    });
+                }
+                Err(e) => {
+                    stats.record_error();
+                    logger.log(LogLevel::Error, &format!("accept error: {}", e)).await;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        // Reap finished connections so the set doesn't keep growing.
+        while connections.try_join_next().is_some() {}
+    }
+
+    println!(
+        "shutting down; closing {} open connections",
+        connections.len()
+    );
+    let _ = stop.send(true);
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        logger
+            .log(
+                LogLevel::Warn,
+                "connections still open after the grace period; aborting them",
+            )
+            .await;
+        connections.shutdown().await;
+    }
+    if let Some(writer) = writer {
+        writer.abort();
+        let _ = writer.await;
+    }
+    if let Some(path) = &stats_file
+        && let Err(e) = write_stats(path, &stats).await
+    {
+        logger.log(LogLevel::Error, &e).await;
+    }
+    if let Some(summary) = summary {
+        summary.abort();
+        // Wait for it to let go of its logger handle.
+        let _ = summary.await;
+    }
+    logger
+        .log(LogLevel::Info, &format!("stats: {}", stats.snapshot()))
+        .await;
+    // The last handle: with --log-file, this waits for queued lines to be
+    // written.
+    drop(logger);
+    Ok(())
+}
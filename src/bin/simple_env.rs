@@ -1,5 +1,9 @@
 use std::env;
 
+use rust_practice::LogLevel;
+use rust_practice::config_struct;
+use rust_practice::utils::config::{ConfigError, ConfigLoader};
+
 fn main() {
     println!("=== Simple & Practical Environment Variables in Rust ===\n");
 
@@ -16,35 +20,43 @@ fn main() {
 
     println!("--- Basic Usage ---\n");
 
+    // `ConfigLoader` reads and parses in one go, so there's no
+    // `env::var(...).unwrap_or_else(...).parse().unwrap_or(...)` chain per
+    // variable.
+    let mut env = ConfigLoader::from_env();
+
     // Method 1: Read with a default value (MOST COMMON)
-    let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
+    let port: u16 = env.optional("PORT", 3000);
     println!("Server will run on port: {}", port);
 
-    // Method 2: Parse to a specific type
-    let port_number: u16 = env::var("PORT")
-        .unwrap_or_else(|_| "3000".to_string())
-        .parse()
-        .unwrap_or(3000);
-    println!("Port as number: {}", port_number);
-
-    // Method 3: Check if a variable exists
-    if let Ok(database_url) = env::var("DATABASE_URL") {
-        println!("Database URL is set: {}", database_url);
-    } else {
-        println!("No DATABASE_URL found, using default");
+    // Method 2: Check if a variable exists
+    match env.maybe::<String>("DATABASE_URL") {
+        Some(database_url) => println!("Database URL is set: {}", database_url),
+        None => println!("No DATABASE_URL found, using default"),
     }
 
-    // Method 4: Boolean flags
-    let debug_mode = env::var("DEBUG")
-        .map(|v| v == "true" || v == "1")
-        .unwrap_or(false);
+    // Method 3: Boolean flags (true/false, 1/0, yes/no, on/off)
+    let debug_mode = env.flag("DEBUG");
     println!("Debug mode: {}", debug_mode);
 
+    // Method 4: Namespaced variables. With a prefix, `PORT` reads `MYAPP_PORT`,
+    // so several programs can share one environment without clashing.
+    let mut myapp = ConfigLoader::from_env().prefix("MYAPP_");
+    let myapp_port: u16 = myapp.optional("PORT", 3000);
+    println!("MYAPP_PORT: {}", myapp_port);
+
+    // Anything set but unparseable (`PORT=80x`) is reported here.
+    if let Err(e) = env.finish().and(myapp.finish()) {
+        println!("✗ {}", e);
+    }
+
     println!("\n--- Real-World Example: Application Config ---\n");
 
     // This is the pattern used in production apps
-    let config = AppConfig::from_env();
-    config.print();
+    match AppConfig::from_env() {
+        Ok(config) => config.print(),
+        Err(e) => println!("✗ {}", e),
+    }
 
     println!("\n--- Command Line Arguments (from your client.rs/server.rs) ---\n");
 
@@ -59,63 +71,46 @@ fn main() {
 // REAL-WORLD PATTERN: Configuration Struct
 // ============================================================
 
-struct AppConfig {
-    // Database
-    database_url: String,
-    db_pool_size: u32,
-
-    // Server
-    host: String,
-    port: u16,
-
-    // Features
-    debug_mode: bool,
-    log_level: String,
-
-    // Secrets (API keys, tokens, etc.)
-    api_key: Option<String>,
-    jwt_secret: Option<String>,
+// `config_struct!` declares the struct and the code that reads it: each
+// field comes from the variable named after it in upper case (`db_pool_size`
+// from `DB_POOL_SIZE`). Unlike `unwrap_or(8080)`, a value that is set but
+// doesn't parse (`PORT=80x`) is reported instead of being replaced by the
+// default, and all problems are reported together.
+config_struct! {
+    struct AppConfig {
+        // Database
+        database_url: String = optional("postgres://localhost:5432/myapp".to_string()),
+        db_pool_size: u32 = optional(10),
+
+        // Server
+        host: String = optional("127.0.0.1".to_string()),
+        port: u16 = optional(8080),
+
+        // Features
+        debug: bool = flag(),
+        log_level: LogLevel = optional(LogLevel::Info),
+
+        // Secrets (API keys, tokens, etc.); no defaults, for security
+        api_key: Option<String> = maybe(),
+        jwt_secret: Option<String> = maybe(),
+    }
 }
 
 impl AppConfig {
-    fn from_env() -> Self {
-        Self {
-            // Database configuration
-            database_url: env::var("DATABASE_URL")
-                .unwrap_or_else(|_| "postgres://localhost:5432/myapp".to_string()),
-            db_pool_size: env::var("DB_POOL_SIZE")
-                .unwrap_or_else(|_| "10".to_string())
-                .parse()
-                .unwrap_or(10),
-
-            // Server configuration
-            host: env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            port: env::var("PORT")
-                .unwrap_or_else(|_| "8080".to_string())
-                .parse()
-                .unwrap_or(8080),
-
-            // Feature flags
-            debug_mode: env::var("DEBUG")
-                .map(|v| v == "true" || v == "1")
-                .unwrap_or(false),
-            log_level: env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
-
-            // Optional secrets (don't have defaults for security)
-            api_key: env::var("API_KEY").ok(),
-            jwt_secret: env::var("JWT_SECRET").ok(),
-        }
+    fn from_env() -> Result<Self, ConfigError> {
+        // `.env` was already loaded in `main`; `with_dotenv()` would do it too.
+        ConfigLoader::from_env().load()
     }
 
     fn print(&self) {
         println!("Application Configuration:");
         println!(
             "  Database URL: {}",
-            self.mask_sensitive(&self.database_url)
+            Self::mask_sensitive(&self.database_url)
         );
         println!("  DB Pool Size: {}", self.db_pool_size);
         println!("  Server: {}:{}", self.host, self.port);
-        println!("  Debug Mode: {}", self.debug_mode);
+        println!("  Debug Mode: {}", self.debug);
         println!("  Log Level: {}", self.log_level);
         println!(
             "  API Key: {}",
@@ -135,11 +130,51 @@ impl AppConfig {
         );
     }
 
-    fn mask_sensitive(&self, s: &str) -> String {
-        if s.len() > 10 {
-            format!("{}...{}", &s[..5], &s[s.len() - 3..])
+    fn mask_sensitive(s: &str) -> String {
+        // Count characters, not bytes: slicing a byte index in the middle of
+        // a multi-byte character would panic.
+        let chars: Vec<char> = s.chars().collect();
+        if chars.len() > 10 {
+            let head: String = chars[..5].iter().collect();
+            let tail: String = chars[chars.len() - 3..].iter().collect();
+            format!("{}...{}", head, tail)
         } else {
             "***".to_string()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_values_are_fully_masked() {
+        for value in ["", "a", "0123456789", "éééééééééé"] {
+            assert_eq!(AppConfig::mask_sensitive(value), "***", "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn long_values_keep_their_ends() {
+        assert_eq!(
+            AppConfig::mask_sensitive("postgres://localhost:5432/myapp"),
+            "postg...app"
+        );
+        assert_eq!(AppConfig::mask_sensitive("01234567890"), "01234...890");
+    }
+
+    #[test]
+    fn multibyte_characters_are_not_split() {
+        // Byte offsets 5 and len - 3 both fall inside a character here.
+        assert_eq!(AppConfig::mask_sensitive("ééééééééééé"), "ééééé...ééé");
+        assert_eq!(
+            AppConfig::mask_sensitive("日本語のデータベース接続"),
+            "日本語のデ...ス接続"
+        );
+        assert_eq!(
+            AppConfig::mask_sensitive("🔑🔑🔑🔑🔑x🔑🔑🔑🔑🔑"),
+            "🔑🔑🔑🔑🔑...🔑🔑🔑"
+        );
+    }
+}
@@ -14,7 +14,7 @@ struct Bird<T> {
 
 trait Desc {
     fn get_desc(&self) -> String {
-        format!("Default get_desc() called !!")
+        "Default get_desc() called !!".to_string()
     }
 }
 
@@ -23,7 +23,7 @@ where
     T: Display,
 {
     fn get_desc(&self) -> String {
-        print!("The dog is {} and it has {} legs.\n", self.name, self.legs);
+        println!("The dog is {} and it has {} legs.", self.name, self.legs);
         format!("The dog is {} and it has {} legs.\n", self.name, self.legs)
     }
 }
@@ -0,0 +1,133 @@
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use rust_practice::DEFAULT_ADDR;
+
+// Echo messages through `udp_server`, one datagram each.
+//
+// UDP may drop a datagram (or its reply) without telling anyone, so each
+// message waits --timeout-ms for its echo and is sent again, up to
+// --retries more times, if none arrives. A reply to an earlier attempt
+// can turn up late; anything that doesn't match the message being waited
+// for is ignored.
+//
+// Usage: cargo run --bin udp_client -- [ADDR] [MESSAGE]...
+//            [--timeout-ms N] [--retries N] [--max-datagram N]
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_RETRIES: u32 = 3;
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+fn parse_flag<T: std::str::FromStr>(name: &str, value: Option<String>) -> std::io::Result<T> {
+    value
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} needs a number", name)))
+}
+
+fn main() -> std::io::Result<()> {
+    let mut positional = Vec::new();
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut retries = DEFAULT_RETRIES;
+    let mut max_datagram = MAX_UDP_PAYLOAD;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout-ms" => {
+                timeout = Duration::from_millis(parse_flag(&arg, args.next())?);
+            }
+            "--retries" => retries = parse_flag(&arg, args.next())?,
+            "--max-datagram" => max_datagram = parse_flag(&arg, args.next())?,
+            _ => positional.push(arg),
+        }
+    }
+    if timeout.is_zero() {
+        // A zero read timeout means "block forever" to std.
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--timeout-ms must be positive",
+        ));
+    }
+    let mut positional = positional.into_iter();
+    let addr = positional
+        .next()
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let mut messages: Vec<String> = positional.collect();
+    if messages.is_empty() {
+        messages.push("hello from udp client".to_string());
+    }
+
+    let server: SocketAddr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "address resolved to nothing"))?;
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let mut buf = vec![0u8; max_datagram];
+    for message in &messages {
+        if message.len() > max_datagram {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes is over --max-datagram {}",
+                    message.len(),
+                    max_datagram
+                ),
+            ));
+        }
+        let mut echoed = false;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                eprintln!(
+                    "no echo within {:?}; retry {} of {}",
+                    timeout, attempt, retries
+                );
+            }
+            socket.send_to(message.as_bytes(), server)?;
+            println!("sent: {:?}", message);
+            if wait_for_echo(&socket, server, message.as_bytes(), &mut buf)? {
+                echoed = true;
+                break;
+            }
+        }
+        if !echoed {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("no echo for {:?} after {} attempts", message, retries + 1),
+            ));
+        }
+        println!("recv: {:?}", message);
+    }
+    Ok(())
+}
+
+// Read until the echo of `message` arrives (true) or the read timeout
+// passes without it (false).
+fn wait_for_echo(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    message: &[u8],
+    buf: &mut [u8],
+) -> std::io::Result<bool> {
+    loop {
+        match socket.recv_from(buf) {
+            Ok((n, from)) if from == server && &buf[..n] == message => return Ok(true),
+            // A late echo of an earlier message, or a stranger.
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(false);
+            }
+            // The server's port answered "unreachable" to an earlier send;
+            // treat it like a lost datagram and let the retry loop decide.
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
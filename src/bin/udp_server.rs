@@ -0,0 +1,67 @@
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::net::UdpSocket;
+
+use rust_practice::utils::echo::EchoServer;
+use rust_practice::utils::transport::Handler;
+use rust_practice::{DEFAULT_ADDR, LogLevel, Logger, StderrLogger};
+
+// The echo server over UDP: every datagram is sent straight back to where
+// it came from. A datagram is already a message, so there is no framing,
+// no connection and nothing to clean up when a client goes away.
+//
+// Datagrams longer than --max-datagram bytes are truncated by the OS when
+// received (and then echoed truncated), so set it to at least the largest
+// message clients send.
+//
+// Usage: cargo run --bin udp_server -- [ADDR] [--max-datagram N]
+
+// The most a UDP datagram can carry over IPv4.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+fn main() -> std::io::Result<()> {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut max_datagram = MAX_UDP_PAYLOAD;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--max-datagram" {
+            max_datagram = args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .filter(|&n| n > 0 && n <= MAX_UDP_PAYLOAD)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "--max-datagram needs a number from 1 to {}",
+                            MAX_UDP_PAYLOAD
+                        ),
+                    )
+                })?;
+        } else {
+            addr = arg;
+        }
+    }
+
+    let mut socket = UdpSocket::bind(&addr)?;
+    println!("udp server listening on {}", addr);
+
+    let mut server = EchoServer::default();
+    let mut buf = vec![0u8; max_datagram];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                // On some platforms an ICMP "port unreachable" from an earlier
+                // reply surfaces here; it's about that client, not us.
+                StderrLogger.log(LogLevel::Warn, &format!("receive error: {}", e));
+                continue;
+            }
+        };
+        let peer = peer.to_string();
+        match server.on_message(&peer, &buf[..n], &mut socket) {
+            Ok(()) => StderrLogger.log(LogLevel::Debug, &format!("echoed {} bytes to {}", n, peer)),
+            Err(e) => StderrLogger.log(LogLevel::Error, &format!("send to {}: {}", peer, e)),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::io;
+
+use crate::utils::file_handling::JsonFileError;
+use crate::utils::i18n::tr;
+use crate::utils::output::Json;
+
+// The error type every subcommand returns.
+//
+// Each error has a category, which decides the process exit code, and a
+// short stable `code` naming the specific failure (`audit.tampered`,
+// `vm.assemble`, ...), so scripts can branch on either. `message` and
+// `hint` are for people and may change wording between releases.
+//
+// Exit codes (also listed in the Readme):
+//   2  usage      bad or missing arguments
+//   3  not-found  a file or directory doesn't exist
+//   4  permission not allowed to read or write something
+//   5  invalid    input that couldn't be parsed or converted
+//   6  check      a verification ran and found a problem
+//   7  io         any other I/O failure
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Usage,
+    NotFound,
+    Permission,
+    Invalid,
+    Check,
+    Io,
+}
+
+impl Category {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Category::Usage => 2,
+            Category::NotFound => 3,
+            Category::Permission => 4,
+            Category::Invalid => 5,
+            Category::Check => 6,
+            Category::Io => 7,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Usage => "usage",
+            Category::NotFound => "not-found",
+            Category::Permission => "permission",
+            Category::Invalid => "invalid",
+            Category::Check => "check",
+            Category::Io => "io",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppError {
+    pub category: Category,
+    pub code: &'static str,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl AppError {
+    pub fn new(category: Category, code: &'static str, message: String) -> Self {
+        Self {
+            category,
+            code,
+            message,
+            hint: None,
+        }
+    }
+
+    pub fn usage(message: String) -> Self {
+        Self::new(Category::Usage, "usage", message)
+    }
+
+    pub fn with_hint(mut self, hint: String) -> Self {
+        self.hint = Some(hint);
+        self
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        self.category.exit_code()
+    }
+
+    // The fields of the `error` JSON result.
+    pub fn json_fields(&self) -> Vec<(&'static str, Json)> {
+        vec![
+            ("code", self.code.into()),
+            ("category", self.category.as_str().into()),
+            ("exit_code", u32::from(self.exit_code()).into()),
+            ("message", self.message.as_str().into()),
+            ("hint", self.hint.clone().into()),
+        ]
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+// A file that can't be read is categorized like any I/O error; one that
+// was read but isn't the JSON expected is invalid input.
+impl From<JsonFileError> for AppError {
+    fn from(e: JsonFileError) -> Self {
+        match &e {
+            JsonFileError::Io { source, .. } => io::Error::new(source.kind(), e.to_string()).into(),
+            _ => Self::new(Category::Invalid, "invalid-data", e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        let message = e.to_string();
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::new(Category::NotFound, "not-found", message)
+                .with_hint(tr("hints.not-found", &[])),
+            io::ErrorKind::PermissionDenied => {
+                Self::new(Category::Permission, "permission-denied", message)
+                    .with_hint(tr("hints.permission-denied", &[]))
+            }
+            io::ErrorKind::InvalidInput => Self::usage(message),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                Self::new(Category::Invalid, "invalid-data", message)
+            }
+            _ => Self::new(Category::Io, "io", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::file_handling::read_json;
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("error-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn every_category_has_its_own_exit_code() {
+        let categories = [
+            Category::Usage,
+            Category::NotFound,
+            Category::Permission,
+            Category::Invalid,
+            Category::Check,
+            Category::Io,
+        ];
+        let codes: Vec<u8> = categories.iter().map(|c| c.exit_code()).collect();
+        // 0 is success and 1 is a panic, so neither is used.
+        assert_eq!(codes, [2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn io_errors_are_categorized_by_kind() {
+        for (kind, category, code) in [
+            (io::ErrorKind::NotFound, Category::NotFound, "not-found"),
+            (
+                io::ErrorKind::PermissionDenied,
+                Category::Permission,
+                "permission-denied",
+            ),
+            (io::ErrorKind::InvalidInput, Category::Usage, "usage"),
+            (
+                io::ErrorKind::InvalidData,
+                Category::Invalid,
+                "invalid-data",
+            ),
+            (
+                io::ErrorKind::UnexpectedEof,
+                Category::Invalid,
+                "invalid-data",
+            ),
+            (io::ErrorKind::Other, Category::Io, "io"),
+        ] {
+            let e = AppError::from(io::Error::new(kind, "what happened"));
+            assert_eq!((e.category, e.code), (category, code), "{:?}", kind);
+            assert_eq!(e.message, "what happened");
+        }
+        // The two a person can usually fix come with a hint.
+        let e = AppError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(e.hint, Some(tr("hints.not-found", &[])));
+        assert_eq!(AppError::from(io::Error::other("x")).hint, None);
+    }
+
+    #[test]
+    fn json_file_errors_keep_the_path_in_the_message() {
+        let dir = TempDir::new("json");
+        let path = dir.0.join("state.json");
+
+        let e = AppError::from(read_json::<Vec<u32>>(&path).unwrap_err());
+        assert_eq!(e.category, Category::NotFound);
+        assert!(e.message.starts_with(&path.display().to_string()), "{}", e);
+
+        fs::write(&path, "not json").unwrap();
+        let e = AppError::from(read_json::<Vec<u32>>(&path).unwrap_err());
+        assert_eq!((e.category, e.code), (Category::Invalid, "invalid-data"));
+        assert!(e.message.contains("invalid JSON"), "{}", e);
+    }
+
+    #[test]
+    fn json_fields_match_the_error_schema() {
+        let e = AppError::new(
+            Category::Check,
+            "audit.tampered",
+            "entry 3 changed".to_string(),
+        );
+        let fields = Json::object(e.json_fields()).to_string();
+        assert_eq!(
+            fields,
+            r#"{"code":"audit.tampered","category":"check","exit_code":6,"message":"entry 3 changed","hint":null}"#
+        );
+
+        let e = AppError::usage("usage: x".to_string()).with_hint("try y".to_string());
+        assert_eq!(e.exit_code(), 2);
+        assert_eq!(e.to_string(), "usage: x");
+        assert!(
+            Json::object(e.json_fields())
+                .to_string()
+                .ends_with(r#""hint":"try y"}"#)
+        );
+    }
+}
@@ -0,0 +1,18 @@
+// The library half of the crate: the utilities, storage building blocks and
+// the CLI's error type. `src/main.rs` and the programs in `src/bin` use it
+// like any other crate would (`use rust_practice::...`).
+pub mod error;
+pub mod storage;
+pub mod utils;
+
+// The modules, client and logger types most programs start with, so they
+// can be reached without going through `utils`.
+pub use utils::client::Client;
+pub use utils::log_format::LogFormat;
+pub use utils::test_closure::{
+    ConsoleLogger, Filter, LevelFilter, LogLevel, Logger, LoggerExt, MultiLogger, StderrLogger, Tee,
+};
+pub use utils::{array, checktypes, file_handling};
+
+// Where the example TCP server listens and the client connects by default.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:4000";
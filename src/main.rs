@@ -1,9 +1,44 @@
-mod utils;
-use utils::array::mod_arr;
-use utils::checktypes::{MyTypes, test_types};
-use utils::file_handling::{read_file, write_file_simple, write_file_with_match};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use utils::test_closure::{Filter, Logger, StderrLogger};
+use rust_practice::array::{ModArrError, filter_arr, fold_arr, map_arr, mod_arr};
+use rust_practice::checktypes::{MyTypes, test_types};
+use rust_practice::file_handling::{
+    WriteOptions, read_file, read_file_chunks, read_json, read_lines, write_file_atomic,
+    write_file_simple, write_file_with_match,
+};
+use rust_practice::{Filter, LevelFilter, LogLevel, Logger, LoggerExt, StderrLogger};
+
+use rust_practice::error::{AppError, Category};
+use rust_practice::storage::audit;
+use rust_practice::storage::retention::{self, Policy, Target};
+use rust_practice::storage::state_archive::{StateArchive, Store};
+use rust_practice::storage::tokens::TokenStore;
+use rust_practice::storage::undo::{self, UndoRecorder};
+use rust_practice::utils;
+use utils::build_info::BUILD_INFO;
+use utils::compat;
+use utils::conformance;
+use utils::echo::{EchoClient, EchoServer};
+use utils::encoding::{self, LineEnding, SourceEncoding};
+use utils::env_os;
+use utils::expr::{Expr, Value};
+use utils::i18n::{self, tr};
+use utils::log_format::rfc3339;
+use utils::mutation::{ApplyToDisk, DryRun, Mutation, MutationSink};
+use utils::output::{Json, Output};
+use utils::proc::{self, RunOptions, RunOutcome};
+use utils::prompt::ConfirmPolicy;
+use utils::server_stats::{ClientSort, StatsReport, top_clients};
+use utils::sha256::{from_hex, to_hex};
+use utils::sim::{SimConfig, SimNet};
+use utils::sniff;
+use utils::vm::Program;
 
 #[allow(dead_code)]
 fn test_arrays() {
@@ -17,16 +52,12 @@ fn test_arrays() {
     println!("Original &str array:");
     // print_arr(&my_str_array);
 
-    match mod_arr(&mut my_str_array) {
-        utils::array::ModArrResult::NewArray(new_array) => {
-            println!("The returned value is {:?}", new_array);
+    if let utils::array::ModArrResult::NewArray(new_array) = mod_arr(&mut my_str_array) {
+        println!("The returned value is {:?}", new_array);
 
-            for (index, value) in new_array.iter().enumerate() {
-                println!("str Index {}: {}", index, value);
-            }
+        for (index, value) in new_array.iter().enumerate() {
+            println!("str Index {}: {}", index, value);
         }
-        // utils::array::ModArrResult::Error(e) => println!("Error: {}", e),
-        _ => {}
     }
 
     // Call mod_arr on string array
@@ -37,10 +68,10 @@ fn test_arrays() {
                 println!("Index {}: {}", index, value);
             }
         }
-        utils::array::ModArrResult::ModifiedValues(modified_map) => {
+        utils::array::ModArrResult::ModifiedValues(modified) => {
             println!("String array modified values:");
-            for (index, value) in modified_map {
-                println!("Index {}: {}", index, value);
+            for change in modified {
+                println!("{}", change);
             }
         }
         utils::array::ModArrResult::Error(e) => println!("Error: {}", e),
@@ -51,11 +82,11 @@ fn test_arrays() {
     // print_arr(&my_int_array);
 
     match mod_arr(&mut my_int_array) {
-        utils::array::ModArrResult::ModifiedValues(modified_map) => {
+        utils::array::ModArrResult::ModifiedValues(modified) => {
             println!("Integer array modified successfully!");
-            println!("Modified values: {:?}", modified_map);
-            // for (index, value) in modified_map {
-            //     println!("Index {}: {}", index, value);
+            println!("Modified values: {:?}", modified);
+            // for change in modified {
+            //     println!("{}", change);
             // }
         }
         utils::array::ModArrResult::NewArray(new_array) => {
@@ -76,11 +107,11 @@ fn test_arrays() {
     // print_arr(&my_float_array);
 
     match mod_arr(&mut my_float_array) {
-        utils::array::ModArrResult::ModifiedValues(modified_map) => {
+        utils::array::ModArrResult::ModifiedValues(modified) => {
             println!("Float array modified successfully!");
-            println!("Modified values: {:?}", modified_map);
-            // for (index, value) in modified_map {
-            //     println!("Index {}: {}", index, value);
+            println!("Modified values: {:?}", modified);
+            // for change in modified {
+            //     println!("{}", change);
             // }
         }
         utils::array::ModArrResult::NewArray(new_array) => {
@@ -101,11 +132,11 @@ fn test_arrays() {
     // print_arr(&my_i32_array);
 
     match mod_arr(&mut my_i32_array) {
-        utils::array::ModArrResult::ModifiedValues(modified_map) => {
+        utils::array::ModArrResult::ModifiedValues(modified) => {
             println!("i32 array modified successfully!");
             println!("Modified values:");
-            for (index, value) in modified_map {
-                println!("Index {}: {}", index, value);
+            for change in modified {
+                println!("{}", change);
             }
         }
         utils::array::ModArrResult::NewArray(new_array) => {
@@ -119,6 +150,25 @@ fn test_arrays() {
 
     println!("After modification (odd indices should be incremented by 1):");
     // print_arr(&my_i32_array);
+
+    // Building new values from an array instead of modifying it
+    println!("\nTesting map/filter/fold on the i32 array:");
+    println!("Doubled: {:?}", map_arr(&my_i32_array, |n| n * 2));
+    println!("Over 20: {:?}", filter_arr(&my_i32_array, |&n| n > 20));
+    println!("Sum: {}", fold_arr(&my_i32_array, 0, |sum, n| sum + n));
+    println!(
+        "Lengths: {:?}",
+        map_arr(&my_string_array, |s: &String| s.len())
+    );
+}
+
+// Seconds since the Unix epoch. A clock set before 1970 reads as 0 rather
+// than panicking.
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
 #[allow(dead_code)]
@@ -130,24 +180,12 @@ fn test_file_handling() {
     match write_file_simple("test.txt", content) {
         Ok(_) => {
             println!("File written successfully!");
-            println!(
-                "Logging: Operation completed at {}",
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-            );
+            println!("Logging: Operation completed at {}", unix_secs());
             println!("File size: {} bytes", content.len());
         }
         Err(e) => {
             println!("Error writing file: {}", e);
-            println!(
-                "Logging: Error occurred at {}",
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-            );
+            println!("Logging: Error occurred at {}", unix_secs());
             println!("Attempting to create backup...");
             // You could add backup logic here
         }
@@ -169,20 +207,58 @@ fn test_file_handling() {
         }
     }
 
+    // Replacing a file atomically: readers see the old or the new contents,
+    // never a partial write
+    match write_file_atomic("test.txt", content) {
+        Ok(()) => println!("File replaced atomically"),
+        Err(e) => println!("Error writing file atomically: {}", e),
+    }
+
+    // Replacing a file but keeping the old contents in test.txt.bak, after
+    // checking what would happen
+    let careful = WriteOptions::new().backup(true);
+    match careful
+        .dry_run(true)
+        .write(Path::new("test.txt"), content.as_bytes())
+    {
+        Ok(report) => println!("Dry run: {}", report),
+        Err(e) => println!("Dry run failed: {}", e),
+    }
+    match careful.write(Path::new("test.txt"), content.as_bytes()) {
+        Ok(report) => println!("{}", report),
+        Err(e) => println!("Error writing file with a backup: {}", e),
+    }
+
     // Reading a file
     match read_file("test.txt") {
         Ok(contents) => println!("File contents: {}", contents),
         Err(e) => println!("Error reading file: {}", e),
     }
 
+    // Reading a file piece by piece, for files too big to load whole
+    let mut total = 0;
+    for chunk in read_file_chunks(Path::new("test.txt"), 8) {
+        match chunk {
+            Ok(chunk) => total += chunk.len(),
+            Err(e) => println!("Error reading chunk: {}", e),
+        }
+    }
+    println!("Read {} bytes in 8-byte chunks", total);
+    for (n, line) in read_lines(Path::new("test.txt")).enumerate() {
+        match line {
+            Ok(line) => println!("Line {}: {}", n + 1, line),
+            Err(e) => println!("Error reading line: {}", e),
+        }
+    }
+
     // Test with unsupported type (bool array)
     println!("\nTesting with unsupported type (bool array):");
     let mut bool_array: [bool; 3] = [true, false, true];
     match mod_arr(&mut bool_array) {
-        utils::array::ModArrResult::ModifiedValues(modified_map) => {
+        utils::array::ModArrResult::ModifiedValues(modified) => {
             println!("Bool array modified successfully!");
-            for (index, value) in modified_map {
-                println!("Index {}: {}", index, value);
+            for change in modified {
+                println!("{}", change);
             }
         }
         utils::array::ModArrResult::NewArray(new_array) => {
@@ -191,6 +267,10 @@ fn test_file_handling() {
                 println!("Index {}: {}", index, value);
             }
         }
+        // Errors are a `ModArrError`, so a caller can react to one kind specifically
+        utils::array::ModArrResult::Error(ModArrError::UnsupportedType { type_name }) => {
+            println!("Error: {} arrays aren't supported", type_name)
+        }
         utils::array::ModArrResult::Error(e) => println!("Error: {}", e),
     }
 
@@ -204,16 +284,1849 @@ fn test_file_handling() {
                 println!("Index {}: {}", index, value);
             }
         }
-        utils::array::ModArrResult::ModifiedValues(modified_map) => {
+        utils::array::ModArrResult::ModifiedValues(modified) => {
             println!("&str array modified values:");
-            for (index, value) in modified_map {
-                println!("Index {}: {}", index, value);
+            for change in modified {
+                println!("{}", change);
             }
         }
         utils::array::ModArrResult::Error(e) => println!("Error: {}", e),
     }
 }
-fn main() {
+// Subcommands: `cargo run -- <command> [args...]`.
+// Running without a command falls through to the demo code in `run_demos`.
+fn main() -> ExitCode {
+    i18n::init(i18n::Catalog::from_env());
+    let all_args: Vec<String> = env::args().skip(1).collect();
+    // `--json`, `--dry-run` and the confirmation flags work with every
+    // subcommand, so they go before it.
+    let (globals, args) = split_globals(&all_args);
+    let out = Output::new(globals.iter().any(|a| a == "--json"));
+    let dry_run = globals.iter().any(|a| a == "--dry-run");
+    // A dry run changes nothing, so there's nothing to ask about.
+    let policy = confirm_policy(globals).map(|policy| {
+        if dry_run {
+            ConfirmPolicy::Never
+        } else {
+            policy
+        }
+    });
+    let mut planned = DryRun::default();
+    let sink: &mut dyn MutationSink = if dry_run {
+        &mut planned
+    } else {
+        &mut ApplyToDisk
+    };
+
+    let result = policy.and_then(|policy| match args.first().map(String::as_str) {
+        Some("dedup-scan") => with_undo(&out, sink, |sink| {
+            cmd_dedup_scan(&args[1..], &out, sink, policy)
+        }),
+        Some("sync") => with_undo(&out, sink, |sink| cmd_sync(&args[1..], &out, sink, policy)),
+        Some("calc") => cmd_calc(&args[1..], &out),
+        Some("vm") => cmd_vm(&args[1..], &out),
+        Some("verify-audit") => cmd_verify_audit(&args[1..], &out),
+        Some("token") => cmd_token(&args[1..], &out),
+        Some("sniff") => cmd_sniff(&args[1..], &out),
+        Some("convert-text") => with_undo(&out, sink, |sink| {
+            cmd_convert_text(&args[1..], &out, sink, policy)
+        }),
+        Some("undo") => cmd_undo(&args[1..], &out, sink),
+        Some("gc") => cmd_gc(&args[1..], &out, sink, policy),
+        Some("conformance") => cmd_conformance(&args[1..], &out),
+        Some("compat") => cmd_compat(&args[1..], &out),
+        Some("stats") => cmd_stats(&args[1..], &out),
+        Some("export-state") => with_undo(&out, sink, |sink| {
+            cmd_export_state(&args[1..], &out, sink, policy)
+        }),
+        Some("import-state") => with_undo(&out, sink, |sink| {
+            cmd_import_state(&args[1..], &out, sink, policy)
+        }),
+        Some("simulate") => cmd_simulate(&args[1..], &out, false),
+        Some("replay") => cmd_simulate(&args[1..], &out, true),
+        Some("version") => cmd_version(&args[1..], &out),
+        Some("exec") => cmd_exec(&args[1..], &out),
+        Some(command) => Err(AppError::usage(tr(
+            "unknown-command",
+            &[("command", &command)],
+        ))),
+        None => {
+            run_demos();
+            Ok(())
+        }
+    });
+
+    if dry_run {
+        report_plan(&planned.planned, &out);
+    }
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if out.is_json() {
+                out.result("error", 1, e.json_fields());
+            } else {
+                eprintln!("{}", tr("error", &[("message", &e)]));
+                if let Some(hint) = &e.hint {
+                    eprintln!("{}", tr("hint", &[("hint", hint)]));
+                }
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+// Split off the global flags at the front of the command line. They end at
+// the first argument that isn't one, or at `--`, so a subcommand's own
+// arguments (`calc -y y=3`) are never mistaken for them.
+fn split_globals(args: &[String]) -> (&[String], &[String]) {
+    let (globals, rest) = args.split_at(args.iter().take_while(|a| is_global(a)).count());
+    match rest.split_first() {
+        Some((first, rest)) if first == "--" => (globals, rest),
+        _ => (globals, rest),
+    }
+}
+
+fn is_global(arg: &str) -> bool {
+    matches!(arg, "--json" | "--dry-run" | "--yes" | "-y" | "--force")
+        || arg.starts_with("--confirm=")
+}
+
+// A global flag after the subcommand would otherwise be taken for one of
+// its arguments, or ignored, and `sync A B --dry-run` would sync for real.
+fn misplaced_global(arg: &str) -> Option<AppError> {
+    is_global(arg).then(|| AppError::usage(tr("global-flag-position", &[("flag", &arg)])))
+}
+
+// Reject any flag among `args` that isn't in `known`, for subcommands that
+// pick their flags out of the arguments rather than parsing them in order.
+fn check_flags(args: &[String], known: &[&str], syntax: &str) -> Result<(), AppError> {
+    match args
+        .iter()
+        .find(|a| a.len() > 1 && a.starts_with('-') && !known.contains(&a.as_str()))
+    {
+        Some(flag) => Err(misplaced_global(flag)
+            .unwrap_or_else(|| AppError::usage(tr("usage", &[("syntax", &syntax)])))),
+        None => Ok(()),
+    }
+}
+
+// The global confirmation flags: `--yes` (or `-y`, or `--force`) never
+// asks; `--confirm=always|destructive|never` picks a policy outright.
+fn confirm_policy(args: &[String]) -> Result<ConfirmPolicy, AppError> {
+    let mut policy = ConfirmPolicy::default();
+    for arg in args {
+        if matches!(arg.as_str(), "--yes" | "-y" | "--force") {
+            policy = ConfirmPolicy::Never;
+        } else if let Some(value) = arg.strip_prefix("--confirm=") {
+            policy = ConfirmPolicy::parse(value).ok_or_else(|| {
+                AppError::usage(tr(
+                    "usage",
+                    &[("syntax", &"--confirm=always|destructive|never")],
+                ))
+            })?;
+        }
+    }
+    Ok(policy)
+}
+
+// Usage: cargo run -- dedup-scan <DIR> [--delete]
+fn cmd_dedup_scan(
+    args: &[String],
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<(), AppError> {
+    let syntax = "dedup-scan <DIR> [--delete]";
+    check_flags(args, &["--delete", "--delete-interactive"], syntax)?;
+    let dir = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .ok_or_else(|| AppError::usage(tr("usage", &[("syntax", &syntax)])))?;
+    // `--delete-interactive` is the old name, from before the confirmation
+    // policy decided whether to ask.
+    let delete = args
+        .iter()
+        .any(|a| a == "--delete" || a == "--delete-interactive");
+
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+    let report = utils::dedup::scan(Path::new(dir), workers)?;
+
+    for (index, set) in report.sets.iter().enumerate() {
+        out.human(tr(
+            "dedup.set",
+            &[
+                ("index", &(index + 1)),
+                ("size", &set.size),
+                ("count", &set.paths.len()),
+            ],
+        ));
+        for path in &set.paths {
+            out.human(format!("  {}", path.display()));
+        }
+    }
+    out.human(tr(
+        "dedup.summary",
+        &[
+            ("files", &report.files_scanned),
+            ("sets", &report.sets.len()),
+            ("bytes", &report.reclaimable_bytes()),
+        ],
+    ));
+    if !report.skipped.is_empty() {
+        out.human(tr("dedup.skipped", &[("count", &report.skipped.len())]));
+    }
+
+    let mut deleted = Vec::new();
+    if delete {
+        // Always keep the first file of each set; delete the rest.
+        for set in &report.sets {
+            for path in &set.paths[1..] {
+                let question = tr(
+                    "dedup.confirm-delete",
+                    &[
+                        ("path", &path.display()),
+                        ("original", &set.paths[0].display()),
+                    ],
+                );
+                // The hashes only say they're probably the same; check
+                // before deleting anything.
+                if !utils::dedup::same_contents(&set.paths[0], path)? {
+                    out.human(tr(
+                        "dedup.differs",
+                        &[
+                            ("path", &path.display()),
+                            ("original", &set.paths[0].display()),
+                        ],
+                    ));
+                    continue;
+                }
+                if policy.confirm(&question, true)? {
+                    sink.apply(Mutation::Remove { path: path.clone() })?;
+                    // A dry run lists the removal with the other planned changes.
+                    if !sink.is_dry_run() {
+                        out.human(tr("dedup.deleted", &[("path", &path.display())]));
+                        deleted.push(path_json(path));
+                    }
+                }
+            }
+        }
+    }
+
+    let sets = report
+        .sets
+        .iter()
+        .map(|set| {
+            Json::object(vec![
+                ("size", set.size.into()),
+                (
+                    "paths",
+                    set.paths
+                        .iter()
+                        .map(|p| path_json(p))
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+            ])
+        })
+        .collect::<Vec<_>>();
+    out.result(
+        "dedup-scan",
+        1,
+        vec![
+            ("files_scanned", report.files_scanned.into()),
+            ("reclaimable_bytes", report.reclaimable_bytes().into()),
+            ("sets", sets.into()),
+            (
+                "skipped",
+                report
+                    .skipped
+                    .iter()
+                    .map(|p| path_json(p))
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            ("deleted", deleted.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- sync <SRC> <DST> [--delete-extraneous] [--delta]
+// (plus the global --dry-run)
+fn cmd_sync(
+    args: &[String],
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<(), AppError> {
+    let syntax = "sync <SRC> <DST> [--delete-extraneous] [--delta]";
+    check_flags(args, &["--delete-extraneous", "--delta"], syntax)?;
+    let paths: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    let [src, dst] = paths[..] else {
+        return Err(AppError::usage(tr("usage", &[("syntax", &syntax)])));
+    };
+    let options = utils::sync::SyncOptions {
+        delete_extraneous: args.iter().any(|a| a == "--delete-extraneous"),
+        delta: args.iter().any(|a| a == "--delta"),
+    };
+
+    let dry_run = sink.is_dry_run();
+    // Updating `dst` is what sync is for; only deleting from it counts as
+    // destructive.
+    let confirm = |action: &utils::sync::SyncAction| {
+        let (key, path, destructive) = match action {
+            utils::sync::SyncAction::Copy { path, .. } => ("confirm.copy", path, false),
+            utils::sync::SyncAction::Delete { path } => ("confirm.delete", path, true),
+        };
+        let path = Path::new(dst).join(path);
+        policy.confirm(&tr(key, &[("path", &path.display())]), destructive)
+    };
+    let report = utils::sync::sync_dirs(
+        Path::new(src),
+        Path::new(dst),
+        options,
+        sink,
+        confirm,
+        |p| {
+            // In a dry run the planned changes are listed at the end instead.
+            if dry_run {
+                return;
+            }
+            match p.action {
+                utils::sync::SyncAction::Copy { path, size } => out.human(format!(
+                    "[{}/{}] copy {} ({} bytes)",
+                    p.index + 1,
+                    p.total,
+                    path.display(),
+                    size
+                )),
+                utils::sync::SyncAction::Delete { path } => out.human(format!(
+                    "[{}/{}] delete {}",
+                    p.index + 1,
+                    p.total,
+                    path.display()
+                )),
+            }
+        },
+    )?;
+
+    out.human(format!(
+        "{} actions, {} unchanged, {} bytes copied, {} reused{}",
+        report.actions.len(),
+        report.unchanged,
+        report.bytes_copied,
+        report.bytes_reused,
+        if dry_run { " (dry run)" } else { "" }
+    ));
+    if !report.declined.is_empty() {
+        out.human(tr("confirm.declined", &[("count", &report.declined.len())]));
+    }
+    let actions = report
+        .actions
+        .iter()
+        .map(|action| match action {
+            utils::sync::SyncAction::Copy { path, size } => Json::object(vec![
+                ("action", "copy".into()),
+                ("path", path_json(path)),
+                ("size", (*size).into()),
+            ]),
+            utils::sync::SyncAction::Delete { path } => {
+                Json::object(vec![("action", "delete".into()), ("path", path_json(path))])
+            }
+        })
+        .collect::<Vec<_>>();
+    out.result(
+        "sync",
+        1,
+        vec![
+            ("dry_run", dry_run.into()),
+            ("actions", actions.into()),
+            ("declined", report.declined.len().into()),
+            ("unchanged", report.unchanged.into()),
+            ("bytes_copied", report.bytes_copied.into()),
+            ("bytes_reused", report.bytes_reused.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- calc <EXPR> [NAME=VALUE ...]
+// e.g. `calc "(price + 1) * qty" price=9 qty=3`
+fn cmd_calc(args: &[String], out: &Output) -> Result<(), AppError> {
+    let invalid = AppError::usage;
+    let Some((source, bindings)) = args.split_first() else {
+        return Err(invalid(tr(
+            "usage",
+            &[("syntax", &"calc <EXPR> [NAME=VALUE ...]")],
+        )));
+    };
+
+    let mut vars: HashMap<String, Value> = HashMap::new();
+    for binding in bindings {
+        if let Some(e) = misplaced_global(binding) {
+            return Err(e);
+        }
+        let (name, value) = binding
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("expected NAME=VALUE, got '{}'", binding)))?;
+        let value = match value.parse::<f64>() {
+            Ok(n) => Value::Num(n),
+            Err(_) => Value::Str(value.to_string()),
+        };
+        vars.insert(name.to_string(), value);
+    }
+
+    let result = Expr::parse(source).and_then(|expr| expr.eval(&vars));
+    match result {
+        Ok(value) => {
+            out.human(&value);
+            out.result("calc", 1, vec![("value", (&value).into())]);
+            Ok(())
+        }
+        Err(e) => Err(AppError::new(
+            Category::Invalid,
+            "calc.expression",
+            e.pretty(source),
+        )),
+    }
+}
+
+// Usage: cargo run -- vm <SCRIPT> [INPUT ...]
+//        cargo run -- vm <SCRIPT> --csv < data.csv
+// Runs a `utils::vm` assembly program. With --csv the program runs once per
+// line of stdin, with that line's comma-separated fields as its inputs.
+fn cmd_vm(args: &[String], out: &Output) -> Result<(), AppError> {
+    let invalid = AppError::usage;
+    let syntax = "vm <SCRIPT> [INPUT ...] | vm <SCRIPT> --csv";
+    let Some((script, rest)) = args.split_first() else {
+        return Err(invalid(tr("usage", &[("syntax", &syntax)])));
+    };
+    // Inputs are numbers or words; anything else flag-like is a mistake.
+    if let Some(flag) = rest.iter().find(|a| a.starts_with("--") && *a != "--csv") {
+        return Err(
+            misplaced_global(flag).unwrap_or_else(|| invalid(tr("usage", &[("syntax", &syntax)])))
+        );
+    }
+    let source = read_file(script)?;
+    let program = Program::assemble(&source).map_err(|e| {
+        AppError::new(
+            Category::Invalid,
+            "vm.assemble",
+            format!("{}: {}", script, e),
+        )
+    })?;
+
+    let parse_input = |field: &str| match field.trim().parse::<f64>() {
+        Ok(n) => Value::Num(n),
+        Err(_) => Value::Str(field.to_string()),
+    };
+    let run = |inputs: Vec<Value>| {
+        program.run(&inputs).map_err(|e| {
+            AppError::new(
+                Category::Invalid,
+                "vm.runtime",
+                format!("{}: {}", script, e),
+            )
+        })
+    };
+
+    // One result per run, so --csv gives one JSON line per input line.
+    let report = |value: Value| {
+        out.human(&value);
+        out.result("vm", 1, vec![("value", (&value).into())]);
+    };
+    if rest.iter().any(|a| a == "--csv") {
+        for line in std::io::stdin().lines() {
+            let line = line?;
+            report(run(line.split(',').map(parse_input).collect())?);
+        }
+    } else {
+        report(run(rest.iter().map(|a| parse_input(a)).collect())?);
+    }
+    Ok(())
+}
+
+fn cmd_verify_audit(args: &[String], out: &Output) -> Result<(), AppError> {
+    let invalid = AppError::usage;
+    let usage = || {
+        invalid(tr(
+            "usage",
+            &[("syntax", &"verify-audit <FILE> [--expect-head HEX]")],
+        ))
+    };
+    let (path, expected) = match args {
+        [path] => (path, None),
+        [path, flag, hex] if flag == "--expect-head" => {
+            let digest =
+                from_hex(hex).ok_or_else(|| invalid(format!("not a SHA-256 digest: {}", hex)))?;
+            (path, Some(digest))
+        }
+        _ => return Err(usage()),
+    };
+
+    let report = audit::verify(path, expected)?;
+    out.human(format!(
+        "{}: {} entries intact, head {}",
+        path,
+        report.entries,
+        to_hex(&report.head)
+    ));
+    out.result(
+        "verify-audit",
+        1,
+        vec![
+            ("path", path.as_str().into()),
+            ("entries", report.entries.into()),
+            ("head", to_hex(&report.head).into()),
+            (
+                "problem",
+                report.problem.as_ref().map(|p| p.to_string()).into(),
+            ),
+        ],
+    );
+    match report.problem {
+        Some(problem) => Err(
+            AppError::new(Category::Check, "audit.tampered", problem.to_string())
+                .with_hint(tr("hints.audit-tampered", &[])),
+        ),
+        None => Ok(()),
+    }
+}
+
+// Usage: cargo run -- token mint <TOKENS> --scope GLOB... [--ttl SECS] [--label TEXT] [--audit FILE]
+//        cargo run -- token revoke <TOKENS> <ID> [--audit FILE]
+//        cargo run -- token list <TOKENS>
+fn cmd_token(args: &[String], out: &Output) -> Result<(), AppError> {
+    let usage = || {
+        AppError::usage(tr(
+            "usage",
+            &[(
+                "syntax",
+                &"token mint <TOKENS> --scope GLOB... [--ttl SECS] [--label TEXT] [--audit FILE] | token revoke <TOKENS> <ID> [--audit FILE] | token list <TOKENS>",
+            )],
+        ))
+    };
+    let (action, path, mut rest) = match args {
+        [action, path, rest @ ..] if !path.starts_with("--") => {
+            (action.as_str(), path, rest.iter())
+        }
+        _ => return Err(usage()),
+    };
+    let mut scopes = Vec::new();
+    let mut ttl = None;
+    let mut label = String::new();
+    let mut audit_path = None;
+    let mut id = None;
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--scope" => scopes.push(rest.next().ok_or_else(usage)?.clone()),
+            "--ttl" => {
+                ttl = Some(
+                    rest.next()
+                        .and_then(|n| n.parse::<u64>().ok())
+                        .ok_or_else(usage)?,
+                )
+            }
+            "--label" => label = rest.next().ok_or_else(usage)?.clone(),
+            "--audit" => audit_path = Some(rest.next().ok_or_else(usage)?),
+            _ if arg.starts_with("--") || id.is_some() => {
+                return Err(misplaced_global(arg).unwrap_or_else(usage));
+            }
+            _ => id = Some(arg.as_str()),
+        }
+    }
+    // Minting and revoking are recorded with the CLI as the actor.
+    let audit = |action: &str, detail: &str| -> Result<(), AppError> {
+        if let Some(path) = audit_path {
+            audit::AuditLog::open(path)?.append("cli", action, detail)?;
+        }
+        Ok(())
+    };
+
+    let mut store = TokenStore::open(path)?;
+    let now_unix = unix_secs();
+    match action {
+        "mint" if id.is_none() => {
+            if scopes.is_empty() {
+                return Err(usage());
+            }
+            let (token, secret) = store.mint(&label, &scopes, ttl.map(|ttl| now_unix + ttl))?;
+            audit(
+                "token.mint",
+                &format!("{} scopes {}", token.id, token.scopes.join(",")),
+            )?;
+            out.human(format!(
+                "minted {} (scopes {}, {})",
+                token.id,
+                token.scopes.join(","),
+                match token.expires_unix {
+                    Some(at) => format!(
+                        "expires {}",
+                        rfc3339(UNIX_EPOCH + Duration::from_secs(at), false)
+                    ),
+                    None => "never expires".to_string(),
+                }
+            ));
+            // The only time the token itself is ever shown.
+            out.human(secret.clone());
+            out.result(
+                "token-mint",
+                1,
+                vec![
+                    ("id", token.id.into()),
+                    ("token", secret.into()),
+                    ("scopes", token.scopes.into()),
+                    ("expires_unix", token.expires_unix.into()),
+                ],
+            );
+            Ok(())
+        }
+        "revoke" if scopes.is_empty() && ttl.is_none() && label.is_empty() => {
+            let id = id.ok_or_else(usage)?;
+            if store.get(id).is_none() {
+                return Err(AppError::new(
+                    Category::NotFound,
+                    "token.unknown",
+                    format!("{} has no token {}", path, id),
+                ));
+            }
+            let revoked = store.revoke(id)?;
+            if revoked {
+                audit("token.revoke", id)?;
+                out.human(format!("revoked {}", id));
+            } else {
+                out.human(format!("{} was already revoked", id));
+            }
+            out.result(
+                "token-revoke",
+                1,
+                vec![("id", id.into()), ("revoked", revoked.into())],
+            );
+            Ok(())
+        }
+        "list"
+            if id.is_none()
+                && scopes.is_empty()
+                && ttl.is_none()
+                && label.is_empty()
+                && audit_path.is_none() =>
+        {
+            let tokens = store.tokens();
+            out.human(format!(
+                "{:<16} {:<8} {:<20} {:<20} {}",
+                "id", "status", "scopes", "expires", "label"
+            ));
+            for token in &tokens {
+                out.human(format!(
+                    "{:<16} {:<8} {:<20} {:<20} {}",
+                    token.id,
+                    token.status(now_unix),
+                    token.scopes.join(","),
+                    token
+                        .expires_unix
+                        .map(|at| rfc3339(UNIX_EPOCH + Duration::from_secs(at), false))
+                        .unwrap_or_else(|| "never".to_string()),
+                    token.label
+                ));
+            }
+            let tokens_json = tokens
+                .iter()
+                .map(|token| {
+                    Json::object(vec![
+                        ("id", token.id.as_str().into()),
+                        ("label", token.label.as_str().into()),
+                        ("scopes", token.scopes.clone().into()),
+                        ("created_unix", token.created_unix.into()),
+                        ("expires_unix", token.expires_unix.into()),
+                        ("revoked_unix", token.revoked_unix.into()),
+                        ("status", token.status(now_unix).to_string().into()),
+                    ])
+                })
+                .collect::<Vec<_>>();
+            out.result("token-list", 1, vec![("tokens", tokens_json.into())]);
+            Ok(())
+        }
+        _ => Err(usage()),
+    }
+}
+
+// Programs `exec` may run, by name, comma-separated.
+const EXEC_ALLOW_VAR: &str = "RUST_PRACTICE_EXEC_ALLOW";
+
+// Usage: cargo run -- exec [--timeout SECS] <PROGRAM> [ARGS...]
+fn cmd_exec(args: &[String], out: &Output) -> Result<(), AppError> {
+    let syntax = "exec [--timeout SECS] <PROGRAM> [ARGS...]";
+    let usage = || AppError::usage(tr("usage", &[("syntax", &syntax)]));
+    let (timeout, rest) = match args {
+        [flag, secs, rest @ ..] if flag == "--timeout" => {
+            let secs = secs.parse().map_err(|_| usage())?;
+            (Some(Duration::from_secs(secs)), rest)
+        }
+        _ => (None, args),
+    };
+    // Everything after the program is its own, flags included.
+    let Some((program, program_args)) = rest.split_first() else {
+        return Err(usage());
+    };
+    if program.starts_with('-') {
+        return Err(misplaced_global(program).unwrap_or_else(usage));
+    }
+    let allowed = env_os::var_utf8(EXEC_ALLOW_VAR)
+        .map_err(|_| AppError::usage(format!("{} is not valid UTF-8", EXEC_ALLOW_VAR)))?
+        .unwrap_or_default();
+    let allowed: Vec<&str> = allowed.split(',').map(str::trim).collect();
+    run_allowed(program, program_args, timeout, &allowed, out)
+}
+
+// Run `program`, found on PATH, if it's in `allowed`, streaming its
+// output as it comes.
+fn run_allowed(
+    program: &str,
+    args: &[String],
+    timeout: Option<Duration>,
+    allowed: &[&str],
+    out: &Output,
+) -> Result<(), AppError> {
+    // A path would get around the list, so only bare names are allowed.
+    if program.is_empty()
+        || program.contains(std::path::is_separator)
+        || !allowed.contains(&program)
+    {
+        return Err(AppError::new(
+            Category::Permission,
+            "exec.not-allowed",
+            format!("{} is not allowed to run", program),
+        )
+        .with_hint(tr("hints.exec-not-allowed", &[("var", &EXEC_ALLOW_VAR)])));
+    }
+    let path = env_os::which(program).ok_or_else(|| {
+        AppError::new(
+            Category::NotFound,
+            "exec.not-found",
+            format!("{} is not on PATH", program),
+        )
+    })?;
+
+    let mut command = Command::new(&path);
+    command.args(args);
+    let options = RunOptions {
+        timeout,
+        cancel: None,
+    };
+    let result = proc::run(&mut command, options, &HumanLines(out))?;
+    let (outcome, exit_code) = match &result.outcome {
+        RunOutcome::Exited(status) => ("exited", status.code()),
+        RunOutcome::TimedOut => ("timed-out", None),
+        RunOutcome::Cancelled => ("cancelled", None),
+    };
+    out.result(
+        "exec",
+        1,
+        vec![
+            ("program", program.into()),
+            ("path", path_json(&path)),
+            ("outcome", outcome.into()),
+            ("exit_code", exit_code.map(f64::from).into()),
+            ("stdout_lines", result.stdout_lines.into()),
+            ("stderr_lines", result.stderr_lines.into()),
+            ("elapsed_ms", (result.elapsed.as_millis() as u64).into()),
+        ],
+    );
+    if result.success() {
+        return Ok(());
+    }
+    let what = match exit_code {
+        Some(code) => format!("exited with status {}", code),
+        None => outcome.replace('-', " "),
+    };
+    Err(AppError::new(
+        Category::Check,
+        "exec.failed",
+        format!("{} {}", program, what),
+    ))
+}
+
+// Passes a child's output lines (already prefixed with its name) on as
+// human output.
+struct HumanLines<'a>(&'a Output);
+
+impl Logger for HumanLines<'_> {
+    fn log(&self, _level: LogLevel, message: &str) {
+        self.0.human(message);
+    }
+}
+
+// Usage: cargo run -- version [--verbose]
+fn cmd_version(args: &[String], out: &Output) -> Result<(), AppError> {
+    let verbose = match args {
+        [] => false,
+        [flag] if flag == "--verbose" || flag == "-v" => true,
+        _ => {
+            return Err(AppError::usage(tr(
+                "usage",
+                &[("syntax", &"version [--verbose]")],
+            )));
+        }
+    };
+    let info = BUILD_INFO;
+    if verbose {
+        out.human(format!("{} {}", info.name, info.version));
+        for (key, value) in info.verbose_lines() {
+            out.human(format!("  {:<9} {}", key, value));
+        }
+    } else {
+        out.human(info);
+    }
+    out.result(
+        "version",
+        1,
+        vec![
+            ("name", info.name.into()),
+            ("package_version", info.version.into()),
+            ("commit", info.commit.into()),
+            ("branch", info.branch.into()),
+            ("built_unix", info.built_unix.into()),
+            ("rustc", info.rustc.into()),
+            (
+                "features",
+                info.features().map(Json::from).collect::<Vec<_>>().into(),
+            ),
+        ],
+    );
+    Ok(())
+}
+
+fn cmd_sniff(args: &[String], out: &Output) -> Result<(), AppError> {
+    let syntax = "sniff <FILE>... [--show]";
+    check_flags(args, &["--show"], syntax)?;
+    let show = args.iter().any(|a| a == "--show");
+    let paths: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    if paths.is_empty() {
+        return Err(AppError::usage(tr("usage", &[("syntax", &syntax)])));
+    }
+    for path in paths {
+        let kind = sniff::sniff_file(Path::new(path))?;
+        out.human(format!("{}: {} ({})", path, kind, kind.mime_type()));
+        if show {
+            out.human(sniff::display_safe(&std::fs::read(path)?).trim_end_matches('\n'));
+        }
+        out.result(
+            "sniff",
+            1,
+            vec![
+                ("path", path.as_str().into()),
+                ("kind", kind.to_string().into()),
+                ("mime_type", kind.mime_type().into()),
+            ],
+        );
+    }
+    Ok(())
+}
+
+// Usage: cargo run -- conformance [ADDR] [--only <CHECK>]... [--list]
+fn cmd_conformance(args: &[String], out: &Output) -> Result<(), AppError> {
+    let usage = || {
+        AppError::usage(tr(
+            "usage",
+            &[("syntax", &"conformance [ADDR] [--only <CHECK>]... [--list]")],
+        ))
+    };
+    let mut addr = None;
+    let mut only = Vec::new();
+    let mut list = false;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--only" => only.push(rest.next().ok_or_else(usage)?.as_str()),
+            "--list" => list = true,
+            _ if arg.starts_with("--") || addr.is_some() => {
+                return Err(misplaced_global(arg).unwrap_or_else(usage));
+            }
+            _ => addr = Some(arg.as_str()),
+        }
+    }
+    let addr = addr.unwrap_or(rust_practice::DEFAULT_ADDR);
+
+    let mut checks = conformance::checks();
+    if list {
+        for check in &checks {
+            out.human(format!("{:<20} {}", check.name, check.description));
+        }
+        return Ok(());
+    }
+    if let Some(unknown) = only
+        .iter()
+        .find(|name| !checks.iter().any(|c| c.name == **name))
+    {
+        return Err(AppError::usage(format!(
+            "no conformance check named '{}' (see --list)",
+            unknown
+        )));
+    }
+    if !only.is_empty() {
+        checks.retain(|check| only.contains(&check.name));
+    }
+
+    let results = conformance::run(addr, &checks);
+    for result in &results {
+        match &result.failure {
+            None => out.human(format!(
+                "PASS {} ({} ms)",
+                result.name,
+                result.elapsed.as_millis()
+            )),
+            Some(failure) => out.human(format!("FAIL {}: {}", result.name, failure)),
+        }
+    }
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    out.human(format!(
+        "{} checks against {}: {} passed, {} failed",
+        results.len(),
+        addr,
+        results.len() - failed,
+        failed
+    ));
+    let checks_json = results
+        .iter()
+        .map(|result| {
+            Json::object(vec![
+                ("name", result.name.into()),
+                ("passed", result.passed().into()),
+                ("failure", result.failure.clone().into()),
+                ("elapsed_ms", (result.elapsed.as_millis() as u64).into()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    out.result(
+        "conformance",
+        1,
+        vec![
+            ("addr", addr.into()),
+            ("checks", checks_json.into()),
+            ("passed", (results.len() - failed).into()),
+            ("failed", failed.into()),
+        ],
+    );
+
+    if failed > 0 {
+        return Err(AppError::new(
+            Category::Check,
+            "conformance.failed",
+            format!(
+                "{} of {} checks failed against {}",
+                failed,
+                results.len(),
+                addr
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Usage: cargo run -- stats clients <stats-file> [--sort messages|bytes|connections|last-active]
+//                    [--top N]
+// Reads the file a server started with --stats-file keeps up to date.
+fn cmd_stats(args: &[String], out: &Output) -> Result<(), AppError> {
+    let usage = || {
+        AppError::usage(tr(
+            "usage",
+            &[(
+                "syntax",
+                &"stats clients <stats-file> [--sort messages|bytes|connections|last-active] [--top N]",
+            )],
+        ))
+    };
+    let mut path = None;
+    let mut sort = ClientSort::default();
+    let mut top = None;
+    let mut rest = match args.split_first() {
+        Some((what, rest)) if what == "clients" => rest.iter(),
+        _ => return Err(usage()),
+    };
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--sort" => {
+                sort = rest
+                    .next()
+                    .ok_or_else(usage)?
+                    .parse()
+                    .map_err(AppError::usage)?;
+            }
+            "--top" => {
+                top = Some(rest.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?);
+            }
+            _ if arg.starts_with("--") || path.is_some() => {
+                return Err(misplaced_global(arg).unwrap_or_else(usage));
+            }
+            _ => path = Some(arg.as_str()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+
+    let report: StatsReport = read_json(Path::new(path))?;
+    let seen = report.clients.len();
+    let clients = top_clients(report.clients, sort, top);
+    out.human(format!(
+        "{:<40} {:>11} {:>9} {:>12} {:>12}",
+        "client", "connections", "messages", "bytes", "idle"
+    ));
+    for client in &clients {
+        let idle = report.written_unix.saturating_sub(client.last_active_unix);
+        out.human(format!(
+            "{:<40} {:>11} {:>9} {:>12} {:>11}s",
+            client.client, client.connections, client.messages, client.bytes, idle
+        ));
+    }
+    out.human(format!(
+        "{} of {} clients, as of {} ({})",
+        clients.len(),
+        seen,
+        report.written_unix,
+        report.totals
+    ));
+    let clients_json = clients
+        .iter()
+        .map(|client| {
+            Json::object(vec![
+                ("client", client.client.as_str().into()),
+                ("connections", client.connections.into()),
+                ("messages", client.messages.into()),
+                ("bytes", client.bytes.into()),
+                ("last_active_unix", client.last_active_unix.into()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    out.result(
+        "stats-clients",
+        1,
+        vec![
+            ("file", path.into()),
+            ("written_unix", report.written_unix.into()),
+            ("clients", clients_json.into()),
+            ("total_clients", seen.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- compat
+// Checks today's frame and codec encodings against bytes captured from
+// every released version, and prints the compatibility matrix.
+fn cmd_compat(args: &[String], out: &Output) -> Result<(), AppError> {
+    if !args.is_empty() {
+        return Err(AppError::usage(tr("usage", &[("syntax", &"compat")])));
+    }
+    let results = compat::run(&compat::fixtures());
+    let cells = compat::matrix(&results);
+    out.human(compat::render_matrix(&cells).trim_end().to_string());
+    let failures: Vec<_> = results.iter().filter(|r| !r.passed()).collect();
+    for result in &failures {
+        out.human(format!(
+            "FAIL {} v{} {} {}: {}",
+            result.format,
+            result.version,
+            result.name,
+            result.direction,
+            result.failure.as_deref().unwrap_or_default()
+        ));
+    }
+    let cells_json = cells
+        .iter()
+        .map(|cell| {
+            Json::object(vec![
+                ("format", cell.format.into()),
+                ("version", cell.version.into()),
+                ("direction", cell.direction.to_string().into()),
+                ("passed", cell.passed.into()),
+                ("failed", cell.failed.into()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let failures_json = failures
+        .iter()
+        .map(|result| {
+            Json::object(vec![
+                ("format", result.format.into()),
+                ("version", result.version.into()),
+                ("name", result.name.into()),
+                ("direction", result.direction.to_string().into()),
+                ("failure", result.failure.clone().into()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    out.result(
+        "compat",
+        1,
+        vec![
+            ("current_version", compat::CURRENT_VERSION.into()),
+            ("cells", cells_json.into()),
+            ("failures", failures_json.into()),
+        ],
+    );
+
+    if !failures.is_empty() {
+        return Err(AppError::new(
+            Category::Check,
+            "compat.failed",
+            format!(
+                "{} of {} compatibility promises broken",
+                failures.len(),
+                results.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+type StorePaths<'a> = Vec<(Store, &'a String)>;
+
+// The archive path and `--<store> PATH` pairs shared by `export-state`
+// and `import-state`.
+fn state_args<'a>(
+    args: &'a [String],
+    syntax: &str,
+) -> Result<(&'a String, StorePaths<'a>), AppError> {
+    let usage = || AppError::usage(tr("usage", &[("syntax", &syntax)]));
+    let (archive, mut rest) = match args.split_first() {
+        Some((archive, rest)) if !archive.starts_with("--") => (archive, rest.iter()),
+        _ => return Err(usage()),
+    };
+    let mut stores: StorePaths = Vec::new();
+    while let Some(flag) = rest.next() {
+        let store = flag
+            .strip_prefix("--")
+            .and_then(|name| name.parse::<Store>().ok())
+            .ok_or_else(|| misplaced_global(flag).unwrap_or_else(usage))?;
+        let path = rest.next().ok_or_else(usage)?;
+        if stores.iter().any(|(s, _)| *s == store) {
+            return Err(AppError::usage(format!("--{} given twice", store)));
+        }
+        stores.push((store, path));
+    }
+    if stores.is_empty() {
+        return Err(usage());
+    }
+    Ok((archive, stores))
+}
+
+// Write `contents` to `path` through `sink`, asking first if that replaces
+// a file. Returns whether it was written.
+fn write_confirmed(
+    path: &str,
+    contents: Vec<u8>,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<bool, AppError> {
+    let overwrites = Path::new(path).exists();
+    let key = if overwrites {
+        "confirm.overwrite"
+    } else {
+        "confirm.write"
+    };
+    if !policy.confirm(&tr(key, &[("path", &path)]), overwrites)? {
+        return Ok(false);
+    }
+    sink.apply(Mutation::Write {
+        path: path.into(),
+        contents,
+    })?;
+    Ok(true)
+}
+
+// Usage: cargo run -- export-state <ARCHIVE> [--counters PATH] [--jobs PATH] [--audit PATH]
+// Snapshots a server's stores into one archive (see
+// `storage::state_archive`), for `import-state` on another host.
+fn cmd_export_state(
+    args: &[String],
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<(), AppError> {
+    let (archive_path, stores) = state_args(
+        args,
+        "export-state <ARCHIVE> [--counters PATH] [--jobs PATH] [--audit PATH]",
+    )?;
+    let sources: Vec<(Store, &Path)> = stores
+        .iter()
+        .map(|(store, path)| (*store, Path::new(path.as_str())))
+        .collect();
+    let archive = StateArchive::export(&sources)?;
+    let bytes = archive.to_bytes()?;
+    let size = bytes.len();
+    let mut sections_json = Vec::new();
+    for ((store, path), section) in stores.iter().zip(&archive.sections) {
+        out.human(format!("{}: {} from {}", store, section.items(), path));
+        sections_json.push(Json::object(vec![
+            ("store", store.as_str().into()),
+            ("path", path.as_str().into()),
+            ("items", section.items().into()),
+        ]));
+    }
+    let written = write_confirmed(archive_path, bytes, sink, policy)?;
+    if written {
+        out.human(format!(
+            "{}: {} bytes (version {})",
+            archive_path, size, archive.version
+        ));
+    } else {
+        out.human(tr("confirm.declined", &[("count", &1)]));
+    }
+    out.result(
+        "export-state",
+        1,
+        vec![
+            ("archive", archive_path.as_str().into()),
+            ("archive_version", archive.version.into()),
+            ("sections", sections_json.into()),
+            ("bytes_written", (if written { size } else { 0 }).into()),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- import-state <ARCHIVE> [--counters PATH] [--jobs PATH] [--audit PATH]
+// Restores the named stores from an `export-state` archive. Every section
+// is checked before anything is written. Stop the server first: it keeps
+// its stores open.
+fn cmd_import_state(
+    args: &[String],
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<(), AppError> {
+    let (archive_path, stores) = state_args(
+        args,
+        "import-state <ARCHIVE> [--counters PATH] [--jobs PATH] [--audit PATH]",
+    )?;
+    let archive = std::fs::read(archive_path)
+        .and_then(|bytes| StateArchive::parse(&bytes))
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", archive_path, e)))?;
+    let mut restored = Vec::new();
+    for (store, path) in &stores {
+        let section = archive.section(*store).ok_or_else(|| {
+            AppError::new(
+                Category::Invalid,
+                "state.missing",
+                format!("{} has no {} section", archive_path, store),
+            )
+        })?;
+        restored.push((*store, path.as_str(), section.items(), section.restore()?));
+    }
+
+    let mut sections_json = Vec::new();
+    let mut declined = 0;
+    for (store, path, items, contents) in restored {
+        let written = write_confirmed(path, contents, sink, policy)?;
+        if written {
+            out.human(format!("{}: {} restored to {}", store, items, path));
+        } else {
+            declined += 1;
+        }
+        sections_json.push(Json::object(vec![
+            ("store", store.as_str().into()),
+            ("path", path.into()),
+            ("items", items.into()),
+            ("written", written.into()),
+        ]));
+    }
+    let skipped: Vec<&str> = archive
+        .sections
+        .iter()
+        .map(|section| section.store)
+        .filter(|store| !stores.iter().any(|(s, _)| s == store))
+        .map(Store::as_str)
+        .collect();
+    for store in &skipped {
+        out.human(format!("{}: skipped (no --{} given)", store, store));
+    }
+    if declined > 0 {
+        out.human(tr("confirm.declined", &[("count", &declined)]));
+    }
+    out.result(
+        "import-state",
+        1,
+        vec![
+            ("archive", archive_path.as_str().into()),
+            ("archive_version", archive.version.into()),
+            ("exported_unix", archive.exported_unix.into()),
+            ("sections", sections_json.into()),
+            ("skipped", skipped.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- simulate [--seed N] [--clients N] [--messages N]
+//                    [--latency MIN-MAX] [--reorder] [--partition START-END] [--trace]
+//        cargo run -- replay <same options> --until EVENT
+// Runs the echo server and clients on a simulated network. The same
+// arguments always give the same run, so `replay` can stop it just before
+// an event (numbered as in `--trace`) and show what every node held then.
+fn cmd_simulate(args: &[String], out: &Output, replay: bool) -> Result<(), AppError> {
+    let usage = || {
+        let syntax = if replay {
+            "replay [--seed N] [--clients N] [--messages N] [--latency MIN-MAX] \
+             [--reorder] [--partition START-END] [--trace] --until EVENT"
+        } else {
+            "simulate [--seed N] [--clients N] [--messages N] [--latency MIN-MAX] \
+             [--reorder] [--partition START-END] [--trace]"
+        };
+        AppError::usage(tr("usage", &[("syntax", &syntax)]))
+    };
+    let number = |value: Option<&String>| -> Result<u64, AppError> {
+        value.and_then(|v| v.parse().ok()).ok_or_else(usage)
+    };
+    let span = |value: Option<&String>| -> Result<(u64, u64), AppError> {
+        let (low, high) = value.and_then(|v| v.split_once('-')).ok_or_else(usage)?;
+        match (low.parse(), high.parse()) {
+            (Ok(low), Ok(high)) if low <= high => Ok((low, high)),
+            _ => Err(usage()),
+        }
+    };
+
+    let mut config = SimConfig::default();
+    let mut clients = 3;
+    let mut messages = 3;
+    let mut partition = None;
+    let mut show_trace = false;
+    let mut until = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--seed" => config.seed = number(rest.next())?,
+            "--clients" => clients = number(rest.next())?,
+            "--messages" => messages = number(rest.next())?,
+            "--latency" => {
+                (config.min_latency_ms, config.max_latency_ms) = span(rest.next())?;
+            }
+            "--reorder" => config.reorder = true,
+            "--partition" => partition = Some(span(rest.next())?),
+            "--trace" => show_trace = true,
+            "--until" if replay => until = Some(number(rest.next())?),
+            _ => return Err(misplaced_global(arg).unwrap_or_else(usage)),
+        }
+    }
+    if replay && until.is_none() {
+        return Err(usage());
+    }
+
+    let mut net = SimNet::new(config);
+    net.add_node("server", EchoServer::default());
+    let mut nodes = Vec::new();
+    for i in 0..clients {
+        let name = format!("client-{}", i);
+        let client = Rc::new(RefCell::new(EchoClient::new("server")));
+        net.add_node(&name, client.clone());
+        for j in 0..messages {
+            let message = format!("{} #{}", name, j);
+            client
+                .borrow_mut()
+                .send(message.as_bytes(), &mut net.transport(&name))?;
+        }
+        nodes.push((name, client));
+    }
+    // The partition cuts the first client off from the server.
+    if let Some((start, end)) = partition {
+        net.partition_at(start, "server", "client-0");
+        net.heal_at(end, "server", "client-0");
+    }
+    let result = match until {
+        Some(event_id) => net.run_until(event_id),
+        None => net.run(1_000_000),
+    };
+    if show_trace {
+        for event in net.history() {
+            out.human(event.to_string());
+        }
+    }
+    if let Err(e) = result {
+        let failed_at = net.next_event_id().saturating_sub(1);
+        return Err(AppError::from(e).with_hint(format!(
+            "event #{} failed (seed {}); run `replay` with the same options and \
+             `--until {}` to see the state just before it",
+            failed_at, config.seed, failed_at
+        )));
+    }
+    if let Some(event_id) = until {
+        return show_replay(&net, event_id, out);
+    }
+    let mut clients_json = Vec::new();
+    for (name, client) in &nodes {
+        let client = client.borrow();
+        let lost = client.outstanding().len();
+        out.human(format!(
+            "{}: {}/{} echoed{}",
+            name,
+            client.echoed,
+            messages,
+            if lost > 0 {
+                format!(", {} lost", lost)
+            } else {
+                String::new()
+            }
+        ));
+        clients_json.push(Json::object(vec![
+            ("name", name.as_str().into()),
+            ("echoed", client.echoed.into()),
+            ("lost", lost.into()),
+            ("unexpected", client.unexpected.into()),
+        ]));
+    }
+    let stats = net.stats();
+    out.human(format!(
+        "{} sent, {} delivered, {} dropped; finished at {} ms (seed {})",
+        stats.sent,
+        stats.delivered,
+        stats.dropped,
+        net.now_ms(),
+        config.seed
+    ));
+    out.result(
+        "simulate",
+        1,
+        vec![
+            ("seed", config.seed.into()),
+            ("finished_ms", net.now_ms().into()),
+            ("sent", stats.sent.into()),
+            ("delivered", stats.delivered.into()),
+            ("dropped", stats.dropped.into()),
+            ("clients", clients_json.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Everything `replay` stopped on: the messages in flight (the first is the
+// next to arrive), the partitions, and each node's state.
+fn show_replay(net: &SimNet, event_id: u64, out: &Output) -> Result<(), AppError> {
+    if net.next_event_id() < event_id {
+        out.human(format!(
+            "the run ended after {} events, before #{}; showing its final state",
+            net.next_event_id(),
+            event_id
+        ));
+    }
+    out.human(format!(
+        "stopped before event #{} at {} ms (seed {})",
+        net.next_event_id(),
+        net.now_ms(),
+        net.seed()
+    ));
+    let in_flight = net.in_flight();
+    out.human(format!("in flight: {}", in_flight.len()));
+    for delivery in &in_flight {
+        out.human(format!("  [{:>5} ms] {}", delivery.at_ms, delivery));
+    }
+    let partitions = net.partitions();
+    for (a, b) in &partitions {
+        out.human(format!("partitioned: {} | {}", a, b));
+    }
+    let nodes = net.node_states();
+    for (name, state) in &nodes {
+        out.human(format!("{}: {}", name, state));
+    }
+    out.result(
+        "replay",
+        1,
+        vec![
+            ("seed", net.seed().into()),
+            ("until", event_id.into()),
+            ("next_event", net.next_event_id().into()),
+            ("now_ms", net.now_ms().into()),
+            (
+                "in_flight",
+                in_flight
+                    .iter()
+                    .map(|delivery| {
+                        Json::object(vec![
+                            ("at_ms", delivery.at_ms.into()),
+                            ("from", delivery.from.as_str().into()),
+                            ("to", delivery.to.as_str().into()),
+                            (
+                                "payload",
+                                String::from_utf8_lossy(&delivery.payload)
+                                    .into_owned()
+                                    .into(),
+                            ),
+                        ])
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            (
+                "partitions",
+                partitions
+                    .into_iter()
+                    .map(|(a, b)| vec![a, b])
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            (
+                "nodes",
+                nodes
+                    .into_iter()
+                    .map(|(name, state)| {
+                        Json::object(vec![("name", name.into()), ("state", state.into())])
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- convert-text <IN> <OUT> [--from utf8|latin1] [--to utf8|latin1]
+//                    [--eol lf|crlf|native] [--bom|--no-bom] [--lossy]
+fn cmd_convert_text(
+    args: &[String],
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<(), AppError> {
+    let invalid = AppError::usage;
+    let usage = tr(
+        "usage",
+        &[(
+            "syntax",
+            &"convert-text <IN> <OUT> [--from utf8|latin1] [--to utf8|latin1] \
+              [--eol lf|crlf|native] [--bom|--no-bom] [--lossy]",
+        )],
+    );
+
+    let mut paths = Vec::new();
+    let mut from = SourceEncoding::Utf8;
+    let mut to_latin1 = false;
+    let mut eol = None;
+    let mut bom = None;
+    let mut mode = encoding::Mode::Strict;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or_else(|| invalid(usage.clone()));
+        match arg.as_str() {
+            "--from" => match value()?.as_str() {
+                "utf8" => from = SourceEncoding::Utf8,
+                "latin1" => from = SourceEncoding::Latin1,
+                other => return Err(invalid(format!("unknown encoding: {}", other))),
+            },
+            "--to" => match value()?.as_str() {
+                "utf8" => to_latin1 = false,
+                "latin1" => to_latin1 = true,
+                other => return Err(invalid(format!("unknown encoding: {}", other))),
+            },
+            "--eol" => match value()?.as_str() {
+                "lf" => eol = Some(LineEnding::Lf),
+                "crlf" => eol = Some(LineEnding::CrLf),
+                "native" => eol = Some(LineEnding::native()),
+                other => return Err(invalid(format!("unknown line ending: {}", other))),
+            },
+            "--bom" => bom = Some(true),
+            "--no-bom" => bom = Some(false),
+            "--lossy" => mode = encoding::Mode::Lossy,
+            _ if arg.starts_with("--") => {
+                return Err(misplaced_global(arg).unwrap_or_else(|| invalid(usage.clone())));
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [input, output] = paths[..] else {
+        return Err(invalid(usage.clone()));
+    };
+
+    let bytes = std::fs::read(input)?;
+    // Keep the input's BOM unless told otherwise.
+    let bom = bom.unwrap_or(from == SourceEncoding::Utf8 && encoding::has_bom(&bytes));
+    let bad_data = |e: encoding::EncodingError| {
+        AppError::new(
+            Category::Invalid,
+            "encoding.invalid",
+            format!("{}: {}", input, e),
+        )
+        .with_hint(tr("hints.encoding-lossy", &[]))
+    };
+    let mut text = encoding::decode(&bytes, from, mode).map_err(bad_data)?;
+    let line_endings = match (encoding::detect_line_ending(&text), eol) {
+        (Some(found), Some(eol)) if found != eol => format!("{} -> {}", found, eol),
+        (Some(found), _) => found.to_string(),
+        (None, _) => "none".to_string(),
+    };
+    if let Some(eol) = eol {
+        text = encoding::normalize_line_endings(&text, eol);
+    }
+    let bytes_out = if to_latin1 {
+        if bom {
+            return Err(invalid(
+                "a BOM only makes sense for UTF-8 output".to_string(),
+            ));
+        }
+        encoding::encode_latin1(&text, mode).map_err(bad_data)?
+    } else if bom {
+        encoding::add_bom(text.as_bytes())
+    } else {
+        text.into_bytes()
+    };
+    // Converting a file in place is what was asked for; replacing some
+    // other existing file is destructive.
+    let overwrites = output != input && Path::new(output).exists();
+    let key = if overwrites {
+        "confirm.overwrite"
+    } else {
+        "confirm.write"
+    };
+    let go_ahead = policy.confirm(&tr(key, &[("path", output)]), overwrites)?;
+    let written = if go_ahead { bytes_out.len() } else { 0 };
+    if go_ahead {
+        sink.apply(Mutation::Write {
+            path: output.into(),
+            contents: bytes_out,
+        })?;
+        out.human(format!(
+            "{}: {} bytes (line endings: {})",
+            output, written, line_endings
+        ));
+    } else {
+        out.human(tr("confirm.declined", &[("count", &1)]));
+    }
+    out.result(
+        "convert-text",
+        1,
+        vec![
+            ("input", input.as_str().into()),
+            ("output", output.as_str().into()),
+            ("bytes_written", written.into()),
+            ("line_endings", line_endings.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Run a command with its changes recorded for `undo`, and say how to undo
+// them afterwards, even if the command failed partway.
+fn with_undo(
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    command: impl FnOnce(&mut dyn MutationSink) -> Result<(), AppError>,
+) -> Result<(), AppError> {
+    if sink.is_dry_run() {
+        return command(sink);
+    }
+    let mut recorder = UndoRecorder::new(&undo::undo_dir_from_env(), sink);
+    let result = command(&mut recorder);
+    if recorder.entries() > 0 {
+        out.human(tr("undo.recorded", &[("run", &recorder.run_id())]));
+        out.result(
+            "undo-recorded",
+            1,
+            vec![
+                ("run_id", recorder.run_id().into()),
+                ("entries", recorder.entries().into()),
+            ],
+        );
+    }
+    result
+}
+
+// Usage: cargo run -- gc [--sessions <COUNTERS> <POLICY>] [--undo <POLICY>] [--logs <LOG> <POLICY>]
+//
+// Not run under `with_undo`: recording what gc removes would keep it all.
+// Each store's removals are asked about together; with --dry-run they're
+// only listed.
+fn cmd_gc(
+    args: &[String],
+    out: &Output,
+    sink: &mut dyn MutationSink,
+    policy: ConfirmPolicy,
+) -> Result<(), AppError> {
+    let usage = || {
+        AppError::usage(tr(
+            "usage",
+            &[(
+                "syntax",
+                &"gc [--sessions <COUNTERS> <POLICY>] [--undo <POLICY>] [--logs <LOG> <POLICY>]",
+            )],
+        ))
+    };
+    let mut rules: Vec<(Target, PathBuf, Policy)> = Vec::new();
+    let mut rest = args.iter();
+    while let Some(flag) = rest.next() {
+        let target: Target = flag
+            .strip_prefix("--")
+            .and_then(|name| name.parse().ok())
+            .ok_or_else(|| misplaced_global(flag).unwrap_or_else(usage))?;
+        let path = match target {
+            Target::Undo => undo::undo_dir_from_env(),
+            _ => PathBuf::from(rest.next().ok_or_else(usage)?),
+        };
+        let limits = rest
+            .next()
+            .ok_or_else(usage)?
+            .parse()
+            .map_err(AppError::usage)?;
+        rules.push((target, path, limits));
+    }
+    if rules.is_empty() {
+        return Err(usage());
+    }
+
+    let dry_run = sink.is_dry_run();
+    let mut reports = Vec::new();
+    for (target, path, limits) in &rules {
+        let now_ms = unix_secs() * 1000;
+        let due = retention::collect(*target, path, limits, now_ms, &mut DryRun::default())?;
+        let question = tr(
+            "confirm.gc",
+            &[
+                ("count", &due.removed.len()),
+                ("store", target),
+                ("path", &path.display()),
+                ("bytes", &due.reclaimed_bytes()),
+            ],
+        );
+        let report = if dry_run || due.removed.is_empty() || policy.confirm(&question, true)? {
+            retention::collect(*target, path, limits, now_ms, sink)?
+        } else {
+            retention::GcReport {
+                kept: due.kept + due.removed.len(),
+                removed: Vec::new(),
+                ..due
+            }
+        };
+        out.human(report.to_string());
+        for item in &report.removed {
+            out.human(format!("  {} ({} bytes)", item.name, item.bytes));
+        }
+        reports.push(report);
+    }
+    let reclaimed: u64 = reports.iter().map(|r| r.reclaimed_bytes()).sum();
+    if dry_run {
+        out.human(format!("{} bytes can be reclaimed", reclaimed));
+    } else {
+        out.human(format!("reclaimed {} bytes", reclaimed));
+    }
+    let stores_json = reports
+        .iter()
+        .map(|report| {
+            Json::object(vec![
+                ("store", report.target.as_str().into()),
+                ("path", path_json(&report.path)),
+                (
+                    "removed",
+                    report
+                        .removed
+                        .iter()
+                        .map(|item| item.name.as_str())
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+                ("kept", report.kept.into()),
+                ("reclaimed_bytes", report.reclaimed_bytes().into()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    out.result(
+        "gc",
+        1,
+        vec![
+            ("dry_run", dry_run.into()),
+            ("stores", stores_json.into()),
+            ("reclaimed_bytes", reclaimed.into()),
+        ],
+    );
+    Ok(())
+}
+
+// Usage: cargo run -- undo <RUN-ID>
+fn cmd_undo(args: &[String], out: &Output, sink: &mut dyn MutationSink) -> Result<(), AppError> {
+    let syntax = "undo <RUN-ID>";
+    check_flags(args, &[], syntax)?;
+    let [run_id] = args else {
+        return Err(AppError::usage(tr("usage", &[("syntax", &syntax)])));
+    };
+    let undo_dir = undo::undo_dir_from_env();
+    if !undo::run_exists(&undo_dir, run_id)? {
+        return Err(AppError::new(
+            Category::NotFound,
+            "undo.unknown-run",
+            format!("no undo run '{}' in {}", run_id, undo_dir.display()),
+        )
+        .with_hint(tr("hints.undo-unknown-run", &[])));
+    }
+
+    let report = undo::undo(&undo_dir, run_id, sink)?;
+    if !sink.is_dry_run() {
+        for path in &report.restored {
+            out.human(tr("undo.restored", &[("path", &path.display())]));
+        }
+        for path in &report.removed {
+            out.human(tr("undo.removed", &[("path", &path.display())]));
+        }
+    }
+    let restored = report
+        .restored
+        .iter()
+        .map(|p| path_json(p))
+        .collect::<Vec<_>>();
+    let removed = report
+        .removed
+        .iter()
+        .map(|p| path_json(p))
+        .collect::<Vec<_>>();
+    out.result(
+        "undo",
+        1,
+        vec![
+            ("run_id", run_id.as_str().into()),
+            ("restored", restored.into()),
+            ("removed", removed.into()),
+        ],
+    );
+    Ok(())
+}
+
+// After a dry run, list what would have changed.
+fn report_plan(planned: &[Mutation], out: &Output) {
+    if planned.is_empty() {
+        out.human(tr("dry-run.nothing", &[]));
+    } else {
+        out.human(tr("dry-run.planned", &[]));
+        for mutation in planned {
+            out.human(format!("  {}", mutation));
+        }
+    }
+    let mutations = planned.iter().map(mutation_json).collect::<Vec<_>>();
+    out.result("dry-run", 1, vec![("mutations", mutations.into())]);
+}
+
+fn mutation_json(mutation: &Mutation) -> Json {
+    match mutation {
+        Mutation::Copy { from, to, .. } => Json::object(vec![
+            ("op", "copy".into()),
+            ("from", path_json(from)),
+            ("to", path_json(to)),
+        ]),
+        Mutation::Write { path, contents } => Json::object(vec![
+            ("op", "write".into()),
+            ("path", path_json(path)),
+            ("bytes", contents.len().into()),
+        ]),
+        Mutation::Remove { path } => {
+            Json::object(vec![("op", "remove".into()), ("path", path_json(path))])
+        }
+        Mutation::SetModified { path, .. } => {
+            Json::object(vec![("op", "set-mtime".into()), ("path", path_json(path))])
+        }
+    }
+}
+
+// Paths go into JSON as strings; the rare non-UTF-8 one is converted lossily.
+fn path_json(path: &Path) -> Json {
+    path.to_string_lossy().into_owned().into()
+}
+
+fn run_demos() {
     // test_arrays();
     // test_file_handling();
     // test_types_match_typeid(&"Hello....");
@@ -242,28 +2155,180 @@ fn main() {
         StderrLogger,
         // The second argument is the `predicate`. We provide a closure here.
         // A closure is an anonymous function you can create on the fly.
-        // - `|...|` defines the parameters for the closure. Here, `_verbosity` and `msg`.
-        //   The underscore `_` in `_verbosity` is a convention to tell the compiler
+        // - `|...|` defines the parameters for the closure. Here, `_level` and `msg`.
+        //   The underscore `_` in `_level` is a convention to tell the compiler
         //   (and other programmers) that we are intentionally not using this parameter.
         // - The code after the `|...|` is the body of the closure.
-        // - The compiler infers that this closure matches the `P: Fn(u8, &str) -> bool` trait bound
+        // - The compiler infers that this closure matches the `P: Fn(LogLevel, &str) -> bool` trait bound
         //   because it takes the correct arguments and `msg.contains(...)` returns a `bool`.
         // So, for this specific instance, the generic type `P` becomes the unique,
         // unnameable type of this specific closure.
-        |_verbosity, msg| msg.contains("yikes"),
+        |_level, msg| msg.contains("yikes"),
     );
     // `logger` now has the type `Filter<StderrLogger, [closure type]>` and implements `Logger`.
 
     // Now we can call the `.log()` method on our `logger` instance.
     // This message does NOT contain "yikes", so the predicate will return `false`.
     // Nothing will be printed to the console.
-    logger.log(5, "FYI");
+    logger.log(LogLevel::Trace, "FYI");
 
     // This message DOES contain "yikes", so the predicate will return `true`.
     // The `Filter` will then call the `.log()` method of its `inner` logger (`StderrLogger`),
     // and the message will be printed to stderr.
-    logger.log(1, "yikes, something went wrong");
+    logger.log(LogLevel::Error, "yikes, something went wrong");
 
     // This message does NOT contain "yikes", so it will also be filtered out.
-    logger.log(2, "uhoh");
+    logger.log(LogLevel::Warn, "uhoh");
+
+    // `LevelFilter` filters on the level instead of the text: with a threshold of
+    // `Warn`, errors and warnings get through and everything chattier is dropped.
+    let quiet = LevelFilter::new(StderrLogger, LogLevel::Warn);
+    quiet.log(LogLevel::Warn, "disk almost full"); // printed
+    quiet.log(LogLevel::Debug, "cache hit"); // dropped
+
+    // `and` (from `LoggerExt`) sends each message to two loggers, each with its
+    // own filtering: here warnings show up twice, debug messages once.
+    let both = StderrLogger.up_to(LogLevel::Warn).and(StderrLogger);
+    both.log(LogLevel::Warn, "printed twice");
+    both.log(LogLevel::Debug, "printed once");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn split(args: &[&str]) -> (Vec<String>, Vec<String>) {
+        let args = strings(args);
+        let (globals, rest) = split_globals(&args);
+        (globals.to_vec(), rest.to_vec())
+    }
+
+    #[test]
+    fn unix_secs_reads_the_clock() {
+        // 2020-01-01; a clock before the epoch would read 0 instead.
+        assert!(unix_secs() > 1_577_836_800);
+    }
+
+    #[test]
+    fn globals_stop_at_the_subcommand() {
+        assert_eq!(
+            split(&["--json", "-y", "calc", "-y", "y=3"]),
+            (strings(&["--json", "-y"]), strings(&["calc", "-y", "y=3"]))
+        );
+        assert_eq!(
+            split(&["sync", "a", "b", "--dry-run"]),
+            (strings(&[]), strings(&["sync", "a", "b", "--dry-run"]))
+        );
+    }
+
+    #[test]
+    fn a_global_flag_after_sync_fails_before_copying() {
+        let dir = std::env::temp_dir().join(format!("main-sync-flag-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/a.txt"), "a").unwrap();
+        let paths = |more: &[&str]| {
+            let mut args = vec![
+                dir.join("src").display().to_string(),
+                dir.join("dst").display().to_string(),
+            ];
+            args.extend(strings(more));
+            args
+        };
+        let sync = |args: &[String]| {
+            cmd_sync(
+                args,
+                &Output::new(false),
+                &mut ApplyToDisk,
+                ConfirmPolicy::Never,
+            )
+        };
+
+        for flag in ["--dry-run", "--yes", "--force", "--json"] {
+            let e = sync(&paths(&[flag])).unwrap_err();
+            assert_eq!(e.category, Category::Usage);
+            assert!(e.message.contains(flag), "{}", e.message);
+        }
+        let e = sync(&paths(&["--delete-extranous"])).unwrap_err();
+        assert_eq!(e.category, Category::Usage);
+        assert!(!dir.join("dst").exists());
+
+        // The same flag in front of the subcommand is a dry run.
+        let args = strings(&["--dry-run", "sync"]);
+        let (globals, _) = split_globals(&args);
+        assert_eq!(globals, ["--dry-run"]);
+        let mut planned = DryRun::default();
+        cmd_sync(
+            &paths(&[]),
+            &Output::new(false),
+            &mut planned,
+            ConfirmPolicy::Never,
+        )
+        .unwrap();
+        assert_eq!(planned.planned.len(), 1);
+        assert!(!dir.join("dst").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn flag_checks_name_misplaced_globals() {
+        let e = check_flags(&strings(&["x", "-y"]), &["--show"], "sniff").unwrap_err();
+        assert!(e.message.contains("-y"), "{}", e.message);
+        let e = check_flags(&strings(&["x", "--shwo"]), &["--show"], "sniff").unwrap_err();
+        assert!(e.message.contains("sniff"), "{}", e.message);
+        assert!(check_flags(&strings(&["x", "--show", "-"]), &["--show"], "sniff").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_runs_only_allowed_programs_from_path() {
+        let out = Output::new(false);
+        let run = |program: &str, args: &[&str], timeout| {
+            run_allowed(program, &strings(args), timeout, &["sh"], &out)
+        };
+
+        assert!(run("sh", &["-c", "echo hi; echo there >&2"], None).is_ok());
+        let e = run("sh", &["-c", "exit 3"], None).unwrap_err();
+        assert_eq!(
+            (e.code, e.message.as_str()),
+            ("exec.failed", "sh exited with status 3")
+        );
+        let e = run("sh", &["-c", "sleep 5"], Some(Duration::from_millis(50))).unwrap_err();
+        assert_eq!(e.message, "sh timed out");
+
+        for refused in ["rm", "/bin/sh", "./sh", ""] {
+            let e = run(refused, &[], None).unwrap_err();
+            assert_eq!(e.code, "exec.not-allowed", "{:?}", refused);
+        }
+        let e = run_allowed("no-such-helper", &[], None, &["no-such-helper"], &out).unwrap_err();
+        assert_eq!(e.category, Category::NotFound);
+    }
+
+    #[test]
+    fn exec_parses_its_own_flags_only_before_the_program() {
+        let e = cmd_exec(&strings(&["--timeout", "x", "sh"]), &Output::new(false)).unwrap_err();
+        assert_eq!(e.category, Category::Usage);
+        let e = cmd_exec(&strings(&["--json", "sh"]), &Output::new(false)).unwrap_err();
+        assert!(e.message.contains("--json"), "{}", e.message);
+        assert_eq!(
+            cmd_exec(&[], &Output::new(false)).unwrap_err().category,
+            Category::Usage
+        );
+    }
+
+    #[test]
+    fn double_dash_ends_the_globals() {
+        assert_eq!(
+            split(&["--confirm=never", "--", "--json"]),
+            (strings(&["--confirm=never"]), strings(&["--json"]))
+        );
+        assert_eq!(
+            split(&["calc", "--", "1"]),
+            (strings(&[]), strings(&["calc", "--", "1"]))
+        );
+    }
 }
@@ -0,0 +1,382 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use super::backend::StorageBackend;
+use super::journal::{self, FsyncPolicy, Journal};
+use super::now_ms;
+use crate::utils::sha256::{Digest, sha256, to_hex};
+
+// Tamper-evident audit log.
+//
+// Each entry is one journal record laid out as
+//
+//   [prev: 32 bytes][at_ms: u64 LE][actor][action][detail]
+//
+// where the three strings are u32-length-prefixed UTF-8 and `prev` is the
+// SHA-256 of the previous record's payload (all zeros for the first one).
+// Changing, removing or reordering any entry breaks the chain at the next
+// one, which `verify` reports.
+//
+// The chain can't tell on its own whether entries were cut off the end, or
+// whether the very last entry was edited. For that, keep the `head()` digest
+// somewhere else (print it, ship it to another machine) and pass it to
+// `verify` later.
+//
+// Opening the log never throws entries away to make it fit: a record
+// that fails its checksum, anywhere, is a reason to refuse. The one thing
+// cut off is a last record that stops short of its length, which is what
+// a crash mid-append leaves and was never a complete entry.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub at_ms: u64,
+    // Who did it, e.g. a token id or "cli".
+    pub actor: String,
+    // What was done, e.g. "auth.denied" or "queue.purge".
+    pub action: String,
+    pub detail: String,
+}
+
+pub struct AuditLog<B = Journal> {
+    backend: B,
+    head: Digest,
+    len: u64,
+}
+
+impl AuditLog {
+    // Open (or create) the log at `path`. The existing chain is checked on
+    // the way in, so appending never extends a chain that's already broken.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_backend(Journal::open_strict(path, FsyncPolicy::Always)?)
+    }
+}
+
+impl<B: StorageBackend> AuditLog<B> {
+    // The log kept in `backend`, checked the same way as by `open`.
+    pub fn with_backend(backend: B) -> io::Result<Self> {
+        let report = verify_records(backend.records()?);
+        if let Some(problem) = report.problem {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                problem.to_string(),
+            ));
+        }
+        Ok(Self {
+            backend,
+            head: report.head,
+            len: report.entries,
+        })
+    }
+
+    pub fn append(&mut self, actor: &str, action: &str, detail: &str) -> io::Result<Digest> {
+        let entry = AuditEntry {
+            at_ms: now_ms(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            detail: detail.to_string(),
+        };
+        let payload = encode(&self.head, &entry);
+        self.backend.append(&payload)?;
+        self.head = sha256(&payload);
+        self.len += 1;
+        Ok(self.head)
+    }
+
+    // Digest of the latest entry; the anchor to keep for `verify`.
+    pub fn head(&self) -> Digest {
+        self.head
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn entries(&self) -> io::Result<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        for record in self.backend.records()? {
+            let (_, entry) = decode(&record?)?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditProblem {
+    // Entry `index` (0-based) doesn't point at the entry before it.
+    BrokenChain { index: u64 },
+    // A record couldn't be read or decoded.
+    Corrupt { index: u64, error: String },
+    // The chain is intact but doesn't end where the caller expected.
+    HeadMismatch { expected: Digest, actual: Digest },
+}
+
+impl fmt::Display for AuditProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditProblem::BrokenChain { index } => write!(
+                f,
+                "entry {} does not follow entry {}: the log was modified",
+                index,
+                index.saturating_sub(1)
+            ),
+            AuditProblem::Corrupt { index, error } => {
+                write!(f, "entry {} is unreadable: {}", index, error)
+            }
+            AuditProblem::HeadMismatch { expected, actual } => write!(
+                f,
+                "log ends at {} but {} was expected: entries were removed or the last one changed",
+                to_hex(actual),
+                to_hex(expected)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    // Entries that checked out before the first problem (or in total).
+    pub entries: u64,
+    // Digest of the last good entry.
+    pub head: Digest,
+    pub problem: Option<AuditProblem>,
+}
+
+// Check the log at `path` without modifying it. With `expected_head`, also
+// check that the log ends exactly at that entry.
+pub fn verify<P: AsRef<Path>>(path: P, expected_head: Option<Digest>) -> io::Result<VerifyReport> {
+    // Not `Journal::open`: that would quietly cut off a torn tail, and a
+    // verifier must not change what it's verifying.
+    let records = journal::read_records(path)?.map(|record| record.map(|r| r.payload));
+    let mut report = verify_records(records);
+    if report.problem.is_none()
+        && let Some(expected) = expected_head
+        && expected != report.head
+    {
+        report.problem = Some(AuditProblem::HeadMismatch {
+            expected,
+            actual: report.head,
+        });
+    }
+    Ok(report)
+}
+
+fn verify_records(records: impl Iterator<Item = io::Result<Vec<u8>>>) -> VerifyReport {
+    let mut head = [0u8; 32];
+    let mut entries = 0;
+    for record in records {
+        let problem = match record {
+            Err(e) => Some(AuditProblem::Corrupt {
+                index: entries,
+                error: e.to_string(),
+            }),
+            Ok(payload) => match decode(&payload) {
+                Err(e) => Some(AuditProblem::Corrupt {
+                    index: entries,
+                    error: e.to_string(),
+                }),
+                Ok((prev, _)) if prev != head => Some(AuditProblem::BrokenChain { index: entries }),
+                Ok(_) => {
+                    head = sha256(&payload);
+                    None
+                }
+            },
+        };
+        if problem.is_some() {
+            return VerifyReport {
+                entries,
+                head,
+                problem,
+            };
+        }
+        entries += 1;
+    }
+    VerifyReport {
+        entries,
+        head,
+        problem: None,
+    }
+}
+
+fn encode(prev: &Digest, entry: &AuditEntry) -> Vec<u8> {
+    let mut out = Vec::with_capacity(32 + 8 + 12 + entry.detail.len());
+    out.extend_from_slice(prev);
+    out.extend_from_slice(&entry.at_ms.to_le_bytes());
+    for s in [&entry.actor, &entry.action, &entry.detail] {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+    out
+}
+
+fn decode(payload: &[u8]) -> io::Result<(Digest, AuditEntry)> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "audit entry is malformed");
+    let prev: Digest = payload.get(..32).ok_or_else(bad)?.try_into().unwrap();
+    let at_ms = u64::from_le_bytes(payload.get(32..40).ok_or_else(bad)?.try_into().unwrap());
+
+    let mut rest = &payload[40..];
+    let mut next_string = || -> io::Result<String> {
+        let len = u32::from_le_bytes(rest.get(..4).ok_or_else(bad)?.try_into().unwrap()) as usize;
+        let bytes = rest.get(4..4 + len).ok_or_else(bad)?;
+        rest = &rest[4 + len..];
+        String::from_utf8(bytes.to_vec()).map_err(|_| bad())
+    };
+    let entry = AuditEntry {
+        at_ms,
+        actor: next_string()?,
+        action: next_string()?,
+        detail: next_string()?,
+    };
+    Ok((prev, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("audit-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A log of three entries; returns its path and head.
+    fn three_entries(dir: &TempDir) -> (PathBuf, Digest) {
+        let path = dir.0.join("audit.log");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append("cli", "token.mint", "id=a").unwrap();
+        log.append("tok-a", "auth.ok", "").unwrap();
+        let head = log.append("cli", "token.revoke", "id=a").unwrap();
+        (path, head)
+    }
+
+    fn payloads(path: &Path) -> Vec<Vec<u8>> {
+        journal::read_records(path)
+            .unwrap()
+            .map(|r| r.unwrap().payload)
+            .collect()
+    }
+
+    // Rewrite the log with `payloads`, checksums and all, the way someone
+    // covering their tracks would.
+    fn rewrite(path: &Path, payloads: &[Vec<u8>]) {
+        let bytes: Vec<u8> = payloads
+            .iter()
+            .flat_map(|p| journal::encode_record(p))
+            .collect();
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn open_err(path: &Path) -> io::Error {
+        match AuditLog::open(path) {
+            Ok(_) => panic!("{} opened", path.display()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn an_untouched_log_verifies() {
+        let dir = TempDir::new("intact");
+        let (path, head) = three_entries(&dir);
+
+        let report = verify(&path, Some(head)).unwrap();
+        assert_eq!(
+            (report.entries, report.head, report.problem),
+            (3, head, None)
+        );
+        let log = AuditLog::open(&path).unwrap();
+        assert_eq!((log.len(), log.head()), (3, head));
+        let actions: Vec<_> = log
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.action)
+            .collect();
+        assert_eq!(actions, ["token.mint", "auth.ok", "token.revoke"]);
+    }
+
+    #[test]
+    fn tampering_is_reported() {
+        let dir = TempDir::new("tamper");
+        let (path, head) = three_entries(&dir);
+        let original = payloads(&path);
+
+        // An edit with the checksum fixed up breaks the chain at the next
+        // entry.
+        let mut edited = original.clone();
+        edited[1][45] ^= 0x20;
+        rewrite(&path, &edited);
+        let report = verify(&path, Some(head)).unwrap();
+        assert_eq!(report.problem, Some(AuditProblem::BrokenChain { index: 2 }));
+        assert_eq!(report.entries, 2);
+
+        // A removed entry does too.
+        rewrite(&path, &[original[0].clone(), original[2].clone()]);
+        let report = verify(&path, None).unwrap();
+        assert_eq!(report.problem, Some(AuditProblem::BrokenChain { index: 1 }));
+
+        // Cutting off the end only shows against the head kept elsewhere.
+        rewrite(&path, &original[..2]);
+        assert_eq!(verify(&path, None).unwrap().problem, None);
+        let report = verify(&path, Some(head)).unwrap();
+        assert!(matches!(
+            report.problem,
+            Some(AuditProblem::HeadMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn a_tampered_log_is_not_cut_down_on_reopen() {
+        let dir = TempDir::new("reopen");
+        let (path, head) = three_entries(&dir);
+        let mut bytes = fs::read(&path).unwrap();
+
+        // A flipped byte, checksum left alone, in the middle and in the last
+        // entry: opening refuses either way and leaves the file for
+        // `verify` to point at.
+        for at in [60, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[at] ^= 0x01;
+            fs::write(&path, &tampered).unwrap();
+
+            assert_eq!(open_err(&path).kind(), io::ErrorKind::InvalidData);
+            assert_eq!(fs::read(&path).unwrap(), tampered);
+            let report = verify(&path, Some(head)).unwrap();
+            assert!(matches!(report.problem, Some(AuditProblem::Corrupt { .. })));
+        }
+
+        // With the checksums fixed up, the broken chain refuses too.
+        fs::write(&path, &bytes).unwrap();
+        let mut edited = payloads(&path);
+        edited[0][33] ^= 0x01;
+        rewrite(&path, &edited);
+        let e = open_err(&path);
+        assert!(e.to_string().contains("entry 1 does not follow"), "{}", e);
+        assert_eq!(verify(&path, None).unwrap().entries, 1);
+
+        // A last entry cut short by a crash is the one thing dropped.
+        bytes.truncate(bytes.len() - 3);
+        fs::write(&path, &bytes).unwrap();
+        let log = AuditLog::open(&path).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(verify(&path, None).unwrap().problem, None);
+    }
+}
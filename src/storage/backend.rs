@@ -0,0 +1,98 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::journal::{Journal, MAX_RECORD_LEN};
+
+// Where a store keeps its records.
+//
+// The stores here (`AuditLog`, `JobQueue`) are all logs underneath: they
+// append one record per change and rebuild their state by reading the
+// records back in order when they open. `StorageBackend` is that much and
+// no more, so a store doesn't care what holds its records:
+//
+// - `Journal`, the default: a checksummed append-only file;
+// - `MemoryBackend`: a `Vec` in memory, for tests and throwaway stores.
+//
+// Another backend (a database table, say) only has to implement these
+// three methods, behind a feature if it needs a new dependency; the
+// stores take any `B: StorageBackend` through their `with_backend`
+// constructors.
+
+// The records of a backend, oldest first. A record that can't be read is
+// an error, after which the iterator should stop.
+pub type Records<'a> = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a>;
+
+pub trait StorageBackend {
+    // Add a record after the existing ones.
+    fn append(&mut self, payload: &[u8]) -> io::Result<()>;
+
+    fn records(&self) -> io::Result<Records<'_>>;
+
+    // Make everything appended so far durable, if the backend can.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl StorageBackend for Journal {
+    fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        Journal::append(self, payload).map(|_| ())
+    }
+
+    fn records(&self) -> io::Result<Records<'_>> {
+        Ok(Box::new(
+            self.iter()?.map(|record| record.map(|r| r.payload)),
+        ))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Journal::sync(self)
+    }
+}
+
+// Records held in memory. Clones share them, so a test can "reopen" a
+// store by handing a clone to a new one, as if it had restarted.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    records: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    // Refuses what a `Journal` would, so a store tested in memory doesn't
+    // fail only once it's on disk.
+    fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_RECORD_LEN as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record too large: {} bytes", payload.len()),
+            ));
+        }
+        self.lock().push(payload.to_vec());
+        Ok(())
+    }
+
+    // A copy as of now; records appended while iterating aren't seen.
+    fn records(&self) -> io::Result<Records<'_>> {
+        Ok(Box::new(self.lock().clone().into_iter().map(Ok)))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
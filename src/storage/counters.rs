@@ -0,0 +1,515 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::now_ms;
+use crate::utils::file_handling::{JsonFileError, read_json, write_json};
+
+// Counters that survive restarts.
+//
+// Each counter has a session value (since this process opened the file)
+// and a lifetime value (across every process that has used the file). The
+// file is JSON, rewritten atomically by `flush`:
+//
+//   {"counters": {"connections": 1042, ...},
+//    "sessions": [{"id": "...", "started_ms": ..., "uptime_ms": ...}, ...]}
+//
+// `flush` re-reads the file and adds only what this process counted since
+// its last flush, so two processes sharing a file (or a flush racing a
+// `reset`) don't overwrite each other's counts. Counters in the file that
+// this process never touches are kept as they are.
+//
+// Between processes, that read-and-rewrite is guarded by `lock_file`.
+//
+// `sessions` is the uptime history: one entry per process, updated on each
+// flush, the newest `MAX_SESSIONS` kept.
+
+pub const MAX_SESSIONS: usize = 20;
+
+// How long `lock_file` waits for another process's lock, and how old a
+// lock has to be before it's taken for one left by a process that died
+// holding it. Nothing holds it for more than a read and a write.
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+const LOCK_STALE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub id: String,
+    pub started_ms: u64,
+    pub uptime_ms: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Saved {
+    #[serde(default)]
+    counters: BTreeMap<String, u64>,
+    #[serde(default)]
+    sessions: Vec<SessionRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterValue {
+    pub name: String,
+    pub session: u64,
+    pub lifetime: u64,
+}
+
+struct State {
+    // The file as of the last load or flush.
+    saved: Saved,
+    session: BTreeMap<String, u64>,
+    // The part of `session` already added to the file.
+    flushed: BTreeMap<String, u64>,
+}
+
+pub struct PersistentCounters {
+    path: PathBuf,
+    session: SessionRecord,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl PersistentCounters {
+    // Load the counters at `path`; a missing file counts as all zeros.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, JsonFileError> {
+        let path = path.as_ref().to_path_buf();
+        let saved = load(&path)?;
+        let started_ms = now_ms();
+        Ok(Self {
+            session: SessionRecord {
+                id: format!("{}-{}", started_ms, std::process::id()),
+                started_ms,
+                uptime_ms: 0,
+            },
+            path,
+            started: Instant::now(),
+            state: Mutex::new(State {
+                saved,
+                session: BTreeMap::new(),
+                flushed: BTreeMap::new(),
+            }),
+        })
+    }
+
+    pub fn add(&self, name: &str, n: u64) {
+        let mut state = self.lock();
+        *state.session.entry(name.to_string()).or_insert(0) += n;
+    }
+
+    pub fn session(&self, name: &str) -> u64 {
+        self.lock().session.get(name).copied().unwrap_or(0)
+    }
+
+    pub fn lifetime(&self, name: &str) -> u64 {
+        let state = self.lock();
+        lifetime(&state, name)
+    }
+
+    // Every counter this session or the file knows about, by name.
+    pub fn snapshot(&self) -> Vec<CounterValue> {
+        let state = self.lock();
+        let mut names: Vec<&String> = state
+            .saved
+            .counters
+            .keys()
+            .chain(state.session.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|name| CounterValue {
+                name: name.clone(),
+                session: state.session.get(name).copied().unwrap_or(0),
+                lifetime: lifetime(&state, name),
+            })
+            .collect()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // This process's entry in the history.
+    pub fn session_id(&self) -> &str {
+        &self.session.id
+    }
+
+    // Earlier sessions' uptimes, oldest first, as of the last flush.
+    pub fn history(&self) -> Vec<SessionRecord> {
+        self.lock().saved.sessions.clone()
+    }
+
+    // Add what's been counted since the last flush to the file, and record
+    // this session's uptime.
+    pub fn flush(&self) -> Result<(), JsonFileError> {
+        let mut state = self.lock();
+        let _file = lock_json(&self.path)?;
+        let mut saved = load(&self.path)?;
+        let State {
+            session, flushed, ..
+        } = &mut *state;
+        for (name, &count) in session.iter() {
+            let already = flushed.get(name).copied().unwrap_or(0);
+            *saved.counters.entry(name.clone()).or_insert(0) += count - already;
+        }
+
+        let record = SessionRecord {
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            ..self.session.clone()
+        };
+        match saved.sessions.iter_mut().find(|s| s.id == record.id) {
+            Some(existing) => *existing = record,
+            None => saved.sessions.push(record),
+        }
+        let excess = saved.sessions.len().saturating_sub(MAX_SESSIONS);
+        saved.sessions.drain(..excess);
+
+        write_json(&self.path, &saved, true)?;
+        state.flushed = state.session.clone();
+        state.saved = saved;
+        Ok(())
+    }
+
+    // Start the lifetime counts (and history) over from zero. Counted but
+    // unflushed session values are dropped from the file too; they still
+    // show as session values.
+    pub fn reset(&self) -> Result<(), JsonFileError> {
+        let mut state = self.lock();
+        let _file = lock_json(&self.path)?;
+        write_json(&self.path, &Saved::default(), true)?;
+        state.flushed = state.session.clone();
+        state.saved = Saved::default();
+        Ok(())
+    }
+
+    // Take the sessions in `ids` out of the history in the file. Unlike
+    // `without_sessions`, this can't race this process's own flushes. This
+    // process's session stays: the next flush would only add it back.
+    pub fn remove_sessions(&self, ids: &[String]) -> Result<(), JsonFileError> {
+        let mut state = self.lock();
+        let _file = lock_json(&self.path)?;
+        let mut saved = load(&self.path)?;
+        saved
+            .sessions
+            .retain(|s| s.id == self.session.id || !ids.contains(&s.id));
+        write_json(&self.path, &saved, true)?;
+        state.saved = saved;
+        Ok(())
+    }
+
+    // Only plain map updates happen under the lock, so a poisoned one still
+    // guards consistent data.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn lifetime(state: &State, name: &str) -> u64 {
+    let saved = state.saved.counters.get(name).copied().unwrap_or(0);
+    let session = state.session.get(name).copied().unwrap_or(0);
+    let flushed = state.flushed.get(name).copied().unwrap_or(0);
+    saved + session - flushed
+}
+
+// The uptime history in the counters file at `path`, oldest first.
+pub fn load_history(path: &Path) -> Result<Vec<SessionRecord>, JsonFileError> {
+    Ok(load(path)?.sessions)
+}
+
+// The counters file at `path` with the sessions in `ids` taken out of its
+// history, as `flush` would write it, for a caller that writes files
+// itself (through a `MutationSink`, say). Hold `lock_file` from before this
+// until the write, or a server flushing the same file in between would
+// have its flush overwritten.
+pub fn without_sessions(path: &Path, ids: &[String]) -> Result<Vec<u8>, JsonFileError> {
+    let mut saved = load(path)?;
+    saved.sessions.retain(|s| !ids.contains(&s.id));
+    let mut text =
+        serde_json::to_string_pretty(&saved).map_err(|source| JsonFileError::Serialize {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    text.push('\n');
+    Ok(text.into_bytes())
+}
+
+// Held until dropped; see `lock_file`.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Lock the counters file at `path` against other processes: a
+// `<path>.lock` file, created exclusively, that every rewrite of the file
+// takes first. Waits up to `LOCK_WAIT` for another holder.
+pub fn lock_file(path: &Path) -> io::Result<FileLock> {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".lock");
+    let lock = PathBuf::from(name);
+    let deadline = Instant::now() + LOCK_WAIT;
+    loop {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock)
+        {
+            Ok(_) => return Ok(FileLock { path: lock }),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        let stale = fs::metadata(&lock)
+            .and_then(|m| m.modified())
+            .is_ok_and(|at| at.elapsed().is_ok_and(|age| age > LOCK_STALE));
+        if stale {
+            let _ = fs::remove_file(&lock);
+        } else if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{} is held by another process (remove it if nothing is)",
+                    lock.display()
+                ),
+            ));
+        } else {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn lock_json(path: &Path) -> Result<FileLock, JsonFileError> {
+    lock_file(path).map_err(|source| JsonFileError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn load(path: &Path) -> Result<Saved, JsonFileError> {
+    match read_json(path) {
+        Err(JsonFileError::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Saved::default())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("counters-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn lock_waits_for_the_holder() {
+        let dir = TempDir::new("lock");
+        let path = dir.0.join("counters.json");
+        let held = lock_file(&path).unwrap();
+        let waiter = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let started = Instant::now();
+                let _lock = lock_file(&path).unwrap();
+                started.elapsed()
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        drop(held);
+        assert!(waiter.join().unwrap() >= Duration::from_millis(100));
+        assert!(!dir.0.join("counters.json.lock").exists());
+    }
+
+    #[test]
+    fn lifetime_counts_carry_over_and_flushes_add_only_whats_new() {
+        let dir = TempDir::new("carry");
+        let path = dir.0.join("counters.json");
+        let first = PersistentCounters::open(&path).unwrap();
+        first.add("connections", 2);
+        first.flush().unwrap();
+        first.add("connections", 1);
+        first.flush().unwrap();
+        // Nothing new since the last flush adds nothing.
+        first.flush().unwrap();
+
+        let second = PersistentCounters::open(&path).unwrap();
+        second.add("connections", 4);
+        second.add("errors", 1);
+
+        assert_eq!(second.session("connections"), 4);
+        assert_eq!(second.lifetime("connections"), 7);
+        assert_eq!(
+            second.snapshot(),
+            [
+                CounterValue {
+                    name: "connections".to_string(),
+                    session: 4,
+                    lifetime: 7
+                },
+                CounterValue {
+                    name: "errors".to_string(),
+                    session: 1,
+                    lifetime: 1
+                },
+            ]
+        );
+        second.flush().unwrap();
+        assert_eq!(load(&path).unwrap().counters["connections"], 7);
+    }
+
+    #[test]
+    fn two_processes_sharing_a_file_keep_each_others_counts() {
+        let dir = TempDir::new("shared");
+        let path = dir.0.join("counters.json");
+        let a = PersistentCounters::open(&path).unwrap();
+        let b = PersistentCounters::open(&path).unwrap();
+
+        a.add("connections", 5);
+        b.add("connections", 3);
+        a.flush().unwrap();
+        b.flush().unwrap();
+        a.add("connections", 1);
+        a.flush().unwrap();
+
+        assert_eq!(load(&path).unwrap().counters["connections"], 9);
+        assert_eq!(a.lifetime("connections"), 9);
+    }
+
+    #[test]
+    fn reset_starts_the_file_over_but_not_the_session() {
+        let dir = TempDir::new("reset");
+        let path = dir.0.join("counters.json");
+        let counters = PersistentCounters::open(&path).unwrap();
+        counters.add("connections", 5);
+        counters.flush().unwrap();
+        counters.add("connections", 2);
+
+        counters.reset().unwrap();
+
+        assert_eq!(counters.session("connections"), 7);
+        assert_eq!(counters.lifetime("connections"), 0);
+        assert!(counters.history().is_empty());
+        counters.add("connections", 1);
+        counters.flush().unwrap();
+        assert_eq!(load(&path).unwrap().counters["connections"], 1);
+    }
+
+    #[test]
+    fn the_history_keeps_the_newest_sessions() {
+        let dir = TempDir::new("history");
+        let path = dir.0.join("counters.json");
+        let old: Vec<_> = (0..MAX_SESSIONS + 5)
+            .map(|i| SessionRecord {
+                id: format!("old-{}", i),
+                started_ms: i as u64,
+                uptime_ms: 1,
+            })
+            .collect();
+        write_json(
+            &path,
+            &Saved {
+                counters: BTreeMap::new(),
+                sessions: old,
+            },
+            true,
+        )
+        .unwrap();
+
+        let counters = PersistentCounters::open(&path).unwrap();
+        counters.flush().unwrap();
+        counters.flush().unwrap();
+
+        let history = load_history(&path).unwrap();
+        assert_eq!(history.len(), MAX_SESSIONS);
+        // This session once, newest, and the oldest ones gone.
+        assert_eq!(history.last().unwrap().id, counters.session_id());
+        assert_eq!(history[0].id, "old-6");
+        assert_eq!(counters.history(), history);
+    }
+
+    #[test]
+    fn a_damaged_file_is_an_error_and_a_missing_one_is_zeros() {
+        let dir = TempDir::new("damaged");
+        let path = dir.0.join("counters.json");
+        assert!(
+            PersistentCounters::open(&path)
+                .unwrap()
+                .snapshot()
+                .is_empty()
+        );
+
+        fs::write(&path, "{\"counters\": [").unwrap();
+        assert!(matches!(
+            PersistentCounters::open(&path),
+            Err(JsonFileError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn a_lock_left_by_a_dead_process_is_taken_over() {
+        let dir = TempDir::new("stale");
+        let path = dir.0.join("counters.json");
+        let lock = dir.0.join("counters.json.lock");
+        let left = fs::File::create(&lock).unwrap();
+        left.set_modified(std::time::SystemTime::now() - LOCK_STALE * 2)
+            .unwrap();
+
+        let started = Instant::now();
+        let held = lock_file(&path).unwrap();
+
+        assert!(started.elapsed() < LOCK_WAIT);
+        assert!(lock.exists());
+        drop(held);
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn removing_sessions_keeps_the_counts() {
+        let dir = TempDir::new("remove");
+        let path = dir.0.join("counters.json");
+        let first = PersistentCounters::open(&path).unwrap();
+        first.add("connections", 3);
+        first.flush().unwrap();
+        let old = first.session_id().to_string();
+
+        let second = PersistentCounters::open(&path).unwrap();
+        second.add("connections", 2);
+        second.flush().unwrap();
+        fs::write(
+            &path,
+            without_sessions(&path, std::slice::from_ref(&old)).unwrap(),
+        )
+        .unwrap();
+
+        let history = load_history(&path).unwrap();
+        assert!(history.iter().all(|s| s.id != old));
+        assert_eq!(load(&path).unwrap().counters["connections"], 5);
+    }
+}
@@ -0,0 +1,550 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use super::backend::StorageBackend;
+use super::journal::{FsyncPolicy, Journal};
+use super::now_ms;
+
+// Persistent priority job queue.
+//
+// Jobs wait in one of two heaps:
+// - `delayed`, ordered by when they become runnable;
+// - `ready`, ordered by priority (highest first, then oldest first).
+// `pop_ready` first moves every job whose time has come from `delayed` into
+// `ready`, then hands out the top of `ready`.
+//
+// Every state change is appended to a journal (or whatever `StorageBackend`
+// `with_backend` was given), and opening replays it, so queued jobs survive
+// a restart. A job that was handed out but never completed or failed before
+// a crash is simply queued again: delivery is at-least-once.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: u64,
+    pub name: String,
+    pub payload: Vec<u8>,
+    // Higher runs first.
+    pub priority: u8,
+    // Milliseconds since the Unix epoch; the job won't run before this.
+    pub run_at_ms: u64,
+    // How many times the job has been attempted so far.
+    pub attempts: u32,
+    pub max_attempts: u32,
+}
+
+// What the caller supplies when queueing a job.
+#[derive(Debug, Clone)]
+pub struct JobSpec {
+    pub name: String,
+    pub payload: Vec<u8>,
+    pub priority: u8,
+    pub delay: Duration,
+    pub max_attempts: u32,
+}
+
+impl JobSpec {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            payload: Vec::new(),
+            priority: 0,
+            delay: Duration::ZERO,
+            max_attempts: 3,
+        }
+    }
+}
+
+// What happened to a job after `fail`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailOutcome {
+    // Queued again to run at the given time (ms since epoch).
+    Retrying { run_at_ms: u64 },
+    // Out of attempts; the job has been dropped.
+    Dead,
+}
+
+pub struct JobQueue<B = Journal> {
+    backend: B,
+    jobs: HashMap<u64, Job>,
+    ready: BinaryHeap<ReadyKey>,
+    delayed: BinaryHeap<Reverse<(u64, u64)>>, // (run_at_ms, id)
+    next_id: u64,
+    // Retry delay is `backoff_base * 2^(attempts - 1)`.
+    backoff_base: Duration,
+}
+
+impl JobQueue {
+    // Open the queue stored at `path`, replaying its journal.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_backend(Journal::open(path, FsyncPolicy::Always)?)
+    }
+}
+
+impl<B: StorageBackend> JobQueue<B> {
+    // The queue kept in `backend`, replaying what's already there.
+    pub fn with_backend(backend: B) -> io::Result<Self> {
+        let mut queue = Self {
+            backend,
+            jobs: HashMap::new(),
+            ready: BinaryHeap::new(),
+            delayed: BinaryHeap::new(),
+            next_id: 1,
+            backoff_base: Duration::from_secs(1),
+        };
+
+        let mut replayed: HashMap<u64, Job> = HashMap::new();
+        for record in queue.backend.records()? {
+            match Event::decode(&record?)? {
+                Event::Enqueued(job) => {
+                    queue.next_id = queue.next_id.max(job.id + 1);
+                    replayed.insert(job.id, job);
+                }
+                Event::Retry {
+                    id,
+                    attempts,
+                    run_at_ms,
+                } => {
+                    if let Some(job) = replayed.get_mut(&id) {
+                        job.attempts = attempts;
+                        job.run_at_ms = run_at_ms;
+                    }
+                }
+                Event::Done { id } => {
+                    replayed.remove(&id);
+                }
+            }
+        }
+        for job in replayed.into_values() {
+            queue.schedule(job);
+        }
+        Ok(queue)
+    }
+
+    pub fn set_backoff_base(&mut self, base: Duration) {
+        self.backoff_base = base;
+    }
+
+    // Queue a job and return its id.
+    pub fn push(&mut self, spec: JobSpec) -> io::Result<u64> {
+        let id = self.next_id;
+        let job = Job {
+            id,
+            name: spec.name,
+            payload: spec.payload,
+            priority: spec.priority,
+            run_at_ms: now_ms() + spec.delay.as_millis() as u64,
+            attempts: 0,
+            max_attempts: spec.max_attempts.max(1),
+        };
+        self.backend
+            .append(&Event::Enqueued(job.clone()).encode())?;
+        self.next_id += 1;
+        self.schedule(job);
+        Ok(id)
+    }
+
+    // Take the highest-priority job that is due at `now_ms`, if any.
+    // The job stays on record until `complete` or `fail` is called for it.
+    pub fn pop_ready(&mut self, now_ms: u64) -> Option<Job> {
+        while let Some(&Reverse((run_at, id))) = self.delayed.peek() {
+            if run_at > now_ms {
+                break;
+            }
+            self.delayed.pop();
+            if let Some(job) = self.jobs.get(&id) {
+                self.ready.push(ReadyKey::of(job));
+            }
+        }
+
+        // Skip heap entries for jobs that were completed in the meantime.
+        while let Some(key) = self.ready.pop() {
+            if let Some(job) = self.jobs.get(&key.id) {
+                let mut job = job.clone();
+                job.attempts += 1;
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    // The job finished; forget about it.
+    pub fn complete(&mut self, id: u64) -> io::Result<()> {
+        self.backend.append(&Event::Done { id }.encode())?;
+        self.jobs.remove(&id);
+        Ok(())
+    }
+
+    // The job failed. It is retried with exponential backoff until it runs
+    // out of attempts.
+    pub fn fail(&mut self, job: &Job) -> io::Result<FailOutcome> {
+        if job.attempts >= job.max_attempts {
+            self.complete(job.id)?;
+            return Ok(FailOutcome::Dead);
+        }
+
+        let exponent = job.attempts.saturating_sub(1).min(16);
+        let delay = self.backoff_base * 2u32.pow(exponent);
+        let run_at_ms = now_ms() + delay.as_millis() as u64;
+        self.backend.append(
+            &Event::Retry {
+                id: job.id,
+                attempts: job.attempts,
+                run_at_ms,
+            }
+            .encode(),
+        )?;
+
+        let mut retry = job.clone();
+        retry.run_at_ms = run_at_ms;
+        self.schedule(retry);
+        Ok(FailOutcome::Retrying { run_at_ms })
+    }
+
+    // Run every job that is due right now through `handler`, completing or
+    // failing each one based on what the handler returns.
+    pub fn run_pending<F>(&mut self, mut handler: F) -> io::Result<usize>
+    where
+        F: FnMut(&Job) -> Result<(), String>,
+    {
+        let mut ran = 0;
+        while let Some(job) = self.pop_ready(now_ms()) {
+            ran += 1;
+            match handler(&job) {
+                Ok(()) => self.complete(job.id)?,
+                Err(_) => {
+                    self.fail(&job)?;
+                }
+            }
+        }
+        Ok(ran)
+    }
+
+    // Jobs queued and not yet completed (including delayed ones).
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    fn schedule(&mut self, job: Job) {
+        self.delayed.push(Reverse((job.run_at_ms, job.id)));
+        self.jobs.insert(job.id, job);
+    }
+}
+
+// Ordering for the ready heap: higher priority first, then lower id (older).
+#[derive(Debug, PartialEq, Eq)]
+struct ReadyKey {
+    priority: u8,
+    id: u64,
+}
+
+impl ReadyKey {
+    fn of(job: &Job) -> Self {
+        Self {
+            priority: job.priority,
+            id: job.id,
+        }
+    }
+}
+
+impl Ord for ReadyKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for ReadyKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Journal entries. Encoded as a tag byte followed by little-endian fields;
+// strings and byte blobs are prefixed with their u32 length.
+enum Event {
+    Enqueued(Job),
+    Retry {
+        id: u64,
+        attempts: u32,
+        run_at_ms: u64,
+    },
+    Done {
+        id: u64,
+    },
+}
+
+impl Event {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Event::Enqueued(job) => {
+                out.push(1);
+                out.extend_from_slice(&job.id.to_le_bytes());
+                put_bytes(&mut out, job.name.as_bytes());
+                put_bytes(&mut out, &job.payload);
+                out.push(job.priority);
+                out.extend_from_slice(&job.run_at_ms.to_le_bytes());
+                out.extend_from_slice(&job.attempts.to_le_bytes());
+                out.extend_from_slice(&job.max_attempts.to_le_bytes());
+            }
+            Event::Retry {
+                id,
+                attempts,
+                run_at_ms,
+            } => {
+                out.push(2);
+                out.extend_from_slice(&id.to_le_bytes());
+                out.extend_from_slice(&attempts.to_le_bytes());
+                out.extend_from_slice(&run_at_ms.to_le_bytes());
+            }
+            Event::Done { id } => {
+                out.push(3);
+                out.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut r = Reader { bytes, pos: 0 };
+        let event = match r.u8()? {
+            1 => Event::Enqueued(Job {
+                id: r.u64()?,
+                name: String::from_utf8(r.bytes()?.to_vec())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                payload: r.bytes()?.to_vec(),
+                priority: r.u8()?,
+                run_at_ms: r.u64()?,
+                attempts: r.u32()?,
+                max_attempts: r.u32()?,
+            }),
+            2 => Event::Retry {
+                id: r.u64()?,
+                attempts: r.u32()?,
+                run_at_ms: r.u64()?,
+            },
+            3 => Event::Done { id: r.u64()? },
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown job queue event tag {}", tag),
+                ));
+            }
+        };
+        Ok(event)
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + n;
+        let slice = self.bytes.get(self.pos..end).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "job queue event is truncated")
+        })?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::MemoryBackend;
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("job-queue-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn spec(name: &str, priority: u8) -> JobSpec {
+        JobSpec {
+            priority,
+            ..JobSpec::new(name)
+        }
+    }
+
+    #[test]
+    fn higher_priority_runs_first_then_older() {
+        let mut queue = JobQueue::with_backend(MemoryBackend::new()).unwrap();
+        queue.push(spec("low", 1)).unwrap();
+        queue.push(spec("high", 5)).unwrap();
+        queue.push(spec("also high", 5)).unwrap();
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop_ready(u64::MAX))
+            .map(|job| job.name)
+            .collect();
+        assert_eq!(order, ["high", "also high", "low"]);
+        // Handed out, but on record until completed.
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn a_delayed_job_waits_for_its_time() {
+        let mut queue = JobQueue::with_backend(MemoryBackend::new()).unwrap();
+        let before = now_ms();
+        queue
+            .push(JobSpec {
+                delay: Duration::from_secs(60),
+                ..JobSpec::new("later")
+            })
+            .unwrap();
+        queue.push(spec("now", 0)).unwrap();
+
+        assert_eq!(queue.pop_ready(now_ms()).unwrap().name, "now");
+        assert_eq!(queue.pop_ready(before + 59_000), None);
+        let later = queue.pop_ready(before + 61_000).unwrap();
+        assert_eq!((later.name.as_str(), later.attempts), ("later", 1));
+    }
+
+    #[test]
+    fn failures_back_off_exponentially_until_the_job_is_dead() {
+        let mut queue = JobQueue::with_backend(MemoryBackend::new()).unwrap();
+        queue.set_backoff_base(Duration::from_secs(10));
+        queue
+            .push(JobSpec {
+                max_attempts: 3,
+                ..JobSpec::new("flaky")
+            })
+            .unwrap();
+
+        let mut job = queue.pop_ready(now_ms()).unwrap();
+        for (attempt, delay_ms) in [(1, 10_000), (2, 20_000)] {
+            assert_eq!(job.attempts, attempt);
+            let before = now_ms();
+            let FailOutcome::Retrying { run_at_ms } = queue.fail(&job).unwrap() else {
+                panic!("attempt {} shouldn't be the last", attempt);
+            };
+            let waited = run_at_ms - before;
+            assert!((delay_ms..delay_ms + 1_000).contains(&waited), "{}", waited);
+            // Not handed out again before its retry time.
+            assert_eq!(queue.pop_ready(run_at_ms - 1), None);
+            job = queue.pop_ready(run_at_ms).unwrap();
+        }
+
+        assert_eq!(job.attempts, 3);
+        assert_eq!(queue.fail(&job).unwrap(), FailOutcome::Dead);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop_ready(u64::MAX), None);
+    }
+
+    #[test]
+    fn run_pending_completes_or_retries_by_the_handler_result() {
+        let mut queue = JobQueue::with_backend(MemoryBackend::new()).unwrap();
+        queue.push(spec("works", 0)).unwrap();
+        queue.push(spec("breaks", 0)).unwrap();
+
+        let ran = queue
+            .run_pending(|job| match job.name.as_str() {
+                "works" => Ok(()),
+                _ => Err("no".to_string()),
+            })
+            .unwrap();
+
+        // The failed job is back, but not due until its backoff is over.
+        assert_eq!(ran, 2);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop_ready(now_ms()), None);
+        assert_eq!(queue.pop_ready(u64::MAX).unwrap().name, "breaks");
+    }
+
+    #[test]
+    fn a_job_never_finished_is_handed_out_again_after_a_restart() {
+        let dir = TempDir::new("restart");
+        let path = dir.0.join("jobs.journal");
+        let (unfinished, retried) = {
+            let mut queue = JobQueue::open(&path).unwrap();
+            let done = queue.push(spec("done", 0)).unwrap();
+            let unfinished = queue.push(spec("unfinished", 0)).unwrap();
+            let retried = queue.push(spec("retried", 0)).unwrap();
+            while let Some(job) = queue.pop_ready(now_ms()) {
+                match job.id {
+                    id if id == done => queue.complete(id).unwrap(),
+                    id if id == retried => {
+                        queue.fail(&job).unwrap();
+                    }
+                    // Handed out, then the process "crashes".
+                    _ => {}
+                }
+            }
+            (unfinished, retried)
+        };
+
+        let mut queue = JobQueue::open(&path).unwrap();
+        assert_eq!(queue.len(), 2);
+        let job = queue.pop_ready(now_ms()).unwrap();
+        assert_eq!((job.id, job.attempts), (unfinished, 1));
+        // The retry keeps its attempt count and its backoff.
+        assert_eq!(queue.pop_ready(now_ms()), None);
+        let job = queue.pop_ready(u64::MAX).unwrap();
+        assert_eq!((job.id, job.attempts), (retried, 2));
+        // Ids carry on after the highest one on record.
+        assert_eq!(queue.push(spec("new", 0)).unwrap(), retried + 1);
+    }
+
+    #[test]
+    fn an_unknown_event_is_refused() {
+        let mut backend = MemoryBackend::new();
+        backend.append(&[9]).unwrap();
+
+        let e = JobQueue::with_backend(backend).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("tag 9"), "{}", e);
+
+        let mut backend = MemoryBackend::new();
+        backend
+            .append(&Event::Done { id: 1 }.encode()[..5])
+            .unwrap();
+        let e = JobQueue::with_backend(backend).err().unwrap();
+        assert!(e.to_string().contains("truncated"), "{}", e);
+    }
+}
@@ -0,0 +1,513 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Append-only journal of checksummed records.
+//
+// On-disk layout, repeated for every record:
+//
+//   [len: u32 LE][crc32(payload): u32 LE][payload: len bytes]
+//
+// Records are only ever appended. If the process dies halfway through an
+// append, the last record is torn: short, or with a bad checksum and
+// nothing after it. `open` cuts a torn tail off so new appends start from
+// a clean state. A bad record with more data after it can't have been
+// torn by a crash, so that's corruption, and `open` refuses with
+// `InvalidData` rather than throw away the good records that follow.
+
+const HEADER_LEN: u64 = 8;
+
+// Upper bound on a single record, so a corrupted length field can't make us
+// try to allocate gigabytes.
+pub const MAX_RECORD_LEN: u32 = 16 * 1024 * 1024;
+
+// When to fsync after appending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // fsync after every record: safest, slowest.
+    Always,
+    // fsync once every N records.
+    EveryN(u32),
+    // Leave it to the OS (or explicit `sync` calls).
+    Never,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    len: u64,
+    unsynced: u32,
+    recovered_bytes: u64,
+}
+
+impl Journal {
+    // Open (or create) the journal at `path`, truncating a torn tail if found.
+    // Corruption anywhere else is an `InvalidData` error and the file is
+    // left as it was.
+    pub fn open<P: AsRef<Path>>(path: P, policy: FsyncPolicy) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), policy, Tail::ShortOrBadChecksum)
+    }
+
+    // Like `open`, but only a last record cut short counts as torn. A whole
+    // last record with a bad checksum is an error like any other, for logs
+    // where a changed record is evidence rather than crash damage.
+    pub fn open_strict<P: AsRef<Path>>(path: P, policy: FsyncPolicy) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), policy, Tail::Short)
+    }
+
+    fn open_with(path: &Path, policy: FsyncPolicy, torn: Tail) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let file_len = file.metadata()?.len();
+        let valid_len = scan_valid_len(&path, file_len, torn)?;
+        if valid_len < file_len {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        Ok(Self {
+            path,
+            file,
+            policy,
+            len: valid_len,
+            unsynced: 0,
+            recovered_bytes: file_len - valid_len,
+        })
+    }
+
+    // Append one record and return the byte offset it was written at.
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<u64> {
+        if payload.len() > MAX_RECORD_LEN as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record too large: {} bytes", payload.len()),
+            ));
+        }
+
+        let offset = self.len;
+        let record = encode_record(payload);
+        let due = match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => self.unsynced + 1 >= n.max(1),
+            FsyncPolicy::Never => false,
+        };
+        let written = self
+            .file
+            .write_all(&record)
+            .and_then(|()| if due { self.sync() } else { Ok(()) });
+        if let Err(e) = written {
+            // Whatever part of the record made it out would sit between
+            // this record and the next; cut it off so a failed append
+            // leaves the journal as it was.
+            let _ = self.file.set_len(offset);
+            return Err(e);
+        }
+        self.len = offset + record.len() as u64;
+        if !due {
+            self.unsynced += 1;
+        }
+        Ok(offset)
+    }
+
+    // Force everything appended so far onto disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // Iterate over the records from the start of the journal.
+    pub fn iter(&self) -> io::Result<JournalIter> {
+        JournalIter::new(&self.path, u64::MAX)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Size of the valid part of the journal in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // How many bytes of torn/corrupt tail `open` had to cut off.
+    pub fn recovered_bytes(&self) -> u64 {
+        self.recovered_bytes
+    }
+}
+
+// One record as it's laid out on disk, header and all. A journal file is
+// nothing but these back to back, so whole files can be built in memory
+// (to go through a `MutationSink`, say). `payload` must be at most
+// `MAX_RECORD_LEN` bytes.
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+// Read the records of the journal at `path` without opening it for
+// writing, so a torn tail is reported as an error instead of truncated.
+// Meant for inspection tools.
+pub fn read_records<P: AsRef<Path>>(path: P) -> io::Result<JournalIter> {
+    JournalIter::new(path.as_ref(), u64::MAX)
+}
+
+// Like `read_records`, but a last record cut short by a crash is left out
+// instead of reported. Any other bad record is an `InvalidData` error from
+// this call, before a record is read. The file is never modified.
+pub fn read_intact_records<P: AsRef<Path>>(path: P) -> io::Result<JournalIter> {
+    let path = path.as_ref();
+    let file_len = fs::metadata(path)?.len();
+    let end = scan_valid_len(path, file_len, Tail::Short)?;
+    JournalIter::new(path, end)
+}
+
+// A single record read back from the journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: u64,
+    pub payload: Vec<u8>,
+}
+
+// Yields records in order. A short or checksum-failing record is reported as
+// an `InvalidData` error, after which the iterator stops.
+pub struct JournalIter {
+    reader: BufReader<File>,
+    offset: u64,
+    // Where to stop: the start of a torn tail, or `u64::MAX` to read it.
+    end: u64,
+    done: bool,
+}
+
+impl JournalIter {
+    fn new(path: &Path, end: u64) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            offset: 0,
+            end,
+            done: false,
+        })
+    }
+}
+
+impl Iterator for JournalIter {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.end {
+            return None;
+        }
+        match read_record(&mut self.reader, self.offset) {
+            Ok(Some(record)) => {
+                self.offset += HEADER_LEN + record.payload.len() as u64;
+                Some(Ok(record))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Read the record starting at `offset`. `Ok(None)` means a clean end of file.
+fn read_record<R: Read>(reader: &mut R, offset: u64) -> io::Result<Option<Record>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read < header.len() {
+        return Err(corrupt(offset, "truncated header"));
+    }
+
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let expected_crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len > MAX_RECORD_LEN {
+        return Err(corrupt(offset, "record length out of range"));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    if read_full(reader, &mut payload)? < payload.len() {
+        return Err(corrupt(offset, "truncated payload"));
+    }
+    if crc32(&payload) != expected_crc {
+        return Err(corrupt(offset, "checksum mismatch"));
+    }
+
+    Ok(Some(Record { offset, payload }))
+}
+
+// Like `read_exact`, but reports how much was read instead of failing on EOF.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// What a crash may have left at the end of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tail {
+    // A record cut short.
+    Short,
+    // That, or a last record whose bytes didn't all reach the disk.
+    ShortOrBadChecksum,
+}
+
+// Length of the prefix of the file made up of intact records, given that
+// anything after it is a torn tail. A bad record that isn't the last thing
+// in the file is returned as the error it is.
+fn scan_valid_len(path: &Path, file_len: u64, torn: Tail) -> io::Result<u64> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut offset = 0;
+    loop {
+        match read_record(&mut reader, offset) {
+            Ok(Some(record)) => offset += HEADER_LEN + record.payload.len() as u64,
+            Ok(None) => return Ok(offset),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return if is_torn(path, offset, file_len, torn)? {
+                    Ok(offset)
+                } else {
+                    Err(e)
+                };
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Whether the bad record at `offset` is what a crash during its append
+// would leave: a header cut short, or a record that runs past (or, with
+// `Tail::ShortOrBadChecksum`, exactly to) the end of the file. A length
+// over the limit is never written, so it's corruption even at the end.
+fn is_torn(path: &Path, offset: u64, file_len: u64, torn: Tail) -> io::Result<bool> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header = [0u8; HEADER_LEN as usize];
+    if read_full(&mut file, &mut header)? < header.len() {
+        return Ok(true);
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let end = offset + HEADER_LEN + len as u64;
+    Ok(len <= MAX_RECORD_LEN
+        && (end > file_len || (end == file_len && torn == Tail::ShortOrBadChecksum)))
+}
+
+fn corrupt(offset: u64, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt journal record at offset {}: {}", offset, what),
+    )
+}
+
+// CRC-32 (IEEE 802.3, the same polynomial zlib and PNG use).
+pub fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("journal-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A journal holding "one", "two", "three"; returns its path and the
+    // offset of each record.
+    fn three_records(dir: &TempDir) -> (PathBuf, Vec<u64>) {
+        let path = dir.0.join("journal");
+        let mut journal = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let offsets = ["one", "two", "three"]
+            .iter()
+            .map(|p| journal.append(p.as_bytes()).unwrap())
+            .collect();
+        (path, offsets)
+    }
+
+    fn payloads(path: &Path) -> Vec<String> {
+        read_records(path)
+            .unwrap()
+            .map(|r| String::from_utf8(r.unwrap().payload).unwrap())
+            .collect()
+    }
+
+    fn open_err(path: &Path) -> io::Error {
+        match Journal::open(path, FsyncPolicy::Never) {
+            Ok(_) => panic!("{} opened", path.display()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn records_survive_a_reopen() {
+        let dir = TempDir::new("reopen");
+        let (path, offsets) = three_records(&dir);
+        assert_eq!(offsets, [0, 11, 22]);
+
+        let mut journal = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(journal.recovered_bytes(), 0);
+        assert_eq!(journal.append(b"four").unwrap(), 35);
+        assert_eq!(payloads(&path), ["one", "two", "three", "four"]);
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_off() {
+        let dir = TempDir::new("torn");
+        let (path, _) = three_records(&dir);
+        let full = fs::read(&path).unwrap();
+        // Cut inside the last payload, inside its header, and keep the
+        // whole record but garble its last byte.
+        for (cut, garble) in [(full.len() - 2, false), (25, false), (full.len(), true)] {
+            let mut bytes = full[..cut].to_vec();
+            if garble {
+                *bytes.last_mut().unwrap() ^= 0xff;
+            }
+            fs::write(&path, &bytes).unwrap();
+
+            let mut journal = Journal::open(&path, FsyncPolicy::Never).unwrap();
+            assert_eq!(journal.recovered_bytes(), cut as u64 - 22);
+            assert_eq!(journal.len(), 22);
+            journal.append(b"again").unwrap();
+            assert_eq!(payloads(&path), ["one", "two", "again"]);
+        }
+    }
+
+    #[test]
+    fn read_intact_records_skips_only_a_short_tail() {
+        let dir = TempDir::new("intact");
+        let (path, _) = three_records(&dir);
+        let full = fs::read(&path).unwrap();
+
+        fs::write(&path, &full[..full.len() - 2]).unwrap();
+        let intact: Vec<_> = read_intact_records(&path)
+            .unwrap()
+            .map(|r| r.unwrap().payload)
+            .collect();
+        assert_eq!(intact, [b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), full.len() as u64 - 2);
+
+        let mut garbled = full.clone();
+        *garbled.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, &garbled).unwrap();
+        let e = read_intact_records(&path)
+            .err()
+            .expect("read a garbled record");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_bad_checksum_in_the_middle_refuses_to_open() {
+        let dir = TempDir::new("middle");
+        let (path, offsets) = three_records(&dir);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[offsets[1] as usize + HEADER_LEN as usize] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+
+        let e = open_err(&path);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("offset 11"), "{}", e);
+        // Nothing was cut off: "three" is still there for whoever repairs it.
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn an_oversized_length_refuses_to_open() {
+        let dir = TempDir::new("oversized");
+        let (path, offsets) = three_records(&dir);
+        let original = fs::read(&path).unwrap();
+        // In the middle, and in the last record, where it would otherwise
+        // look like a record running past the end of the file.
+        for offset in [offsets[1], offsets[2]] {
+            let mut bytes = original.clone();
+            let at = offset as usize;
+            bytes[at..at + 4].copy_from_slice(&(MAX_RECORD_LEN + 1).to_le_bytes());
+            fs::write(&path, &bytes).unwrap();
+
+            let e = open_err(&path);
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().contains("length out of range"), "{}", e);
+            assert_eq!(fs::read(&path).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn a_rejected_append_changes_nothing() {
+        let dir = TempDir::new("reject");
+        let (path, _) = three_records(&dir);
+        let mut journal = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let big = vec![0u8; MAX_RECORD_LEN as usize + 1];
+
+        let e = journal.append(&big).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(journal.len(), 35);
+        assert_eq!(journal.append(b"four").unwrap(), 35);
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
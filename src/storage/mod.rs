@@ -0,0 +1,19 @@
+pub mod audit;
+pub mod backend;
+pub mod counters;
+pub mod job_queue;
+pub mod journal;
+pub mod retention;
+pub mod state_archive;
+pub mod tokens;
+pub mod undo;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Milliseconds since the Unix epoch, or 0 if the clock is before it.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,563 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::counters::{self, SessionRecord};
+use crate::utils::file_handling::{JsonFileError, dir_size, walk_files};
+use crate::utils::mutation::{Mutation, MutationSink};
+
+// Retention: how much history a store keeps, and garbage collection to
+// hold it to that.
+//
+// A policy is one or more limits, separated by commas:
+//
+//     age=30d       drop entries older than this (s, m, h or d)
+//     bytes=100MB   keep only the newest entries that fit in this (B, KB,
+//                   MB or GB)
+//     entries=20    keep at most this many entries
+//
+// Entries are kept newest first until a limit is reached; that one and
+// every older one go. The stores it applies to:
+//
+//     sessions   the uptime history in a counters file
+//     undo       runs in an undo directory (gone for good: `undo` can't
+//                use them afterwards)
+//     logs       rotated copies of a log file (`app.log.1`, ...); the live
+//                file is never touched
+//
+// Removals and rewrites go through a `MutationSink`, so a dry run lists
+// them instead.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<usize>,
+}
+
+// Something a store could drop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    // What reports call it: a session or run id, a file name.
+    pub name: String,
+    // When it was last written, in ms since the Unix epoch.
+    pub at_ms: u64,
+    pub bytes: u64,
+}
+
+impl Policy {
+    // The indexes of the `items` this policy drops at `now_ms`, in order.
+    pub fn expired(&self, items: &[Item], now_ms: u64) -> Vec<usize> {
+        let mut newest_first: Vec<usize> = (0..items.len()).collect();
+        newest_first.sort_by_key(|&i| Reverse(items[i].at_ms));
+        let mut kept = 0;
+        let mut bytes = 0;
+        let mut full = false;
+        let mut dropped = Vec::new();
+        for i in newest_first {
+            let item = &items[i];
+            full = full
+                || self
+                    .max_age
+                    .is_some_and(|age| now_ms.saturating_sub(item.at_ms) > age.as_millis() as u64)
+                || self.max_entries.is_some_and(|max| kept >= max)
+                || self.max_bytes.is_some_and(|max| bytes + item.bytes > max);
+            if full {
+                dropped.push(i);
+            } else {
+                kept += 1;
+                bytes += item.bytes;
+            }
+        }
+        dropped.sort_unstable();
+        dropped
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = Policy::default();
+        for limit in s.split(',') {
+            let bad = || format!("bad retention limit '{}'", limit);
+            let (name, value) = limit.split_once('=').ok_or_else(bad)?;
+            match name.trim() {
+                "age" => policy.max_age = Some(parse_age(value.trim()).ok_or_else(bad)?),
+                "bytes" => policy.max_bytes = Some(parse_bytes(value.trim()).ok_or_else(bad)?),
+                "entries" => policy.max_entries = Some(value.trim().parse().map_err(|_| bad())?),
+                _ => {
+                    return Err(format!(
+                        "unknown retention limit '{}' (expected age, bytes or entries)",
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut limits = Vec::new();
+        if let Some(age) = self.max_age {
+            limits.push(format!("age={}s", age.as_secs()));
+        }
+        if let Some(bytes) = self.max_bytes {
+            limits.push(format!("bytes={}", bytes));
+        }
+        if let Some(entries) = self.max_entries {
+            limits.push(format!("entries={}", entries));
+        }
+        f.write_str(&limits.join(","))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Sessions,
+    Undo,
+    Logs,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::Sessions, Target::Undo, Target::Logs];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Target::Sessions => "sessions",
+            Target::Undo => "undo",
+            Target::Logs => "logs",
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::ALL
+            .into_iter()
+            .find(|target| target.as_str() == s)
+            .ok_or_else(|| format!("unknown store {:?} (expected sessions, undo or logs)", s))
+    }
+}
+
+// What one collection did, or with a dry run, would do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    pub target: Target,
+    pub path: PathBuf,
+    pub removed: Vec<Item>,
+    pub kept: usize,
+}
+
+impl GcReport {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.removed.iter().map(|item| item.bytes).sum()
+    }
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}): removed {}, kept {}, reclaimed {} bytes",
+            self.target,
+            self.path.display(),
+            self.removed.len(),
+            self.kept,
+            self.reclaimed_bytes()
+        )
+    }
+}
+
+// The entries `target` keeps at `path`: the counters file for sessions,
+// the undo directory, or the live log file whose rotated copies count.
+pub fn items(target: Target, path: &Path) -> io::Result<Vec<Item>> {
+    match target {
+        Target::Sessions => Ok(session_items(
+            &counters::load_history(path).map_err(json_error)?,
+        )),
+        Target::Undo => undo_items(path),
+        Target::Logs => rotated_logs(path),
+    }
+}
+
+// Apply `policy` to `target` at `path` as of `now_ms`.
+pub fn collect(
+    target: Target,
+    path: &Path,
+    policy: &Policy,
+    now_ms: u64,
+    sink: &mut dyn MutationSink,
+) -> io::Result<GcReport> {
+    let mut items = items(target, path)?;
+    let dropped = policy.expired(&items, now_ms);
+    let mut report = GcReport {
+        target,
+        path: path.to_path_buf(),
+        removed: Vec::new(),
+        kept: items.len() - dropped.len(),
+    };
+    if dropped.is_empty() {
+        return Ok(report);
+    }
+    for i in dropped.into_iter().rev() {
+        report.removed.insert(0, items.remove(i));
+    }
+    match target {
+        Target::Sessions => {
+            // From the read to the write, so a server's flush can't land in
+            // between and be lost.
+            let _lock = counters::lock_file(path)?;
+            let ids: Vec<String> = report.removed.iter().map(|i| i.name.clone()).collect();
+            sink.apply(Mutation::Write {
+                path: path.to_path_buf(),
+                contents: counters::without_sessions(path, &ids).map_err(json_error)?,
+            })?;
+        }
+        Target::Undo => {
+            for run in &report.removed {
+                let dir = path.join(&run.name);
+                for file in walk_files(&dir)? {
+                    sink.apply(Mutation::Remove { path: file })?;
+                }
+                // Like `undo`, the directories themselves go outside the
+                // sink.
+                if !sink.is_dry_run() {
+                    fs::remove_dir_all(&dir)?;
+                }
+            }
+        }
+        Target::Logs => {
+            let dir = log_dir(path);
+            for copy in &report.removed {
+                sink.apply(Mutation::Remove {
+                    path: dir.join(&copy.name),
+                })?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+// A session was last written when its uptime was.
+pub fn session_items(sessions: &[SessionRecord]) -> Vec<Item> {
+    sessions
+        .iter()
+        .map(|session| Item {
+            name: session.id.clone(),
+            at_ms: session.started_ms + session.uptime_ms,
+            bytes: serde_json::to_vec(session).map_or(0, |json| json.len() as u64),
+        })
+        .collect()
+}
+
+// Run ids start with the time the run began; a directory without a
+// manifest isn't a run and is left alone.
+fn undo_items(dir: &Path) -> io::Result<Vec<Item>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut items = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.path().join("manifest").is_file() {
+            continue;
+        }
+        let at_ms = match name.split('-').next().and_then(|ms| ms.parse().ok()) {
+            Some(ms) => ms,
+            None => modified_ms(&entry.metadata()?),
+        };
+        items.push(Item {
+            bytes: dir_size(&entry.path())?,
+            name,
+            at_ms,
+        });
+    }
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(items)
+}
+
+// `app.log.1`, `app.log.2`, ... next to `app.log`, in that order.
+fn rotated_logs(log: &Path) -> io::Result<Vec<Item>> {
+    let prefix = match log.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(Vec::new()),
+    };
+    let entries = match fs::read_dir(log_dir(log)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut copies = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(n) = name
+            .strip_prefix(&prefix)
+            .and_then(|n| n.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            copies.push((
+                Item {
+                    name,
+                    at_ms: modified_ms(&metadata),
+                    bytes: metadata.len(),
+                },
+                n,
+            ));
+        }
+    }
+    copies.sort_by_key(|&(_, n)| n);
+    Ok(copies.into_iter().map(|(item, _)| item).collect())
+}
+
+fn log_dir(log: &Path) -> &Path {
+    match log.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn modified_ms(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn json_error(e: JsonFileError) -> io::Error {
+    match e {
+        JsonFileError::Io { source, .. } => source,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+fn parse_age(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| c.is_ascii_alphabetic())?;
+    let (number, unit) = text.split_at(split);
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return None,
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .map(Duration::from_secs)
+}
+
+fn parse_bytes(text: &str) -> Option<u64> {
+    let split = text
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let scale = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_MS: u64 = 86_400_000;
+
+    fn item(name: &str, at_ms: u64, bytes: u64) -> Item {
+        Item {
+            name: name.to_string(),
+            at_ms,
+            bytes,
+        }
+    }
+
+    // Oldest first, one a day, 10 bytes each, ending at day 10.
+    fn daily() -> Vec<Item> {
+        (1..=10)
+            .map(|day| item(&format!("day-{}", day), day * DAY_MS, 10))
+            .collect()
+    }
+
+    #[test]
+    fn age_drops_what_is_older() {
+        let policy: Policy = "age=3d".parse().unwrap();
+        assert_eq!(
+            policy.expired(&daily(), 10 * DAY_MS),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn entries_keeps_the_newest() {
+        let policy: Policy = "entries=2".parse().unwrap();
+        assert_eq!(
+            policy.expired(&daily(), 10 * DAY_MS),
+            (0..8).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn bytes_keeps_what_fits() {
+        let policy: Policy = "bytes=25".parse().unwrap();
+        assert_eq!(
+            policy.expired(&daily(), 10 * DAY_MS),
+            (0..8).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn first_limit_reached_drops_the_rest() {
+        // A small one after a big one still goes: the big one hit the limit.
+        let items = vec![item("small", 1, 1), item("big", 2, 100), item("new", 3, 10)];
+        let policy: Policy = "bytes=50".parse().unwrap();
+        assert_eq!(policy.expired(&items, 3), vec![0, 1]);
+    }
+
+    #[test]
+    fn order_of_items_does_not_matter() {
+        let mut items = daily();
+        items.reverse();
+        let policy: Policy = "entries=3".parse().unwrap();
+        assert_eq!(
+            policy.expired(&items, 10 * DAY_MS),
+            (3..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn empty_policy_keeps_everything() {
+        assert!(Policy::default().expired(&daily(), u64::MAX).is_empty());
+        assert!(
+            "entries=0"
+                .parse::<Policy>()
+                .unwrap()
+                .expired(&[], 0)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn future_items_are_not_too_old() {
+        let policy: Policy = "age=1s".parse().unwrap();
+        assert!(policy.expired(&[item("later", 5_000, 1)], 0).is_empty());
+    }
+
+    #[test]
+    fn parses_every_unit() {
+        let cases = [
+            ("age=10s", Duration::from_secs(10)),
+            ("age=2m", Duration::from_secs(120)),
+            ("age=3h", Duration::from_secs(3 * 3600)),
+            ("age=30d", Duration::from_secs(30 * 86_400)),
+        ];
+        for (text, age) in cases {
+            assert_eq!(
+                text.parse::<Policy>().unwrap().max_age,
+                Some(age),
+                "{}",
+                text
+            );
+        }
+        let cases = [
+            ("bytes=7", 7),
+            ("bytes=7B", 7),
+            ("bytes=2KB", 2048),
+            ("bytes=3mb", 3 * 1024 * 1024),
+            ("bytes=1GB", 1024 * 1024 * 1024),
+        ];
+        for (text, bytes) in cases {
+            assert_eq!(
+                text.parse::<Policy>().unwrap().max_bytes,
+                Some(bytes),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn parses_combined_limits() {
+        let policy: Policy = "age=1d, bytes=1KB ,entries=5".parse().unwrap();
+        assert_eq!(
+            policy,
+            Policy {
+                max_age: Some(Duration::from_secs(86_400)),
+                max_bytes: Some(1024),
+                max_entries: Some(5),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_limits() {
+        for text in [
+            "",
+            "age",
+            "age=",
+            "age=10",
+            "age=10w",
+            "age=-1d",
+            "bytes=1TB",
+            "bytes=x",
+            "entries=-1",
+            "count=3",
+            "age=99999999999999999d",
+            "bytes=99999999999999999GB",
+        ] {
+            assert!(text.parse::<Policy>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for text in [
+            "age=3h",
+            "bytes=2MB",
+            "entries=4",
+            "age=1d,bytes=10,entries=2",
+        ] {
+            let policy: Policy = text.parse().unwrap();
+            assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+        }
+        assert_eq!(
+            "entries=4,age=2m".parse::<Policy>().unwrap().to_string(),
+            "age=120s,entries=4"
+        );
+    }
+
+    #[test]
+    fn targets_parse_by_name() {
+        for target in Target::ALL {
+            assert_eq!(target.as_str().parse::<Target>(), Ok(target));
+        }
+        assert!("session".parse::<Target>().is_err());
+    }
+}
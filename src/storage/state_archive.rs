@@ -0,0 +1,425 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::audit::AuditLog;
+use super::backend::{MemoryBackend, StorageBackend};
+use super::job_queue::JobQueue;
+use super::journal::{self, encode_record};
+use crate::utils::sha256::to_hex;
+
+// A server's stored state in one file, for moving it to another host or
+// seeding a test environment:
+//
+//   {"format": "rust-practice-state", "version": 1, "exported_unix": ...,
+//    "sections": [{"store": "counters", "json": {...}},
+//                 {"store": "jobs", "records": ["0a1b...", ...]}, ...]}
+//
+// The counters file is carried as the JSON it is; journals (the job queue
+// and the audit log) as their records, hex-encoded, oldest first. Records
+// are copied byte for byte, so an audit chain's digests still hold on the
+// other side.
+//
+// Each store is checked the way it checks itself when it opens (the audit
+// chain, the job records) both when it's exported and before it's
+// restored, so a broken store is refused rather than carried along.
+// `version` only changes when a field is removed or changes meaning; an
+// archive newer than this program is refused.
+
+pub const FORMAT: &str = "rust-practice-state";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Store {
+    Counters,
+    Jobs,
+    Audit,
+}
+
+impl Store {
+    pub const ALL: [Store; 3] = [Store::Counters, Store::Jobs, Store::Audit];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Store::Counters => "counters",
+            Store::Jobs => "jobs",
+            Store::Audit => "audit",
+        }
+    }
+}
+
+impl fmt::Display for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Store {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Store::ALL
+            .into_iter()
+            .find(|store| store.as_str() == s)
+            .ok_or_else(|| format!("unknown store {:?} (expected counters, jobs or audit)", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateArchive {
+    pub format: String,
+    pub version: u32,
+    pub exported_unix: u64,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    pub store: Store,
+    // The counters file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+    // A journal's records.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub records: Vec<String>,
+}
+
+impl Section {
+    // How much the section holds, for reports: a journal's record count, or
+    // the number of counters.
+    pub fn items(&self) -> usize {
+        match &self.json {
+            Some(json) => json
+                .get("counters")
+                .and_then(|counters| counters.as_object())
+                .map_or(0, |counters| counters.len()),
+            None => self.records.len(),
+        }
+    }
+
+    // The file to write to restore this section, once it has been checked.
+    pub fn restore(&self) -> io::Result<Vec<u8>> {
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", self.store, message),
+            )
+        };
+        match self.store {
+            Store::Counters => {
+                let json = self
+                    .json
+                    .as_ref()
+                    .filter(|json| json.is_object())
+                    .ok_or_else(|| invalid("expected the counters file's JSON".to_string()))?;
+                let mut text =
+                    serde_json::to_string_pretty(json).map_err(|e| invalid(e.to_string()))?;
+                text.push('\n');
+                Ok(text.into_bytes())
+            }
+            Store::Jobs | Store::Audit => {
+                let records = self
+                    .records
+                    .iter()
+                    .enumerate()
+                    .map(|(i, hex)| {
+                        decode_hex(hex).ok_or_else(|| invalid(format!("record {} isn't hex", i)))
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                check_records(self.store, &records)?;
+                Ok(records.iter().flat_map(|r| encode_record(r)).collect())
+            }
+        }
+    }
+}
+
+impl StateArchive {
+    // Read each store at its path, checking it on the way.
+    pub fn export(sources: &[(Store, &Path)]) -> io::Result<Self> {
+        let mut sections = Vec::new();
+        for &(store, path) in sources {
+            let in_path =
+                |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+            let section = match store {
+                Store::Counters => {
+                    let text = fs::read_to_string(path).map_err(in_path)?;
+                    let json: serde_json::Value = serde_json::from_str(&text)
+                        .map_err(|e| in_path(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                    Section {
+                        store,
+                        json: Some(json),
+                        records: Vec::new(),
+                    }
+                }
+                Store::Jobs | Store::Audit => {
+                    // Not `Journal::open`, which would cut off a torn tail:
+                    // exporting shouldn't change what it exports.
+                    let records = journal::read_records(path)
+                        .and_then(|records| {
+                            records
+                                .map(|record| record.map(|r| r.payload))
+                                .collect::<io::Result<Vec<_>>>()
+                        })
+                        .map_err(in_path)?;
+                    check_records(store, &records).map_err(in_path)?;
+                    Section {
+                        store,
+                        json: None,
+                        records: records.iter().map(|r| to_hex(r)).collect(),
+                    }
+                }
+            };
+            sections.push(section);
+        }
+        Ok(Self {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            exported_unix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            sections,
+        })
+    }
+
+    // Parse an archive, refusing other formats and newer versions.
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let archive: Self = serde_json::from_slice(bytes)
+            .map_err(|e| invalid(format!("not a state archive: {}", e)))?;
+        if archive.format != FORMAT {
+            return Err(invalid(format!(
+                "not a state archive: format is {:?}",
+                archive.format
+            )));
+        }
+        if archive.version > VERSION {
+            return Err(invalid(format!(
+                "archive version {} is newer than this program's ({})",
+                archive.version, VERSION
+            )));
+        }
+        Ok(archive)
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut text = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        text.push('\n');
+        Ok(text.into_bytes())
+    }
+
+    pub fn section(&self, store: Store) -> Option<&Section> {
+        self.sections.iter().find(|section| section.store == store)
+    }
+}
+
+// Open the records as `store` would, in memory, to see that it accepts them.
+fn check_records(store: Store, records: &[Vec<u8>]) -> io::Result<()> {
+    let invalid =
+        |e: io::Error| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", store, e));
+    let mut backend = MemoryBackend::new();
+    for record in records {
+        backend.append(record).map_err(invalid)?;
+    }
+    match store {
+        Store::Jobs => JobQueue::with_backend(backend).map(|_| ()),
+        Store::Audit => AuditLog::with_backend(backend).map(|_| ()),
+        Store::Counters => Ok(()),
+    }
+    .map_err(invalid)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::audit;
+    use crate::storage::counters::PersistentCounters;
+    use crate::storage::job_queue::JobSpec;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("state-archive-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A counters file with two counters, a queue of two jobs and an audit
+    // log of three entries.
+    fn stores(dir: &Path) -> [(Store, PathBuf); 3] {
+        let counters = PersistentCounters::open(dir.join("counters.json")).unwrap();
+        counters.add("connections", 4);
+        counters.add("errors", 1);
+        counters.flush().unwrap();
+        let mut jobs = JobQueue::open(dir.join("jobs.journal")).unwrap();
+        jobs.push(JobSpec::new("compact")).unwrap();
+        jobs.push(JobSpec::new("rotate")).unwrap();
+        let mut log = AuditLog::open(dir.join("audit.log")).unwrap();
+        for action in ["token.mint", "token.use", "token.revoke"] {
+            log.append("cli", action, "id=a").unwrap();
+        }
+        [
+            (Store::Counters, dir.join("counters.json")),
+            (Store::Jobs, dir.join("jobs.journal")),
+            (Store::Audit, dir.join("audit.log")),
+        ]
+    }
+
+    fn export(stores: &[(Store, PathBuf)]) -> StateArchive {
+        let sources: Vec<_> = stores.iter().map(|(s, p)| (*s, p.as_path())).collect();
+        StateArchive::export(&sources).unwrap()
+    }
+
+    #[test]
+    fn every_store_round_trips_byte_for_byte() {
+        let from = TempDir::new("from");
+        let stores = stores(&from.0);
+        let archive = StateArchive::parse(&export(&stores).to_bytes().unwrap()).unwrap();
+
+        let items: Vec<_> = archive
+            .sections
+            .iter()
+            .map(|s| (s.store, s.items()))
+            .collect();
+        assert_eq!(
+            items,
+            [(Store::Counters, 2), (Store::Jobs, 2), (Store::Audit, 3)]
+        );
+        for (store, path) in &stores {
+            let restored = archive.section(*store).unwrap().restore().unwrap();
+            if *store == Store::Counters {
+                let json =
+                    |bytes: &[u8]| serde_json::from_slice::<serde_json::Value>(bytes).unwrap();
+                assert_eq!(json(&restored), json(&fs::read(path).unwrap()));
+            } else {
+                assert_eq!(restored, fs::read(path).unwrap(), "{}", store);
+            }
+        }
+
+        // The restored audit log still verifies on the other side.
+        let to = TempDir::new("to");
+        let restored = to.0.join("audit.log");
+        fs::write(
+            &restored,
+            archive.section(Store::Audit).unwrap().restore().unwrap(),
+        )
+        .unwrap();
+        let report = audit::verify(&restored, None).unwrap();
+        assert_eq!((report.entries, report.problem), (3, None));
+    }
+
+    #[test]
+    fn a_tampered_section_is_refused_on_restore() {
+        let dir = TempDir::new("tampered");
+        let mut archive = export(&stores(&dir.0));
+
+        let audit = archive
+            .sections
+            .iter_mut()
+            .find(|s| s.store == Store::Audit)
+            .unwrap();
+        audit.records.swap(0, 1);
+        let e = audit.restore().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().starts_with("audit: "), "{}", e);
+
+        let jobs = archive
+            .sections
+            .iter_mut()
+            .find(|s| s.store == Store::Jobs)
+            .unwrap();
+        jobs.records[0].push('z');
+        assert_eq!(
+            jobs.restore().unwrap_err().to_string(),
+            "jobs: record 0 isn't hex"
+        );
+
+        let counters = Section {
+            store: Store::Counters,
+            json: Some(serde_json::json!([1, 2])),
+            records: Vec::new(),
+        };
+        assert!(counters.restore().is_err());
+    }
+
+    #[test]
+    fn a_broken_store_is_refused_on_export() {
+        let dir = TempDir::new("broken");
+        let stores = stores(&dir.0);
+        let jobs = &stores[1].1;
+        let mut bytes = fs::read(jobs).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(jobs, bytes).unwrap();
+
+        let e = StateArchive::export(&[(Store::Jobs, jobs.as_path())]).unwrap_err();
+        assert!(
+            e.to_string().starts_with(&jobs.display().to_string()),
+            "{}",
+            e
+        );
+
+        let missing = dir.0.join("missing.json");
+        let e = StateArchive::export(&[(Store::Counters, missing.as_path())]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn other_formats_and_newer_versions_are_refused() {
+        let archive = StateArchive {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            exported_unix: 0,
+            sections: Vec::new(),
+        };
+        assert!(StateArchive::parse(&archive.to_bytes().unwrap()).is_ok());
+
+        let newer = StateArchive {
+            version: VERSION + 1,
+            ..archive.clone()
+        };
+        let e = StateArchive::parse(&newer.to_bytes().unwrap()).unwrap_err();
+        assert!(
+            e.to_string().contains("is newer than this program's"),
+            "{}",
+            e
+        );
+
+        let other = StateArchive {
+            format: "something-else".to_string(),
+            ..archive
+        };
+        assert!(StateArchive::parse(&other.to_bytes().unwrap()).is_err());
+        assert!(StateArchive::parse(b"{}").is_err());
+        assert_eq!("jobs".parse(), Ok(Store::Jobs));
+        assert!("queue".parse::<Store>().is_err());
+    }
+}
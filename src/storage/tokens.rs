@@ -0,0 +1,664 @@
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(all(not(feature = "e2e"), unix))]
+use std::fs::File;
+use std::io;
+#[cfg(all(not(feature = "e2e"), unix))]
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::audit::AuditLog;
+use super::backend::StorageBackend;
+use super::journal::{FsyncPolicy, Journal};
+use super::now_ms;
+use crate::utils::pattern::{Glob, glob_match_str};
+use crate::utils::sha256::{Digest, from_hex, sha256, to_hex};
+
+// API tokens, each allowed a set of scopes until it expires or is revoked.
+//
+// A token as handed out is `rpt_<id>.<secret>`: the id names it in lists,
+// logs and `revoke`, and the secret is what proves the holder has it. Only
+// the secret's SHA-256 is stored, so the store itself can't be used to
+// make requests.
+//
+// A scope is a glob (see `pattern::Glob`) over the method a request calls,
+// like `echo`, `ping` or `*` for everything. A token is allowed a request
+// when one of its scopes matches, it hasn't expired, and it hasn't been
+// revoked.
+//
+// A secret is checked by hashing it and comparing the digest with the
+// stored one without stopping at the first differing byte, so how long a
+// check takes doesn't say how much of a guess was right. The id is looked
+// up first and isn't secret. Anything that gates on a token (scopes now,
+// roles if they're ever added) goes through `verify` and so gets this
+// comparison; nothing else compares secrets.
+//
+// Like the job queue, the store is a log of events (minted, revoked)
+// replayed on open. `refresh` picks up events appended since, so a server
+// holding the store open sees a token revoked by another process without
+// restarting. A damaged record is never skipped or cut off to make the
+// rest load: dropping one could bring a revoked token back, so the store
+// refuses to open (and `refresh` fails, which the gate turns into denying
+// every request) until the file is repaired. Only a last record cut short
+// by a crash mid-append is dropped; its `mint` or `revoke` never returned.
+//
+// Secrets come from the OS's random source: ring's with the `e2e` feature,
+// otherwise /dev/urandom on Unix. Elsewhere, without `e2e`, minting fails;
+// there's no weaker fallback, since a guessable token is worse than none.
+
+const PREFIX: &str = "rpt_";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub id: String,
+    pub label: String,
+    pub scopes: Vec<String>,
+    pub created_unix: u64,
+    // None: never expires.
+    pub expires_unix: Option<u64>,
+    pub revoked_unix: Option<u64>,
+    secret_sha256: Digest,
+}
+
+impl TokenInfo {
+    pub fn status(&self, now_unix: u64) -> TokenStatus {
+        if self.revoked_unix.is_some() {
+            TokenStatus::Revoked
+        } else if self.expires_unix.is_some_and(|at| now_unix >= at) {
+            TokenStatus::Expired
+        } else {
+            TokenStatus::Active
+        }
+    }
+
+    pub fn allows(&self, method: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| glob_match_str(scope, method))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenStatus {
+    Active,
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for TokenStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TokenStatus::Active => "active",
+            TokenStatus::Expired => "expired",
+            TokenStatus::Revoked => "revoked",
+        })
+    }
+}
+
+// Why `authorize` turned a request down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denied {
+    // The request came without a token.
+    Missing,
+    // Not shaped like a token at all.
+    Malformed,
+    // No such token, or the secret doesn't match.
+    Unknown,
+    Expired { id: String },
+    Revoked { id: String },
+    OutOfScope { id: String, method: String },
+    // The store couldn't be read, or the audit log written, so nothing is
+    // let through.
+    Unavailable(String),
+}
+
+impl Denied {
+    // The token's id, when the token itself checked out.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Denied::Missing | Denied::Malformed | Denied::Unknown | Denied::Unavailable(_) => None,
+            Denied::Expired { id } | Denied::Revoked { id } | Denied::OutOfScope { id, .. } => {
+                Some(id)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::Missing => f.write_str("no token given"),
+            Denied::Malformed => f.write_str("not a token"),
+            Denied::Unknown => f.write_str("unknown token"),
+            Denied::Expired { id } => write!(f, "token {} has expired", id),
+            Denied::Revoked { id } => write!(f, "token {} was revoked", id),
+            Denied::OutOfScope { id, method } => {
+                write!(f, "token {} is not allowed to call {}", id, method)
+            }
+            Denied::Unavailable(reason) => write!(f, "tokens can't be checked: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Denied {}
+
+pub struct TokenStore<B = Journal> {
+    backend: B,
+    tokens: BTreeMap<String, TokenInfo>,
+    // How many of the backend's records have been replayed.
+    applied: usize,
+}
+
+impl TokenStore {
+    // Open (or create) the store at `path`, replaying its journal.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_backend(Journal::open_strict(path, FsyncPolicy::Always)?)
+    }
+}
+
+impl<B: StorageBackend> TokenStore<B> {
+    // The store kept in `backend`, replaying what's already there.
+    pub fn with_backend(backend: B) -> io::Result<Self> {
+        let mut store = Self {
+            backend,
+            tokens: BTreeMap::new(),
+            applied: 0,
+        };
+        store.refresh()?;
+        Ok(store)
+    }
+
+    // Replay events appended to the backend since the last look, e.g. by
+    // `rust-practice token revoke` while a server has the store open.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let mut events = Vec::new();
+        for record in self.backend.records()?.skip(self.applied) {
+            events.push(decode(&record?)?);
+        }
+        for event in events {
+            self.apply(event);
+            self.applied += 1;
+        }
+        Ok(())
+    }
+
+    // Create a token allowed `scopes` until `expires_unix` (for ever with
+    // None). Returns it along with the token string, which is only ever
+    // available here.
+    pub fn mint(
+        &mut self,
+        label: &str,
+        scopes: &[String],
+        expires_unix: Option<u64>,
+    ) -> io::Result<(TokenInfo, String)> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if scopes.is_empty() {
+            return Err(invalid("a token needs at least one scope".to_string()));
+        }
+        for scope in scopes {
+            Glob::new(scope).map_err(|e| invalid(format!("scope {:?}: {}", scope, e)))?;
+        }
+        let id = to_hex(&random_bytes::<8>()?);
+        let secret = to_hex(&random_bytes::<32>()?);
+        let event = Event::Minted {
+            id: id.clone(),
+            label: label.to_string(),
+            scopes: scopes.to_vec(),
+            created_unix: now_ms() / 1000,
+            expires_unix,
+            secret_sha256: to_hex(&sha256(secret.as_bytes())),
+        };
+        self.record(event)?;
+        Ok((
+            self.tokens[&id].clone(),
+            format!("{}{}.{}", PREFIX, id, secret),
+        ))
+    }
+
+    // Revoke the token `id`. False if there's no such token or it was
+    // already revoked.
+    pub fn revoke(&mut self, id: &str) -> io::Result<bool> {
+        self.refresh()?;
+        match self.tokens.get(id) {
+            Some(token) if token.revoked_unix.is_none() => {
+                self.record(Event::Revoked {
+                    id: id.to_string(),
+                    at_unix: now_ms() / 1000,
+                })?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Check that `token` is one of ours and still good at `now_unix`,
+    // whatever it's used for. Call `refresh` first to see revocations made
+    // elsewhere.
+    pub fn verify(&self, token: &str, now_unix: u64) -> Result<&TokenInfo, Denied> {
+        let (id, secret) = token
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once('.'))
+            .ok_or(Denied::Malformed)?;
+        let info = self.tokens.get(id).ok_or(Denied::Unknown)?;
+        if !digests_equal(&sha256(secret.as_bytes()), &info.secret_sha256) {
+            return Err(Denied::Unknown);
+        }
+        let id = info.id.clone();
+        match info.status(now_unix) {
+            TokenStatus::Revoked => Err(Denied::Revoked { id }),
+            TokenStatus::Expired => Err(Denied::Expired { id }),
+            TokenStatus::Active => Ok(info),
+        }
+    }
+
+    // `verify`, and check that the token's scopes allow `method`.
+    pub fn authorize(
+        &self,
+        token: &str,
+        method: &str,
+        now_unix: u64,
+    ) -> Result<&TokenInfo, Denied> {
+        let info = self.verify(token, now_unix)?;
+        if !info.allows(method) {
+            return Err(Denied::OutOfScope {
+                id: info.id.clone(),
+                method: method.to_string(),
+            });
+        }
+        Ok(info)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&TokenInfo> {
+        self.tokens.get(id)
+    }
+
+    // Every token ever minted, revoked and expired ones included, oldest
+    // first.
+    pub fn tokens(&self) -> Vec<&TokenInfo> {
+        let mut tokens: Vec<_> = self.tokens.values().collect();
+        tokens.sort_by_key(|token| token.created_unix);
+        tokens
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    fn record(&mut self, event: Event) -> io::Result<()> {
+        // Catch up first, so `applied` keeps counting the backend's records.
+        self.refresh()?;
+        let payload = serde_json::to_vec(&event)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.backend.append(&payload)?;
+        self.apply(event);
+        self.applied += 1;
+        Ok(())
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Minted {
+                id,
+                label,
+                scopes,
+                created_unix,
+                expires_unix,
+                secret_sha256,
+            } => {
+                // A digest that doesn't parse matches no secret.
+                let secret_sha256 = from_hex(&secret_sha256).unwrap_or([0; 32]);
+                self.tokens.insert(
+                    id.clone(),
+                    TokenInfo {
+                        id,
+                        label,
+                        scopes,
+                        created_unix,
+                        expires_unix,
+                        revoked_unix: None,
+                        secret_sha256,
+                    },
+                );
+            }
+            Event::Revoked { id, at_unix } => {
+                if let Some(token) = self.tokens.get_mut(&id) {
+                    token.revoked_unix.get_or_insert(at_unix);
+                }
+            }
+        }
+    }
+}
+
+// The check a server puts in front of every request: the token store,
+// refreshed each time so revocations take effect at once, and optionally
+// an audit log recording each use and each refusal.
+pub struct TokenGate {
+    tokens: Mutex<TokenStore>,
+    audit: Option<Mutex<AuditLog>>,
+}
+
+impl TokenGate {
+    pub fn new(tokens: TokenStore, audit: Option<AuditLog>) -> Self {
+        Self {
+            tokens: Mutex::new(tokens),
+            audit: audit.map(Mutex::new),
+        }
+    }
+
+    // Check `token` for a call to `method` from `client`, returning the
+    // token's id. With `method` None, only check that the token is good
+    // (for a client presenting it before making any calls).
+    pub fn check(
+        &self,
+        token: Option<&str>,
+        method: Option<&str>,
+        client: &str,
+    ) -> Result<String, Denied> {
+        let now_unix = now_ms() / 1000;
+        let result = match token {
+            None => Err(Denied::Missing),
+            Some(token) => {
+                let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+                match tokens.refresh() {
+                    Err(e) => Err(Denied::Unavailable(e.to_string())),
+                    Ok(()) => match method {
+                        Some(method) => tokens.authorize(token, method, now_unix),
+                        None => tokens.verify(token, now_unix),
+                    }
+                    .map(|info| info.id.clone()),
+                }
+            }
+        };
+        let method = method.unwrap_or("auth");
+        let (actor, action, detail) = match &result {
+            Ok(id) => (
+                id.as_str(),
+                "token.use",
+                format!("{} from {}", method, client),
+            ),
+            Err(denied) => (
+                denied.id().unwrap_or("-"),
+                "auth.denied",
+                format!("{} from {}: {}", method, client, denied),
+            ),
+        };
+        if let Some(audit) = &self.audit {
+            let mut audit = audit.lock().unwrap_or_else(|e| e.into_inner());
+            // A use that can't be recorded isn't allowed.
+            if let Err(e) = audit.append(actor, action, &detail) {
+                return Err(Denied::Unavailable(format!("audit log: {}", e)));
+            }
+        }
+        result
+    }
+}
+
+// Journal entries, as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event {
+    Minted {
+        id: String,
+        label: String,
+        scopes: Vec<String>,
+        created_unix: u64,
+        expires_unix: Option<u64>,
+        secret_sha256: String,
+    },
+    Revoked {
+        id: String,
+        at_unix: u64,
+    },
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Event> {
+    serde_json::from_slice(bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("token store event is malformed: {}", e),
+        )
+    })
+}
+
+// Every byte is compared; see the module comment.
+fn digests_equal(a: &Digest, b: &Digest) -> bool {
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(feature = "e2e")]
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    use ring::rand::{SecureRandom, SystemRandom};
+    let mut bytes = [0; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| io::Error::other("the system random source failed"))?;
+    Ok(bytes)
+}
+
+#[cfg(all(not(feature = "e2e"), unix))]
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| io::Error::new(e.kind(), format!("reading /dev/urandom: {}", e)))?;
+    Ok(bytes)
+}
+
+#[cfg(all(not(feature = "e2e"), not(unix)))]
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "minting tokens on this platform needs the e2e feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::MemoryBackend;
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tokens-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn scopes(globs: &[&str]) -> Vec<String> {
+        globs.iter().map(|g| g.to_string()).collect()
+    }
+
+    const NOW: u64 = 1_000_000;
+
+    #[test]
+    fn minted_tokens_verify_within_their_scopes() {
+        let mut store = TokenStore::with_backend(MemoryBackend::new()).unwrap();
+        let (info, token) = store
+            .mint("ci", &scopes(&["echo", "stats.*"]), None)
+            .unwrap();
+
+        assert!(token.starts_with(&format!("rpt_{}.", info.id)));
+        assert_eq!(store.verify(&token, NOW).unwrap().label, "ci");
+        assert!(store.authorize(&token, "echo", NOW).is_ok());
+        assert!(store.authorize(&token, "stats.clients", NOW).is_ok());
+        assert_eq!(
+            store.authorize(&token, "queue.purge", NOW).unwrap_err(),
+            Denied::OutOfScope {
+                id: info.id.clone(),
+                method: "queue.purge".into()
+            }
+        );
+    }
+
+    #[test]
+    fn wrong_or_malformed_tokens_are_refused() {
+        let mut store = TokenStore::with_backend(MemoryBackend::new()).unwrap();
+        let (info, token) = store.mint("ci", &scopes(&["*"]), None).unwrap();
+        let wrong_secret = format!("rpt_{}.{}", info.id, "0".repeat(64));
+
+        assert_eq!(
+            store.verify(&wrong_secret, NOW).unwrap_err(),
+            Denied::Unknown
+        );
+        assert_eq!(
+            store.verify("rpt_nosuchid.abc", NOW).unwrap_err(),
+            Denied::Unknown
+        );
+        assert_eq!(store.verify("hunter2", NOW).unwrap_err(), Denied::Malformed);
+        assert_eq!(
+            store
+                .verify(token.trim_start_matches("rpt_"), NOW)
+                .unwrap_err(),
+            Denied::Malformed
+        );
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let mut store = TokenStore::with_backend(MemoryBackend::new()).unwrap();
+        let (info, token) = store.mint("ci", &scopes(&["*"]), Some(NOW)).unwrap();
+
+        assert!(store.verify(&token, NOW - 1).is_ok());
+        assert_eq!(
+            store.verify(&token, NOW).unwrap_err(),
+            Denied::Expired { id: info.id }
+        );
+    }
+
+    #[test]
+    fn bad_scopes_are_refused_at_mint() {
+        let mut store = TokenStore::with_backend(MemoryBackend::new()).unwrap();
+        for bad in [scopes(&[]), scopes(&["[unclosed"])] {
+            let e = store.mint("ci", &bad, None).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", bad);
+        }
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn a_revocation_reaches_another_handle_on_refresh() {
+        let dir = TempDir::new("refresh");
+        let path = dir.0.join("tokens.journal");
+        let mut server = TokenStore::open(&path).unwrap();
+        let (info, token) = server.mint("ci", &scopes(&["*"]), None).unwrap();
+
+        let mut cli = TokenStore::open(&path).unwrap();
+        assert!(cli.revoke(&info.id).unwrap());
+        assert!(!cli.revoke(&info.id).unwrap());
+        assert!(!cli.revoke("nosuchid").unwrap());
+
+        assert!(server.verify(&token, NOW).is_ok());
+        server.refresh().unwrap();
+        assert_eq!(
+            server.verify(&token, NOW).unwrap_err(),
+            Denied::Revoked {
+                id: info.id.clone()
+            }
+        );
+        // And after a restart.
+        let reopened = TokenStore::open(&path).unwrap();
+        assert_eq!(
+            reopened.get(&info.id).unwrap().status(NOW),
+            TokenStatus::Revoked
+        );
+    }
+
+    #[test]
+    fn a_damaged_store_refuses_to_open_rather_than_forget_a_revocation() {
+        let dir = TempDir::new("damaged");
+        let path = dir.0.join("tokens.journal");
+        let mut store = TokenStore::open(&path).unwrap();
+        let (revoked, _) = store.mint("old", &scopes(&["*"]), None).unwrap();
+        store.mint("new", &scopes(&["*"]), None).unwrap();
+        store.revoke(&revoked.id).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        // A flipped byte in the second mint, with the revocation after it,
+        // and in the revocation itself.
+        for at in [bytes.len() / 2, bytes.len() - 2] {
+            let mut damaged = bytes.clone();
+            damaged[at] ^= 0x01;
+            fs::write(&path, &damaged).unwrap();
+            let e = TokenStore::open(&path)
+                .err()
+                .expect("opened a damaged store");
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(fs::read(&path).unwrap(), damaged);
+        }
+
+        // A revocation cut short by a crash never happened.
+        fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+        let store = TokenStore::open(&path).unwrap();
+        assert_eq!(
+            store.get(&revoked.id).unwrap().status(NOW),
+            TokenStatus::Active
+        );
+    }
+
+    #[test]
+    fn the_gate_audits_uses_and_refusals() {
+        let dir = TempDir::new("gate");
+        let mut store = TokenStore::open(dir.0.join("tokens.journal")).unwrap();
+        let (info, token) = store.mint("ci", &scopes(&["echo"]), None).unwrap();
+        let audit = AuditLog::open(dir.0.join("audit.log")).unwrap();
+        let gate = TokenGate::new(store, Some(audit));
+
+        assert_eq!(
+            gate.check(Some(&token), Some("echo"), "10.0.0.1"),
+            Ok(info.id.clone())
+        );
+        assert_eq!(
+            gate.check(Some(&token), None, "10.0.0.1"),
+            Ok(info.id.clone())
+        );
+        assert!(gate.check(Some(&token), Some("ping"), "10.0.0.1").is_err());
+        assert_eq!(
+            gate.check(None, Some("echo"), "10.0.0.2"),
+            Err(Denied::Missing)
+        );
+
+        let entries = AuditLog::open(dir.0.join("audit.log"))
+            .unwrap()
+            .entries()
+            .unwrap();
+        let logged: Vec<_> = entries
+            .iter()
+            .map(|e| (e.actor.as_str(), e.action.as_str()))
+            .collect();
+        assert_eq!(
+            logged,
+            [
+                (info.id.as_str(), "token.use"),
+                (info.id.as_str(), "token.use"),
+                (info.id.as_str(), "auth.denied"),
+                ("-", "auth.denied"),
+            ]
+        );
+        assert_eq!(
+            entries[2].detail,
+            format!(
+                "ping from 10.0.0.1: token {} is not allowed to call ping",
+                info.id
+            )
+        );
+    }
+}
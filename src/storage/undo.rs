@@ -0,0 +1,368 @@
+use std::fs;
+use std::io;
+use std::path::{self, Path, PathBuf};
+
+use super::journal::{self, FsyncPolicy, Journal};
+use super::now_ms;
+use crate::utils::env_os;
+use crate::utils::mutation::{Applied, Mutation, MutationSink};
+
+// Undo journal for file changes.
+//
+// `UndoRecorder` wraps the sink a command applies its changes through.
+// Before a change goes through, whatever it is about to destroy is saved
+// into a directory for the run:
+//
+//   <undo dir>/<run id>/manifest   a journal with one record per change
+//   <undo dir>/<run id>/<n>        the original contents of record n's file
+//
+// Each record is written (and fsynced) before the change is made, so even
+// a run that dies halfway can be undone. `undo` goes through the records
+// newest first, putting originals back and removing files the run
+// created. Directories the run created are left behind.
+//
+// Record layout: [kind: u8][path][backup], both strings u32-length-prefixed
+// UTF-8. Paths are stored absolute so `undo` works from any directory.
+
+// Where runs are kept unless RUST_PRACTICE_UNDO_DIR says otherwise.
+const DEFAULT_UNDO_DIR: &str = ".rust-practice-undo";
+
+const MANIFEST: &str = "manifest";
+
+const KIND_RESTORE: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UndoEntry {
+    // `path` existed before the change; `backup` (a file name in the run
+    // directory) holds what it contained.
+    Restore { path: PathBuf, backup: String },
+    // `path` didn't exist before the change.
+    Remove { path: PathBuf },
+}
+
+pub fn undo_dir_from_env() -> PathBuf {
+    env_os::var_path("RUST_PRACTICE_UNDO_DIR").unwrap_or_else(|| PathBuf::from(DEFAULT_UNDO_DIR))
+}
+
+// The directory of run `run_id`. Ids come from the command line, so
+// anything that could point outside `undo_dir` is rejected.
+pub fn run_dir(undo_dir: &Path, run_id: &str) -> io::Result<PathBuf> {
+    let valid = !run_id.is_empty()
+        && run_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not an undo run id", run_id),
+        ));
+    }
+    Ok(undo_dir.join(run_id))
+}
+
+pub fn run_exists(undo_dir: &Path, run_id: &str) -> io::Result<bool> {
+    Ok(run_dir(undo_dir, run_id)?.join(MANIFEST).is_file())
+}
+
+pub struct UndoRecorder<'a> {
+    inner: &'a mut dyn MutationSink,
+    run_id: String,
+    dir: PathBuf,
+    // Opened on the first change, so runs that change nothing leave no
+    // directory behind.
+    manifest: Option<Journal>,
+    entries: u64,
+}
+
+impl<'a> UndoRecorder<'a> {
+    pub fn new(undo_dir: &Path, inner: &'a mut dyn MutationSink) -> Self {
+        let run_id = format!("{}-{}", now_ms(), std::process::id());
+        Self {
+            dir: undo_dir.join(&run_id),
+            run_id,
+            inner,
+            manifest: None,
+            entries: 0,
+        }
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    // How many changes were recorded.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    fn record(&mut self, path: &Path) -> io::Result<()> {
+        let path = path::absolute(path)?;
+        let manifest = match &mut self.manifest {
+            Some(manifest) => manifest,
+            None => {
+                fs::create_dir_all(&self.dir)?;
+                let journal = Journal::open(self.dir.join(MANIFEST), FsyncPolicy::Always)?;
+                self.manifest.insert(journal)
+            }
+        };
+
+        let entry = if path.is_file() {
+            let backup = self.entries.to_string();
+            let backup_path = self.dir.join(&backup);
+            fs::copy(&path, &backup_path)?;
+            // Keep the mtime too, so restoring doesn't make the file look
+            // changed to a later sync.
+            let file = fs::File::options().write(true).open(&backup_path)?;
+            file.set_modified(fs::metadata(&path)?.modified()?)?;
+            file.sync_all()?;
+            UndoEntry::Restore { path, backup }
+        } else {
+            UndoEntry::Remove { path }
+        };
+        manifest.append(&encode(&entry)?)?;
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+impl MutationSink for UndoRecorder<'_> {
+    fn apply(&mut self, mutation: Mutation) -> io::Result<Applied> {
+        let target = match &mutation {
+            Mutation::Copy { to, .. } => to,
+            Mutation::Write { path, .. } | Mutation::Remove { path } => path,
+            // Only done to a file that already matches its source, so a
+            // later sync needn't hash it; backing the file up to undo that
+            // would cost far more than the hashing.
+            Mutation::SetModified { .. } => return self.inner.apply(mutation),
+        };
+        // Nothing happens in a dry run, so there's nothing to undo.
+        if !self.inner.is_dry_run() {
+            self.record(target)?;
+        }
+        self.inner.apply(mutation)
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.inner.is_dry_run()
+    }
+}
+
+// The recorded changes of a run, oldest first.
+pub fn entries(undo_dir: &Path, run_id: &str) -> io::Result<Vec<UndoEntry>> {
+    // A record is complete before its change is made, so one cut short
+    // was never acted on and can be left out. Any other damage is an
+    // error: undoing part of a run is worse than undoing none of it. The
+    // manifest is only read, so it stays as it was for a second look.
+    journal::read_intact_records(run_dir(undo_dir, run_id)?.join(MANIFEST))?
+        .map(|record| decode(&record?.payload))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UndoReport {
+    pub restored: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+// Revert run `run_id` through `sink`. Once the changes are reverted for
+// real (not in a dry run) the run directory is deleted, so the same run
+// can't be undone twice on top of later changes.
+pub fn undo(undo_dir: &Path, run_id: &str, sink: &mut dyn MutationSink) -> io::Result<UndoReport> {
+    let dir = run_dir(undo_dir, run_id)?;
+    let mut report = UndoReport::default();
+    for entry in entries(undo_dir, run_id)?.into_iter().rev() {
+        match entry {
+            UndoEntry::Restore { path, backup } => {
+                sink.apply(Mutation::Copy {
+                    from: dir.join(backup),
+                    to: path.clone(),
+                    reuse: false,
+                })?;
+                report.restored.push(path);
+            }
+            // Already gone if the change failed or the file was deleted
+            // since.
+            UndoEntry::Remove { path } if !path.exists() => {}
+            UndoEntry::Remove { path } => {
+                sink.apply(Mutation::Remove { path: path.clone() })?;
+                report.removed.push(path);
+            }
+        }
+    }
+    if !sink.is_dry_run() {
+        fs::remove_dir_all(&dir)?;
+    }
+    Ok(report)
+}
+
+fn encode(entry: &UndoEntry) -> io::Result<Vec<u8>> {
+    let (kind, path, backup) = match entry {
+        UndoEntry::Restore { path, backup } => (KIND_RESTORE, path, backup.as_str()),
+        UndoEntry::Remove { path } => (KIND_REMOVE, path, ""),
+    };
+    // Paths go in as UTF-8 so the manifest reads the same on every
+    // platform; the rare path that isn't can't be recorded.
+    let path = path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: can't record a non-UTF-8 path for undo", path.display()),
+        )
+    })?;
+    let mut out = Vec::with_capacity(9 + path.len() + backup.len());
+    out.push(kind);
+    for s in [path, backup] {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+    Ok(out)
+}
+
+fn decode(payload: &[u8]) -> io::Result<UndoEntry> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "undo record is malformed");
+    let (&kind, mut rest) = payload.split_first().ok_or_else(bad)?;
+    let mut next_string = || -> io::Result<String> {
+        let len = u32::from_le_bytes(rest.get(..4).ok_or_else(bad)?.try_into().unwrap()) as usize;
+        let bytes = rest.get(4..4 + len).ok_or_else(bad)?;
+        rest = &rest[4 + len..];
+        String::from_utf8(bytes.to_vec()).map_err(|_| bad())
+    };
+    let path = PathBuf::from(next_string()?);
+    let backup = next_string()?;
+    match kind {
+        // A backup name is only ever a number; anything else would point
+        // outside the run directory.
+        KIND_RESTORE if !backup.is_empty() && backup.bytes().all(|b| b.is_ascii_digit()) => {
+            Ok(UndoEntry::Restore { path, backup })
+        }
+        KIND_REMOVE => Ok(UndoEntry::Remove { path }),
+        _ => Err(bad()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::mutation::{ApplyToDisk, DryRun};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("undo-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Overwrite `kept`, create `created` and delete `deleted` through a
+    // recorder; returns the run id.
+    fn record_a_run(dir: &TempDir, undo_dir: &Path) -> String {
+        let mut disk = ApplyToDisk;
+        let mut recorder = UndoRecorder::new(undo_dir, &mut disk);
+        recorder
+            .apply(Mutation::Write {
+                path: dir.0.join("kept"),
+                contents: b"changed".to_vec(),
+            })
+            .unwrap();
+        recorder
+            .apply(Mutation::Write {
+                path: dir.0.join("created"),
+                contents: b"new".to_vec(),
+            })
+            .unwrap();
+        recorder
+            .apply(Mutation::Remove {
+                path: dir.0.join("deleted"),
+            })
+            .unwrap();
+        assert_eq!(recorder.entries(), 3);
+        recorder.run_id().to_string()
+    }
+
+    #[test]
+    fn undo_reverts_a_recorded_run() {
+        let dir = TempDir::new("round-trip");
+        let undo_dir = dir.0.join("undo");
+        fs::write(dir.0.join("kept"), "original").unwrap();
+        fs::write(dir.0.join("deleted"), "doomed").unwrap();
+        let run_id = record_a_run(&dir, &undo_dir);
+        assert!(run_exists(&undo_dir, &run_id).unwrap());
+
+        let mut dry = DryRun::default();
+        undo(&undo_dir, &run_id, &mut dry).unwrap();
+        assert_eq!(dry.planned.len(), 3);
+        assert!(run_exists(&undo_dir, &run_id).unwrap());
+
+        let report = undo(&undo_dir, &run_id, &mut ApplyToDisk).unwrap();
+        assert_eq!(report.restored, [dir.0.join("deleted"), dir.0.join("kept")]);
+        assert_eq!(report.removed, [dir.0.join("created")]);
+        assert_eq!(fs::read_to_string(dir.0.join("kept")).unwrap(), "original");
+        assert_eq!(fs::read_to_string(dir.0.join("deleted")).unwrap(), "doomed");
+        assert!(!dir.0.join("created").exists());
+        assert!(!run_exists(&undo_dir, &run_id).unwrap());
+    }
+
+    #[test]
+    fn a_torn_last_record_is_left_out_and_other_damage_refused() {
+        let dir = TempDir::new("torn");
+        let undo_dir = dir.0.join("undo");
+        fs::write(dir.0.join("kept"), "original").unwrap();
+        fs::write(dir.0.join("deleted"), "doomed").unwrap();
+        let run_id = record_a_run(&dir, &undo_dir);
+        let manifest = run_dir(&undo_dir, &run_id).unwrap().join(MANIFEST);
+        let bytes = fs::read(&manifest).unwrap();
+
+        fs::write(&manifest, &bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(entries(&undo_dir, &run_id).unwrap().len(), 2);
+        // Reading didn't cut the torn record off.
+        assert_eq!(fs::read(&manifest).unwrap().len(), bytes.len() - 3);
+
+        let mut damaged = bytes.clone();
+        damaged[10] ^= 0x01;
+        fs::write(&manifest, &damaged).unwrap();
+        let e = undo(&undo_dir, &run_id, &mut ApplyToDisk).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read_to_string(dir.0.join("kept")).unwrap(), "changed");
+        assert_eq!(fs::read(&manifest).unwrap(), damaged);
+    }
+
+    #[test]
+    fn run_ids_cant_leave_the_undo_dir() {
+        let dir = TempDir::new("run-id");
+        let undo_dir = dir.0.join("undo");
+        for id in ["..", "../undo", "a/../b", "/etc", "", "a.b"] {
+            let e = run_dir(&undo_dir, id).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", id);
+            assert!(run_exists(&undo_dir, id).is_err());
+            assert!(undo(&undo_dir, id, &mut DryRun::default()).is_err());
+        }
+        assert_eq!(
+            run_dir(&undo_dir, "1700000000000-42").unwrap(),
+            undo_dir.join("1700000000000-42")
+        );
+    }
+
+    #[test]
+    fn a_backup_name_that_isnt_a_number_is_refused() {
+        let entry = UndoEntry::Restore {
+            path: PathBuf::from("/tmp/x"),
+            backup: "../../secret".into(),
+        };
+        let e = decode(&encode(&entry).unwrap()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let entry = UndoEntry::Remove {
+            path: PathBuf::from("/tmp/x"),
+        };
+        assert_eq!(decode(&encode(&entry).unwrap()).unwrap(), entry);
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Alert rules over a server's numbers, checked every so often:
+//
+//     rate(errors) > 5 for 30s     errors climbing by more than 5 a second,
+//                                  for 30 seconds straight
+//     active > 100 for 10s         more than 100 connections open
+//     active < 1                   no connections at all, right away
+//
+// A rule is on a metric's value (a gauge, like `active`) or on how fast it
+// grows per second (`rate(..)`, for counters like `errors`). It fires once
+// the condition has held for its `for` duration, and resolves as soon as
+// it stops holding. Each of those is one `AlertEvent`; nothing is repeated
+// while the state stays the same.
+//
+// Time comes from the caller, so evaluation is deterministic.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub metric: String,
+    // Compare the per-second growth instead of the value.
+    pub rate: bool,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    // How long the condition has to hold before the alert fires.
+    pub sustained: Duration,
+}
+
+impl AlertRule {
+    // Parse `[rate(]METRIC[)] (>|<) NUMBER [for DURATION]`, where DURATION
+    // is a number of seconds with an optional `s`, `m` or `h`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let err = |msg: &str| format!("alert rule '{}': {}", text.trim(), msg);
+        let (condition, sustained) = match text.split_once(" for ") {
+            Some((condition, duration)) => (
+                condition,
+                parse_duration(duration.trim()).map_err(|e| err(&e))?,
+            ),
+            None => (text, Duration::ZERO),
+        };
+        let (subject, comparison, threshold) = if let Some((s, t)) = condition.split_once('>') {
+            (s, Comparison::Above, t)
+        } else if let Some((s, t)) = condition.split_once('<') {
+            (s, Comparison::Below, t)
+        } else {
+            return Err(err("expected '>' or '<'"));
+        };
+        let threshold = threshold
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|t| t.is_finite())
+            .ok_or_else(|| err("the threshold must be a number"))?;
+        let subject = subject.trim();
+        let (metric, rate) = match subject
+            .strip_prefix("rate(")
+            .and_then(|s| s.strip_suffix(')'))
+        {
+            Some(metric) => (metric.trim(), true),
+            None => (subject, false),
+        };
+        if metric.is_empty()
+            || !metric
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(err("expected a metric name"));
+        }
+        Ok(Self {
+            metric: metric.to_string(),
+            rate,
+            comparison,
+            threshold,
+            sustained,
+        })
+    }
+
+    fn holds(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+}
+
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = match text.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let scale = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown duration unit '{}'", unit)),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)
+        .map(|n| Duration::from_secs_f64(n * scale))
+        .ok_or_else(|| format!("bad duration '{}'", text))
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.rate {
+            write!(f, "rate({})", self.metric)?;
+        } else {
+            write!(f, "{}", self.metric)?;
+        }
+        let op = match self.comparison {
+            Comparison::Above => '>',
+            Comparison::Below => '<',
+        };
+        write!(f, " {} {}", op, self.threshold)?;
+        if !self.sustained.is_zero() {
+            write!(f, " for {}s", self.sustained.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub rule: AlertRule,
+    pub state: AlertState,
+    // The value (or rate) that made the change.
+    pub value: f64,
+}
+
+impl fmt::Display for AlertEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        };
+        write!(f, "alert {}: {} (now {:.2})", state, self.rule, self.value)
+    }
+}
+
+#[derive(Debug)]
+struct Tracked {
+    rule: AlertRule,
+    // The last sample of the rule's metric, for rates.
+    previous: Option<(Instant, f64)>,
+    // When the condition started holding, if it does.
+    holding_since: Option<Instant>,
+    firing: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Alerts {
+    rules: Vec<Tracked>,
+}
+
+impl Alerts {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .map(|rule| Tracked {
+                    rule,
+                    previous: None,
+                    holding_since: None,
+                    firing: false,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &AlertRule> {
+        self.rules.iter().map(|t| &t.rule)
+    }
+
+    pub fn firing(&self) -> impl Iterator<Item = &AlertRule> {
+        self.rules.iter().filter(|t| t.firing).map(|t| &t.rule)
+    }
+
+    // Check every rule against `metrics`, sampled at `now`, and return the
+    // alerts that started firing or resolved. A rate needs two samples, so
+    // rate rules say nothing on the first call; a metric missing from
+    // `metrics` counts as the condition not holding.
+    pub fn evaluate(&mut self, now: Instant, metrics: &[(&str, f64)]) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+        for tracked in &mut self.rules {
+            let sample = metrics
+                .iter()
+                .find(|(name, _)| *name == tracked.rule.metric)
+                .map(|&(_, value)| value);
+            let value = match (tracked.rule.rate, sample) {
+                (false, sample) => sample,
+                (true, Some(value)) => {
+                    let rate = tracked.previous.and_then(|(then, before)| {
+                        let secs = now.saturating_duration_since(then).as_secs_f64();
+                        (secs > 0.0).then(|| (value - before) / secs)
+                    });
+                    tracked.previous = Some((now, value));
+                    rate
+                }
+                (true, None) => None,
+            };
+            let holds = value.is_some_and(|v| tracked.rule.holds(v));
+            if !holds {
+                tracked.holding_since = None;
+                if tracked.firing {
+                    tracked.firing = false;
+                    events.push(AlertEvent {
+                        rule: tracked.rule.clone(),
+                        state: AlertState::Resolved,
+                        value: value.unwrap_or(0.0),
+                    });
+                }
+                continue;
+            }
+            let since = *tracked.holding_since.get_or_insert(now);
+            if !tracked.firing && now.saturating_duration_since(since) >= tracked.rule.sustained {
+                tracked.firing = true;
+                events.push(AlertEvent {
+                    rule: tracked.rule.clone(),
+                    state: AlertState::Firing,
+                    value: value.unwrap_or(0.0),
+                });
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(events: &[AlertEvent]) -> Vec<(String, AlertState)> {
+        events
+            .iter()
+            .map(|e| (e.rule.to_string(), e.state))
+            .collect()
+    }
+
+    #[test]
+    fn rules_parse_and_print_back() {
+        let rule = AlertRule::parse(" rate(errors) > 5 for 30s ").unwrap();
+        assert_eq!(
+            rule,
+            AlertRule {
+                metric: "errors".to_string(),
+                rate: true,
+                comparison: Comparison::Above,
+                threshold: 5.0,
+                sustained: Duration::from_secs(30),
+            }
+        );
+        assert_eq!(rule.to_string(), "rate(errors) > 5 for 30s");
+
+        assert_eq!(
+            AlertRule::parse("active<1").unwrap().to_string(),
+            "active < 1"
+        );
+        assert_eq!(
+            AlertRule::parse("active > 100 for 2m").unwrap().sustained,
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            AlertRule::parse("active > 1 for 1.5").unwrap().sustained,
+            Duration::from_millis(1500)
+        );
+    }
+
+    #[test]
+    fn bad_rules_say_what_is_wrong() {
+        for (text, problem) in [
+            ("active = 3", "expected '>' or '<'"),
+            ("active > lots", "the threshold must be a number"),
+            ("active > inf", "the threshold must be a number"),
+            ("> 3", "expected a metric name"),
+            ("rate(bytes in) > 3", "expected a metric name"),
+            ("active > 3 for 10d", "unknown duration unit 'd'"),
+            ("active > 3 for -1s", "bad duration '-1s'"),
+        ] {
+            let e = AlertRule::parse(text).unwrap_err();
+            assert_eq!(e, format!("alert rule '{}': {}", text, problem));
+        }
+    }
+
+    #[test]
+    fn an_alert_fires_once_sustained_and_resolves_at_once() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = Alerts::new(vec![AlertRule::parse("active > 10 for 5s").unwrap()]);
+
+        assert!(alerts.evaluate(at(0), &[("active", 20.0)]).is_empty());
+        assert!(alerts.evaluate(at(4), &[("active", 20.0)]).is_empty());
+        let fired = alerts.evaluate(at(5), &[("active", 30.0)]);
+        assert_eq!(
+            states(&fired),
+            [("active > 10 for 5s".to_string(), AlertState::Firing)]
+        );
+        assert_eq!(
+            fired[0].to_string(),
+            "alert firing: active > 10 for 5s (now 30.00)"
+        );
+        // Nothing repeated while it stays firing.
+        assert!(alerts.evaluate(at(6), &[("active", 30.0)]).is_empty());
+        assert_eq!(alerts.firing().count(), 1);
+
+        let resolved = alerts.evaluate(at(7), &[("active", 3.0)]);
+        assert_eq!(resolved[0].state, AlertState::Resolved);
+        assert_eq!(alerts.firing().count(), 0);
+    }
+
+    #[test]
+    fn a_dip_restarts_the_sustained_clock() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = Alerts::new(vec![AlertRule::parse("active > 10 for 5s").unwrap()]);
+
+        alerts.evaluate(at(0), &[("active", 20.0)]);
+        alerts.evaluate(at(3), &[("active", 5.0)]);
+        assert!(alerts.evaluate(at(6), &[("active", 20.0)]).is_empty());
+        assert!(alerts.evaluate(at(10), &[("active", 20.0)]).is_empty());
+        assert_eq!(alerts.evaluate(at(11), &[("active", 20.0)]).len(), 1);
+    }
+
+    #[test]
+    fn rate_rules_need_two_samples_and_use_growth_per_second() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = Alerts::new(vec![AlertRule::parse("rate(errors) > 5").unwrap()]);
+
+        // A high count alone isn't a high rate.
+        assert!(alerts.evaluate(at(0), &[("errors", 1000.0)]).is_empty());
+        assert!(alerts.evaluate(at(10), &[("errors", 1040.0)]).is_empty());
+        let fired = alerts.evaluate(at(20), &[("errors", 1100.0)]);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].value, 6.0);
+        // Two samples at the same instant give no rate, which resolves it.
+        assert_eq!(
+            alerts.evaluate(at(20), &[("errors", 1200.0)])[0].state,
+            AlertState::Resolved
+        );
+    }
+
+    #[test]
+    fn a_missing_metric_counts_as_not_holding() {
+        let start = Instant::now();
+        let mut alerts = Alerts::new(vec![AlertRule::parse("active < 1").unwrap()]);
+
+        assert_eq!(alerts.evaluate(start, &[("active", 0.0)]).len(), 1);
+        let resolved = alerts.evaluate(start, &[("connections", 0.0)]);
+        assert_eq!(resolved[0].state, AlertState::Resolved);
+        assert_eq!(resolved[0].value, 0.0);
+    }
+}
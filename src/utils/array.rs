@@ -1,21 +1,62 @@
-use std::collections::HashMap;
+use std::fmt;
 
 // Enum to handle different return types
 #[derive(Debug)]
 pub enum ModArrResult<T> {
-    ModifiedValues(HashMap<usize, T>),  // For integers/floats
+    ModifiedValues(Vec<Modified<T>>),   // For integers/floats, in index order
     NewArray(Vec<T>),                   // For strings/&str
-    Error(String),                      // For unsupported types or any error occurred in the function
+    Error(ModArrError),                 // For unsupported types or any error occurred in the function
 }
-#[allow(dead_code)]
+
+// One element that changed: where it is, what it was and what it is now
+#[derive(Debug, Clone, PartialEq)]
+pub struct Modified<T> {
+    pub index: usize,
+    pub original: T,
+    pub modified: T,
+}
+
+// `Index 1: 2 -> 3`
+impl<T: fmt::Display> fmt::Display for Modified<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Index {}: {} -> {}", self.index, self.original, self.modified)
+    }
+}
+
+// What went wrong, as something callers can match on instead of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModArrError {
+    UnsupportedType { type_name: &'static str },
+    EmptyArray,
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for ModArrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModArrError::UnsupportedType { type_name } => write!(
+                f,
+                "Unsupported types of array: {}. Use integers, floats, or string arrays",
+                type_name
+            ),
+            ModArrError::EmptyArray => write!(f, "Array cannot be empty"),
+            ModArrError::TooLarge { len, max } => {
+                write!(f, "Array too large ({} elements, max {})", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ModArrError {}
+
+// The most elements `mod_arr_with_validation` accepts
+pub const MAX_VALIDATED_LEN: usize = 1000;
 pub fn print_arr<T>(array: &[T])
 where
     T: std::fmt::Debug,
 {
-    let mut count = 0;
-    for item in array {
+    for (count, item) in array.iter().enumerate() {
         println!("count number {:?} : {:?}", count, item);
-        count += 1;
     }
 }
 
@@ -167,6 +208,61 @@ impl SupportedType for &str {
     }
 }
 
+// Apply `strategy` to every element of `array`, with its index. Works on
+// any slice, so `Vec`s and arrays of any element type too. Returns the
+// elements the strategy actually changed, in index order.
+pub fn mod_arr_with<T, F>(array: &mut [T], mut strategy: F) -> ModArrResult<T>
+where
+    T: Clone + PartialEq,
+    F: FnMut(usize, &mut T),
+{
+    let mut modified = Vec::new();
+    for (index, item) in array.iter_mut().enumerate() {
+        let original = item.clone();
+        strategy(index, item);
+        if *item != original {
+            modified.push(Modified { index, original, modified: item.clone() });
+        }
+    }
+    ModArrResult::ModifiedValues(modified)
+}
+
+// The strategy `mod_arr` uses: increment numbers at odd indices and leave
+// everything else alone. `mod_arr_with(&mut v, increment_odd)`.
+pub fn increment_odd<T: ModifiableArray>(index: usize, item: &mut T) {
+    item.modify_array(index);
+}
+
+// The read-only counterparts of `mod_arr_with`: they leave `array` alone
+// and build something new from it, for slices of any element type.
+
+// A new `Vec` with `f` applied to every element, in order.
+// `map_arr(&[1, 2, 3], |n| n * 10)` gives `[10, 20, 30]`.
+pub fn map_arr<T, U, F>(array: &[T], f: F) -> Vec<U>
+where
+    F: FnMut(&T) -> U,
+{
+    array.iter().map(f).collect()
+}
+
+// Copies of the elements `keep` returns true for, in order.
+pub fn filter_arr<T, F>(array: &[T], mut keep: F) -> Vec<T>
+where
+    T: Clone,
+    F: FnMut(&T) -> bool,
+{
+    array.iter().filter(|item| keep(item)).cloned().collect()
+}
+
+// Combine every element into one value, left to right, starting from
+// `init`. `fold_arr(&[1, 2, 3], 0, |sum, n| sum + n)` gives `6`.
+pub fn fold_arr<T, A, F>(array: &[T], init: A, f: F) -> A
+where
+    F: FnMut(A, &T) -> A,
+{
+    array.iter().fold(init, f)
+}
+
 // New version that returns different types based on array type, with error handling for unexpected cases
 pub fn mod_arr<T>(array: &mut [T]) -> ModArrResult<T>
 where
@@ -174,10 +270,9 @@ where
 {
     // Check if the type is supported
     if !T::is_supported() {
-        return ModArrResult::Error(format!(
-            "Unsupported types of array: {}. Use integers, floats, or string arrays",
-            T::type_name()
-        ));
+        return ModArrResult::Error(ModArrError::UnsupportedType {
+            type_name: T::type_name(),
+        });
     }
 
     // Simple, direct approach - no need for catch_unwind for these operations
@@ -187,20 +282,20 @@ where
         ModArrResult::NewArray(new_array)
     } else {
         // For numeric types, modify odd-indexed items in place and track changes
-        let mut modified_map = HashMap::new();
+        let mut modified = Vec::new();
         for (index, item) in array.iter_mut().enumerate() {
+            let original = item.clone();
             item.modify_array(index);
 
-            // Only add to map if the value actually changed (odd indices)
+            // Only record the values that actually changed (odd indices)
             if index % 2 == 1 {
-                modified_map.insert(index, item.clone());
+                modified.push(Modified { index, original, modified: item.clone() });
             }
         }
-        ModArrResult::ModifiedValues(modified_map)
+        ModArrResult::ModifiedValues(modified)
     }
 }
 
-#[allow(dead_code)]
 // Example of proper error handling for operations that can actually fail
 pub fn mod_arr_with_validation<T>(array: &mut [T]) -> ModArrResult<T>
 where
@@ -208,19 +303,21 @@ where
 {
     // Check if the type is supported
     if !T::is_supported() {
-        return ModArrResult::Error(format!(
-            "Unsupported types of array: {}. Use integers, floats, or string arrays",
-            T::type_name()
-        ));
+        return ModArrResult::Error(ModArrError::UnsupportedType {
+            type_name: T::type_name(),
+        });
     }
 
     // Validate array length (example of a check that could fail)
     if array.is_empty() {
-        return ModArrResult::Error("Array cannot be empty".to_string());
+        return ModArrResult::Error(ModArrError::EmptyArray);
     }
 
-    if array.len() > 1000 {
-        return ModArrResult::Error("Array too large (max 1000 elements)".to_string());
+    if array.len() > MAX_VALIDATED_LEN {
+        return ModArrResult::Error(ModArrError::TooLarge {
+            len: array.len(),
+            max: MAX_VALIDATED_LEN,
+        });
     }
 
     // Now do the actual work - these operations are infallible
@@ -228,13 +325,14 @@ where
         let new_array = array.to_vec();
         ModArrResult::NewArray(new_array)
     } else {
-        let mut modified_map = HashMap::new();
+        let mut modified = Vec::new();
         for (index, item) in array.iter_mut().enumerate() {
+            let original = item.clone();
             item.modify_array(index);
             if index % 2 == 1 {
-                modified_map.insert(index, item.clone());
+                modified.push(Modified { index, original, modified: item.clone() });
             }
         }
-        ModArrResult::ModifiedValues(modified_map)
+        ModArrResult::ModifiedValues(modified)
     }
 }
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+
+use tokio::sync::mpsc;
+
+use super::test_closure::{LogLevel, Logger};
+
+// Logging from async code without blocking the runtime.
+//
+// `Logger::log` is synchronous: fine for stderr, but a `FileLogger` can
+// stall on a slow disk, and every task on that worker thread stalls with
+// it. `AsyncLogger::log` returns a future instead. Two adapters bridge the
+// existing loggers:
+//
+// - `Inline` calls a sync logger right away. Only for loggers that can't
+//   block for long, like `StderrLogger`.
+// - `Background` hands each message to a dedicated thread that feeds the
+//   sync logger. `log().await` waits for room in the queue, so a logger
+//   that falls behind slows its callers down instead of growing without
+//   bound. It's also a sync `Logger`, so threaded and async code can share
+//   one: from sync code it blocks for room outside a runtime, and inside
+//   one drops the message (counted in `dropped`) rather than block.
+
+pub type LogFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+pub trait AsyncLogger: Send + Sync {
+    fn log<'a>(&'a self, level: LogLevel, message: &'a str) -> LogFuture<'a>;
+}
+
+pub struct Inline<L>(pub L);
+
+impl<L: Logger + Send + Sync> AsyncLogger for Inline<L> {
+    fn log<'a>(&'a self, level: LogLevel, message: &'a str) -> LogFuture<'a> {
+        self.0.log(level, message);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+pub struct Background {
+    // `None` only while dropping.
+    sender: Option<mpsc::Sender<(LogLevel, String)>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: AtomicU64,
+}
+
+impl Background {
+    // Start the thread that feeds `logger`, with room for `capacity` queued
+    // messages.
+    pub fn spawn<L: Logger + Send + 'static>(logger: L, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<(LogLevel, String)>(capacity.max(1));
+        let writer = thread::spawn(move || {
+            while let Some((level, message)) = receiver.blocking_recv() {
+                logger.log(level, &message);
+            }
+        });
+        Self {
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Messages sync callers inside a runtime dropped because the queue was
+    // full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn sender(&self) -> &mpsc::Sender<(LogLevel, String)> {
+        self.sender.as_ref().expect("only taken in drop")
+    }
+}
+
+impl AsyncLogger for Background {
+    fn log<'a>(&'a self, level: LogLevel, message: &'a str) -> LogFuture<'a> {
+        Box::pin(async move {
+            // Only fails once the writer thread is gone, and then there's
+            // nowhere left to log to.
+            let _ = self.sender().send((level, message.to_string())).await;
+        })
+    }
+}
+
+impl Logger for Background {
+    fn log(&self, level: LogLevel, message: &str) {
+        let entry = (level, message.to_string());
+        if tokio::runtime::Handle::try_current().is_ok() {
+            if let Err(mpsc::error::TrySendError::Full(_)) = self.sender().try_send(entry) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            let _ = self.sender().blocking_send(entry);
+        }
+    }
+}
+
+// Write out whatever is still queued before going away. This blocks for as
+// long as that takes, so drop the last handle once the runtime's work is
+// done (at the end of `main`), not in the middle of a busy task.
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
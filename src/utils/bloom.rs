@@ -0,0 +1,261 @@
+use std::io;
+
+use super::stable_hash::stable_hash;
+
+// Bloom filter: a compact set that can answer "definitely not seen" or
+// "probably seen". False positives happen at roughly the configured rate;
+// false negatives never do.
+//
+// Items are bytes (`&str`, `&[u8]`, ...), hashed with `stable_hash`, so a
+// filter saved with `to_bytes` answers the same on any machine.
+
+// `BLM1` filters hashed items through `Hash`, which isn't portable; their
+// bits don't match what this version looks up, so they're refused.
+const MAGIC: &[u8; 4] = b"BLM2";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    items: u64,
+}
+
+impl BloomFilter {
+    // Size the filter for `expected_items` insertions at a false-positive
+    // rate of `fp_rate` (e.g. 0.01 for 1%), using the textbook formulas:
+    //   bits   m = -n * ln(p) / ln(2)^2
+    //   hashes k = m / n * ln(2)
+    pub fn new(expected_items: usize, fp_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let p = fp_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let num_bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+        Self::with_params(num_bits, num_hashes)
+    }
+
+    // Build a filter with an explicit bit count and number of hash functions.
+    pub fn with_params(num_bits: u64, num_hashes: u32) -> Self {
+        let num_bits = num_bits.max(1);
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes: num_hashes.max(1),
+            items: 0,
+        }
+    }
+
+    pub fn insert<T: AsRef<[u8]> + ?Sized>(&mut self, item: &T) {
+        for index in self.bit_indexes(item) {
+            self.bits[(index / 64) as usize] |= 1 << (index % 64);
+        }
+        self.items += 1;
+    }
+
+    // `false` means the item was never inserted. `true` means it probably was.
+    pub fn contains<T: AsRef<[u8]> + ?Sized>(&self, item: &T) -> bool {
+        self.bit_indexes(item)
+            .all(|index| self.bits[(index / 64) as usize] & (1 << (index % 64)) != 0)
+    }
+
+    // Insert `item` and report whether it was (probably) already present.
+    // Handy for "skip if already seen" loops.
+    pub fn check_and_insert<T: AsRef<[u8]> + ?Sized>(&mut self, item: &T) -> bool {
+        let seen = self.contains(item);
+        if !seen {
+            self.insert(item);
+        }
+        seen
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+        self.items = 0;
+    }
+
+    // Number of `insert` calls so far (duplicates included).
+    pub fn len(&self) -> u64 {
+        self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    // False-positive rate expected at the current fill level:
+    //   (1 - e^(-k * n / m))^k
+    pub fn estimated_fp_rate(&self) -> f64 {
+        let k = self.num_hashes as f64;
+        let fill = 1.0 - (-k * self.items as f64 / self.num_bits as f64).exp();
+        fill.powf(k)
+    }
+
+    // Serialize as: magic, num_bits (u64 LE), num_hashes (u32 LE),
+    // items (u64 LE), then the bit words (u64 LE each).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24 + self.bits.len() * 8);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.num_bits.to_le_bytes());
+        out.extend_from_slice(&self.num_hashes.to_le_bytes());
+        out.extend_from_slice(&self.items.to_le_bytes());
+        for word in &self.bits {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if bytes.len() < 24 || &bytes[..4] != MAGIC {
+            return Err(invalid("not a bloom filter"));
+        }
+        let num_bits = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
+        let num_hashes = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let items = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+
+        let words = &bytes[24..];
+        if num_bits == 0 || num_hashes == 0 || words.len() as u64 != num_bits.div_ceil(64) * 8 {
+            return Err(invalid("bloom filter header doesn't match its size"));
+        }
+        let bits = words
+            .chunks_exact(8)
+            .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            bits,
+            num_bits,
+            num_hashes,
+            items,
+        })
+    }
+
+    // Double hashing (Kirsch & Mitzenmacher): two base hashes h1, h2 give all
+    // k indexes as h1 + i * h2, which is as good as k independent hashes.
+    fn bit_indexes<T: AsRef<[u8]> + ?Sized>(&self, item: &T) -> impl Iterator<Item = u64> + use<T> {
+        let item = item.as_ref();
+        let h1 = stable_hash(item, 0xcbf2_9ce4_8422_2325);
+        let h2 = stable_hash(item, 0x6c62_272e_07bb_0142) | 1; // odd so it never stalls
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The measured false-positive rate over this many non-members has a
+    // standard deviation of about 0.03 points at 1%, so these bounds are
+    // many deviations wide: a failure means the filter is wrong, not
+    // unlucky. (And `stable_hash` is fixed, so the run is the same every
+    // time anyway.)
+    const PROBES: usize = 100_000;
+
+    fn measured_fp_rate(filter: &BloomFilter) -> f64 {
+        let hits = (0..PROBES)
+            .filter(|i| filter.contains(&format!("absent-{}", i)))
+            .count();
+        hits as f64 / PROBES as f64
+    }
+
+    #[test]
+    fn fp_rate_matches_the_configured_rate() {
+        for (items, rate) in [(1_000, 0.05), (10_000, 0.01), (10_000, 0.001)] {
+            let mut filter = BloomFilter::new(items, rate);
+            for i in 0..items {
+                filter.insert(&format!("present-{}", i));
+            }
+            let measured = measured_fp_rate(&filter);
+            assert!(
+                measured < rate * 1.5 && measured > rate * 0.5,
+                "{} items at {}: measured {}",
+                items,
+                rate,
+                measured
+            );
+            let estimated = filter.estimated_fp_rate();
+            assert!(
+                (estimated - rate).abs() < rate * 0.25,
+                "estimated {}",
+                estimated
+            );
+        }
+    }
+
+    #[test]
+    fn never_a_false_negative() {
+        let mut filter = BloomFilter::new(5_000, 0.01);
+        for i in 0..5_000 {
+            filter.insert(&format!("item-{}", i));
+        }
+        assert!((0..5_000).all(|i| filter.contains(&format!("item-{}", i))));
+    }
+
+    #[test]
+    fn overfilling_raises_the_fp_rate() {
+        let mut filter = BloomFilter::new(1_000, 0.01);
+        for i in 0..10_000 {
+            filter.insert(&format!("present-{}", i));
+        }
+        assert!(measured_fp_rate(&filter) > 0.2);
+    }
+
+    #[test]
+    fn check_and_insert_reports_repeats() {
+        let mut filter = BloomFilter::new(100, 0.01);
+        assert!(!filter.check_and_insert("id-1"));
+        assert!(filter.check_and_insert("id-1"));
+        assert_eq!(filter.len(), 1);
+        filter.clear();
+        assert!(filter.is_empty() && !filter.contains("id-1"));
+    }
+
+    #[test]
+    fn round_trips_through_bytes() -> io::Result<()> {
+        let mut filter = BloomFilter::new(500, 0.02);
+        for i in 0..500 {
+            filter.insert(format!("k{}", i).as_bytes());
+        }
+        let restored = BloomFilter::from_bytes(&filter.to_bytes())?;
+        assert_eq!(restored, filter);
+        assert!((0..500).all(|i| restored.contains(&format!("k{}", i))));
+        Ok(())
+    }
+
+    #[test]
+    fn bytes_are_pinned() {
+        // Same items, same bits, on every machine.
+        let mut filter = BloomFilter::with_params(64, 3);
+        filter.insert("a");
+        let bytes = filter.to_bytes();
+        assert_eq!(&bytes[..4], b"BLM2");
+        assert_eq!(
+            u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            PINNED_BITS
+        );
+    }
+
+    #[test]
+    fn rejects_bad_bytes() {
+        let good = BloomFilter::new(10, 0.1).to_bytes();
+        let mut old = good.clone();
+        old[..4].copy_from_slice(b"BLM1");
+        assert!(BloomFilter::from_bytes(&old).is_err());
+        assert!(BloomFilter::from_bytes(&good[..good.len() - 1]).is_err());
+        assert!(BloomFilter::from_bytes(b"BLM2").is_err());
+    }
+
+    const PINNED_BITS: u64 = 0x2000_1000_0800_0000;
+}
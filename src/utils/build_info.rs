@@ -0,0 +1,113 @@
+use std::fmt;
+
+// Where this binary came from, as recorded by build.rs at compile time.
+//
+// `Display` is the one-line form for logs ("rust-practice 0.1.0 (3f2c1a9e
+// on main)"); `verbose_lines` has everything, for `version --verbose`.
+// Values build.rs couldn't find out (no git checkout, say) are "unknown".
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub branch: &'static str,
+    // Seconds since the Unix epoch; 0 if unknown.
+    pub built_unix: u64,
+    pub rustc: &'static str,
+    // Comma-separated cargo features; empty if none.
+    pub features: &'static str,
+}
+
+const UNKNOWN: &str = "unknown";
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    commit: or_unknown(option_env!("RUST_PRACTICE_GIT_COMMIT")),
+    branch: or_unknown(option_env!("RUST_PRACTICE_GIT_BRANCH")),
+    built_unix: parse_u64(option_env!("RUST_PRACTICE_BUILD_UNIX")),
+    rustc: or_unknown(option_env!("RUST_PRACTICE_RUSTC_VERSION")),
+    features: match option_env!("RUST_PRACTICE_FEATURES") {
+        Some(features) => features,
+        None => "",
+    },
+};
+
+impl BuildInfo {
+    // The commit shortened the way git usually shows it.
+    pub fn short_commit(&self) -> &'static str {
+        match self.commit.get(..8) {
+            Some(short) if self.commit != UNKNOWN => short,
+            _ => self.commit,
+        }
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = &'static str> {
+        self.features.split(',').filter(|f| !f.is_empty())
+    }
+
+    pub fn verbose_lines(&self) -> Vec<(&'static str, String)> {
+        let features: Vec<_> = self.features().collect();
+        vec![
+            ("version", self.version.to_string()),
+            ("commit", self.commit.to_string()),
+            ("branch", self.branch.to_string()),
+            (
+                "built",
+                if self.built_unix == 0 {
+                    UNKNOWN.to_string()
+                } else {
+                    format!("{} (unix seconds)", self.built_unix)
+                },
+            ),
+            ("rustc", self.rustc.to_string()),
+            (
+                "features",
+                if features.is_empty() {
+                    "none".to_string()
+                } else {
+                    features.join(", ")
+                },
+            ),
+        ]
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({} on {})",
+            self.name,
+            self.version,
+            self.short_commit(),
+            self.branch
+        )
+    }
+}
+
+const fn or_unknown(value: Option<&'static str>) -> &'static str {
+    match value {
+        Some(value) => value,
+        None => UNKNOWN,
+    }
+}
+
+// `str::parse` isn't const; digits only, anything else gives 0.
+const fn parse_u64(value: Option<&'static str>) -> u64 {
+    let Some(value) = value else {
+        return 0;
+    };
+    let bytes = value.as_bytes();
+    let mut n: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() {
+            return 0;
+        }
+        n = n * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    n
+}
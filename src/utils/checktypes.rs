@@ -16,27 +16,131 @@ pub fn test_types(some_type: MyTypes) {
 }
 
 use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
 
-#[allow(dead_code)]
-pub fn test_types_match_typeid(value: &dyn Any) {
-    match value.type_id() {
-        id if id == TypeId::of::<i32>() => {
-            println!("hehe i32: {}", value.downcast_ref::<i32>().unwrap())
-        }
-        id if id == TypeId::of::<f64>() => {
-            println!("hehe f64: {}", value.downcast_ref::<f64>().unwrap())
-        }
-        id if id == TypeId::of::<String>() => {
-            println!("hehe String: {}", value.downcast_ref::<String>().unwrap())
+// Dynamic dispatch on the concrete type behind a `&dyn Any`: register a
+// handler per type, then hand the registry values whose type is only known
+// at runtime.
+//
+//     let mut registry = TypeRegistry::new();
+//     registry.register::<i32>(|v| format!("i32: {}", v));
+//     registry.register::<String>(|v| format!("String: {}", v));
+//     registry.set_fallback(|_| "unsupported".to_string());
+//     registry.dispatch(&5);          // Some("i32: 5")
+//
+// Every handler returns an `R` (`()` when they only print). Registering a
+// type again replaces its handler. Without a fallback, `dispatch` returns
+// None for a type nobody registered.
+//
+// The type is the value's exact type: a `Box<dyn Any>` has to be passed as
+// `&*boxed`, or it's looked up as a `Box`, and `&str` and `String` are
+// different types.
+type Handler<R> = Box<dyn Fn(&dyn Any) -> R + Send + Sync>;
+
+pub struct TypeRegistry<R = ()> {
+    handlers: HashMap<TypeId, (&'static str, Handler<R>)>,
+    fallback: Option<Handler<R>>,
+}
+
+impl<R> TypeRegistry<R> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            fallback: None,
         }
-        id if id == TypeId::of::<&str>() => {
-            println!("hehe &str: {}", value.downcast_ref::<&str>().unwrap())
+    }
+
+    pub fn register<T: Any>(
+        &mut self,
+        handler: impl Fn(&T) -> R + Send + Sync + 'static,
+    ) -> &mut Self {
+        let handler: Handler<R> = Box::new(move |value| {
+            // Only called for values whose TypeId is T's.
+            handler(
+                value
+                    .downcast_ref::<T>()
+                    .expect("handler registered for another type"),
+            )
+        });
+        self.handlers
+            .insert(TypeId::of::<T>(), (std::any::type_name::<T>(), handler));
+        self
+    }
+
+    // Remove `T`'s handler, returning whether there was one.
+    pub fn unregister<T: Any>(&mut self) -> bool {
+        self.handlers.remove(&TypeId::of::<T>()).is_some()
+    }
+
+    // The handler for values of types nobody registered.
+    pub fn set_fallback(
+        &mut self,
+        fallback: impl Fn(&dyn Any) -> R + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    // Run the handler for `value`'s type, or the fallback.
+    pub fn dispatch(&self, value: &dyn Any) -> Option<R> {
+        match self.handlers.get(&value.type_id()) {
+            Some((_, handler)) => Some(handler(value)),
+            None => self.fallback.as_ref().map(|fallback| fallback(value)),
         }
-        _ => println!("Unsupported type"),
+    }
+
+    pub fn handles<T: Any>(&self) -> bool {
+        self.handles_id(TypeId::of::<T>())
+    }
+
+    pub fn handles_id(&self, id: TypeId) -> bool {
+        self.handlers.contains_key(&id)
+    }
+
+    // The names of the registered types, sorted, for error messages and
+    // debugging.
+    pub fn type_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.handlers.values().map(|(name, _)| *name).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
     }
 }
 
-#[allow(dead_code)]
+impl<R> Default for TypeRegistry<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R> fmt::Debug for TypeRegistry<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypeRegistry")
+            .field("types", &self.type_names())
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+pub fn test_types_match_typeid(value: &dyn Any) {
+    let mut registry = TypeRegistry::new();
+    registry
+        .register::<i32>(|v| println!("hehe i32: {}", v))
+        .register::<f64>(|v| println!("hehe f64: {}", v))
+        .register::<String>(|v| println!("hehe String: {}", v))
+        .register::<&str>(|v| println!("hehe &str: {}", v))
+        .set_fallback(|_| println!("Unsupported type"));
+    registry.dispatch(value);
+}
+
 pub fn test_types_generics<T>(some_type: T)
 where
     T: Debug,
@@ -48,7 +152,6 @@ where
 // Generic + trait approach: call with i32, f64, String, &str, etc.
 // ------------------------------------------------------------
 
-#[allow(dead_code)]
 pub trait TypeAction {
     fn handle(self);
 }
@@ -78,7 +181,77 @@ impl TypeAction for &str {
 }
 
 // Function that accepts any type implementing TypeAction
-#[allow(dead_code)]
 pub fn test_types_trait<T: TypeAction>(value: T) {
     value.handle();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describing() -> TypeRegistry<String> {
+        let mut registry = TypeRegistry::new();
+        registry
+            .register::<i32>(|v| format!("i32: {}", v))
+            .register::<String>(|v| format!("String: {}", v))
+            .register::<&str>(|v| format!("&str: {}", v));
+        registry
+    }
+
+    #[test]
+    fn dispatch_goes_by_the_exact_type() {
+        let registry = describing();
+
+        assert_eq!(registry.dispatch(&5), Some("i32: 5".to_string()));
+        assert_eq!(registry.dispatch(&"hi"), Some("&str: hi".to_string()));
+        assert_eq!(
+            registry.dispatch(&"hi".to_string()),
+            Some("String: hi".to_string())
+        );
+        // An i64 isn't an i32, and without a fallback nothing handles it.
+        assert_eq!(registry.dispatch(&5i64), None);
+    }
+
+    #[test]
+    fn a_boxed_value_has_to_be_unboxed_first() {
+        let registry = describing();
+        let boxed: Box<dyn Any> = Box::new(7);
+
+        assert_eq!(registry.dispatch(&*boxed), Some("i32: 7".to_string()));
+        assert_eq!(registry.dispatch(&boxed), None);
+    }
+
+    #[test]
+    fn the_fallback_takes_what_nobody_registered() {
+        let mut registry = describing();
+        registry.set_fallback(|value| match value.downcast_ref::<f64>() {
+            Some(v) => format!("some float {}", v),
+            None => "unsupported".to_string(),
+        });
+
+        assert_eq!(registry.dispatch(&1.5), Some("some float 1.5".to_string()));
+        assert_eq!(registry.dispatch(&()), Some("unsupported".to_string()));
+        assert_eq!(registry.dispatch(&5), Some("i32: 5".to_string()));
+    }
+
+    #[test]
+    fn registering_again_replaces_and_unregister_removes() {
+        let mut registry = describing();
+        registry.register::<i32>(|v| format!("number {}", v));
+
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.dispatch(&1), Some("number 1".to_string()));
+        assert!(registry.unregister::<i32>());
+        assert!(!registry.unregister::<i32>());
+        assert!(!registry.handles::<i32>() && registry.handles::<String>());
+        // `type_name`'s exact text isn't promised, only that it names the type.
+        let names = registry.type_names();
+        assert_eq!(names.len(), 2);
+        assert!(
+            names.iter().any(|name| name.ends_with("String")),
+            "{:?}",
+            names
+        );
+        assert!(format!("{:?}", registry).ends_with("fallback: false }"));
+    }
+}
@@ -0,0 +1,312 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A circuit breaker for calls to something that can go away (a server, a
+// remote log sink, a config service).
+//
+// Closed: calls go through and consecutive failures are counted. Reaching
+// `failure_threshold` opens the breaker.
+// Open: calls fail immediately with `CallError::Open`, without touching
+// the dependency, until `cooldown` has passed.
+// Half-open: one trial call at a time is let through. `success_threshold`
+// successes in a row close the breaker again; a failure reopens it for
+// another cooldown.
+//
+// State changes are reported to an optional listener, e.g. for logging.
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+    pub success_threshold: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(10),
+            success_threshold: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallError<E> {
+    // Rejected without calling: the breaker is open, or half-open with a
+    // trial call already running.
+    Open,
+    // The call ran and failed.
+    Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CallError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Open => write!(f, "circuit open; not calling"),
+            CallError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for CallError<E> {}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerStats {
+    pub succeeded: u64,
+    pub failed: u64,
+    pub rejected: u64,
+    pub opened: u64,
+}
+
+struct Inner {
+    state: BreakerState,
+    // Consecutive failures while closed, or successes while half-open.
+    streak: u32,
+    opened_at: Option<Instant>,
+    trial_running: bool,
+    stats: BreakerStats,
+}
+
+type Listener = Box<dyn Fn(BreakerState, BreakerState) + Send + Sync>;
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+    listener: Option<Listener>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                streak: 0,
+                opened_at: None,
+                trial_running: false,
+                stats: BreakerStats::default(),
+            }),
+            listener: None,
+        }
+    }
+
+    // Call `listener(from, to)` on every state change. It runs with the
+    // breaker locked, so it mustn't call back into the breaker.
+    pub fn on_transition(
+        mut self,
+        listener: impl Fn(BreakerState, BreakerState) + Send + Sync + 'static,
+    ) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    // Run `operation` if the breaker allows it, and record how it went.
+    pub fn call<T, E>(&self, operation: impl FnOnce() -> Result<T, E>) -> Result<T, CallError<E>> {
+        if !self.acquire(Instant::now()) {
+            return Err(CallError::Open);
+        }
+        let result = operation();
+        self.record(result.is_ok(), Instant::now());
+        result.map_err(CallError::Failed)
+    }
+
+    // The state as of now; an open breaker whose cooldown is over reports
+    // half-open, since the next call would be let through as a trial.
+    pub fn state(&self) -> BreakerState {
+        let inner = self.lock();
+        match inner.state {
+            BreakerState::Open if self.cooled_down(&inner, Instant::now()) => {
+                BreakerState::HalfOpen
+            }
+            state => state,
+        }
+    }
+
+    pub fn stats(&self) -> BreakerStats {
+        self.lock().stats
+    }
+
+    // Whether a call may go ahead now.
+    fn acquire(&self, now: Instant) -> bool {
+        let mut inner = self.lock();
+        if inner.state == BreakerState::Open && self.cooled_down(&inner, now) {
+            self.transition(&mut inner, BreakerState::HalfOpen, now);
+        }
+        let allowed = match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => !inner.trial_running,
+        };
+        if !allowed {
+            inner.stats.rejected += 1;
+        } else if inner.state == BreakerState::HalfOpen {
+            inner.trial_running = true;
+        }
+        allowed
+    }
+
+    fn record(&self, ok: bool, now: Instant) {
+        let mut inner = self.lock();
+        inner.trial_running = false;
+        if ok {
+            inner.stats.succeeded += 1;
+        } else {
+            inner.stats.failed += 1;
+        }
+        match (inner.state, ok) {
+            (BreakerState::Closed, true) => inner.streak = 0,
+            (BreakerState::Closed, false) => {
+                inner.streak += 1;
+                if inner.streak >= self.config.failure_threshold {
+                    self.transition(&mut inner, BreakerState::Open, now);
+                }
+            }
+            (BreakerState::HalfOpen, true) => {
+                inner.streak += 1;
+                if inner.streak >= self.config.success_threshold {
+                    self.transition(&mut inner, BreakerState::Closed, now);
+                }
+            }
+            (BreakerState::HalfOpen, false) => self.transition(&mut inner, BreakerState::Open, now),
+            // A call let through before the breaker opened finished late;
+            // the breaker is already doing the right thing.
+            (BreakerState::Open, _) => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: BreakerState, now: Instant) {
+        let from = inner.state;
+        inner.state = to;
+        inner.streak = 0;
+        if to == BreakerState::Open {
+            inner.opened_at = Some(now);
+            inner.stats.opened += 1;
+        }
+        if let Some(listener) = &self.listener {
+            listener(from, to);
+        }
+    }
+
+    fn cooled_down(&self, inner: &Inner, now: Instant) -> bool {
+        inner
+            .opened_at
+            .is_some_and(|at| now.duration_since(at) >= self.config.cooldown)
+    }
+
+    // No user code runs under the lock except the listener, which only
+    // sees the state after it's consistent.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn breaker(failure_threshold: u32, success_threshold: u32) -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            failure_threshold,
+            cooldown: Duration::from_secs(10),
+            success_threshold,
+        })
+    }
+
+    // Run a call at `now` that succeeds or fails, as `call` would.
+    fn attempt(breaker: &CircuitBreaker, ok: bool, now: Instant) -> bool {
+        let allowed = breaker.acquire(now);
+        if allowed {
+            breaker.record(ok, now);
+        }
+        allowed
+    }
+
+    #[test]
+    fn consecutive_failures_open_the_breaker() {
+        let breaker = breaker(3, 1);
+        let calls = std::cell::Cell::new(0);
+        let fail = || {
+            calls.set(calls.get() + 1);
+            Err::<(), _>("down")
+        };
+
+        assert_eq!(breaker.call(fail), Err(CallError::Failed("down")));
+        assert_eq!(breaker.call(|| Ok::<_, &str>(1)), Ok(1));
+        // The success reset the streak, so it takes three more.
+        for _ in 0..3 {
+            assert_eq!(breaker.call(fail), Err(CallError::Failed("down")));
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.call(fail), Err(CallError::Open));
+        assert_eq!(calls.get(), 4);
+        assert_eq!(
+            breaker.stats(),
+            BreakerStats {
+                succeeded: 1,
+                failed: 4,
+                rejected: 1,
+                opened: 1
+            }
+        );
+    }
+
+    #[test]
+    fn after_the_cooldown_one_trial_decides() {
+        let breaker = breaker(1, 2);
+        let start = Instant::now();
+        assert!(attempt(&breaker, false, start));
+        assert!(!attempt(&breaker, true, start + Duration::from_secs(9)));
+
+        // Cooled down: a failed trial reopens it for another cooldown.
+        let later = start + Duration::from_secs(10);
+        assert!(attempt(&breaker, false, later));
+        assert!(!attempt(&breaker, true, later + Duration::from_secs(9)));
+
+        // Two good trials in a row close it.
+        let later = later + Duration::from_secs(10);
+        assert!(breaker.acquire(later));
+        // Only one trial at a time.
+        assert!(!breaker.acquire(later));
+        breaker.record(true, later);
+        assert!(attempt(&breaker, true, later));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn transitions_are_reported() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let breaker = breaker(1, 1).on_transition(move |from, to| {
+            log.lock().unwrap().push(format!("{} -> {}", from, to));
+        });
+        let start = Instant::now();
+
+        attempt(&breaker, false, start);
+        attempt(&breaker, true, start + Duration::from_secs(10));
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["closed -> open", "open -> half-open", "half-open -> closed"]
+        );
+    }
+}
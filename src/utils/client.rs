@@ -0,0 +1,183 @@
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::protocol::{MAX_FRAME_LEN, read_frame, write_frame};
+use super::sim::SimRng;
+use super::test_closure::{LogLevel, Logger};
+
+// A blocking client for the server in `src/bin/server.rs`, so programs
+// talking to it call methods instead of writing frames by hand.
+//
+// The server only speaks the echo protocol, so `echo` is the only call
+// there is. Each call is one request frame and one reply frame on the
+// same connection; calls can't overlap.
+//
+// `connect_with_retry` keeps trying while the server isn't up yet, waiting
+// longer after each failure: the nth retry waits `backoff_base * 2^(n-1)`,
+// capped at `backoff_max`, with the lower half of that randomized so many
+// clients started together don't all retry in step.
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Connection attempts in all, the first included.
+    pub attempts: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    // If set, keep trying past `attempts` until this much time has passed.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff_base: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(5),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // One attempt, no retries.
+    pub fn none() -> Self {
+        Self {
+            attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    // The wait before retry number `retry` (1 for the first).
+    fn delay(&self, retry: u32, rng: &mut SimRng) -> Duration {
+        let full = self
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.backoff_max);
+        let half = full.as_millis() as u64 / 2;
+        Duration::from_millis(half + rng.range(0, half))
+    }
+}
+
+#[derive(Debug)]
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    // Connect with DEFAULT_TIMEOUT for every read and write.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::connect_timeout(addr, DEFAULT_TIMEOUT)
+    }
+
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self { stream })
+    }
+
+    // Connect, retrying under `policy` while the connection fails. Each
+    // failed attempt and the wait before the next one is logged at Warn.
+    pub fn connect_with_retry(
+        addr: &str,
+        policy: &RetryPolicy,
+        logger: &dyn Logger,
+    ) -> io::Result<Self> {
+        let started = Instant::now();
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+            ^ std::process::id() as u64;
+        let mut rng = SimRng::new(seed);
+        let mut attempt = 1;
+        loop {
+            let error = match Self::connect(addr) {
+                Ok(client) => {
+                    if attempt > 1 {
+                        logger.log(
+                            LogLevel::Info,
+                            &format!("connected to {} on attempt {}", addr, attempt),
+                        );
+                    }
+                    return Ok(client);
+                }
+                Err(e) => e,
+            };
+            let delay = policy.delay(attempt, &mut rng);
+            let give_up = match policy.deadline {
+                Some(deadline) => started.elapsed() + delay > deadline,
+                None => attempt >= policy.attempts,
+            };
+            if give_up {
+                return Err(io::Error::new(
+                    error.kind(),
+                    format!(
+                        "couldn't connect to {} after {} attempt(s): {}",
+                        addr, attempt, error
+                    ),
+                ));
+            }
+            logger.log(
+                LogLevel::Warn,
+                &format!(
+                    "connecting to {} failed (attempt {}): {}; retrying in {} ms",
+                    addr,
+                    attempt,
+                    error,
+                    delay.as_millis()
+                ),
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    pub fn echo(&mut self, message: &str) -> io::Result<String> {
+        let reply = self.echo_bytes(message.as_bytes())?;
+        String::from_utf8(reply).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Send `payload` and return the server's reply, which has to be the
+    // same bytes; anything else means the server is broken or isn't an
+    // echo server, and is an `InvalidData` error.
+    pub fn echo_bytes(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let reply = self.call(payload)?;
+        if reply != payload {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "echo mismatch: sent {} bytes, got {} different ones back",
+                    payload.len(),
+                    reply.len()
+                ),
+            ));
+        }
+        Ok(reply)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    // The connection itself, for speaking something other than frames over
+    // it (see `text_protocol`).
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+
+    // One request frame out, one reply frame back.
+    fn call(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        write_frame(&mut self.stream, request)?;
+        read_frame(&mut self.stream, MAX_FRAME_LEN)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection before replying",
+            )
+        })
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+
+use super::expr::Value;
+
+// Payload codecs: ways of turning a `Value` into bytes and back, looked up
+// by name so the two ends of a connection can agree on one.
+//
+// Built in:
+// - "json":   `null`, `true`, `3.5`, `"text"`; NaN and the infinities
+//             can't be encoded
+// - "binary": one tag byte (0 null, 1 bool, 2 number, 3 string) followed by
+//             the data: a byte for bools, f64 little-endian for numbers,
+//             raw UTF-8 for strings
+// - "text":   the value's display form; decoding guesses the type the same
+//             way the CLI does (null/true/false/number, otherwise a string)
+//
+// New formats implement `Codec` and get registered; nothing else changes.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &'static str;
+    // Fails for a value the format can't represent.
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError>;
+}
+
+pub struct CodecRegistry {
+    // In registration order, which is also the server's preference order.
+    codecs: Vec<Box<dyn Codec>>,
+}
+
+impl Default for CodecRegistry {
+    // A registry with the built-in codecs: json, binary, text.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(Box::new(JsonCodec));
+        registry.register(Box::new(BinaryCodec));
+        registry.register(Box::new(TextCodec));
+        registry
+    }
+}
+
+impl CodecRegistry {
+    pub fn empty() -> Self {
+        Self { codecs: Vec::new() }
+    }
+
+    // Add a codec, replacing any existing one with the same name.
+    pub fn register(&mut self, codec: Box<dyn Codec>) {
+        match self.codecs.iter().position(|c| c.name() == codec.name()) {
+            Some(i) => self.codecs[i] = codec,
+            None => self.codecs.push(codec),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Codec> {
+        self.codecs
+            .iter()
+            .find(|c| c.name() == name)
+            .map(|c| c.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.codecs.iter().map(|c| c.name()).collect()
+    }
+
+    // Pick a codec for a peer that offered `offered`, in its order of
+    // preference: the first one we also support wins.
+    pub fn negotiate(&self, offered: &[&str]) -> Option<&dyn Codec> {
+        offered.iter().find_map(|name| self.get(name))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// JSON
+// ---------------------------------------------------------------------------
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        let text = match value {
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Num(n) if !n.is_finite() => {
+                return Err(CodecError(format!("json: {} has no JSON form", n)));
+            }
+            // Not serde_json's `3.0`: integers have always gone out as `3`.
+            Value::Num(n) => n.to_string(),
+            Value::Str(s) => {
+                serde_json::to_string(s).map_err(|e| CodecError(format!("json: {}", e)))?
+            }
+        };
+        Ok(text.into_bytes())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+        let err = |msg: String| CodecError(format!("json: {}", msg));
+        match serde_json::from_slice(bytes).map_err(|e| err(e.to_string()))? {
+            serde_json::Value::Null => Ok(Value::Null),
+            serde_json::Value::Bool(b) => Ok(Value::Bool(b)),
+            serde_json::Value::Number(n) => n
+                .as_f64()
+                .map(Value::Num)
+                .ok_or_else(|| err(format!("number {} is out of range", n))),
+            serde_json::Value::String(s) => Ok(Value::Str(s)),
+            other => Err(err(format!(
+                "unsupported value '{}' (only null, bools, numbers and strings)",
+                other
+            ))),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tagged binary
+// ---------------------------------------------------------------------------
+
+pub struct BinaryCodec;
+
+const TAG_NULL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUM: u8 = 2;
+const TAG_STR: u8 = 3;
+
+impl Codec for BinaryCodec {
+    fn name(&self) -> &'static str {
+        "binary"
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        Ok(match value {
+            Value::Null => vec![TAG_NULL],
+            Value::Bool(b) => vec![TAG_BOOL, *b as u8],
+            Value::Num(n) => {
+                let mut out = vec![TAG_NUM];
+                out.extend_from_slice(&n.to_le_bytes());
+                out
+            }
+            Value::Str(s) => {
+                let mut out = vec![TAG_STR];
+                out.extend_from_slice(s.as_bytes());
+                out
+            }
+        })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+        let err = |msg: String| CodecError(format!("binary: {}", msg));
+        let (&tag, data) = bytes
+            .split_first()
+            .ok_or_else(|| err("empty payload".to_string()))?;
+        match (tag, data) {
+            (TAG_NULL, []) => Ok(Value::Null),
+            (TAG_BOOL, [b @ (0 | 1)]) => Ok(Value::Bool(*b == 1)),
+            (TAG_NUM, _) => {
+                let raw: [u8; 8] = data
+                    .try_into()
+                    .map_err(|_| err(format!("number needs 8 bytes, got {}", data.len())))?;
+                Ok(Value::Num(f64::from_le_bytes(raw)))
+            }
+            (TAG_STR, _) => String::from_utf8(data.to_vec())
+                .map(Value::Str)
+                .map_err(|e| err(e.to_string())),
+            (TAG_NULL | TAG_BOOL, _) => Err(err(format!("malformed value with tag {}", tag))),
+            _ => Err(err(format!("unknown tag {}", tag))),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Plain text
+// ---------------------------------------------------------------------------
+
+pub struct TextCodec;
+
+impl Codec for TextCodec {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        Ok(value.to_string().into_bytes())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+        let text = std::str::from_utf8(bytes).map_err(|e| CodecError(format!("text: {}", e)))?;
+        Ok(match text {
+            "null" => Value::Null,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => match text.parse::<f64>() {
+                Ok(n) => Value::Num(n),
+                Err(_) => Value::Str(text.to_string()),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(text: &str) -> Result<Value, CodecError> {
+        JsonCodec.decode(text.as_bytes())
+    }
+
+    #[test]
+    fn json_decodes_strict_json_only() {
+        assert_eq!(json(" null\n"), Ok(Value::Null));
+        assert_eq!(json("-2.5e3"), Ok(Value::Num(-2500.0)));
+        assert_eq!(json(r#""A\n""#), Ok(Value::Str("A\n".to_string())));
+        assert_eq!(json(r#""😀""#), Ok(Value::Str("😀".to_string())));
+        for bad in [
+            r#""\u41""#,
+            r#""\ud83d""#,
+            r#""\x""#,
+            r#""a"b""#,
+            "\"open",
+            "NaN",
+            "Infinity",
+            "1e400",
+            "01",
+            "+1",
+            "nul",
+            "[1]",
+            "{}",
+            "true false",
+            "",
+        ] {
+            assert!(json(bad).is_err(), "{:?} decoded", bad);
+        }
+    }
+
+    #[test]
+    fn json_refuses_non_finite_numbers() {
+        for n in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(JsonCodec.encode(&Value::Num(n)).is_err(), "{}", n);
+        }
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        let values = [
+            Value::Null,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Num(0.0),
+            Value::Num(-3.0),
+            Value::Num(0.1),
+            Value::Num(1e300),
+            Value::Num(f64::MIN_POSITIVE),
+            Value::Str(String::new()),
+            Value::Str("quote \" backslash \\ tab \t bell \u{7} é 😀".to_string()),
+        ];
+        let registry = CodecRegistry::default();
+        for name in registry.names() {
+            let codec = registry.get(name).unwrap();
+            for value in &values {
+                let bytes = codec.encode(value).unwrap();
+                assert_eq!(
+                    codec.decode(&bytes).as_ref(),
+                    Ok(value),
+                    "{} {:?}",
+                    name,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn json_numbers_keep_their_old_form() {
+        let encode = |n: f64| String::from_utf8(JsonCodec.encode(&Value::Num(n)).unwrap()).unwrap();
+        assert_eq!(encode(3.0), "3");
+        assert_eq!(encode(3.5), "3.5");
+        assert_eq!(encode(-0.25), "-0.25");
+    }
+
+    #[test]
+    fn negotiation_follows_the_peer() {
+        let registry = CodecRegistry::default();
+        let pick = |offered: &[&str]| registry.negotiate(offered).map(|c| c.name());
+        assert_eq!(pick(&["msgpack", "binary", "json"]), Some("binary"));
+        assert_eq!(pick(&["msgpack"]), None);
+        assert_eq!(pick(&[]), None);
+    }
+}
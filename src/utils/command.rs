@@ -0,0 +1,426 @@
+use std::fmt;
+
+use super::text_protocol::TextCommand;
+
+// The command language of `client --interactive`, e.g.
+//
+//   send "hello world" x3
+//   auth rp_0123...
+//   verify E2E-HELLO 9f2c... "1a2b 3c4d ..."
+//
+// Lines are split into words. A word is either bare (anything up to the
+// next whitespace) or quoted with '"' or '\''. Inside double quotes the
+// escapes \" \\ \n \t work; single quotes take everything literally. A bare
+// word of the form `x<N>` is a repeat count. `#` starts a comment. Command
+// names are case-insensitive, and may start with a '/' as they once had to.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandError {
+    pub message: String,
+    pub pos: usize,
+}
+
+impl CommandError {
+    fn new(message: impl Into<String>, pos: usize) -> Self {
+        Self {
+            message: message.into(),
+            pos,
+        }
+    }
+
+    // Render the error under the input line with a caret, under the
+    // character at `pos`.
+    pub fn pretty(&self, source: &str) -> String {
+        let column = source
+            .get(..self.pos)
+            .map_or(self.pos, |before| before.chars().count());
+        format!("{}\n{}^ {}", source, " ".repeat(column), self.message)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at offset {})", self.message, self.pos)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+// ---------------------------------------------------------------------------
+// Lexer
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WordKind {
+    Bare,
+    Quoted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word {
+    pub text: String,
+    pub kind: WordKind,
+    // Byte offset of the word in the input line.
+    pub pos: usize,
+}
+
+pub fn lex(line: &str) -> Result<Vec<Word>, CommandError> {
+    let mut words = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '#' {
+            break;
+        }
+
+        if c == '"' || c == '\'' {
+            let quote = c;
+            chars.next();
+            let mut text = String::new();
+            let mut closed = false;
+            while let Some((at, c)) = chars.next() {
+                match c {
+                    _ if c == quote => {
+                        closed = true;
+                        break;
+                    }
+                    '\\' if quote == '"' => {
+                        let escaped = match chars.next() {
+                            Some((_, 'n')) => '\n',
+                            Some((_, 't')) => '\t',
+                            Some((_, '"')) => '"',
+                            Some((_, '\\')) => '\\',
+                            Some((_, other)) => {
+                                return Err(CommandError::new(
+                                    format!("unknown escape '\\{}'", other),
+                                    at,
+                                ));
+                            }
+                            None => break,
+                        };
+                        text.push(escaped);
+                    }
+                    c => text.push(c),
+                }
+            }
+            if !closed {
+                return Err(CommandError::new("unterminated string", pos));
+            }
+            words.push(Word {
+                text,
+                kind: WordKind::Quoted,
+                pos,
+            });
+            continue;
+        }
+
+        let mut text = String::new();
+        while let Some(&(_, c)) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            if c == '"' || c == '\'' {
+                return Err(CommandError::new(
+                    "quotes must start a new word",
+                    pos + text.len(),
+                ));
+            }
+            text.push(c);
+            chars.next();
+        }
+        words.push(Word {
+            text,
+            kind: WordKind::Bare,
+            pos,
+        });
+    }
+
+    Ok(words)
+}
+
+// ---------------------------------------------------------------------------
+// Parser
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // Echo a message through the server `times` times.
+    Send { message: String, times: u32 },
+    Ping,
+    // Ask for the server's clock.
+    Time,
+    // Present an API token (see `text_protocol`).
+    Auth { token: String },
+    // Show this client's public key and fingerprint (feature `e2e`).
+    Key,
+    // Check a peer's public key against the fingerprint they read out
+    // (feature `e2e`). The fingerprint may be split over several words.
+    Verify { key: String, fingerprint: String },
+    Help,
+    Quit,
+}
+
+// What a peer's hello line starts with (`e2e::HELLO`, which needs the
+// feature); `verify` skips it, so the whole line can be pasted.
+const HELLO: &str = "E2E-HELLO";
+
+impl Command {
+    // Parse one input line. Returns `Ok(None)` for blank lines and comments.
+    pub fn parse(line: &str) -> Result<Option<Command>, CommandError> {
+        let words = lex(line)?;
+        let Some((verb, args)) = words.split_first() else {
+            return Ok(None);
+        };
+        if verb.kind == WordKind::Quoted {
+            return Err(CommandError::new("expected a command name", verb.pos));
+        }
+
+        let name = verb.text.to_ascii_lowercase();
+        let command = match name.strip_prefix('/').unwrap_or(&name) {
+            "send" | "echo" => {
+                let (message, rest) = one_arg(verb, args, "message")?;
+                // It goes out as one line.
+                if message.text.contains(['\n', '\r']) {
+                    return Err(CommandError::new(
+                        "a message can't contain a line break",
+                        message.pos,
+                    ));
+                }
+                let times = match rest.split_first() {
+                    None => 1,
+                    Some((word, extra)) => {
+                        let times = repeat_count(word)?;
+                        no_more(extra)?;
+                        times
+                    }
+                };
+                Command::Send {
+                    message: message.text.clone(),
+                    times,
+                }
+            }
+            "ping" => {
+                no_more(args)?;
+                Command::Ping
+            }
+            "time" => {
+                no_more(args)?;
+                Command::Time
+            }
+            "auth" => {
+                let (token, rest) = one_arg(verb, args, "token")?;
+                no_more(rest)?;
+                Command::Auth {
+                    token: token.text.clone(),
+                }
+            }
+            "key" => {
+                no_more(args)?;
+                Command::Key
+            }
+            "verify" => {
+                let args = match args.split_first() {
+                    Some((first, rest)) if first.text == HELLO => rest,
+                    _ => args,
+                };
+                let (key, rest) = one_arg(verb, args, "key")?;
+                if rest.is_empty() {
+                    return Err(CommandError::new(
+                        "'verify' needs the fingerprint after the key",
+                        key.pos + key.text.len(),
+                    ));
+                }
+                Command::Verify {
+                    key: key.text.clone(),
+                    fingerprint: rest
+                        .iter()
+                        .map(|w| w.text.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                }
+            }
+            "help" | "?" => {
+                no_more(args)?;
+                Command::Help
+            }
+            "quit" | "exit" => {
+                no_more(args)?;
+                Command::Quit
+            }
+            _ => {
+                return Err(CommandError::new(
+                    format!("unknown command '{}' (try 'help')", verb.text),
+                    verb.pos,
+                ));
+            }
+        };
+        Ok(Some(command))
+    }
+
+    // What to ask the server for, once per `times` for `Send`, or `None` for
+    // a command the client handles itself.
+    pub fn request(&self) -> Option<TextCommand> {
+        match self {
+            Command::Send { message, .. } => Some(TextCommand::Echo(message.clone())),
+            Command::Ping => Some(TextCommand::Ping),
+            Command::Time => Some(TextCommand::Time),
+            Command::Auth { token } => Some(TextCommand::Auth(token.clone())),
+            Command::Quit => Some(TextCommand::Quit),
+            Command::Key | Command::Verify { .. } | Command::Help => None,
+        }
+    }
+}
+
+pub const HELP: &str = "\
+commands:
+  send <message> [xN]          echo a message, optionally N times
+                               (quote it if it has spaces)
+  ping
+  time                         the server's clock
+  auth <token>                 present an API token
+  key                          this client's public key (e2e)
+  verify <key> <fingerprint>   check a peer's key (e2e)
+  help
+  quit";
+
+fn one_arg<'w>(
+    verb: &Word,
+    args: &'w [Word],
+    what: &str,
+) -> Result<(&'w Word, &'w [Word]), CommandError> {
+    args.split_first().ok_or_else(|| {
+        CommandError::new(
+            format!("'{}' needs a {}", verb.text, what),
+            verb.pos + verb.text.len(),
+        )
+    })
+}
+
+fn no_more(args: &[Word]) -> Result<(), CommandError> {
+    match args.first() {
+        Some(word) => Err(CommandError::new("unexpected argument", word.pos)),
+        None => Ok(()),
+    }
+}
+
+fn repeat_count(word: &Word) -> Result<u32, CommandError> {
+    let bad = || {
+        CommandError::new(
+            "expected a repeat count like x3 (quote a message with spaces in it)",
+            word.pos,
+        )
+    };
+    if word.kind != WordKind::Bare {
+        return Err(bad());
+    }
+    let n: u32 = word
+        .text
+        .strip_prefix('x')
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(bad)?;
+    if n == 0 {
+        return Err(CommandError::new(
+            "repeat count must be at least 1",
+            word.pos,
+        ));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        Command::parse(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parses_every_command() {
+        let send = |message: &str, times| Command::Send {
+            message: message.to_string(),
+            times,
+        };
+        assert_eq!(parse("send hi"), send("hi", 1));
+        assert_eq!(parse("SEND \"hello world\" x3"), send("hello world", 3));
+        assert_eq!(parse("echo 'a \\n b'"), send("a \\n b", 1));
+        assert_eq!(parse("ping # still there?"), Command::Ping);
+        assert_eq!(parse("  time"), Command::Time);
+        assert_eq!(
+            parse("auth rp_abc"),
+            Command::Auth {
+                token: "rp_abc".to_string()
+            }
+        );
+        assert_eq!(parse("/key"), Command::Key);
+        let verify = Command::Verify {
+            key: "00ff".to_string(),
+            fingerprint: "1a2b 3c4d".to_string(),
+        };
+        assert_eq!(parse("verify 00ff 1a2b 3c4d"), verify);
+        assert_eq!(parse("/verify E2E-HELLO 00ff \"1a2b 3c4d\""), verify);
+        assert_eq!(parse("?"), Command::Help);
+        assert_eq!(parse("exit"), Command::Quit);
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_nothing() {
+        for line in ["", "   ", "# a comment", "\t# another"] {
+            assert_eq!(Command::parse(line), Ok(None), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let cases = [
+            ("send", 4, "needs a message"),
+            ("send hello world", 11, "repeat count"),
+            ("send hi x0", 8, "at least 1"),
+            ("send hi x2 more", 11, "unexpected argument"),
+            ("send \"a\\nb\"", 5, "line break"),
+            ("send \"open", 5, "unterminated"),
+            ("send \"\\q\"", 6, "unknown escape"),
+            ("send hi\"there\"", 7, "quotes must start"),
+            ("\"send\" hi", 0, "command name"),
+            ("ping now", 5, "unexpected argument"),
+            ("verify 00ff", 11, "fingerprint"),
+            ("frobnicate", 0, "unknown command"),
+        ];
+        for (line, pos, message) in cases {
+            let err = Command::parse(line).unwrap_err();
+            assert_eq!(err.pos, pos, "{:?}: {}", line, err);
+            assert!(err.message.contains(message), "{:?}: {}", line, err);
+        }
+    }
+
+    #[test]
+    fn caret_counts_characters_not_bytes() {
+        let line = "send \"größe\" y";
+        let err = Command::parse(line).unwrap_err();
+        assert_eq!(
+            err.pretty(line).lines().nth(1),
+            Some(
+                "             ^ expected a repeat count like x3 (quote a message with spaces in it)"
+            )
+        );
+    }
+
+    #[test]
+    fn requests_are_what_the_server_parses() {
+        for line in ["send 'hi there'", "ping", "time", "auth rp_secret", "quit"] {
+            let request = parse(line).request().unwrap();
+            assert_eq!(TextCommand::parse(&request.line()), Ok(request));
+        }
+        assert_eq!(
+            parse("auth rp_secret").request().unwrap().line(),
+            "AUTH rp_secret"
+        );
+        for line in ["help", "key", "verify 00 11"] {
+            assert_eq!(parse(line).request(), None, "{:?}", line);
+        }
+    }
+}
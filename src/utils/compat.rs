@@ -0,0 +1,425 @@
+use std::fmt;
+
+use super::codec::{Codec, CodecRegistry};
+use super::expr::Value;
+use super::protocol::{FrameDecoder, encode_frame};
+
+// Wire compatibility checks: bytes captured from each released version of
+// the frame format and the payload codecs, checked against the code as it
+// is now.
+//
+// Two promises are checked, per fixture:
+// - decode: today's code reads what that version wrote, to the same value
+//   (newer readers understand older writers);
+// - encode: today's code writes exactly those bytes for that value, so a
+//   reader from that version still understands it. Only promised for
+//   fixtures marked `encode`; some inputs are only ever accepted, like JSON
+//   with stray whitespace, and never produced.
+//
+// Fixtures are never edited once released. When an encoding changes, bump
+// CURRENT_VERSION and add fixtures for it next to the old ones: old
+// fixtures that still decode are what keeps the change compatible, and
+// those whose encoding legitimately changed lose their `encode` mark.
+
+pub const CURRENT_VERSION: u32 = 1;
+
+// What a fixture's bytes stand for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subject {
+    // A protocol frame carrying this payload.
+    Frame(Vec<u8>),
+    // A value as written by the named codec.
+    Value(&'static str, Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    // The version that wrote these bytes.
+    pub version: u32,
+    pub name: &'static str,
+    pub subject: Subject,
+    pub bytes: Vec<u8>,
+    // Whether today's encoder must still produce exactly `bytes`.
+    pub encode: bool,
+}
+
+impl Fixture {
+    // "frame", or the codec's name.
+    pub fn format(&self) -> &'static str {
+        match &self.subject {
+            Subject::Frame(_) => "frame",
+            Subject::Value(codec, _) => codec,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Decode,
+    Encode,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Decode => "decode",
+            Direction::Encode => "encode",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatResult {
+    pub format: &'static str,
+    pub version: u32,
+    pub name: &'static str,
+    pub direction: Direction,
+    // `None` if the promise holds, else how it's broken.
+    pub failure: Option<String>,
+}
+
+impl CompatResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+pub fn fixtures() -> Vec<Fixture> {
+    let v1 = |name, subject, bytes: &[u8], encode| Fixture {
+        version: 1,
+        name,
+        subject,
+        bytes: bytes.to_vec(),
+        encode,
+    };
+    let value = |codec, value: Value| Subject::Value(codec, value);
+    let text = |s: &str| Value::Str(s.to_string());
+    vec![
+        // Frames: a big-endian u32 length, then the payload.
+        v1(
+            "hello",
+            Subject::Frame(b"hello".to_vec()),
+            b"\0\0\0\x05hello",
+            true,
+        ),
+        v1("empty", Subject::Frame(Vec::new()), b"\0\0\0\0", true),
+        v1(
+            "binary",
+            Subject::Frame(vec![0, 0xff]),
+            b"\0\0\0\x02\0\xff",
+            true,
+        ),
+        // JSON
+        v1("null", value("json", Value::Null), b"null", true),
+        v1("bool", value("json", Value::Bool(true)), b"true", true),
+        v1("number", value("json", Value::Num(3.5)), b"3.5", true),
+        v1(
+            "string",
+            value("json", text("a\"b\n")),
+            b"\"a\\\"b\\n\"",
+            true,
+        ),
+        v1(
+            "unicode",
+            value("json", text("h\u{e9}")),
+            "\"h\u{e9}\"".as_bytes(),
+            true,
+        ),
+        v1(
+            "unicode-escape",
+            value("json", text("h\u{e9}")),
+            b"\"h\\u00e9\"",
+            false,
+        ),
+        v1(
+            "whitespace",
+            value("json", Value::Bool(false)),
+            b" false\n",
+            false,
+        ),
+        // Tagged binary: a tag byte, then the data.
+        v1("null", value("binary", Value::Null), b"\0", true),
+        v1(
+            "bool",
+            value("binary", Value::Bool(true)),
+            b"\x01\x01",
+            true,
+        ),
+        v1(
+            "number",
+            value("binary", Value::Num(3.5)),
+            b"\x02\0\0\0\0\0\0\x0c\x40",
+            true,
+        ),
+        v1("string", value("binary", text("hi")), b"\x03hi", true),
+        // Text
+        v1("null", value("text", Value::Null), b"null", true),
+        v1("bool", value("text", Value::Bool(false)), b"false", true),
+        v1("number", value("text", Value::Num(-2.0)), b"-2", true),
+        v1(
+            "string",
+            value("text", text("hello world")),
+            b"hello world",
+            true,
+        ),
+    ]
+}
+
+// Check every promise `fixtures` make against today's code.
+pub fn run(fixtures: &[Fixture]) -> Vec<CompatResult> {
+    let codecs = CodecRegistry::default();
+    let mut results = Vec::new();
+    for fixture in fixtures {
+        let result = |direction, failure| CompatResult {
+            format: fixture.format(),
+            version: fixture.version,
+            name: fixture.name,
+            direction,
+            failure,
+        };
+        let codec = match &fixture.subject {
+            Subject::Value(name, _) => match codecs.get(name) {
+                Some(codec) => Some(codec),
+                None => {
+                    let failure = Some(format!("no codec named '{}' any more", name));
+                    results.push(result(Direction::Decode, failure));
+                    continue;
+                }
+            },
+            Subject::Frame(_) => None,
+        };
+        results.push(result(Direction::Decode, check_decode(fixture, codec)));
+        if fixture.encode {
+            results.push(result(Direction::Encode, check_encode(fixture, codec)));
+        }
+    }
+    results
+}
+
+fn check_decode(fixture: &Fixture, codec: Option<&dyn Codec>) -> Option<String> {
+    match (&fixture.subject, codec) {
+        (Subject::Frame(payload), _) => {
+            let mut frames = FrameDecoder::default();
+            frames.push(&fixture.bytes);
+            match frames.next_frame() {
+                Ok(Some(frame)) if frame == *payload && frames.buffered() == 0 => None,
+                Ok(Some(frame)) if frame == *payload => Some(format!(
+                    "{} bytes left over after the frame",
+                    frames.buffered()
+                )),
+                Ok(Some(frame)) => Some(format!("read payload {:?}, want {:?}", frame, payload)),
+                Ok(None) => Some("no complete frame".to_string()),
+                Err(e) => Some(e.to_string()),
+            }
+        }
+        (Subject::Value(_, want), Some(codec)) => match codec.decode(&fixture.bytes) {
+            Ok(got) if got == *want => None,
+            Ok(got) => Some(format!("read {:?}, want {:?}", got, want)),
+            Err(e) => Some(e.to_string()),
+        },
+        (Subject::Value(..), None) => unreachable!("looked up by `run`"),
+    }
+}
+
+fn check_encode(fixture: &Fixture, codec: Option<&dyn Codec>) -> Option<String> {
+    let bytes = match (&fixture.subject, codec) {
+        (Subject::Frame(payload), _) => encode_frame(payload),
+        (Subject::Value(_, value), Some(codec)) => match codec.encode(value) {
+            Ok(bytes) => bytes,
+            Err(e) => return Some(e.to_string()),
+        },
+        (Subject::Value(..), None) => unreachable!("looked up by `run`"),
+    };
+    if bytes == fixture.bytes {
+        None
+    } else {
+        Some(format!("wrote {:?}, want {:?}", bytes, fixture.bytes))
+    }
+}
+
+// One cell of the matrix: how one format's fixtures from one version fare
+// in one direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub format: &'static str,
+    pub version: u32,
+    pub direction: Direction,
+    pub passed: usize,
+    pub failed: usize,
+}
+
+// Group `results` by format, version and direction, in fixture order.
+pub fn matrix(results: &[CompatResult]) -> Vec<Cell> {
+    let mut cells: Vec<Cell> = Vec::new();
+    for result in results {
+        let found = cells.iter_mut().find(|cell| {
+            cell.format == result.format
+                && cell.version == result.version
+                && cell.direction == result.direction
+        });
+        let cell = match found {
+            Some(cell) => cell,
+            None => {
+                cells.push(Cell {
+                    format: result.format,
+                    version: result.version,
+                    direction: result.direction,
+                    passed: 0,
+                    failed: 0,
+                });
+                cells.last_mut().expect("just pushed")
+            }
+        };
+        if result.passed() {
+            cell.passed += 1;
+        } else {
+            cell.failed += 1;
+        }
+    }
+    cells
+}
+
+// The matrix as a table, one row per format and version:
+//
+//     format   written by   old read by current    current read by old
+//     frame    v1           ok (3)                 ok (3)
+pub fn render_matrix(cells: &[Cell]) -> String {
+    let describe = |cell: Option<&Cell>| match cell {
+        None => "-".to_string(),
+        Some(cell) if cell.failed == 0 => format!("ok ({})", cell.passed),
+        Some(cell) => format!("FAIL ({} of {})", cell.failed, cell.passed + cell.failed),
+    };
+    let mut out = format!(
+        "{:<8} {:<12} {:<22} {}\n",
+        "format", "written by", "old read by current", "current read by old"
+    );
+    let mut rows: Vec<(&str, u32)> = Vec::new();
+    for cell in cells {
+        if !rows.contains(&(cell.format, cell.version)) {
+            rows.push((cell.format, cell.version));
+        }
+    }
+    for (format, version) in rows {
+        let find = |direction| {
+            cells
+                .iter()
+                .find(|c| c.format == format && c.version == version && c.direction == direction)
+        };
+        out.push_str(&format!(
+            "{:<8} {:<12} {:<22} {}\n",
+            format,
+            format!("v{}", version),
+            describe(find(Direction::Decode)),
+            describe(find(Direction::Encode))
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &'static str, payload: &[u8], bytes: &[u8]) -> Fixture {
+        Fixture {
+            version: 1,
+            name,
+            subject: Subject::Frame(payload.to_vec()),
+            bytes: bytes.to_vec(),
+            encode: true,
+        }
+    }
+
+    #[test]
+    fn broken_promises_are_reported_per_direction() {
+        let fixtures = [
+            frame("good", b"hi", b"\0\0\0\x02hi"),
+            // Decodes to "hi" with a byte left over; encodes without it.
+            frame("trailing", b"hi", b"\0\0\0\x02hi!"),
+            frame("short", b"hi", b"\0\0\0\x05hi"),
+        ];
+        let results = run(&fixtures);
+
+        let failures: Vec<_> = results
+            .iter()
+            .filter(|r| !r.passed())
+            .map(|r| (r.name, r.direction))
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                ("trailing", Direction::Decode),
+                ("trailing", Direction::Encode),
+                ("short", Direction::Decode),
+                ("short", Direction::Encode),
+            ]
+        );
+        assert_eq!(
+            results[2].failure.as_deref(),
+            Some("1 bytes left over after the frame")
+        );
+    }
+
+    #[test]
+    fn a_missing_codec_fails_the_decode_cell() {
+        let fixture = Fixture {
+            version: 1,
+            name: "gone",
+            subject: Subject::Value("msgpack", Value::Null),
+            bytes: vec![0xc0],
+            encode: true,
+        };
+        let results = run(&[fixture]);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].direction, Direction::Decode);
+        assert_eq!(
+            results[0].failure.as_deref(),
+            Some("no codec named 'msgpack' any more")
+        );
+    }
+
+    #[test]
+    fn decode_only_fixtures_have_no_encode_cell() {
+        let mut fixture = frame("hello", b"hello", b"\0\0\0\x05hello");
+        fixture.encode = false;
+        let cells = matrix(&run(&[fixture]));
+
+        assert_eq!(cells.len(), 1);
+        let rendered = render_matrix(&cells);
+        let row = rendered.lines().nth(1).unwrap();
+        assert_eq!(
+            row.split_whitespace().collect::<Vec<_>>(),
+            ["frame", "v1", "ok", "(1)", "-"]
+        );
+    }
+
+    #[test]
+    fn matrix_counts_per_format_version_and_direction() {
+        let mut v2 = frame("hello", b"hello", b"\0\0\0\x05hello");
+        v2.version = 2;
+        let fixtures = [
+            frame("good", b"hi", b"\0\0\0\x02hi"),
+            frame("short", b"hi", b"\0\0\0\x05hi"),
+            v2,
+        ];
+        let cells = matrix(&run(&fixtures));
+
+        let counts: Vec<_> = cells
+            .iter()
+            .map(|c| (c.version, c.direction, c.passed, c.failed))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (1, Direction::Decode, 1, 1),
+                (1, Direction::Encode, 1, 1),
+                (2, Direction::Decode, 1, 0),
+                (2, Direction::Encode, 1, 0),
+            ]
+        );
+        let rendered = render_matrix(&cells);
+        assert!(rendered.contains("FAIL (1 of 2)"), "{}", rendered);
+        assert_eq!(rendered.lines().count(), 3);
+    }
+}
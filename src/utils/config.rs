@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use super::env_os;
+
+// Typed configuration from environment variables.
+//
+//     let mut env = ConfigLoader::with_dotenv();
+//     let port: u16 = env.optional("PORT", 8080);
+//     let database_url: String = env.required("DATABASE_URL");
+//     let api_key: Option<String> = env.maybe("API_KEY");
+//     env.finish()?;
+//
+// Every lookup records what went wrong instead of stopping at the first
+// problem or quietly using a default, and `finish` reports all of them at
+// once, so one run shows everything that needs fixing. Defaults only apply
+// to variables that aren't set: `PORT=80x` is an error, not port 8080.
+// Until `finish` has returned `Ok`, the values handed out may be
+// placeholders and shouldn't be used.
+//
+// For a whole struct, `config_struct!` writes the lookups: each field is
+// read from the variable named after it in upper case, behind the
+// loader's prefix.
+//
+//     config_struct! {
+//         pub struct ServerConfig {
+//             pub port: u16 = optional(8080),
+//             pub database_url: String = required(),
+//             pub api_key: Option<String> = maybe(),
+//             pub debug: bool = flag(),
+//         }
+//     }
+//
+//     // Reads MYAPP_PORT, MYAPP_DATABASE_URL, MYAPP_API_KEY and MYAPP_DEBUG.
+//     let config: ServerConfig = ConfigLoader::with_prefix("MYAPP_").load()?;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Missing,
+    Invalid { value: String, reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub key: String,
+    pub problem: Problem,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            Problem::Missing => write!(f, "{} is required but not set", self.key),
+            Problem::Invalid { value, reason } => {
+                write!(f, "{}={:?} is invalid: {}", self.key, value, reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl ConfigError {
+    pub fn missing(&self) -> impl Iterator<Item = &str> {
+        self.issues
+            .iter()
+            .filter(|i| i.problem == Problem::Missing)
+            .map(|i| i.key.as_str())
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} configuration problem(s):", self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+type Lookup = Box<dyn Fn(&str) -> Result<Option<String>, String>>;
+
+pub struct ConfigLoader {
+    lookup: Lookup,
+    prefix: String,
+    issues: Vec<ConfigIssue>,
+}
+
+impl ConfigLoader {
+    // Read from the process environment.
+    pub fn from_env() -> Self {
+        Self::new(Box::new(|key| {
+            env_os::var_utf8(key)
+                .map_err(|raw| format!("not valid UTF-8 (read as {:?})", raw.to_string_lossy()))
+        }))
+    }
+
+    // Load `.env` from the current directory or a parent first, if there is
+    // one. Variables already in the environment win over the file. Built
+    // without the `dotenv` feature, this is just `from_env`.
+    pub fn with_dotenv() -> Self {
+        #[cfg(feature = "dotenv")]
+        let _ = dotenvy::dotenv();
+        Self::from_env()
+    }
+
+    // `with_dotenv`, reading every key with `prefix` in front.
+    pub fn with_prefix(prefix: &str) -> Self {
+        Self::with_dotenv().prefix(prefix)
+    }
+
+    // Read from a fixed set of variables instead of the environment.
+    pub fn from_map(vars: HashMap<String, String>) -> Self {
+        Self::new(Box::new(move |key| Ok(vars.get(key).cloned())))
+    }
+
+    fn new(lookup: Lookup) -> Self {
+        Self {
+            lookup,
+            prefix: String::new(),
+            issues: Vec::new(),
+        }
+    }
+
+    // Put `prefix` in front of every key looked up: with "APP_", `port`
+    // reads `APP_PORT`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    // A variable that has to be set. Records `Missing` if it isn't.
+    pub fn required<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: fmt::Display,
+    {
+        match self.get(key) {
+            Some(value) => value,
+            None => {
+                if !self.has_issue(key) {
+                    self.issue(key, Problem::Missing);
+                }
+                T::default()
+            }
+        }
+    }
+
+    // A variable with a default for when it isn't set.
+    pub fn optional<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(key).unwrap_or(default)
+    }
+
+    // A variable that may be left unset, e.g. a secret with no sensible
+    // default.
+    pub fn maybe<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(key)
+    }
+
+    // An on/off switch: true/false, 1/0, yes/no or on/off, any case.
+    // Unset means off.
+    pub fn flag(&mut self, key: &str) -> bool {
+        let Some(raw) = self.raw(key) else {
+            return false;
+        };
+        match raw.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => true,
+            "false" | "0" | "no" | "off" | "" => false,
+            _ => {
+                self.issue(
+                    key,
+                    Problem::Invalid {
+                        value: raw,
+                        reason: "expected true/false, 1/0, yes/no or on/off".to_string(),
+                    },
+                );
+                false
+            }
+        }
+    }
+
+    // Read a whole `T` (see `config_struct!`), reporting every problem with
+    // it at once.
+    pub fn load<T: FromConfig>(mut self) -> Result<T, ConfigError> {
+        let value = T::from_config(&mut self);
+        self.finish()?;
+        Ok(value)
+    }
+
+    // Everything recorded so far, or `Ok` if nothing went wrong.
+    pub fn finish(self) -> Result<(), ConfigError> {
+        if self.issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError {
+                issues: self.issues,
+            })
+        }
+    }
+
+    // `None` if unset or unparseable; the latter is recorded.
+    fn get<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let raw = self.raw(key)?;
+        match raw.trim().parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.issue(
+                    key,
+                    Problem::Invalid {
+                        value: raw,
+                        reason: e.to_string(),
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    fn raw(&mut self, key: &str) -> Option<String> {
+        let full = self.full_key(key);
+        match (self.lookup)(&full) {
+            Ok(value) => value,
+            Err(reason) => {
+                self.issue(
+                    key,
+                    Problem::Invalid {
+                        value: String::new(),
+                        reason,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn has_issue(&self, key: &str) -> bool {
+        let full = self.full_key(key);
+        self.issues.iter().any(|i| i.key == full)
+    }
+
+    fn issue(&mut self, key: &str, problem: Problem) {
+        let key = self.full_key(key);
+        self.issues.push(ConfigIssue { key, problem });
+    }
+}
+
+// A struct that can be read from a `ConfigLoader`. `config_struct!`
+// implements it; it can also be written by hand for keys that don't follow
+// the field names.
+pub trait FromConfig: Sized {
+    fn from_config(env: &mut ConfigLoader) -> Self;
+}
+
+// Declare a struct and implement `FromConfig` for it. Each field is
+// `name: Type = kind(args)`, where `kind` is one of `ConfigLoader`'s
+// `required`, `optional`, `maybe` or `flag`, and `args` is whatever that
+// takes after the key (just the default, for `optional`). The key is the
+// field name in upper case.
+#[macro_export]
+macro_rules! config_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $ty:ty = $kind:ident ( $($arg:expr),* $(,)? )
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $crate::utils::config::FromConfig for $name {
+            fn from_config(env: &mut $crate::utils::config::ConfigLoader) -> Self {
+                Self {
+                    $(
+                        $field: env.$kind(
+                            &stringify!($field).to_ascii_uppercase()
+                            $(, $arg)*
+                        ),
+                    )*
+                }
+            }
+        }
+    };
+}
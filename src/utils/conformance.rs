@@ -0,0 +1,311 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::protocol::{MAX_FRAME_LEN, encode_frame, read_frame, write_frame};
+use super::scope::scope;
+
+// Conformance checks for the echo protocol spoken by `src/bin/server.rs`,
+// run against any server over TCP so other implementations can check
+// they behave the same.
+//
+// Messages travel as length-prefixed frames (see `protocol`). Every frame
+// a client sends comes back as the same frame on the same connection, in
+// order, and the server closes its side once the client has closed its
+// write half and everything is echoed. A frame over MAX_FRAME_LEN, or a
+// connection that ends mid-frame, gets the connection dropped without
+// harming anyone else's. There's no version byte yet; checks for one
+// belong here once the protocol has it.
+
+// How long any single read or write may take before a check fails.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Check {
+    pub name: &'static str,
+    pub description: &'static str,
+    run: fn(&str) -> Result<(), String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    // `None` if the check passed, else what went wrong.
+    pub failure: Option<String>,
+    pub elapsed: Duration,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+pub fn checks() -> Vec<Check> {
+    vec![
+        Check {
+            name: "roundtrip",
+            description: "a short message comes back unchanged",
+            run: roundtrip,
+        },
+        Check {
+            name: "empty-frame",
+            description: "a zero-length frame is echoed as a zero-length frame",
+            run: empty_frame,
+        },
+        Check {
+            name: "binary",
+            description: "all 256 byte values are echoed verbatim",
+            run: binary,
+        },
+        Check {
+            name: "large-payload",
+            description: "a 1 MiB frame sent while reading comes back complete and in order",
+            run: large_payload,
+        },
+        Check {
+            name: "pipelined",
+            description: "several frames in one write come back as the same frames, in order",
+            run: pipelined,
+        },
+        Check {
+            name: "slow-writer",
+            description: "a frame dribbled in one byte at a time, header included, is still echoed",
+            run: slow_writer,
+        },
+        Check {
+            name: "half-close",
+            description: "closing the write half gets the rest of the echo, then EOF",
+            run: half_close,
+        },
+        Check {
+            name: "oversized-frame",
+            description: "a header over the frame limit gets the connection closed, not echoed",
+            run: oversized_frame,
+        },
+        Check {
+            name: "abrupt-disconnect",
+            description: "a client vanishing mid-frame doesn't stop the server",
+            run: abrupt_disconnect,
+        },
+        Check {
+            name: "concurrent-clients",
+            description: "8 clients at once each get only their own frames back",
+            run: concurrent_clients,
+        },
+    ]
+}
+
+// Run `checks` against the server at `addr`, in order. Every check gets
+// its own connections, so one failing doesn't poison the next.
+pub fn run(addr: &str, checks: &[Check]) -> Vec<CheckResult> {
+    checks
+        .iter()
+        .map(|check| {
+            let started = Instant::now();
+            let failure = (check.run)(addr).err();
+            CheckResult {
+                name: check.name,
+                failure,
+                elapsed: started.elapsed(),
+            }
+        })
+        .collect()
+}
+
+fn connect(addr: &str) -> Result<TcpStream, String> {
+    let stream = TcpStream::connect(addr).map_err(|e| format!("connect: {}", e))?;
+    stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        .map_err(|e| format!("set timeouts: {}", e))?;
+    Ok(stream)
+}
+
+// Send `payload` as a frame and read one frame back.
+fn echo(stream: &mut TcpStream, payload: &[u8]) -> Result<(), String> {
+    write_frame(stream, payload).map_err(|e| format!("write: {}", e))?;
+    expect_echo(stream, payload)
+}
+
+fn expect_echo(stream: &mut TcpStream, payload: &[u8]) -> Result<(), String> {
+    let back = match read_frame(stream, MAX_FRAME_LEN) {
+        Ok(Some(back)) => back,
+        Ok(None) => return Err("connection closed before the echo arrived".to_string()),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err("connection closed in the middle of the echo".to_string());
+        }
+        Err(e) => return Err(format!("read: {}", e)),
+    };
+    if back.len() != payload.len() {
+        return Err(format!(
+            "echo is {} bytes, sent {}",
+            back.len(),
+            payload.len()
+        ));
+    }
+    match back.iter().zip(payload).position(|(a, b)| a != b) {
+        Some(offset) => Err(format!(
+            "echo differs at byte {}: sent {:#04x}, got {:#04x}",
+            offset, payload[offset], back[offset]
+        )),
+        None => Ok(()),
+    }
+}
+
+// Wait for the server to hang up. A reset counts too: a server closing
+// with our bytes still unread in its buffer sends one instead of a FIN.
+fn expect_close(stream: &mut TcpStream) -> Result<(), String> {
+    let mut rest = Vec::new();
+    match stream.read_to_end(&mut rest) {
+        Ok(_) if rest.is_empty() => Ok(()),
+        Ok(_) => Err(format!("{} unexpected bytes before the close", rest.len())),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            ) =>
+        {
+            Ok(())
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err("the server kept the connection open".to_string())
+        }
+        Err(e) => Err(format!("waiting for the close: {}", e)),
+    }
+}
+
+// The server should still serve a fresh connection after something went
+// wrong on another one.
+fn still_serving(addr: &str, after: &str) -> Result<(), String> {
+    // Give the server a moment to notice.
+    thread::sleep(Duration::from_millis(100));
+    echo(&mut connect(addr)?, b"still there?")
+        .map_err(|e| format!("server unusable after {}: {}", after, e))
+}
+
+fn roundtrip(addr: &str) -> Result<(), String> {
+    echo(&mut connect(addr)?, b"conformance: hello\n")
+}
+
+fn empty_frame(addr: &str) -> Result<(), String> {
+    let mut stream = connect(addr)?;
+    echo(&mut stream, b"")?;
+    // And the connection is still in sync afterwards.
+    echo(&mut stream, b"after nothing")
+}
+
+fn binary(addr: &str) -> Result<(), String> {
+    let payload: Vec<u8> = (0..=255).collect();
+    echo(&mut connect(addr)?, &payload)
+}
+
+fn large_payload(addr: &str) -> Result<(), String> {
+    const SIZE: usize = 1 << 20;
+    // Not a repeating pattern with a power-of-two period, so a dropped or
+    // repeated chunk shows up as a mismatch.
+    let payload: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+
+    let mut stream = connect(addr)?;
+    // Write from another thread: a server that streams its reply stops
+    // reading once the reply backs up, so writing everything before
+    // reading could deadlock on a frame bigger than the socket buffers.
+    let mut writer = stream
+        .try_clone()
+        .map_err(|e| format!("clone socket: {}", e))?;
+    scope(|s| {
+        s.spawn(|_| write_frame(&mut writer, &payload).map_err(|e| format!("write: {}", e)));
+        expect_echo(&mut stream, &payload)
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn pipelined(addr: &str) -> Result<(), String> {
+    let messages: Vec<Vec<u8>> = (0..16u8).map(|i| vec![i; i as usize * 7]).collect();
+    let batch: Vec<u8> = messages.iter().flat_map(|m| encode_frame(m)).collect();
+    let mut stream = connect(addr)?;
+    stream
+        .write_all(&batch)
+        .map_err(|e| format!("write: {}", e))?;
+    for (i, message) in messages.iter().enumerate() {
+        expect_echo(&mut stream, message).map_err(|e| format!("frame {}: {}", i, e))?;
+    }
+    Ok(())
+}
+
+fn slow_writer(addr: &str) -> Result<(), String> {
+    let payload = b"slow and steady";
+    let mut stream = connect(addr)?;
+    for byte in encode_frame(payload) {
+        stream
+            .write_all(&[byte])
+            .map_err(|e| format!("write: {}", e))?;
+        thread::sleep(Duration::from_millis(50));
+    }
+    expect_echo(&mut stream, payload)
+}
+
+fn half_close(addr: &str) -> Result<(), String> {
+    let payload = b"last words";
+    let mut stream = connect(addr)?;
+    write_frame(&mut stream, payload).map_err(|e| format!("write: {}", e))?;
+    stream
+        .shutdown(Shutdown::Write)
+        .map_err(|e| format!("shutdown: {}", e))?;
+    expect_echo(&mut stream, payload)?;
+    expect_close(&mut stream)
+}
+
+fn oversized_frame(addr: &str) -> Result<(), String> {
+    {
+        let mut stream = connect(addr)?;
+        let header = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        stream
+            .write_all(&header)
+            .map_err(|e| format!("write: {}", e))?;
+        // The server should give up on the header alone, not wait for
+        // (or allocate) the payload it announces.
+        expect_close(&mut stream)?;
+    }
+    still_serving(addr, "an oversized frame")
+}
+
+fn abrupt_disconnect(addr: &str) -> Result<(), String> {
+    {
+        let mut stream = connect(addr)?;
+        // A header promising 100 bytes, then only some of them.
+        let frame = encode_frame(&[b'x'; 100]);
+        stream
+            .write_all(&frame[..20])
+            .map_err(|e| format!("write: {}", e))?;
+        // Dropped without finishing the frame or shutting down cleanly.
+    }
+    still_serving(addr, "a client vanished")
+}
+
+fn concurrent_clients(addr: &str) -> Result<(), String> {
+    scope(|s| {
+        for id in 0..8u8 {
+            s.spawn(move |token| {
+                let mut stream = connect(addr).map_err(|e| format!("client {}: {}", id, e))?;
+                // Several rounds each, so the conversations interleave. Once
+                // one client has failed, the others stop early.
+                for round in 0..4u8 {
+                    if token.is_cancelled() {
+                        break;
+                    }
+                    let payload = vec![id.wrapping_mul(31).wrapping_add(round); 4096];
+                    echo(&mut stream, &payload).map_err(|e| format!("client {}: {}", id, e))?;
+                }
+                Ok(())
+            });
+        }
+        Ok::<_, String>(())
+    })
+    .map_err(|e| e.to_string())
+}
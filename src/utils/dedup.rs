@@ -0,0 +1,287 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use super::bloom::BloomFilter;
+use super::file_handling::walk_files;
+use super::sha256::{Digest, Sha256};
+
+// A group of files that all have the same size and the same SHA-256.
+#[derive(Debug)]
+pub struct DuplicateSet {
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateSet {
+    // Keeping one copy, every other file in the set is wasted space.
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+#[derive(Debug)]
+pub struct DedupReport {
+    pub files_scanned: usize,
+    pub sets: Vec<DuplicateSet>,
+    // Files that disappeared or couldn't be read while scanning.
+    pub skipped: Vec<PathBuf>,
+}
+
+impl DedupReport {
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.sets.iter().map(DuplicateSet::reclaimable_bytes).sum()
+    }
+}
+
+// Hash a file by streaming it through SHA-256 in fixed-size chunks, so a
+// multi-GB file never has to be loaded into memory at once. Equal digests
+// are only a strong hint: anything that deletes on the strength of one
+// should check `same_contents` first.
+pub fn hash_file(path: &Path) -> io::Result<Digest> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+// Whether the two files hold exactly the same bytes, compared chunk by
+// chunk. Either file may have changed since it was hashed.
+pub fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = fs::File::open(a)?;
+    let mut b = fs::File::open(b)?;
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut buf_a = [0u8; 64 * 1024];
+    let mut buf_b = [0u8; 64 * 1024];
+    loop {
+        let n = read_full(&mut a, &mut buf_a)?;
+        if n != read_full(&mut b, &mut buf_b)? || buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+// Fill `buf` unless the file ends first, so two files are compared over
+// the same ranges however their reads come back.
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Find duplicate files under `dir`.
+// Step 1 sizes every file (one metadata call each). Only sizes shared by
+// two or more files can contain duplicates, so only those get hashed in
+// step 2, spread across `workers` threads.
+pub fn scan(dir: &Path, workers: usize) -> io::Result<DedupReport> {
+    let files = walk_files(dir)?;
+    let files_scanned = files.len();
+    let mut skipped = Vec::new();
+
+    let mut sized = Vec::with_capacity(files.len());
+    for path in files {
+        match fs::metadata(&path) {
+            Ok(meta) => sized.push((meta.len(), path)),
+            Err(_) => skipped.push(path),
+        }
+    }
+
+    let candidates = repeated_sizes(sized);
+
+    let hashed = hash_in_parallel(&candidates, workers.max(1));
+
+    let mut by_hash: HashMap<(u64, Digest), Vec<PathBuf>> = HashMap::new();
+    for ((size, path), hash) in candidates.into_iter().zip(hashed) {
+        match hash {
+            Ok(hash) => by_hash.entry((size, hash)).or_default().push(path),
+            Err(_) => skipped.push(path),
+        }
+    }
+
+    let mut sets: Vec<DuplicateSet> = by_hash
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((size, _), mut paths)| {
+            paths.sort();
+            DuplicateSet { size, paths }
+        })
+        .collect();
+
+    // Biggest wins first, then by path so the output is stable between runs.
+    sets.sort_by(|a, b| {
+        b.reclaimable_bytes()
+            .cmp(&a.reclaimable_bytes())
+            .then_with(|| a.paths.cmp(&b.paths))
+    });
+
+    Ok(DedupReport {
+        files_scanned,
+        sets,
+        skipped,
+    })
+}
+
+// Keep only the files whose size some other file also has. Most sizes in a
+// big tree are unique, so rather than a map entry for every size, a Bloom
+// filter notes the sizes seen so far and only the repeats go in a set.
+// A false positive just lets one unique file through to be hashed for
+// nothing; there are no false negatives, so no duplicate is missed.
+fn repeated_sizes(sized: Vec<(u64, PathBuf)>) -> Vec<(u64, PathBuf)> {
+    let mut seen = BloomFilter::new(sized.len(), 0.01);
+    let repeated: HashSet<u64> = sized
+        .iter()
+        .map(|(size, _)| *size)
+        .filter(|size| seen.check_and_insert(&size.to_le_bytes()))
+        .collect();
+    sized
+        .into_iter()
+        .filter(|(size, _)| repeated.contains(size))
+        .collect()
+}
+
+// Hash each candidate on a small pool of scoped threads.
+// The result vector lines up index-for-index with `candidates`.
+fn hash_in_parallel(candidates: &[(u64, PathBuf)], workers: usize) -> Vec<io::Result<Digest>> {
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+    let chunk_size = candidates.len().div_ceil(workers);
+
+    thread::scope(|s| {
+        let handles: Vec<_> = candidates
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || chunk.iter().map(|(_, p)| hash_file(p)).collect::<Vec<_>>())
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("hash worker panicked"))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory under the system temp dir, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("dedup-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn same_contents_compares_every_byte() -> io::Result<()> {
+        let dir = TempDir::new("same");
+        let big = vec![7u8; 200 * 1024];
+        let mut other = big.clone();
+        *other.last_mut().unwrap() = 8;
+        fs::write(dir.0.join("a"), &big)?;
+        fs::write(dir.0.join("b"), &big)?;
+        fs::write(dir.0.join("c"), &other)?;
+        fs::write(dir.0.join("d"), &big[1..])?;
+
+        assert!(same_contents(&dir.0.join("a"), &dir.0.join("b"))?);
+        assert!(!same_contents(&dir.0.join("a"), &dir.0.join("c"))?);
+        assert!(!same_contents(&dir.0.join("a"), &dir.0.join("d"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_groups_identical_files_only() -> io::Result<()> {
+        let dir = TempDir::new("scan");
+        fs::write(dir.0.join("one"), b"hello")?;
+        fs::write(dir.0.join("two"), b"hello")?;
+        fs::write(dir.0.join("same-size"), b"jello")?;
+        fs::write(dir.0.join("unique"), b"something else")?;
+
+        let report = scan(&dir.0, 2)?;
+
+        assert_eq!(report.files_scanned, 4);
+        assert_eq!(report.sets.len(), 1);
+        assert_eq!(
+            report.sets[0].paths,
+            vec![dir.0.join("one"), dir.0.join("two")]
+        );
+        assert_eq!(report.reclaimable_bytes(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_sizes_keeps_every_repeat_and_few_unique_sizes() {
+        // 10,000 files of distinct sizes, and 100 sizes held by two files.
+        let unique = (0..10_000u64).map(|size| (size, PathBuf::from(format!("u{}", size))));
+        let pairs = (0..100u64).flat_map(|i| {
+            let size = 1_000_000 + i;
+            [
+                (size, PathBuf::from(format!("a{}", i))),
+                (size, PathBuf::from(format!("b{}", i))),
+            ]
+        });
+
+        let kept = repeated_sizes(unique.chain(pairs).collect());
+
+        let (repeats, let_through): (Vec<_>, Vec<_>) =
+            kept.iter().partition(|(size, _)| *size >= 1_000_000);
+        assert_eq!(repeats.len(), 200);
+        // About 1% of the unique sizes are let through by the filter.
+        assert!(
+            let_through.len() < 300,
+            "{} unique sizes kept",
+            let_through.len()
+        );
+    }
+
+    #[test]
+    fn scan_finds_duplicates_among_many_unique_sizes() -> io::Result<()> {
+        let dir = TempDir::new("many");
+        for len in 0..200 {
+            fs::write(dir.0.join(format!("unique-{}", len)), vec![b'u'; len])?;
+        }
+        fs::write(dir.0.join("copy-1"), vec![b'c'; 500])?;
+        fs::write(dir.0.join("copy-2"), vec![b'c'; 500])?;
+
+        let report = scan(&dir.0, 4)?;
+
+        assert_eq!(report.files_scanned, 202);
+        assert_eq!(report.sets.len(), 1);
+        assert_eq!(
+            report.sets[0].paths,
+            vec![dir.0.join("copy-1"), dir.0.join("copy-2")]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::sha256::{Digest, Sha256, sha256};
+
+// rsync-style delta encoding: describe a new version of a file in terms of
+// blocks of an old version (the "basis") that the other side already has.
+//
+//   1. The side holding the basis computes a `Signature`: a weak rolling
+//      checksum and a SHA-256 for each fixed-size block.
+//   2. The side holding the new data slides a window over it one byte at a
+//      time. Wherever the window's rolling checksum (and then its SHA-256)
+//      matches a basis block, it emits "copy block n"; bytes in between go
+//      out as literals.
+//   3. `apply` rebuilds the new data from the basis and the delta, and
+//      checks the result against the new data's overall SHA-256.
+//
+// Only the literals are real data; a copy costs a few bytes however big the
+// block is, so a small edit to a large file produces a small delta.
+
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+// How much unmatched data to hold before emitting it as a literal, so memory
+// stays bounded when the new data has little in common with the basis.
+const MAX_LITERAL: usize = 64 * 1024;
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockSig {
+    weak: u32,
+    strong: Digest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    block_size: usize,
+    // Every block is `block_size` long except possibly the last.
+    blocks: Vec<BlockSig>,
+    last_len: usize,
+}
+
+impl Signature {
+    pub fn compute<R: Read>(mut basis: R, block_size: usize) -> io::Result<Self> {
+        assert!(block_size > 0, "block size must be positive");
+        let mut blocks = Vec::new();
+        let mut last_len = block_size;
+        let mut block = vec![0u8; block_size];
+        loop {
+            let n = read_full(&mut basis, &mut block)?;
+            if n == 0 {
+                break;
+            }
+            blocks.push(BlockSig {
+                weak: Rolling::new(&block[..n]).digest(),
+                strong: sha256(&block[..n]),
+            });
+            last_len = n;
+            if n < block_size {
+                break;
+            }
+        }
+        Ok(Self {
+            block_size,
+            blocks,
+            last_len,
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaOp {
+    // Copy basis block `index`.
+    Copy { index: usize },
+    Literal(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    block_size: usize,
+    pub ops: Vec<DeltaOp>,
+    // SHA-256 of the whole new data, checked by `apply`.
+    pub checksum: Digest,
+}
+
+impl Delta {
+    // Bytes that have to be sent as-is.
+    pub fn literal_bytes(&self) -> u64 {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                DeltaOp::Literal(bytes) => bytes.len() as u64,
+                DeltaOp::Copy { .. } => 0,
+            })
+            .sum()
+    }
+
+    pub fn copied_blocks(&self) -> usize {
+        self.ops
+            .iter()
+            .filter(|op| matches!(op, DeltaOp::Copy { .. }))
+            .count()
+    }
+
+    fn push_literal(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self.ops.last_mut() {
+            Some(DeltaOp::Literal(prev)) => prev.extend_from_slice(bytes),
+            _ => self.ops.push(DeltaOp::Literal(bytes.to_vec())),
+        }
+    }
+}
+
+// Work out how to turn the basis described by `signature` into `new`.
+pub fn delta<R: Read>(signature: &Signature, mut new: R) -> io::Result<Delta> {
+    let block_size = signature.block_size;
+    let mut delta = Delta {
+        block_size,
+        ops: Vec::new(),
+        checksum: [0; 32],
+    };
+    let mut whole = Sha256::new();
+
+    // Only full-size blocks can match mid-stream; a short last block can
+    // only match the very end of `new`.
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        if index + 1 < signature.blocks.len() || signature.last_len == block_size {
+            by_weak.entry(block.weak).or_default().push(index);
+        }
+    }
+    let find = |weak: u32, window: &[u8]| -> Option<usize> {
+        let candidates = by_weak.get(&weak)?;
+        let strong = sha256(window);
+        candidates
+            .iter()
+            .copied()
+            .find(|&i| signature.blocks[i].strong == strong)
+    };
+
+    // `buf[..start]` is pending literal data, `buf[start..start + block_size]`
+    // the current window.
+    let mut buf = Vec::new();
+    let mut start = 0;
+    let mut rolling: Option<Rolling> = None;
+    let mut eof = false;
+
+    loop {
+        if !eof && buf.len() < start + block_size + 1 {
+            eof = fill(&mut new, &mut buf, start + block_size + 1, &mut whole)?;
+        }
+        if buf.len() < start + block_size {
+            break;
+        }
+
+        let window = &buf[start..start + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        if let Some(index) = find(weak, window) {
+            delta.push_literal(&buf[..start]);
+            delta.ops.push(DeltaOp::Copy { index });
+            buf.drain(..start + block_size);
+            start = 0;
+            rolling = None;
+            continue;
+        }
+
+        if buf.len() == start + block_size {
+            // Nothing left to roll in; the rest is literal.
+            break;
+        }
+        let (out, incoming) = (buf[start], buf[start + block_size]);
+        rolling.as_mut().unwrap().roll(out, incoming);
+        start += 1;
+        if start >= MAX_LITERAL {
+            delta.push_literal(&buf[..start]);
+            buf.drain(..start);
+            start = 0;
+        }
+    }
+
+    // See whether what's left is the basis's short last block.
+    let tail = &buf[start..];
+    let last = signature.blocks.len().checked_sub(1);
+    match last {
+        Some(index)
+            if !tail.is_empty()
+                && signature.last_len < block_size
+                && tail.len() == signature.last_len
+                && signature.blocks[index].strong == sha256(tail) =>
+        {
+            delta.push_literal(&buf[..start]);
+            delta.ops.push(DeltaOp::Copy { index });
+        }
+        _ => delta.push_literal(&buf),
+    }
+
+    delta.checksum = whole.finish();
+    Ok(delta)
+}
+
+// Rebuild the new data into `out` from `basis` and `delta`, returning the
+// number of bytes written. Fails with `InvalidData` if the result doesn't
+// match the checksum, e.g. because the basis changed after the signature
+// was taken.
+pub fn apply<B, W>(mut basis: B, delta: &Delta, mut out: W) -> io::Result<u64>
+where
+    B: Read + Seek,
+    W: Write,
+{
+    let mut hasher = Sha256::new();
+    let mut written = 0u64;
+    let mut block = vec![0u8; delta.block_size];
+    for op in &delta.ops {
+        let bytes = match op {
+            DeltaOp::Literal(bytes) => bytes.as_slice(),
+            DeltaOp::Copy { index } => {
+                basis.seek(SeekFrom::Start((*index * delta.block_size) as u64))?;
+                let n = read_full(&mut basis, &mut block)?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("basis has no block {}", index),
+                    ));
+                }
+                &block[..n]
+            }
+        };
+        hasher.update(bytes);
+        out.write_all(bytes)?;
+        written += bytes.len() as u64;
+    }
+    if hasher.finish() != delta.checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "delta result doesn't match the expected checksum",
+        ));
+    }
+    Ok(written)
+}
+
+// The rsync weak checksum: `a` is the sum of the bytes and `b` the sum of
+// the running `a`s, both mod 2^16. Sliding the window one byte along is O(1).
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, incoming: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(out as u32)
+            .wrapping_add(incoming as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+// Read until `buf` is full or the reader runs dry; returns the bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Append to `buf` until it holds at least `want` bytes, hashing what comes
+// in. Returns true at end of input.
+fn fill<R: Read>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    want: usize,
+    hasher: &mut Sha256,
+) -> io::Result<bool> {
+    let mut chunk = [0u8; READ_CHUNK];
+    while buf.len() < want {
+        let n = read_full(reader, &mut chunk)?;
+        hasher.update(&chunk[..n]);
+        buf.extend_from_slice(&chunk[..n]);
+        if n < chunk.len() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
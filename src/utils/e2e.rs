@@ -0,0 +1,377 @@
+use std::fmt;
+
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{HKDF_SHA256, Salt};
+use ring::rand::SystemRandom;
+
+use super::sha256::{sha256, to_hex};
+
+// End-to-end encrypted payloads between two clients, for a server that only
+// relays lines it can't read.
+//
+// Each side makes a `Handshake` (a fresh X25519 key pair) and sends its
+// `hello()` line through the server:
+//
+//     E2E-HELLO <public key, hex>
+//
+// `finish` with the other side's hello agrees on a shared secret and
+// derives one ChaCha20-Poly1305 key per direction from it (HKDF-SHA256
+// over both public keys). After that each message is one line:
+//
+//     E2E-MSG <counter> <ciphertext and tag, hex>
+//
+// The counter is the nonce, and has to go up: a message replayed or sent
+// back by the relay doesn't open. Gaps are fine, since a relay may drop
+// messages.
+//
+// The relay could swap the hellos for its own keys and read everything,
+// so both people should compare `Session::safety_number` (or each other's
+// `fingerprint`) over some other channel before trusting the session.
+
+pub const HELLO: &str = "E2E-HELLO";
+pub const MESSAGE: &str = "E2E-MSG";
+
+const KEY_LEN: usize = 32;
+// Labels the derived keys, so they can't be mistaken for keys derived the
+// same way for something else.
+const INFO: &str = "rust-practice e2e v1";
+
+pub struct Handshake {
+    private: EphemeralPrivateKey,
+    public: [u8; KEY_LEN],
+}
+
+impl Handshake {
+    pub fn new() -> Result<Self, String> {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| "couldn't generate a key".to_string())?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| "couldn't compute the public key".to_string())?;
+        Ok(Self {
+            public: public
+                .as_ref()
+                .try_into()
+                .map_err(|_| "X25519 public key isn't 32 bytes".to_string())?,
+            private,
+        })
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public
+    }
+
+    // The line to send the other side.
+    pub fn hello(&self) -> String {
+        format!("{} {}", HELLO, to_hex(&self.public))
+    }
+
+    // Agree on keys with the side whose hello line is `peer_hello`. The
+    // private key is used up, so each handshake makes one session.
+    pub fn finish(self, peer_hello: &str) -> Result<Session, String> {
+        let peer = parse_hello(peer_hello)?;
+        if peer == self.public {
+            return Err("that's our own hello".to_string());
+        }
+        let (first, second) = if self.public < peer {
+            (self.public, peer)
+        } else {
+            (peer, self.public)
+        };
+        let salt = [first, second].concat();
+        let keys = agreement::agree_ephemeral(
+            self.private,
+            &UnparsedPublicKey::new(&agreement::X25519, peer),
+            |shared| {
+                let prk = Salt::new(HKDF_SHA256, &salt).extract(shared);
+                let key = |direction: &str| -> Result<LessSafeKey, String> {
+                    let info = [INFO.as_bytes(), direction.as_bytes()];
+                    let okm = prk
+                        .expand(&info, &CHACHA20_POLY1305)
+                        .map_err(|_| "key derivation failed".to_string())?;
+                    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+                };
+                Ok::<_, String>((key(" first->second")?, key(" second->first")?))
+            },
+        )
+        .map_err(|_| "key agreement failed: not a valid public key".to_string())??;
+        let (send, receive) = if self.public == first {
+            keys
+        } else {
+            (keys.1, keys.0)
+        };
+        Ok(Session {
+            send,
+            receive,
+            sent: 0,
+            received: None,
+            local: self.public,
+            peer,
+        })
+    }
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handshake")
+            .field("public", &to_hex(&self.public))
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct Session {
+    send: LessSafeKey,
+    receive: LessSafeKey,
+    // Counter for the next message sent.
+    sent: u64,
+    // Counter of the last message opened.
+    received: Option<u64>,
+    local: [u8; KEY_LEN],
+    peer: [u8; KEY_LEN],
+}
+
+impl Session {
+    // Encrypt `plaintext` into a message line for the other side.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<String, String> {
+        let counter = self.sent;
+        self.sent = counter
+            .checked_add(1)
+            .ok_or_else(|| "out of nonces; start a new session".to_string())?;
+        let mut data = plaintext.to_vec();
+        self.send
+            .seal_in_place_append_tag(nonce(counter), Aad::empty(), &mut data)
+            .map_err(|_| "encryption failed".to_string())?;
+        Ok(format!("{} {} {}", MESSAGE, counter, to_hex(&data)))
+    }
+
+    // Decrypt a message line from the other side. Anything tampered with,
+    // replayed, or not from the other side is an error.
+    pub fn open(&mut self, line: &str) -> Result<Vec<u8>, String> {
+        let malformed = || format!("not an {} line", MESSAGE);
+        let mut parts = line.split(' ');
+        if parts.next() != Some(MESSAGE) {
+            return Err(malformed());
+        }
+        let counter: u64 = parts
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(malformed)?;
+        let mut data = parts.next().and_then(decode_hex).ok_or_else(malformed)?;
+        if parts.next().is_some() {
+            return Err(malformed());
+        }
+        if self.received.is_some_and(|last| counter <= last) {
+            return Err(format!("message {} was replayed or reordered", counter));
+        }
+        let plaintext = self
+            .receive
+            .open_in_place(nonce(counter), Aad::empty(), &mut data)
+            .map_err(|_| "message doesn't decrypt: tampered with, or not for this session")?;
+        let plaintext = plaintext.to_vec();
+        self.received = Some(counter);
+        Ok(plaintext)
+    }
+
+    pub fn peer_key(&self) -> [u8; KEY_LEN] {
+        self.peer
+    }
+
+    // The same on both sides of a session, and different for any other
+    // pair of keys: compare it out of band to rule out a relay in the
+    // middle.
+    pub fn safety_number(&self) -> String {
+        let (first, second) = if self.local < self.peer {
+            (self.local, self.peer)
+        } else {
+            (self.peer, self.local)
+        };
+        group(&sha256(&[first, second].concat())[..20])
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("local", &to_hex(&self.local))
+            .field("peer", &to_hex(&self.peer))
+            .field("sent", &self.sent)
+            .field("received", &self.received)
+            .finish_non_exhaustive()
+    }
+}
+
+// A public key's fingerprint, for reading aloud: the first 20 bytes of its
+// SHA-256 in groups of four hex digits.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    group(&sha256(public_key)[..20])
+}
+
+// Whether `claimed` is `public_key`'s fingerprint, ignoring spacing and
+// case, the way people copy them.
+pub fn fingerprint_matches(public_key: &[u8], claimed: &str) -> bool {
+    let normalize = |s: &str| {
+        s.chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    normalize(&fingerprint(public_key)) == normalize(claimed)
+}
+
+// A public key from a hello line, or from its bare hex.
+pub fn parse_hello(line: &str) -> Result<[u8; KEY_LEN], String> {
+    let hex = line.strip_prefix(HELLO).unwrap_or(line).trim();
+    decode_hex(hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("not an {} line or a 64-digit public key", HELLO))
+}
+
+// Counters fill the last 8 bytes of the 12-byte nonce. Each direction has
+// its own key, so the two sides' counters never share a nonce.
+fn nonce(counter: u64) -> Nonce {
+    let mut bytes = [0; 12];
+    bytes[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(bytes)
+}
+
+fn group(bytes: &[u8]) -> String {
+    to_hex(bytes)
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_pair() -> (Session, Session) {
+        let alice = Handshake::new().unwrap();
+        let bob = Handshake::new().unwrap();
+        let (alice_hello, bob_hello) = (alice.hello(), bob.hello());
+        (
+            alice.finish(&bob_hello).unwrap(),
+            bob.finish(&alice_hello).unwrap(),
+        )
+    }
+
+    #[test]
+    fn a_fingerprint_is_ten_groups_of_the_keys_sha256() {
+        assert_eq!(
+            fingerprint(&[0; KEY_LEN]),
+            "6668 7aad f862 bd77 6c8f c18b 8e9f 8e20 0897 1485"
+        );
+    }
+
+    #[test]
+    fn a_fingerprint_matches_however_it_was_copied() {
+        let key = Handshake::new().unwrap().public_key();
+        let printed = fingerprint(&key);
+
+        assert!(fingerprint_matches(&key, &printed));
+        assert!(fingerprint_matches(&key, &printed.to_uppercase()));
+        assert!(fingerprint_matches(&key, &printed.replace(' ', "")));
+        assert!(fingerprint_matches(
+            &key,
+            &format!("  {}\n", printed.replace(' ', "\t"))
+        ));
+    }
+
+    #[test]
+    fn a_wrong_or_shortened_fingerprint_doesnt_match() {
+        let key = Handshake::new().unwrap().public_key();
+        let other = Handshake::new().unwrap().public_key();
+        let printed = fingerprint(&key);
+
+        assert!(!fingerprint_matches(&other, &printed));
+        assert!(!fingerprint_matches(&key, &printed[..printed.len() - 5]));
+        assert!(!fingerprint_matches(&key, &format!("{} 0000", printed)));
+        assert!(!fingerprint_matches(&key, ""));
+    }
+
+    #[test]
+    fn keys_come_from_a_hello_line_or_bare_hex() {
+        let handshake = Handshake::new().unwrap();
+        let key = handshake.public_key();
+
+        assert_eq!(parse_hello(&handshake.hello()), Ok(key));
+        assert_eq!(parse_hello(&to_hex(&key)), Ok(key));
+        for bad in [
+            "",
+            "E2E-HELLO",
+            "E2E-HELLO abcd",
+            &"zz".repeat(KEY_LEN),
+            &"00".repeat(KEY_LEN + 1),
+        ] {
+            assert!(parse_hello(bad).is_err(), "{:?}", bad);
+        }
+        // Our own hello bounced back by the relay isn't a peer.
+        let hello = handshake.hello();
+        assert_eq!(
+            handshake.finish(&hello).unwrap_err(),
+            "that's our own hello"
+        );
+    }
+
+    #[test]
+    fn both_sides_see_the_same_safety_number_and_each_others_key() {
+        let alice = Handshake::new().unwrap();
+        let bob = Handshake::new().unwrap();
+        let (alice_key, bob_key) = (alice.public_key(), bob.public_key());
+        let (alice_hello, bob_hello) = (alice.hello(), bob.hello());
+        let alice = alice.finish(&bob_hello).unwrap();
+        let bob = bob.finish(&alice_hello).unwrap();
+
+        assert_eq!(alice.safety_number(), bob.safety_number());
+        assert_eq!((alice.peer_key(), bob.peer_key()), (bob_key, alice_key));
+        assert!(fingerprint_matches(
+            &alice.peer_key(),
+            &fingerprint(&bob_key)
+        ));
+    }
+
+    #[test]
+    fn messages_open_in_order_with_gaps_but_not_replayed() {
+        let (mut alice, mut bob) = session_pair();
+        let first = alice.seal(b"one").unwrap();
+        let dropped = alice.seal(b"two").unwrap();
+        let third = alice.seal(b"three").unwrap();
+
+        assert_eq!(bob.open(&first).unwrap(), b"one");
+        assert_eq!(bob.open(&third).unwrap(), b"three");
+        assert_eq!(
+            bob.open(&dropped).unwrap_err(),
+            "message 1 was replayed or reordered"
+        );
+        // A message only opens in the direction it was sent.
+        let reflected = alice.open(&third);
+        assert!(reflected.is_err());
+    }
+
+    #[test]
+    fn a_tampered_message_doesnt_open() {
+        let (mut alice, mut bob) = session_pair();
+        let sealed = alice.seal(b"pay 10").unwrap();
+        let (head, last) = sealed.split_at(sealed.len() - 1);
+        let flipped = if last == "0" { "1" } else { "0" };
+
+        assert!(bob.open(&format!("{}{}", head, flipped)).is_err());
+        assert!(bob.open(&sealed.replacen(" 0 ", " 5 ", 1)).is_err());
+        assert_eq!(bob.open("E2E-MSG 0").unwrap_err(), "not an E2E-MSG line");
+        // The failed attempts didn't use up the real message's counter.
+        assert_eq!(bob.open(&sealed).unwrap(), b"pay 10");
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::sync::Arc;
+
+use super::transport::{Handler, Transport};
+use super::vm::Program;
+
+// The echo protocol as `Handler`s, shared by `src/bin/server.rs`,
+// `src/bin/client.rs` and simulations.
+
+// Sends every message straight back to whoever sent it, or with a
+// transform, what the transform makes of it.
+#[derive(Debug, Default)]
+pub struct EchoServer {
+    pub messages: u64,
+    pub bytes: u64,
+    pub transform: Option<Arc<Program>>,
+}
+
+impl EchoServer {
+    pub fn with_transform(transform: Arc<Program>) -> Self {
+        Self {
+            transform: Some(transform),
+            ..Self::default()
+        }
+    }
+}
+
+// The echo of `text` through `transform`. A script that fails gets its
+// error echoed instead, so the client sees why.
+pub fn transform_echo(transform: &Program, text: &str) -> String {
+    transform
+        .transform(text)
+        .unwrap_or_else(|e| format!("ERR transform: {}", e))
+}
+
+impl Handler for EchoServer {
+    fn on_message(
+        &mut self,
+        from: &str,
+        payload: &[u8],
+        transport: &mut dyn Transport,
+    ) -> io::Result<()> {
+        self.messages += 1;
+        self.bytes += payload.len() as u64;
+        match &self.transform {
+            Some(transform) => {
+                let text = String::from_utf8_lossy(payload);
+                transport.send(from, transform_echo(transform, &text).as_bytes())
+            }
+            None => transport.send(from, payload),
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("messages={} bytes={}", self.messages, self.bytes)
+    }
+}
+
+// Sends messages to a server and checks each comes back. Replies may
+// arrive in any order; each one has to match something still outstanding.
+#[derive(Debug)]
+pub struct EchoClient {
+    server: String,
+    outstanding: Vec<Vec<u8>>,
+    pub echoed: usize,
+    // Replies that didn't match any outstanding message.
+    pub unexpected: usize,
+}
+
+impl EchoClient {
+    pub fn new(server: &str) -> Self {
+        Self {
+            server: server.to_string(),
+            outstanding: Vec::new(),
+            echoed: 0,
+            unexpected: 0,
+        }
+    }
+
+    pub fn send(&mut self, message: &[u8], transport: &mut dyn Transport) -> io::Result<()> {
+        transport.send(&self.server, message)?;
+        self.outstanding.push(message.to_vec());
+        Ok(())
+    }
+
+    // Messages sent but not echoed yet.
+    pub fn outstanding(&self) -> &[Vec<u8>] {
+        &self.outstanding
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+}
+
+impl Handler for EchoClient {
+    fn on_message(
+        &mut self,
+        _from: &str,
+        payload: &[u8],
+        _transport: &mut dyn Transport,
+    ) -> io::Result<()> {
+        match self.outstanding.iter().position(|m| m == payload) {
+            Some(index) => {
+                self.outstanding.remove(index);
+                self.echoed += 1;
+            }
+            None => self.unexpected += 1,
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> String {
+        format!(
+            "echoed={} outstanding={} unexpected={}",
+            self.echoed,
+            self.outstanding.len(),
+            self.unexpected
+        )
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+
+// Text conversions for files moving between Windows and Unix: line endings,
+// the UTF-8 byte order mark, and Latin-1 (ISO-8859-1) <-> UTF-8.
+//
+// Strict conversions fail on the first byte or character they can't handle
+// and say where it was; lossy ones substitute (U+FFFD for bad UTF-8, `?`
+// when encoding to Latin-1) and carry on.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        }
+    }
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineEnding::Lf => write!(f, "LF"),
+            LineEnding::CrLf => write!(f, "CRLF"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceEncoding {
+    Utf8,
+    Latin1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    Strict,
+    Lossy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodingError {
+    // Byte offset into the input (or char offset, when encoding a `str`).
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+pub const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+pub fn has_bom(bytes: &[u8]) -> bool {
+    bytes.starts_with(UTF8_BOM)
+}
+
+pub fn strip_bom(bytes: &[u8]) -> &[u8] {
+    bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes)
+}
+
+// Prepend a BOM unless there already is one.
+pub fn add_bom(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + UTF8_BOM.len());
+    if !has_bom(bytes) {
+        out.extend_from_slice(UTF8_BOM);
+    }
+    out.extend_from_slice(bytes);
+    out
+}
+
+// The line ending used by most lines, or `None` if there are no line breaks.
+pub fn detect_line_ending(text: &str) -> Option<LineEnding> {
+    let lf = text.matches('\n').count();
+    let crlf = text.matches("\r\n").count();
+    match (lf, crlf) {
+        (0, _) => None,
+        (lf, crlf) if crlf * 2 >= lf => Some(LineEnding::CrLf),
+        _ => Some(LineEnding::Lf),
+    }
+}
+
+// Rewrite every line break (LF or CRLF, mixed is fine) as `to`. A lone CR
+// isn't treated as a line break and is left alone.
+pub fn normalize_line_endings(text: &str, to: LineEnding) -> String {
+    let mut out = String::with_capacity(text.len() + text.len() / 32);
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        if lines.peek().is_none() {
+            out.push_str(line);
+            break;
+        }
+        out.push_str(line.strip_suffix('\r').unwrap_or(line));
+        out.push_str(to.as_str());
+    }
+    out
+}
+
+// Decode `bytes` into a `String`, dropping a leading BOM.
+//
+// For UTF-8 input, strict mode rejects invalid sequences. Latin-1 maps every
+// byte to a character, so strict mode instead rejects the C1 controls
+// 0x80-0x9F: real Latin-1 text doesn't use them, and seeing them usually
+// means the file is actually Windows-1252. Lossy mode passes them through.
+pub fn decode(bytes: &[u8], from: SourceEncoding, mode: Mode) -> Result<String, EncodingError> {
+    match from {
+        SourceEncoding::Utf8 => {
+            let body = strip_bom(bytes);
+            match (std::str::from_utf8(body), mode) {
+                (Ok(text), _) => Ok(text.to_string()),
+                (Err(_), Mode::Lossy) => Ok(String::from_utf8_lossy(body).into_owned()),
+                (Err(e), Mode::Strict) => Err(EncodingError {
+                    offset: (bytes.len() - body.len()) + e.valid_up_to(),
+                    message: "invalid UTF-8".to_string(),
+                }),
+            }
+        }
+        SourceEncoding::Latin1 => {
+            let mut out = String::with_capacity(bytes.len());
+            for (offset, &b) in bytes.iter().enumerate() {
+                if mode == Mode::Strict && (0x80..0xa0).contains(&b) {
+                    return Err(EncodingError {
+                        offset,
+                        message: format!("byte 0x{:02x} is a C1 control, not Latin-1 text", b),
+                    });
+                }
+                out.push(b as char);
+            }
+            Ok(out)
+        }
+    }
+}
+
+// Encode `text` as Latin-1. Strict mode fails on characters above U+00FF;
+// lossy mode writes `?` for them.
+pub fn encode_latin1(text: &str, mode: Mode) -> Result<Vec<u8>, EncodingError> {
+    let mut out = Vec::with_capacity(text.len());
+    for (offset, c) in text.chars().enumerate() {
+        match u8::try_from(c as u32) {
+            Ok(b) => out.push(b),
+            Err(_) if mode == Mode::Lossy => out.push(b'?'),
+            Err(_) => {
+                return Err(EncodingError {
+                    offset,
+                    message: format!("'{}' (U+{:04X}) has no Latin-1 encoding", c, c as u32),
+                });
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_endings_are_detected_by_majority() {
+        assert_eq!(detect_line_ending("one line"), None);
+        assert_eq!(detect_line_ending("a\nb\n"), Some(LineEnding::Lf));
+        assert_eq!(detect_line_ending("a\r\nb\r\n"), Some(LineEnding::CrLf));
+        // A tie goes to CRLF: one of each.
+        assert_eq!(detect_line_ending("a\r\nb\n"), Some(LineEnding::CrLf));
+        assert_eq!(detect_line_ending("a\r\nb\nc\n"), Some(LineEnding::Lf));
+    }
+
+    #[test]
+    fn normalizing_rewrites_every_break_but_not_a_lone_cr() {
+        let mixed = "a\r\nb\nc\rd\n";
+
+        assert_eq!(
+            normalize_line_endings(mixed, LineEnding::Lf),
+            "a\nb\nc\rd\n"
+        );
+        assert_eq!(
+            normalize_line_endings(mixed, LineEnding::CrLf),
+            "a\r\nb\r\nc\rd\r\n"
+        );
+        // No final break stays no final break.
+        assert_eq!(normalize_line_endings("a\r\nb", LineEnding::Lf), "a\nb");
+        assert_eq!(normalize_line_endings("", LineEnding::CrLf), "");
+    }
+
+    #[test]
+    fn a_bom_is_added_once_and_dropped_on_decode() {
+        let with_bom = add_bom(b"text");
+        assert!(has_bom(&with_bom));
+        assert_eq!(add_bom(&with_bom), with_bom);
+        assert_eq!(strip_bom(&with_bom), b"text");
+        assert_eq!(
+            decode(&with_bom, SourceEncoding::Utf8, Mode::Strict).unwrap(),
+            "text"
+        );
+    }
+
+    #[test]
+    fn strict_utf8_decoding_points_at_the_bad_byte() {
+        let bytes = b"\xef\xbb\xbfok \xff rest";
+
+        let e = decode(bytes, SourceEncoding::Utf8, Mode::Strict).unwrap_err();
+        // The offset is into the input, BOM included.
+        assert_eq!(e.offset, 6);
+        assert_eq!(e.to_string(), "invalid UTF-8 at offset 6");
+        assert_eq!(
+            decode(bytes, SourceEncoding::Utf8, Mode::Lossy).unwrap(),
+            "ok \u{fffd} rest"
+        );
+    }
+
+    #[test]
+    fn latin1_round_trips_and_refuses_what_it_cant_hold() {
+        let bytes = b"Gr\xfc\xdfe \xa9";
+        let text = decode(bytes, SourceEncoding::Latin1, Mode::Strict).unwrap();
+        assert_eq!(text, "Grüße ©");
+        assert_eq!(encode_latin1(&text, Mode::Strict).unwrap(), bytes);
+
+        // C1 controls usually mean Windows-1252 rather than Latin-1.
+        let e = decode(b"a\x80", SourceEncoding::Latin1, Mode::Strict).unwrap_err();
+        assert_eq!(e.offset, 1);
+        assert_eq!(
+            decode(b"a\x80", SourceEncoding::Latin1, Mode::Lossy).unwrap(),
+            "a\u{80}"
+        );
+
+        // Offsets are in characters when encoding.
+        let e = encode_latin1("ü€", Mode::Strict).unwrap_err();
+        assert_eq!(e.offset, 1);
+        assert!(e.message.contains("U+20AC"), "{}", e);
+        assert_eq!(encode_latin1("ü€", Mode::Lossy).unwrap(), b"\xfc?");
+    }
+}
@@ -0,0 +1,196 @@
+use std::env::{self, JoinPathsError};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+// Environment helpers that keep values as `OsString` from end to end.
+//
+// `env::var` fails on anything that isn't valid UTF-8, and
+// `to_string_lossy` quietly mangles it. Neither is acceptable for PATH-like
+// variables or file paths, which on Unix are arbitrary bytes. These helpers
+// never convert, so a path from the environment reaches the filesystem
+// exactly as it was given.
+//
+// `which` / `which_all` search PATH for an executable the same way.
+
+// A variable as a path. Unset and empty both give `None`.
+pub fn var_path<K: AsRef<OsStr>>(key: K) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+// A PATH-like variable split on the platform separator (':' or ';').
+// Empty entries are dropped. An unset variable gives an empty list.
+pub fn path_list<K: AsRef<OsStr>>(key: K) -> Vec<PathBuf> {
+    match env::var_os(key) {
+        Some(value) => split_paths(&value),
+        None => Vec::new(),
+    }
+}
+
+pub fn split_paths(value: &OsStr) -> Vec<PathBuf> {
+    env::split_paths(value)
+        .filter(|p| !p.as_os_str().is_empty())
+        .collect()
+}
+
+// The inverse of `split_paths`. Fails if an entry contains the separator.
+pub fn join_paths<I, P>(paths: I) -> Result<OsString, JoinPathsError>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<OsStr>,
+{
+    env::join_paths(paths)
+}
+
+// The value of a PATH-like variable with `dir` added in front, skipping it
+// if it's already there. Returns the new value for the caller to set
+// (on a child `Command`, say), rather than mutating our own environment.
+pub fn prepend_path<K: AsRef<OsStr>>(key: K, dir: PathBuf) -> Result<OsString, JoinPathsError> {
+    let mut paths = path_list(key);
+    paths.retain(|p| *p != dir);
+    paths.insert(0, dir);
+    join_paths(paths)
+}
+
+// A variable that must be text (a port number, a log level...). Unlike
+// `env::var`, a non-UTF-8 value is handed back as-is in the error so the
+// caller can report exactly what was there.
+pub fn var_utf8<K: AsRef<OsStr>>(key: K) -> Result<Option<String>, OsString> {
+    match env::var_os(key) {
+        None => Ok(None),
+        Some(value) => value.into_string().map(Some),
+    }
+}
+
+// Every executable called `program` on PATH, in search order (the first
+// one is what a shell would run). A `program` containing a path separator
+// is checked as given instead of searched for.
+pub fn which_all(program: &str) -> Vec<PathBuf> {
+    let candidates = if program.contains(std::path::is_separator) {
+        vec![PathBuf::from(program)]
+    } else {
+        path_list("PATH")
+            .into_iter()
+            .map(|dir| dir.join(program))
+            .collect()
+    };
+
+    let mut found = Vec::new();
+    for candidate in candidates {
+        for path in with_exe_extensions(candidate) {
+            if is_executable(&path) && !found.contains(&path) {
+                found.push(path);
+            }
+        }
+    }
+    found
+}
+
+pub fn which(program: &str) -> Option<PathBuf> {
+    which_all(program).into_iter().next()
+}
+
+// On Windows `foo` may really be `foo.exe`, `foo.bat`..., as listed in
+// PATHEXT. Elsewhere the name is used as is.
+#[cfg(windows)]
+fn with_exe_extensions(path: PathBuf) -> Vec<PathBuf> {
+    if path.extension().is_some() {
+        return vec![path];
+    }
+    let pathext = env::var_os("PATHEXT").unwrap_or_else(|| ".COM;.EXE;.BAT;.CMD".into());
+    pathext
+        .to_string_lossy()
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| {
+            let mut name = path.clone().into_os_string();
+            name.push(ext);
+            PathBuf::from(name)
+        })
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn with_exe_extensions(path: PathBuf) -> Vec<PathBuf> {
+    vec![path]
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("env-os-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn path_lists_round_trip_and_drop_empty_entries() {
+        let paths = [PathBuf::from("/usr/bin"), PathBuf::from("/opt/tool bin")];
+        let joined = join_paths(&paths).unwrap();
+        assert_eq!(split_paths(&joined), paths);
+
+        let sep = if cfg!(windows) { ";" } else { ":" };
+        let sparse = OsString::from(format!("{sep}/a{sep}{sep}/b{sep}"));
+        assert_eq!(
+            split_paths(&sparse),
+            [PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+        assert!(join_paths([format!("/a{sep}b")]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_survive_a_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+        let odd = PathBuf::from(OsStr::from_bytes(b"/srv/caf\xe9"));
+        let joined = join_paths([&odd, &PathBuf::from("/bin")]).unwrap();
+        assert_eq!(split_paths(&joined)[0], odd);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn which_only_finds_executable_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("which");
+        let tool = dir.0.join("tool");
+        let notes = dir.0.join("notes");
+        fs::write(&tool, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(&notes, "").unwrap();
+
+        // With a separator in it, the name is checked as given.
+        assert_eq!(which(tool.to_str().unwrap()), Some(tool.clone()));
+        assert_eq!(which(notes.to_str().unwrap()), None);
+        assert_eq!(which(dir.0.to_str().unwrap()), None);
+        assert_eq!(which(dir.0.join("missing").to_str().unwrap()), None);
+        // Searched for on PATH, `sh` is always somewhere.
+        assert!(which_all("sh").iter().all(|p| p.ends_with("sh")));
+        assert!(which("sh").is_some());
+    }
+}
@@ -0,0 +1,849 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// A small expression language for filters and conditions, e.g.
+//
+//   level >= 3 && target.starts_with("server")
+//   size > 10MB || name == "core"
+//
+// Pipeline: `tokenize` turns the text into tokens, `Parser` builds an `Expr`
+// tree with Pratt parsing (each operator has a binding power, which is how
+// `a || b && c` ends up as `a || (b && c)`), and `Expr::eval` walks the tree
+// looking up identifiers in a `Context`.
+//
+// Parsing and evaluating both recurse, so trees are limited to `MAX_DEPTH`
+// levels: deeper input (`((((...` or a few thousand `!`s) is an `ExprError`
+// rather than a stack overflow.
+
+pub const MAX_DEPTH: usize = 128;
+
+// ---------------------------------------------------------------------------
+// Values
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Num(_) => "number",
+            Value::Str(_) => "string",
+        }
+    }
+
+    // Null, false, 0 and "" are falsy; everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Num(n) => *n != 0.0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Num(n)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Num(n as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+// Where identifiers get their values from.
+pub trait Context {
+    fn get(&self, name: &str) -> Option<Value>;
+}
+
+impl Context for HashMap<String, Value> {
+    fn get(&self, name: &str) -> Option<Value> {
+        HashMap::get(self, name).cloned()
+    }
+}
+
+impl Context for HashMap<&str, Value> {
+    fn get(&self, name: &str) -> Option<Value> {
+        HashMap::get(self, name).cloned()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Errors
+// ---------------------------------------------------------------------------
+
+// `pos` is the byte offset in the source text the error points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    pub pos: usize,
+}
+
+impl ExprError {
+    fn new(message: impl Into<String>, pos: usize) -> Self {
+        Self {
+            message: message.into(),
+            pos,
+        }
+    }
+
+    // Render the error under the source line with a caret, like:
+    //   size > > 3
+    //          ^ unexpected '>'
+    // The caret goes under the character at `pos`, however many bytes the
+    // ones before it take.
+    pub fn pretty(&self, source: &str) -> String {
+        let column = source
+            .get(..self.pos)
+            .map_or(self.pos, |before| before.chars().count());
+        format!("{}\n{}^ {}", source, " ".repeat(column), self.message)
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at offset {})", self.message, self.pos)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+// ---------------------------------------------------------------------------
+// Tokenizer
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    True,
+    False,
+    Null,
+    LParen,
+    RParen,
+    Comma,
+    Dot,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            TokenKind::Num(n) => return write!(f, "number {}", n),
+            TokenKind::Str(s) => return write!(f, "string {:?}", s),
+            TokenKind::Ident(name) => return write!(f, "name '{}'", name),
+            TokenKind::True => "true",
+            TokenKind::False => "false",
+            TokenKind::Null => "null",
+            TokenKind::LParen => "'('",
+            TokenKind::RParen => "')'",
+            TokenKind::Comma => "','",
+            TokenKind::Dot => "'.'",
+            TokenKind::Not => "'!'",
+            TokenKind::And => "'&&'",
+            TokenKind::Or => "'||'",
+            TokenKind::Eq => "'=='",
+            TokenKind::Ne => "'!='",
+            TokenKind::Lt => "'<'",
+            TokenKind::Le => "'<='",
+            TokenKind::Gt => "'>'",
+            TokenKind::Ge => "'>='",
+            TokenKind::Plus => "'+'",
+            TokenKind::Minus => "'-'",
+            TokenKind::Star => "'*'",
+            TokenKind::Slash => "'/'",
+            TokenKind::Percent => "'%'",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub pos: usize,
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Token>, ExprError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let two = |next: u8| bytes.get(i + 1) == Some(&next);
+        let (kind, len) = match c {
+            b'(' => (TokenKind::LParen, 1),
+            b')' => (TokenKind::RParen, 1),
+            b',' => (TokenKind::Comma, 1),
+            b'.' => (TokenKind::Dot, 1),
+            b'+' => (TokenKind::Plus, 1),
+            b'-' => (TokenKind::Minus, 1),
+            b'*' => (TokenKind::Star, 1),
+            b'/' => (TokenKind::Slash, 1),
+            b'%' => (TokenKind::Percent, 1),
+            b'&' if two(b'&') => (TokenKind::And, 2),
+            b'|' if two(b'|') => (TokenKind::Or, 2),
+            b'=' if two(b'=') => (TokenKind::Eq, 2),
+            b'!' if two(b'=') => (TokenKind::Ne, 2),
+            b'!' => (TokenKind::Not, 1),
+            b'<' if two(b'=') => (TokenKind::Le, 2),
+            b'<' => (TokenKind::Lt, 1),
+            b'>' if two(b'=') => (TokenKind::Ge, 2),
+            b'>' => (TokenKind::Gt, 1),
+            b'"' | b'\'' => {
+                let (s, len) = lex_string(source, i)?;
+                (TokenKind::Str(s), len)
+            }
+            b'0'..=b'9' => lex_number(source, i)?,
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                let len = bytes[i..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_alphanumeric() || **b == b'_')
+                    .count();
+                let word = &source[i..i + len];
+                let kind = match word {
+                    "true" => TokenKind::True,
+                    "false" => TokenKind::False,
+                    "null" => TokenKind::Null,
+                    "and" => TokenKind::And,
+                    "or" => TokenKind::Or,
+                    "not" => TokenKind::Not,
+                    _ => TokenKind::Ident(word.to_string()),
+                };
+                (kind, len)
+            }
+            _ => {
+                let ch = source[i..].chars().next().unwrap_or('?');
+                return Err(ExprError::new(format!("unexpected character '{}'", ch), i));
+            }
+        };
+
+        tokens.push(Token { kind, pos: start });
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+// Quoted string with `\n`, `\t`, `\\` and `\"`/`\'` escapes.
+fn lex_string(source: &str, start: usize) -> Result<(String, usize), ExprError> {
+    let quote = source.as_bytes()[start] as char;
+    let mut out = String::new();
+    let mut chars = source[start + 1..].char_indices();
+
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((out, offset + 2)),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, other)) => out.push(other),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    Err(ExprError::new("unterminated string", start))
+}
+
+// Decimal number with an optional size suffix: KB, MB, GB (powers of 1024).
+fn lex_number(source: &str, start: usize) -> Result<(TokenKind, usize), ExprError> {
+    let bytes = source.as_bytes();
+    let mut len = bytes[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit() || **b == b'.' || **b == b'_')
+        .count();
+    let digits: String = source[start..start + len]
+        .chars()
+        .filter(|&c| c != '_')
+        .collect();
+    let mut value: f64 = digits
+        .parse()
+        .map_err(|_| ExprError::new(format!("invalid number '{}'", digits), start))?;
+
+    let suffix_len = bytes[start + len..]
+        .iter()
+        .take_while(|b| b.is_ascii_alphabetic())
+        .count();
+    if suffix_len > 0 {
+        let suffix = &source[start + len..start + len + suffix_len];
+        let multiplier = match suffix.to_ascii_uppercase().as_str() {
+            "B" => 1.0,
+            "KB" => 1024.0,
+            "MB" => 1024.0 * 1024.0,
+            "GB" => 1024.0 * 1024.0 * 1024.0,
+            _ => {
+                return Err(ExprError::new(
+                    format!("unknown number suffix '{}'", suffix),
+                    start + len,
+                ));
+            }
+        };
+        value *= multiplier;
+        len += suffix_len;
+    }
+
+    Ok((TokenKind::Num(value), len))
+}
+
+// ---------------------------------------------------------------------------
+// Syntax tree and parser
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    // A (possibly dotted) name such as `level` or `request.path`.
+    Var {
+        name: String,
+        pos: usize,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+        pos: usize,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        pos: usize,
+    },
+    // `receiver.method(args)`
+    Call {
+        receiver: Box<Expr>,
+        method: String,
+        args: Vec<Expr>,
+        pos: usize,
+    },
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, ExprError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.len(),
+            depth: 0,
+        };
+        let expr = parser.expression(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(ExprError::new(
+                "unexpected input after expression",
+                token.pos,
+            ));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, ctx: &dyn Context) -> Result<Value, ExprError> {
+        match self {
+            Expr::Literal(v) => Ok(v.clone()),
+            Expr::Var { name, pos } => ctx
+                .get(name)
+                .ok_or_else(|| ExprError::new(format!("unknown variable '{}'", name), *pos)),
+            Expr::Unary { op, expr, pos } => {
+                let value = expr.eval(ctx)?;
+                match op {
+                    UnaryOp::Not => Ok(Value::Bool(!value.is_truthy())),
+                    UnaryOp::Neg => match value {
+                        Value::Num(n) => Ok(Value::Num(-n)),
+                        other => Err(ExprError::new(
+                            format!("cannot negate a {}", other.type_name()),
+                            *pos,
+                        )),
+                    },
+                }
+            }
+            Expr::Binary {
+                op,
+                left,
+                right,
+                pos,
+            } => eval_binary(*op, left, right, *pos, ctx),
+            Expr::Call {
+                receiver,
+                method,
+                args,
+                pos,
+            } => {
+                let receiver = receiver.eval(ctx)?;
+                let args = args
+                    .iter()
+                    .map(|a| a.eval(ctx))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call_method(&receiver, method, &args, *pos)
+            }
+        }
+    }
+
+    // Evaluate and require a boolean result, which is what filters want.
+    // A non-bool result is reported at offset 0, i.e. the whole expression.
+    pub fn eval_bool(&self, ctx: &dyn Context) -> Result<bool, ExprError> {
+        match self.eval(ctx)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(ExprError::new(
+                format!("expected a bool result, got {}", other.type_name()),
+                0,
+            )),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Source length, used as the error position for "unexpected end".
+    end: usize,
+    // How deep in the tree the node being parsed is, at most.
+    depth: usize,
+}
+
+// Binding powers (left, right). Higher binds tighter; right > left makes an
+// operator left-associative.
+fn infix_binding_power(kind: &TokenKind) -> Option<(u8, u8, BinaryOp)> {
+    let entry = match kind {
+        TokenKind::Or => (1, 2, BinaryOp::Or),
+        TokenKind::And => (3, 4, BinaryOp::And),
+        TokenKind::Eq => (5, 6, BinaryOp::Eq),
+        TokenKind::Ne => (5, 6, BinaryOp::Ne),
+        TokenKind::Lt => (7, 8, BinaryOp::Lt),
+        TokenKind::Le => (7, 8, BinaryOp::Le),
+        TokenKind::Gt => (7, 8, BinaryOp::Gt),
+        TokenKind::Ge => (7, 8, BinaryOp::Ge),
+        TokenKind::Plus => (9, 10, BinaryOp::Add),
+        TokenKind::Minus => (9, 10, BinaryOp::Sub),
+        TokenKind::Star => (11, 12, BinaryOp::Mul),
+        TokenKind::Slash => (11, 12, BinaryOp::Div),
+        TokenKind::Percent => (11, 12, BinaryOp::Rem),
+        _ => return None,
+    };
+    Some(entry)
+}
+
+const PREFIX_BINDING_POWER: u8 = 13;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, ExprError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| ExprError::new("unexpected end of expression", self.end))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &str) -> Result<(), ExprError> {
+        let token = self.next()?;
+        if token.kind == kind {
+            Ok(())
+        } else {
+            Err(ExprError::new(format!("expected {}", what), token.pos))
+        }
+    }
+
+    fn expression(&mut self, min_bp: u8) -> Result<Expr, ExprError> {
+        let depth = self.depth;
+        let result = self.operators(min_bp);
+        self.depth = depth;
+        result
+    }
+
+    // Each operator applied adds a level above everything parsed so far,
+    // so it counts against `MAX_DEPTH` like a nested one.
+    fn deeper(&mut self, pos: usize) -> Result<(), ExprError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ExprError::new(
+                format!("expression nested more than {} levels deep", MAX_DEPTH),
+                pos,
+            ));
+        }
+        Ok(())
+    }
+
+    fn operators(&mut self, min_bp: u8) -> Result<Expr, ExprError> {
+        let pos = self.peek().map_or(self.end, |t| t.pos);
+        self.deeper(pos)?;
+        let mut left = self.prefix()?;
+
+        while let Some(token) = self.peek() {
+            let (pos, kind) = (token.pos, token.kind.clone());
+
+            if kind == TokenKind::Dot {
+                self.deeper(pos)?;
+                left = self.member(left)?;
+                continue;
+            }
+
+            let Some((left_bp, right_bp, op)) = infix_binding_power(&kind) else {
+                break;
+            };
+            if left_bp < min_bp {
+                break;
+            }
+            self.deeper(pos)?;
+            self.pos += 1;
+            let right = self.expression(right_bp)?;
+            left = Expr::Binary {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+                pos,
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn prefix(&mut self) -> Result<Expr, ExprError> {
+        let token = self.next()?;
+        let expr = match token.kind {
+            TokenKind::Num(n) => Expr::Literal(Value::Num(n)),
+            TokenKind::Str(s) => Expr::Literal(Value::Str(s)),
+            TokenKind::True => Expr::Literal(Value::Bool(true)),
+            TokenKind::False => Expr::Literal(Value::Bool(false)),
+            TokenKind::Null => Expr::Literal(Value::Null),
+            TokenKind::Ident(name) => Expr::Var {
+                name,
+                pos: token.pos,
+            },
+            TokenKind::Not => Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(self.expression(PREFIX_BINDING_POWER)?),
+                pos: token.pos,
+            },
+            TokenKind::Minus => Expr::Unary {
+                op: UnaryOp::Neg,
+                expr: Box::new(self.expression(PREFIX_BINDING_POWER)?),
+                pos: token.pos,
+            },
+            TokenKind::LParen => {
+                let inner = self.expression(0)?;
+                self.expect(TokenKind::RParen, "')'")?;
+                inner
+            }
+            other => {
+                return Err(ExprError::new(format!("unexpected {}", other), token.pos));
+            }
+        };
+        Ok(expr)
+    }
+
+    // After a `.`: either a method call `x.name(args)` or, on a variable, a
+    // longer dotted name `x.name`.
+    fn member(&mut self, receiver: Expr) -> Result<Expr, ExprError> {
+        self.pos += 1; // the dot
+        let token = self.next()?;
+        let TokenKind::Ident(name) = token.kind else {
+            return Err(ExprError::new("expected a name after '.'", token.pos));
+        };
+
+        if self.peek().map(|t| &t.kind) == Some(&TokenKind::LParen) {
+            self.pos += 1;
+            let mut args = Vec::new();
+            if self.peek().map(|t| &t.kind) != Some(&TokenKind::RParen) {
+                loop {
+                    args.push(self.expression(0)?);
+                    if self.peek().map(|t| &t.kind) == Some(&TokenKind::Comma) {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            self.expect(TokenKind::RParen, "')' after arguments")?;
+            return Ok(Expr::Call {
+                receiver: Box::new(receiver),
+                method: name,
+                args,
+                pos: token.pos,
+            });
+        }
+
+        match receiver {
+            Expr::Var { name: base, pos } => Ok(Expr::Var {
+                name: format!("{}.{}", base, name),
+                pos,
+            }),
+            _ => Err(ExprError::new(
+                "field access only works on variables",
+                token.pos,
+            )),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Evaluation
+// ---------------------------------------------------------------------------
+
+fn eval_binary(
+    op: BinaryOp,
+    left: &Expr,
+    right: &Expr,
+    pos: usize,
+    ctx: &dyn Context,
+) -> Result<Value, ExprError> {
+    // && and || short-circuit, so `x != null && x.len() > 3` is safe.
+    match op {
+        BinaryOp::And => {
+            let l = left.eval(ctx)?;
+            return if !l.is_truthy() {
+                Ok(Value::Bool(false))
+            } else {
+                Ok(Value::Bool(right.eval(ctx)?.is_truthy()))
+            };
+        }
+        BinaryOp::Or => {
+            let l = left.eval(ctx)?;
+            return if l.is_truthy() {
+                Ok(Value::Bool(true))
+            } else {
+                Ok(Value::Bool(right.eval(ctx)?.is_truthy()))
+            };
+        }
+        _ => {}
+    }
+
+    let l = left.eval(ctx)?;
+    let r = right.eval(ctx)?;
+    let mismatch = || {
+        ExprError::new(
+            format!(
+                "cannot apply {:?} to {} and {}",
+                op,
+                l.type_name(),
+                r.type_name()
+            ),
+            pos,
+        )
+    };
+
+    let value = match op {
+        BinaryOp::Eq => Value::Bool(l == r),
+        BinaryOp::Ne => Value::Bool(l != r),
+        BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            let ordering = match (&l, &r) {
+                (Value::Num(a), Value::Num(b)) => a.partial_cmp(b),
+                (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                _ => None,
+            }
+            .ok_or_else(mismatch)?;
+            Value::Bool(match op {
+                BinaryOp::Lt => ordering.is_lt(),
+                BinaryOp::Le => ordering.is_le(),
+                BinaryOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        BinaryOp::Add => match (&l, &r) {
+            (Value::Num(a), Value::Num(b)) => Value::Num(a + b),
+            (Value::Str(a), Value::Str(b)) => Value::Str(format!("{}{}", a, b)),
+            _ => return Err(mismatch()),
+        },
+        BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+            let (Value::Num(a), Value::Num(b)) = (&l, &r) else {
+                return Err(mismatch());
+            };
+            if matches!(op, BinaryOp::Div | BinaryOp::Rem) && *b == 0.0 {
+                return Err(ExprError::new("division by zero", pos));
+            }
+            Value::Num(match op {
+                BinaryOp::Sub => a - b,
+                BinaryOp::Mul => a * b,
+                BinaryOp::Div => a / b,
+                _ => a % b,
+            })
+        }
+        BinaryOp::And | BinaryOp::Or => unreachable!("handled above"),
+    };
+    Ok(value)
+}
+
+// Built-in methods on values.
+fn call_method(
+    receiver: &Value,
+    method: &str,
+    args: &[Value],
+    pos: usize,
+) -> Result<Value, ExprError> {
+    let str_arg = |i: usize| match args.get(i) {
+        Some(Value::Str(s)) => Ok(s.as_str()),
+        _ => Err(ExprError::new(
+            format!("{}() expects a string argument", method),
+            pos,
+        )),
+    };
+
+    match (receiver, method) {
+        (Value::Str(s), "starts_with") => Ok(Value::Bool(s.starts_with(str_arg(0)?))),
+        (Value::Str(s), "ends_with") => Ok(Value::Bool(s.ends_with(str_arg(0)?))),
+        (Value::Str(s), "contains") => Ok(Value::Bool(s.contains(str_arg(0)?))),
+        (Value::Str(s), "len") => Ok(Value::Num(s.chars().count() as f64)),
+        (Value::Str(s), "lower") => Ok(Value::Str(s.to_lowercase())),
+        (Value::Str(s), "upper") => Ok(Value::Str(s.to_uppercase())),
+        _ => Err(ExprError::new(
+            format!("{} has no method '{}'", receiver.type_name(), method),
+            pos,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Result<Value, ExprError> {
+        Expr::parse(source)?.eval(&HashMap::<&str, Value>::new())
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_parses() {
+        let depth = MAX_DEPTH - 1;
+        let source = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&source), Ok(Value::Num(1.0)));
+        assert_eq!(
+            eval(&format!("{}true", "!".repeat(MAX_DEPTH - 2))),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        for source in [
+            format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("{}1", "-".repeat(100_000)),
+            format!("{}x", "not ".repeat(100_000)),
+            format!("1{}", " + 1".repeat(100_000)),
+            format!("x{}", ".a".repeat(100_000)),
+            format!("s{}", ".lower()".repeat(100_000)),
+        ] {
+            let err = Expr::parse(&source).unwrap_err();
+            assert!(err.message.contains("levels deep"), "{}", err.message);
+            assert!(err.pos < source.len());
+        }
+    }
+
+    #[test]
+    fn unclosed_nesting_past_the_limit_is_the_same_error() {
+        let err = Expr::parse(&"(".repeat(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.pos, MAX_DEPTH);
+    }
+
+    #[test]
+    fn caret_counts_characters_not_bytes() {
+        let source = "name == \"größe\" >";
+        let err = Expr::parse(source).unwrap_err();
+        assert_eq!(err.pos, source.len());
+        let pretty = err.pretty(source);
+        let caret = pretty.lines().nth(1).unwrap();
+        assert_eq!(caret.find('^'), Some(source.chars().count()));
+
+        let source = "\"ü\" ? 1";
+        let err = Expr::parse(source).unwrap_err();
+        assert_eq!(
+            err.pretty(source),
+            "\"ü\" ? 1\n    ^ unexpected character '?'"
+        );
+    }
+
+    #[test]
+    fn precedence_and_short_circuit() {
+        assert_eq!(eval("1 + 2 * 3 == 7"), Ok(Value::Bool(true)));
+        assert_eq!(eval("true || missing"), Ok(Value::Bool(true)));
+        assert_eq!(eval("1KB / 2"), Ok(Value::Num(512.0)));
+        assert!(eval("1 / 0").is_err());
+    }
+}
@@ -1,6 +1,16 @@
 use core::str;
+use std::collections::VecDeque;
+use std::fmt;
 use std::fs;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::line_splitter::LineSplitter;
 // Method 1: Using fs::write (simplest approach)
 pub fn write_file_simple(path: &str, content: &str) -> Result<(), std::io::Error> {
     fs::write(path, content)?; // ? operator handles the Result
@@ -29,16 +39,1188 @@ pub fn write_file_with_match(path: &str, content: &str) -> Result<i8, std::io::E
 }
 
 // Method 3: Using File::create and write_all (more control)
-#[allow(dead_code)]
 pub fn write_file_detailed(path: &str, content: &str) -> Result<(), std::io::Error> {
     let mut file = fs::File::create(path)?; // ? handles the Result<File, Error>
     file.write_all(content.as_bytes())?; // ? handles the Result<(), Error>
     Ok(())
 }
 
+// Method 4: Atomically, via a temporary file renamed into place. A crash
+// mid-write leaves either the old contents or the new ones, never a mix.
+pub fn write_file_atomic(path: &str, content: &str) -> Result<(), std::io::Error> {
+    write_atomic(Path::new(path), content.as_bytes())
+}
+
+// The temporary file goes in the same directory as `path` (a rename can't
+// cross filesystems) and is synced before the rename, so the rename can't
+// reach the disk before the data does. The directory is synced afterwards
+// where that's possible, so the rename itself survives a power cut.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let (dir, tmp_path) = write_temp(path, bytes)?;
+    if let Err(e) = replace(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    sync_dir(dir)
+}
+
+// Write `bytes` to a new synced temporary file next to `path`. Returns the
+// directory and the temporary file; nothing is left behind on error.
+fn write_temp<'a>(path: &'a Path, bytes: &[u8]) -> Result<(&'a Path, PathBuf), std::io::Error> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    // Unique per process and call, so concurrent writers don't share one.
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = dir.join(tmp_name);
+
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok((dir, tmp_path))
+}
+
+// Directories can't be opened as files on Windows; there a rename is as
+// durable as the filesystem makes it.
+fn sync_dir(dir: &Path) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// Rename `from` over `to`. On Windows that fails while something else has
+// `to` open without delete sharing, so as a fallback `to` is removed first,
+// which briefly leaves no file at `to` at all.
+fn replace(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    match fs::rename(from, to) {
+        Err(e) if cfg!(windows) && e.kind() == std::io::ErrorKind::PermissionDenied => {
+            fs::remove_file(to)?;
+            fs::rename(from, to)
+        }
+        result => result,
+    }
+}
+
+// Method 5: With options, for writes that need more care than "replace it":
+//
+//     let report = WriteOptions::new().backup(true).write(path, bytes)?;
+//
+// - `backup(true)` keeps the old contents: an existing file is hard-linked
+//   (or, where that fails, copied) to `<path>.bak`, replacing any older
+//   backup, before the new one is written. The file itself stays in place
+//   until the new contents replace it, so a failed write leaves it as it
+//   was.
+// - `create_new(true)` refuses to touch an existing file (AlreadyExists).
+//   The contents go to a temporary file that's then linked into place, so
+//   a failed write leaves no partial file to block a retry.
+// - `dry_run(true)` writes nothing, only checks what can be checked without
+//   writing (the directory exists, the target isn't a directory or
+//   read-only) and reports what would happen.
+//
+// Otherwise the write is atomic, like `write_file_atomic`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    backup: bool,
+    create_new: bool,
+    dry_run: bool,
+}
+
+// What a write did, or with `dry_run`, would do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteReport {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub dry_run: bool,
+    // Whether there was a file at `path` before.
+    pub replaced: bool,
+    // Where the old contents went, if they were kept.
+    pub backup: Option<PathBuf>,
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn backup(mut self, backup: bool) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn write(&self, path: &Path, contents: &[u8]) -> Result<WriteReport, std::io::Error> {
+        let existing = match fs::metadata(path) {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let error = |kind, what: &str| {
+            Err(std::io::Error::new(
+                kind,
+                format!("{}: {}", path.display(), what),
+            ))
+        };
+        match &existing {
+            Some(_) if self.create_new => {
+                return error(std::io::ErrorKind::AlreadyExists, "already exists");
+            }
+            Some(metadata) if metadata.is_dir() => {
+                return error(std::io::ErrorKind::InvalidInput, "is a directory");
+            }
+            Some(metadata) if metadata.permissions().readonly() => {
+                return error(std::io::ErrorKind::PermissionDenied, "is read-only");
+            }
+            _ => {}
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if !dir.is_dir() {
+            return error(std::io::ErrorKind::NotFound, "directory doesn't exist");
+        }
+        let backup = (self.backup && existing.is_some()).then(|| backup_path(path));
+        let report = WriteReport {
+            path: path.to_path_buf(),
+            bytes: contents.len() as u64,
+            dry_run: self.dry_run,
+            replaced: existing.is_some(),
+            backup,
+        };
+        if self.dry_run {
+            return Ok(report);
+        }
+
+        if self.create_new {
+            let (dir, tmp_path) = write_temp(path, contents)?;
+            // Unlike a rename, a link fails if the file appeared since the
+            // check above, so it still isn't overwritten.
+            let linked = fs::hard_link(&tmp_path, path);
+            let _ = fs::remove_file(&tmp_path);
+            linked?;
+            sync_dir(dir)?;
+            return Ok(report);
+        }
+        if let Some(backup) = &report.backup {
+            keep_backup(path, backup)?;
+        }
+        write_atomic(path, contents)?;
+        Ok(report)
+    }
+}
+
+// Make `backup` hold what `path` holds now. A hard link costs nothing and
+// survives `write_atomic`, which replaces `path` with a new file rather
+// than writing into the old one.
+fn keep_backup(path: &Path, backup: &Path) -> Result<(), std::io::Error> {
+    match fs::remove_file(backup) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if fs::hard_link(path, backup).is_err() {
+        fs::copy(path, backup)?;
+    }
+    Ok(())
+}
+
+// `app.conf` -> `app.conf.bak`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+impl fmt::Display for WriteReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match (self.dry_run, self.replaced) {
+            (true, true) => "would replace",
+            (true, false) => "would create",
+            (false, true) => "replaced",
+            (false, false) => "created",
+        };
+        write!(f, "{} {} ({} bytes)", verb, self.path.display(), self.bytes)?;
+        if let Some(backup) = &self.backup {
+            let kept = if self.dry_run {
+                "would be kept"
+            } else {
+                "kept"
+            };
+            write!(f, "; old contents {} in {}", kept, backup.display())?;
+        }
+        Ok(())
+    }
+}
+
 pub fn read_file(path: &str) -> Result<String, std::io::Error> {
     let mut file = fs::File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
     Ok(contents)
 }
+
+// Read `path` a piece at a time instead of all at once, for files too big
+// to hold in memory. Every chunk is `chunk_size` bytes except the last,
+// which may be shorter. If the file can't be opened, that error is the
+// only item; after any error the iterator ends.
+pub fn read_file_chunks(
+    path: &Path,
+    chunk_size: usize,
+) -> impl Iterator<Item = Result<Vec<u8>, std::io::Error>> {
+    let (opened, error) = match fs::File::open(path) {
+        Ok(file) => (Some(file), None),
+        Err(e) => (None, Some(e)),
+    };
+    let chunks = Chunks {
+        reader: opened,
+        chunk_size: chunk_size.max(1),
+    };
+    error.map(Err).into_iter().chain(chunks)
+}
+
+struct Chunks<R> {
+    // `None` once the end of the file or an error has been reached.
+    reader: Option<R>,
+    chunk_size: usize,
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = Result<Vec<u8>, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = self.reader.as_mut()?;
+        // A single `read` can return less than asked for well before the
+        // end, so keep going until the chunk is full or the file runs out.
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        match reader
+            .by_ref()
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(n) if n < self.chunk_size => self.reader = None,
+            Ok(_) => {}
+            Err(e) => {
+                self.reader = None;
+                return Some(Err(e));
+            }
+        }
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(Ok(chunk))
+        }
+    }
+}
+
+// Read `path` one line at a time, without the `\n` or `\r\n` ending. A line
+// that isn't valid UTF-8 is an `InvalidData` error. If the file can't be
+// opened, that error is the only item, and after any other read error the
+// iterator ends.
+pub fn read_lines(path: &Path) -> impl Iterator<Item = Result<String, std::io::Error>> {
+    lines_in_chunks(path, 64 * 1024)
+}
+
+// `read_lines`, reading `chunk_size` bytes at a time. A `LineSplitter` cuts
+// each chunk up, so only a line that spans two chunks is copied twice.
+fn lines_in_chunks(
+    path: &Path,
+    chunk_size: usize,
+) -> impl Iterator<Item = Result<String, std::io::Error>> {
+    let mut chunks = read_file_chunks(path, chunk_size);
+    let mut splitter = LineSplitter::new();
+    let mut ready = VecDeque::new();
+    let mut finished = false;
+    std::iter::from_fn(move || {
+        loop {
+            if let Some(line) = ready.pop_front() {
+                return Some(line);
+            }
+            if finished {
+                return None;
+            }
+            match chunks.next() {
+                Some(Ok(chunk)) => ready.extend(splitter.feed(&chunk).map(line_string)),
+                Some(Err(e)) => {
+                    finished = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    finished = true;
+                    ready.extend(splitter.finish().map(line_string));
+                }
+            }
+        }
+    })
+}
+
+fn line_string(line: &[u8]) -> Result<String, std::io::Error> {
+    String::from_utf8(line.to_vec())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// What went wrong reading or writing a JSON file: the file itself, or its
+// contents.
+#[derive(Debug)]
+pub enum JsonFileError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    // The file was read but isn't JSON of the expected shape.
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    // The value couldn't be turned into JSON (e.g. a map with non-string
+    // keys). Nothing was written.
+    Serialize {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+impl fmt::Display for JsonFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonFileError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            JsonFileError::Parse { path, source } => {
+                write!(f, "{}: invalid JSON: {}", path.display(), source)
+            }
+            JsonFileError::Serialize { path, source } => {
+                write!(f, "{}: can't serialize: {}", path.display(), source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonFileError::Io { source, .. } => Some(source),
+            JsonFileError::Parse { source, .. } | JsonFileError::Serialize { source, .. } => {
+                Some(source)
+            }
+        }
+    }
+}
+
+// Read `path` and deserialize it as JSON into a `T`.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, JsonFileError> {
+    let text = fs::read_to_string(path).map_err(|source| JsonFileError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    serde_json::from_str(&text).map_err(|source| JsonFileError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Serialize `value` as JSON (indented if `pretty`) and write it to `path`.
+// Written like `write_file_atomic`, so a crash mid-write leaves the old
+// contents rather than half a document.
+pub fn write_json<T: Serialize + ?Sized>(
+    path: &Path,
+    value: &T,
+    pretty: bool,
+) -> Result<(), JsonFileError> {
+    let serialized = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    let mut text = serialized.map_err(|source| JsonFileError::Serialize {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    text.push('\n');
+
+    write_atomic(path, text.as_bytes()).map_err(|source| JsonFileError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Open `path` for appending, creating it if needed. Every write lands at the
+// current end of the file, even if something else appended in the meantime.
+pub fn open_append(path: &Path) -> Result<fs::File, std::io::Error> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+// The path of the `n`th rotated copy of `path`: `app.log` -> `app.log.2`.
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Shift `path` into a numbered series, keeping at most `keep` old copies:
+// `path.{keep}` is deleted, `path.{n}` becomes `path.{n+1}`, and `path`
+// becomes `path.1`. With `keep == 0`, `path` is just removed. Missing files
+// anywhere in the series are fine.
+pub fn rotate_numbered(path: &Path, keep: usize) -> Result<(), std::io::Error> {
+    let ignore_missing = |result: Result<(), std::io::Error>| match result {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    };
+    if keep == 0 {
+        return ignore_missing(fs::remove_file(path));
+    }
+    ignore_missing(fs::remove_file(rotated_path(path, keep)))?;
+    for n in (1..keep).rev() {
+        ignore_missing(fs::rename(rotated_path(path, n), rotated_path(path, n + 1)))?;
+    }
+    ignore_missing(fs::rename(path, rotated_path(path, 1)))
+}
+
+// Recursively collect every regular file under `dir`.
+// Symlinks are skipped rather than followed, so a link pointing back up the
+// tree can't send the walk around in circles.
+pub fn walk_files(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?; // does not follow symlinks
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+// Copy `src` to `dst`, calling `on_progress(bytes_copied_so_far)` after each
+// chunk. The data goes to a temporary file next to `dst` first and is renamed
+// into place at the end, so an interrupted copy never leaves a half-written
+// `dst` behind.
+pub fn copy_file_with_progress<F>(
+    src: &Path,
+    dst: &Path,
+    mut on_progress: F,
+) -> Result<u64, std::io::Error>
+where
+    F: FnMut(u64),
+{
+    let mut tmp_name = dst.as_os_str().to_owned();
+    tmp_name.push(".partial");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut reader = fs::File::open(src)?;
+    let result = (|| {
+        let mut writer = fs::File::create(&tmp_path)?;
+        let mut buf = [0u8; 64 * 1024];
+        let mut copied = 0u64;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buf[..n])?;
+            copied += n as u64;
+            on_progress(copied);
+        }
+        writer.sync_all()?;
+        drop(writer);
+        fs::rename(&tmp_path, dst)?;
+        Ok(copied)
+    })();
+    // Whatever failed, don't leave a half-written copy behind.
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// One entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntryInfo {
+    pub path: PathBuf,
+    pub name: String,
+    // 0 for directories.
+    pub size: u64,
+    // None where the platform doesn't record it.
+    pub modified: Option<SystemTime>,
+    pub is_dir: bool,
+    // The size, time and kind above are of whatever the link points at
+    // (or of the link itself when that's missing).
+    pub is_symlink: bool,
+}
+
+// The entries of `path`, sorted by path; with `recursive`, those of every
+// directory below it too. Symlinks are followed, but a link back to a
+// directory the walk is already inside is listed without being entered.
+pub fn list_dir(path: &Path, recursive: bool) -> Result<Vec<DirEntryInfo>, std::io::Error> {
+    let mut entries = Vec::new();
+    walk_dir(path, recursive, &mut Vec::new(), &mut |entry, _| {
+        entries.push(entry.clone());
+        Ok(())
+    })?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+// Total size of the files under `path`, following symlinks (so a file
+// linked into the tree counts where it's linked) but not loops. Dangling
+// links count for nothing.
+pub fn dir_size(path: &Path) -> Result<u64, std::io::Error> {
+    let mut total = 0;
+    walk_dir(path, true, &mut Vec::new(), &mut |entry, _| {
+        if !entry.is_dir && (!entry.is_symlink || entry.path.exists()) {
+            total += entry.size;
+        }
+        Ok(())
+    })?;
+    Ok(total)
+}
+
+// Copy the tree at `src` to `dst`, creating `dst` and merging into it if it
+// exists, and return the number of bytes copied. Symlinks are followed, so
+// the copy holds what they point at. A symlink loop, or `dst` inside `src`,
+// is an `InvalidInput` error rather than a copy that never ends. After an
+// error, whatever was copied before it stays in `dst`.
+pub fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<u64, std::io::Error> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    let real_src = fs::canonicalize(src)?;
+    // `dst` may not exist yet; its parent has to.
+    let real_dst = match (fs::canonicalize(dst), dst.parent(), dst.file_name()) {
+        (Ok(real), _, _) => real,
+        (Err(_), Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            fs::canonicalize(parent)?.join(name)
+        }
+        (Err(e), _, _) => return Err(e),
+    };
+    if real_dst.starts_with(&real_src) {
+        return Err(invalid(format!(
+            "can't copy {} into itself ({})",
+            src.display(),
+            dst.display()
+        )));
+    }
+
+    fs::create_dir_all(dst)?;
+    let mut copied = 0;
+    walk_dir(src, true, &mut Vec::new(), &mut |entry, is_loop| {
+        let target = dst.join(entry.path.strip_prefix(src).unwrap_or(&entry.path));
+        if is_loop {
+            Err(invalid(format!("symlink loop at {}", entry.path.display())))
+        } else if entry.is_dir {
+            fs::create_dir_all(&target)
+        } else {
+            copied += fs::copy(&entry.path, &target).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("{}: {}", entry.path.display(), e))
+            })?;
+            Ok(())
+        }
+    })?;
+    Ok(copied)
+}
+
+// Visit every entry under `dir`, a directory before what's in it. The flag
+// passed to `visit` says the entry is a directory the walk is already inside
+// (through a symlink), which isn't entered again. `ancestors` holds the real
+// paths of the directories being walked.
+fn walk_dir(
+    dir: &Path,
+    recursive: bool,
+    ancestors: &mut Vec<PathBuf>,
+    visit: &mut dyn FnMut(&DirEntryInfo, bool) -> Result<(), std::io::Error>,
+) -> Result<(), std::io::Error> {
+    ancestors.push(fs::canonicalize(dir)?);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_symlink = entry.file_type()?.is_symlink();
+        // A dangling link is listed as the link itself.
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) if is_symlink => fs::symlink_metadata(&path)?,
+            Err(e) => return Err(e),
+        };
+        let info = DirEntryInfo {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+            is_dir: metadata.is_dir(),
+            is_symlink,
+            path,
+        };
+        let is_loop =
+            info.is_dir && is_symlink && ancestors.contains(&fs::canonicalize(&info.path)?);
+        visit(&info, is_loop)?;
+        if recursive && info.is_dir && !is_loop {
+            walk_dir(&info.path, recursive, ancestors, visit)?;
+        }
+    }
+    ancestors.pop();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("file-handling-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        // Names in the directory, sorted, so stray temporary files show.
+        fn names(&self) -> Vec<String> {
+            let mut names: Vec<_> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_file_and_leaves_nothing_else() {
+        let dir = TempDir::new("atomic");
+        let path = dir.0.join("state.json");
+
+        write_atomic(&path, b"one").unwrap();
+        write_atomic(&path, b"two").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"two");
+        assert_eq!(dir.names(), ["state.json"]);
+    }
+
+    #[test]
+    fn a_failed_rename_leaves_no_temporary_file() {
+        let dir = TempDir::new("atomic-rename");
+        // A file can't be renamed over a non-empty directory, so the temp
+        // file gets written and synced, and only the rename fails.
+        let path = dir.0.join("state.json");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("inside"), "").unwrap();
+
+        assert!(write_atomic(&path, b"data").is_err());
+
+        assert_eq!(dir.names(), ["state.json"]);
+        assert!(path.is_dir());
+    }
+
+    // Hands out at most one byte per `read`, then fails if `fail` is set.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        fail: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+            match self.data.split_first() {
+                Some((byte, rest)) if !buf.is_empty() => {
+                    buf[0] = *byte;
+                    self.data = rest;
+                    Ok(1)
+                }
+                _ if self.fail => Err(std::io::Error::other("disk gone")),
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn chunk_lengths(path: &Path, chunk_size: usize) -> Vec<usize> {
+        read_file_chunks(path, chunk_size)
+            .map(|chunk| chunk.unwrap().len())
+            .collect()
+    }
+
+    #[test]
+    fn chunks_are_full_but_for_the_last() {
+        let dir = TempDir::new("chunks");
+        let path = dir.0.join("data");
+        fs::write(&path, b"0123456789").unwrap();
+
+        assert_eq!(chunk_lengths(&path, 4), [4, 4, 2]);
+        // An exact multiple ends without an empty chunk.
+        assert_eq!(chunk_lengths(&path, 5), [5, 5]);
+        assert_eq!(chunk_lengths(&path, 100), [10]);
+        // A chunk size of 0 is taken as 1, not as a loop of empty chunks.
+        assert_eq!(chunk_lengths(&path, 0), [1; 10]);
+        let joined: Vec<u8> = read_file_chunks(&path, 3)
+            .flat_map(Result::unwrap)
+            .collect();
+        assert_eq!(joined, b"0123456789");
+
+        fs::write(&path, b"").unwrap();
+        assert!(chunk_lengths(&path, 4).is_empty());
+    }
+
+    #[test]
+    fn short_reads_still_fill_each_chunk() {
+        let chunks = Chunks {
+            reader: Some(Trickle {
+                data: b"abcdefg",
+                fail: false,
+            }),
+            chunk_size: 3,
+        };
+
+        let chunks: Vec<_> = chunks.map(Result::unwrap).collect();
+        assert_eq!(chunks, [&b"abc"[..], b"def", b"g"]);
+    }
+
+    #[test]
+    fn a_read_error_is_the_last_item() {
+        let chunks = Chunks {
+            reader: Some(Trickle {
+                data: b"abcd",
+                fail: true,
+            }),
+            chunk_size: 3,
+        };
+
+        let chunks: Vec<_> = chunks.collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap(), b"abc");
+        assert_eq!(chunks[1].as_ref().unwrap_err().to_string(), "disk gone");
+
+        let dir = TempDir::new("chunks-missing");
+        let missing: Vec<_> = read_file_chunks(&dir.0.join("missing"), 4).collect();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(
+            missing[0].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn lines_are_split_the_same_across_chunk_boundaries() {
+        let dir = TempDir::new("lines");
+        let path = dir.0.join("text");
+        fs::write(&path, "first\r\n\na line longer than a chunk\nno newline").unwrap();
+
+        // One-byte chunks put a boundary everywhere, including between
+        // '\r' and '\n'.
+        for chunk_size in [1, 2, 7, 64 * 1024] {
+            let lines: Vec<String> = lines_in_chunks(&path, chunk_size)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(
+                lines,
+                ["first", "", "a line longer than a chunk", "no newline"],
+                "chunks of {}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn a_final_newline_ends_the_last_line_rather_than_starting_one() {
+        let dir = TempDir::new("lines-final");
+        let path = dir.0.join("text");
+
+        for (contents, expected) in [
+            ("a\r\nb\n", &["a", "b"][..]),
+            ("a\r\nb", &["a", "b"]),
+            ("a\n\n", &["a", ""]),
+            // Only a '\r' right before the '\n' is part of the ending.
+            ("a\rb\r\r\n", &["a\rb\r"]),
+            ("", &[]),
+        ] {
+            fs::write(&path, contents).unwrap();
+            let lines: Vec<String> = lines_in_chunks(&path, 2).collect::<Result<_, _>>().unwrap();
+            assert_eq!(lines, expected, "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn a_line_that_isnt_utf8_is_an_error_of_its_own() {
+        let dir = TempDir::new("lines-utf8");
+        let path = dir.0.join("text");
+        fs::write(&path, b"ok\n\xff\xfe\nstill ok\n").unwrap();
+
+        let lines: Vec<_> = read_lines(&path).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].as_ref().unwrap(), "ok");
+        assert_eq!(
+            lines[1].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(lines[2].as_ref().unwrap(), "still ok");
+
+        let missing: Vec<_> = read_lines(&dir.0.join("missing")).collect();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(
+            missing[0].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn a_plain_write_creates_or_replaces() {
+        let dir = TempDir::new("plain");
+        let path = dir.0.join("app.conf");
+
+        let report = WriteOptions::new().write(&path, b"one").unwrap();
+        assert!(!report.replaced);
+        let report = WriteOptions::new().write(&path, b"two!").unwrap();
+        assert_eq!(
+            report.to_string(),
+            format!("replaced {} (4 bytes)", path.display())
+        );
+
+        assert_eq!(fs::read(&path).unwrap(), b"two!");
+        assert_eq!(dir.names(), ["app.conf"]);
+    }
+
+    #[test]
+    fn a_backup_keeps_the_old_contents_beside_the_new() {
+        let dir = TempDir::new("backup");
+        let path = dir.0.join("app.conf");
+        let backup = WriteOptions::new().backup(true);
+
+        // Nothing to keep the first time.
+        assert_eq!(backup.write(&path, b"one").unwrap().backup, None);
+        let report = backup.write(&path, b"two").unwrap();
+        assert_eq!(report.backup, Some(dir.0.join("app.conf.bak")));
+        let report = backup.write(&path, b"three").unwrap();
+        let kept = format!(
+            "; old contents kept in {}",
+            dir.0.join("app.conf.bak").display()
+        );
+        assert!(report.to_string().ends_with(&kept), "{}", report);
+
+        assert_eq!(fs::read(&path).unwrap(), b"three");
+        assert_eq!(fs::read(dir.0.join("app.conf.bak")).unwrap(), b"two");
+        assert_eq!(dir.names(), ["app.conf", "app.conf.bak"]);
+    }
+
+    #[test]
+    fn create_new_never_touches_an_existing_file() {
+        let dir = TempDir::new("create-new");
+        let path = dir.0.join("app.conf");
+        let create = WriteOptions::new().create_new(true);
+
+        assert!(!create.write(&path, b"first").unwrap().replaced);
+        let e = create.write(&path, b"second").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        assert_eq!(dir.names(), ["app.conf"]);
+    }
+
+    #[test]
+    fn a_dry_run_checks_but_writes_nothing() {
+        let dir = TempDir::new("dry-run");
+        let path = dir.0.join("app.conf");
+        fs::write(&path, "old").unwrap();
+        let dry = WriteOptions::new().backup(true).dry_run(true);
+
+        let report = dry.write(&path, b"new").unwrap();
+        assert!(report.dry_run && report.replaced);
+        assert!(report.to_string().starts_with("would replace"));
+        assert!(report.to_string().contains("would be kept in"));
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(dir.names(), ["app.conf"]);
+
+        let missing_dir = dir.0.join("nope").join("app.conf");
+        assert_eq!(
+            dry.write(&missing_dir, b"").unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            dry.write(&dir.0, b"").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+        assert_eq!(
+            dry.write(&path, b"").unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+    }
+
+    // dir/
+    //   top.txt       3 bytes
+    //   sub/
+    //     mid.txt     5 bytes
+    //     deeper/
+    //       low.txt   7 bytes
+    fn make_tree(dir: &Path) {
+        fs::create_dir_all(dir.join("sub").join("deeper")).unwrap();
+        fs::write(dir.join("top.txt"), "top").unwrap();
+        fs::write(dir.join("sub").join("mid.txt"), "mid..").unwrap();
+        fs::write(dir.join("sub").join("deeper").join("low.txt"), "low....").unwrap();
+    }
+
+    fn listed(dir: &Path, recursive: bool) -> Vec<(String, u64, bool)> {
+        list_dir(dir, recursive)
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                let relative = e
+                    .path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                (relative, e.size, e.is_dir)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn list_dir_goes_down_only_when_recursive() {
+        let dir = TempDir::new("list");
+        make_tree(&dir.0);
+
+        assert_eq!(
+            listed(&dir.0, false),
+            [
+                ("sub".to_string(), 0, true),
+                ("top.txt".to_string(), 3, false)
+            ]
+        );
+        let all: Vec<_> = listed(&dir.0, true)
+            .into_iter()
+            .map(|(path, ..)| path)
+            .collect();
+        assert_eq!(
+            all,
+            [
+                "sub",
+                "sub/deeper",
+                "sub/deeper/low.txt",
+                "sub/mid.txt",
+                "top.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn dir_size_adds_up_every_level() {
+        let dir = TempDir::new("size");
+        make_tree(&dir.0);
+
+        assert_eq!(dir_size(&dir.0).unwrap(), 3 + 5 + 7);
+        assert_eq!(dir_size(&dir.0.join("sub")).unwrap(), 5 + 7);
+        assert_eq!(
+            dir_size(&dir.0.join("missing")).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_loop_is_listed_but_not_entered() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("loop");
+        make_tree(&dir.0);
+        // sub/deeper/up -> sub, and a link to a file and one to nothing.
+        symlink(
+            dir.0.join("sub"),
+            dir.0.join("sub").join("deeper").join("up"),
+        )
+        .unwrap();
+        symlink(dir.0.join("top.txt"), dir.0.join("sub").join("top-link")).unwrap();
+        symlink(dir.0.join("gone"), dir.0.join("sub").join("dangling")).unwrap();
+
+        let entries = list_dir(&dir.0, true).unwrap();
+        let up = entries.iter().find(|e| e.name == "up").unwrap();
+        assert!(up.is_dir && up.is_symlink);
+        assert!(
+            !entries
+                .iter()
+                .any(|e| e.path.starts_with(&up.path) && e.path != up.path)
+        );
+        let dangling = entries.iter().find(|e| e.name == "dangling").unwrap();
+        assert!(dangling.is_symlink && !dangling.is_dir);
+
+        // The linked file counts where it's linked; the loop and the
+        // dangling link count for nothing.
+        assert_eq!(dir_size(&dir.0).unwrap(), 3 + 5 + 7 + 3);
+
+        // A copy would follow the dangling link and fail on that first.
+        fs::remove_file(dir.0.join("sub").join("dangling")).unwrap();
+        let copy = TempDir::new("loop-copy");
+        let e = copy_dir_recursive(&dir.0, &copy.0.join("tree")).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("symlink loop"), "{}", e);
+    }
+
+    #[test]
+    fn copy_dir_recursive_copies_and_merges_the_tree() {
+        let src = TempDir::new("copy-src");
+        make_tree(&src.0);
+        let dst = TempDir::new("copy-dst");
+        fs::write(dst.0.join("already.txt"), "kept").unwrap();
+
+        let copied = copy_dir_recursive(&src.0, &dst.0).unwrap();
+
+        assert_eq!(copied, 3 + 5 + 7);
+        assert_eq!(
+            fs::read_to_string(dst.0.join("sub").join("deeper").join("low.txt")).unwrap(),
+            "low...."
+        );
+        assert_eq!(dst.names(), ["already.txt", "sub", "top.txt"]);
+    }
+
+    #[test]
+    fn copy_dir_recursive_refuses_to_copy_into_itself() {
+        let dir = TempDir::new("copy-into");
+        make_tree(&dir.0);
+
+        for dst in [
+            dir.0.join("sub").join("copy"),
+            dir.0.clone(),
+            dir.0.join("sub"),
+        ] {
+            let e = copy_dir_recursive(&dir.0, &dst).unwrap_err();
+            assert_eq!(
+                e.kind(),
+                std::io::ErrorKind::InvalidInput,
+                "{}",
+                dst.display()
+            );
+        }
+        // Refused before anything was created.
+        assert!(!dir.0.join("sub").join("copy").exists());
+        assert_eq!(dir.names(), ["sub", "top.txt"]);
+        // A sibling whose name merely starts with the source's is fine.
+        let sibling = TempDir(PathBuf::from(format!("{}-copy", dir.0.display())));
+        assert_eq!(copy_dir_recursive(&dir.0, &sibling.0).unwrap(), 3 + 5 + 7);
+    }
+
+    #[test]
+    fn json_round_trips_compact_or_pretty() {
+        use std::collections::BTreeMap;
+
+        let dir = TempDir::new("json");
+        let path = dir.0.join("state.json");
+        let value = BTreeMap::from([("a".to_string(), vec![1, 2]), ("b".to_string(), vec![])]);
+
+        write_json(&path, &value, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"a\":[1,2],\"b\":[]}\n"
+        );
+        let read: BTreeMap<String, Vec<u32>> = read_json(&path).unwrap();
+        assert_eq!(read, value);
+
+        write_json(&path, &value, true).unwrap();
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .starts_with("{\n  \"a\": [")
+        );
+        let read: BTreeMap<String, Vec<u32>> = read_json(&path).unwrap();
+        assert_eq!(read, value);
+        assert_eq!(dir.names(), ["state.json"]);
+    }
+
+    #[test]
+    fn json_errors_say_which_file_and_what_went_wrong() {
+        use std::collections::BTreeMap;
+        use std::error::Error as _;
+
+        let dir = TempDir::new("json-errors");
+        let path = dir.0.join("state.json");
+
+        let e = read_json::<Vec<u32>>(&path).unwrap_err();
+        assert!(
+            matches!(&e, JsonFileError::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound)
+        );
+        assert!(e.to_string().starts_with(&path.display().to_string()));
+
+        fs::write(&path, "{not json").unwrap();
+        let e = read_json::<Vec<u32>>(&path).unwrap_err();
+        assert!(matches!(e, JsonFileError::Parse { .. }), "{}", e);
+        assert!(e.to_string().contains(": invalid JSON: "), "{}", e);
+        // Valid JSON of the wrong shape is a parse error too.
+        fs::write(&path, "{\"a\": 1}").unwrap();
+        let e = read_json::<Vec<u32>>(&path).unwrap_err();
+        assert!(matches!(e, JsonFileError::Parse { .. }), "{}", e);
+        assert!(e.source().is_some());
+
+        // JSON object keys have to be strings; nothing gets written.
+        let e = write_json(&path, &BTreeMap::from([((1, 2), 3)]), false).unwrap_err();
+        assert!(matches!(e, JsonFileError::Serialize { .. }), "{}", e);
+        assert!(e.to_string().contains(": can't serialize: "), "{}", e);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"a\": 1}");
+
+        let e = write_json(&dir.0.join("missing").join("state.json"), &[1], false).unwrap_err();
+        assert!(
+            matches!(&e, JsonFileError::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound)
+        );
+        assert_eq!(dir.names(), ["state.json"]);
+    }
+
+    #[test]
+    fn failed_copy_leaves_no_partial_file() {
+        let dir = std::env::temp_dir().join(format!("copy-partial-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dst")).unwrap();
+        fs::write(dir.join("src"), "data").unwrap();
+        // A file can't be renamed over a non-empty directory.
+        fs::write(dir.join("dst").join("inside"), "").unwrap();
+
+        let result = copy_file_with_progress(&dir.join("src"), &dir.join("dst"), |_| {});
+        let partial_left = dir.join("dst.partial").exists();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(result.is_err());
+        assert!(!partial_left);
+    }
+}
@@ -0,0 +1,245 @@
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+
+use super::codec::CodecRegistry;
+use super::command::Command;
+use super::compat::{self, Subject};
+use super::echo::EchoServer;
+use super::protocol::{FrameDecoder, encode_frame, read_frame};
+use super::scope::panic_message;
+use super::sim::SimRng;
+use super::stable_hash::stable_hash;
+use super::transport::Handler;
+
+// A seeded fuzzer for everything that parses bytes off the wire: framing,
+// the codecs, the command language, and the echo handler behind them.
+//
+// Inputs are whole connection streams. Each one is replayed in-process,
+// fed to a `FrameDecoder` in randomly sized pieces the way TCP might
+// deliver it, and every frame that comes out goes through the rest of the
+// stack. An input fails if anything panics, or if one of these breaks:
+//
+// - the decoder never holds more than one unfinished frame (bounded memory,
+//   whatever the header claims);
+// - `read_frame` on the same bytes agrees with the decoder, frame for frame;
+// - the echo handler replies with exactly the frame it was sent;
+// - a payload the binary codec accepts re-encodes to the same bytes.
+//
+// New inputs come from mutating the seeds (captured streams) and any
+// corpus files: bit flips, inserted and deleted bytes, splices, truncation,
+// and headers rewritten to interesting lengths. Everything is derived from
+// the seed, so a failing run can be repeated exactly.
+
+// Small, so oversized headers are easy to hit and memory stays small.
+pub const FUZZ_MAX_FRAME_LEN: usize = 64 * 1024;
+
+// Inputs longer than this are cut down after mutating.
+const MAX_INPUT_LEN: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub input: Vec<u8>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FuzzReport {
+    pub executed: u64,
+    pub failures: Vec<Failure>,
+}
+
+// Byte streams as a client would send them: every compat fixture as a
+// framed payload, several frames back to back, and a few awkward shapes.
+pub fn seeds() -> Vec<Vec<u8>> {
+    let mut seeds = Vec::new();
+    let mut all = Vec::new();
+    for fixture in compat::fixtures() {
+        let stream = match fixture.subject {
+            Subject::Frame(_) => fixture.bytes,
+            Subject::Value(..) => encode_frame(&fixture.bytes),
+        };
+        all.extend_from_slice(&stream);
+        seeds.push(stream);
+    }
+    seeds.push(all);
+    seeds.push(encode_frame(b"send \"hi\" x3"));
+    seeds.push(encode_frame(b"subscribe metrics.*\nget /path"));
+    // Half a frame, and a header on its own.
+    seeds.push(encode_frame(b"truncated")[..7].to_vec());
+    seeds.push(vec![0, 0, 1, 0]);
+    seeds
+}
+
+// Replay `input` through the parsing stack. Err says what went wrong,
+// panics included.
+pub fn check(input: &[u8]) -> Result<(), String> {
+    match panic::catch_unwind(AssertUnwindSafe(|| check_unguarded(input))) {
+        Ok(result) => result,
+        Err(payload) => Err(format!("panicked: {}", panic_message(&*payload))),
+    }
+}
+
+fn check_unguarded(input: &[u8]) -> Result<(), String> {
+    // Where the stream is split depends only on its contents, so a saved
+    // input fails the same way every time.
+    let mut rng = SimRng::new(stable_hash(input, 0));
+    let codecs = CodecRegistry::default();
+    let mut decoder = FrameDecoder::new(FUZZ_MAX_FRAME_LEN);
+    let mut reader = Cursor::new(input);
+    let mut server = EchoServer::default();
+    let mut rest = input;
+    let mut decoder_failed = false;
+    while !rest.is_empty() && !decoder_failed {
+        let n = (rng.range(1, 64) as usize).min(rest.len());
+        decoder.push(&rest[..n]);
+        rest = &rest[n..];
+        loop {
+            let frame = match decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => {
+                    decoder_failed = true;
+                    break;
+                }
+            };
+            match read_frame(&mut reader, FUZZ_MAX_FRAME_LEN) {
+                Ok(Some(read)) if read == frame => {}
+                other => {
+                    return Err(format!(
+                        "read_frame disagrees with the decoder: got {:?}",
+                        other.map(|f| f.map(|f| f.len()))
+                    ));
+                }
+            }
+            check_payload(&frame, &codecs, &mut server)?;
+        }
+        let limit = FUZZ_MAX_FRAME_LEN + 4;
+        if decoder.buffered() >= limit {
+            return Err(format!(
+                "decoder holds {} bytes, over the {} an unfinished frame can take",
+                decoder.buffered(),
+                limit
+            ));
+        }
+    }
+    // Wherever the decoder stopped (the end, half a frame, an oversized
+    // header), `read_frame` mustn't find another frame there.
+    if let Ok(Some(frame)) = read_frame(&mut reader, FUZZ_MAX_FRAME_LEN) {
+        return Err(format!(
+            "read_frame found a {} byte frame the decoder didn't",
+            frame.len()
+        ));
+    }
+    Ok(())
+}
+
+fn check_payload(
+    payload: &[u8],
+    codecs: &CodecRegistry,
+    server: &mut EchoServer,
+) -> Result<(), String> {
+    let mut reply = Vec::new();
+    server
+        .on_message("fuzz", payload, &mut reply)
+        .map_err(|e| format!("echo failed: {}", e))?;
+    if reply != encode_frame(payload) {
+        return Err("echo reply isn't the frame it was sent".to_string());
+    }
+    for name in codecs.names() {
+        let codec = codecs.get(name).expect("listed by the registry");
+        let decoded = codec.decode(payload);
+        if name == "binary"
+            && let Ok(value) = decoded
+            && codec.encode(&value).ok().as_deref() != Some(payload)
+        {
+            return Err(format!("binary codec doesn't round-trip {:?}", value));
+        }
+    }
+    for line in String::from_utf8_lossy(payload).lines() {
+        let _ = Command::parse(line);
+    }
+    Ok(())
+}
+
+// A variation on one of `pool`'s inputs.
+pub fn mutate(pool: &[Vec<u8>], rng: &mut SimRng) -> Vec<u8> {
+    let pick = |rng: &mut SimRng| &pool[rng.range(0, pool.len() as u64 - 1) as usize];
+    let mut input = pick(rng).clone();
+    for _ in 0..rng.range(1, 4) {
+        let at = |rng: &mut SimRng, input: &Vec<u8>| rng.range(0, input.len() as u64) as usize;
+        match rng.range(0, 5) {
+            // Flip a bit.
+            0 if !input.is_empty() => {
+                let i = at(rng, &input).min(input.len() - 1);
+                input[i] ^= 1 << rng.range(0, 7);
+            }
+            // Insert random bytes.
+            1 => {
+                let i = at(rng, &input);
+                let bytes: Vec<u8> = (0..rng.range(1, 8)).map(|_| rng.next_u64() as u8).collect();
+                input.splice(i..i, bytes);
+            }
+            // Delete a run of bytes.
+            2 if !input.is_empty() => {
+                let i = at(rng, &input).min(input.len() - 1);
+                let end = (i + rng.range(1, 8) as usize).min(input.len());
+                input.drain(i..end);
+            }
+            // Splice in part of another input.
+            3 => {
+                let other = pick(rng);
+                let from = rng.range(0, other.len() as u64) as usize;
+                let to = rng.range(from as u64, other.len() as u64) as usize;
+                let i = at(rng, &input);
+                input.splice(i..i, other[from..to].iter().copied());
+            }
+            // Cut the stream short.
+            4 => {
+                let i = at(rng, &input);
+                input.truncate(i);
+            }
+            // Overwrite four bytes with a length at or around a limit.
+            _ => {
+                let lengths = [
+                    0,
+                    1,
+                    FUZZ_MAX_FRAME_LEN as u32,
+                    FUZZ_MAX_FRAME_LEN as u32 + 1,
+                    u32::MAX,
+                ];
+                let len = lengths[rng.range(0, lengths.len() as u64 - 1) as usize];
+                let i = at(rng, &input);
+                let end = (i + 4).min(input.len());
+                input.splice(i..end, len.to_be_bytes());
+            }
+        }
+    }
+    input.truncate(MAX_INPUT_LEN);
+    input
+}
+
+// Check every input in `corpus` as it is, then `iterations` mutations of
+// it and the seeds.
+pub fn run(seed: u64, iterations: u64, corpus: &[Vec<u8>]) -> FuzzReport {
+    let mut report = FuzzReport::default();
+    let record = |input: &[u8], report: &mut FuzzReport| {
+        report.executed += 1;
+        if let Err(reason) = check(input) {
+            report.failures.push(Failure {
+                input: input.to_vec(),
+                reason,
+            });
+        }
+    };
+    for input in corpus {
+        record(input, &mut report);
+    }
+    let mut pool = seeds();
+    pool.extend(corpus.iter().cloned());
+    let mut rng = SimRng::new(seed);
+    for _ in 0..iterations {
+        let input = mutate(&pool, &mut rng);
+        record(&input, &mut report);
+    }
+    report
+}
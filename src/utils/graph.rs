@@ -0,0 +1,300 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+
+// Directed graph stored as adjacency lists.
+//
+// Nodes are kept in insertion order and referred to internally by index, so
+// every traversal (and therefore every topological order) is deterministic.
+// An edge `a -> b` means "a comes before b" (e.g. "a must be initialized
+// before b", or "${A} refers to ${B}").
+
+#[derive(Debug, Clone)]
+pub struct Graph<N> {
+    nodes: Vec<N>,
+    index: HashMap<N, usize>,
+    edges: Vec<Vec<usize>>,
+}
+
+// Returned when a topological sort is impossible. `path` starts and ends
+// with the same node, e.g. [A, B, A].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle<N> {
+    pub path: Vec<N>,
+}
+
+impl<N: fmt::Display> fmt::Display for Cycle<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cycle detected: ")?;
+        for (i, node) in self.path.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", node)?;
+        }
+        Ok(())
+    }
+}
+
+impl<N: fmt::Debug + fmt::Display> std::error::Error for Cycle<N> {}
+
+impl<N> Default for Graph<N>
+where
+    N: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N> Graph<N>
+where
+    N: Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    // Add a node (no-op if it's already there).
+    pub fn add_node(&mut self, node: N) {
+        self.node_index(node);
+    }
+
+    // Add the edge `from -> to`, adding either node if needed.
+    pub fn add_edge(&mut self, from: N, to: N) {
+        let from = self.node_index(from);
+        let to = self.node_index(to);
+        if !self.edges[from].contains(&to) {
+            self.edges[from].push(to);
+        }
+    }
+
+    pub fn contains(&self, node: &N) -> bool {
+        self.index.contains_key(node)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &N> {
+        self.nodes.iter()
+    }
+
+    // Nodes that `node` has an edge to.
+    pub fn neighbors(&self, node: &N) -> impl Iterator<Item = &N> {
+        let targets = match self.index.get(node) {
+            Some(&i) => self.edges[i].as_slice(),
+            None => &[],
+        };
+        targets.iter().map(|&i| &self.nodes[i])
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Order the nodes so every edge points forward (Kahn's algorithm).
+    // Fails with one of the cycles if the graph isn't acyclic.
+    pub fn topo_sort(&self) -> Result<Vec<N>, Cycle<N>> {
+        let mut in_degree = vec![0usize; self.nodes.len()];
+        for targets in &self.edges {
+            for &t in targets {
+                in_degree[t] += 1;
+            }
+        }
+
+        let mut queue: VecDeque<usize> = (0..self.nodes.len())
+            .filter(|&i| in_degree[i] == 0)
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+
+        while let Some(i) = queue.pop_front() {
+            order.push(self.nodes[i].clone());
+            for &t in &self.edges[i] {
+                in_degree[t] -= 1;
+                if in_degree[t] == 0 {
+                    queue.push_back(t);
+                }
+            }
+        }
+
+        if order.len() == self.nodes.len() {
+            Ok(order)
+        } else {
+            Err(self
+                .find_cycle()
+                .expect("nodes left over after a topological sort means there is a cycle"))
+        }
+    }
+
+    // Group the nodes into levels: level 0 has no incoming edges, and every
+    // other node is one level after the latest of its predecessors. Nodes in
+    // the same level don't depend on each other, so they can be processed
+    // at the same time. Fails like `topo_sort` on a cycle.
+    pub fn topo_levels(&self) -> Result<Vec<Vec<N>>, Cycle<N>> {
+        let order = self.topo_sort()?;
+        let mut level = vec![0usize; self.nodes.len()];
+        for node in &order {
+            let i = self.index[node];
+            for &t in &self.edges[i] {
+                level[t] = level[t].max(level[i] + 1);
+            }
+        }
+        let mut levels: Vec<Vec<N>> = Vec::new();
+        for node in order {
+            let l = level[self.index[&node]];
+            if levels.len() <= l {
+                levels.resize_with(l + 1, Vec::new);
+            }
+            levels[l].push(node);
+        }
+        Ok(levels)
+    }
+
+    // Find a cycle, if there is one, using a depth-first search that keeps
+    // track of the nodes on the current path.
+    pub fn find_cycle(&self) -> Option<Cycle<N>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            Unvisited,
+            OnPath,
+            Done,
+        }
+
+        let mut marks = vec![Mark::Unvisited; self.nodes.len()];
+
+        for start in 0..self.nodes.len() {
+            if marks[start] != Mark::Unvisited {
+                continue;
+            }
+
+            // Explicit stack of (node, next edge to look at) instead of
+            // recursion, so deep graphs can't overflow the call stack.
+            let mut stack = vec![(start, 0usize)];
+            marks[start] = Mark::OnPath;
+
+            while let Some(&mut (node, ref mut next_edge)) = stack.last_mut() {
+                if let Some(&target) = self.edges[node].get(*next_edge) {
+                    *next_edge += 1;
+                    match marks[target] {
+                        Mark::Unvisited => {
+                            marks[target] = Mark::OnPath;
+                            stack.push((target, 0));
+                        }
+                        Mark::OnPath => {
+                            // Back edge: the cycle is the part of the current
+                            // path from `target` onwards, closed with `target`.
+                            let from = stack.iter().position(|&(n, _)| n == target).unwrap();
+                            let mut path: Vec<N> = stack[from..]
+                                .iter()
+                                .map(|&(n, _)| self.nodes[n].clone())
+                                .collect();
+                            path.push(self.nodes[target].clone());
+                            return Some(Cycle { path });
+                        }
+                        Mark::Done => {}
+                    }
+                } else {
+                    marks[node] = Mark::Done;
+                    stack.pop();
+                }
+            }
+        }
+        None
+    }
+
+    pub fn has_cycle(&self) -> bool {
+        self.find_cycle().is_some()
+    }
+
+    fn node_index(&mut self, node: N) -> usize {
+        if let Some(&i) = self.index.get(&node) {
+            return i;
+        }
+        let i = self.nodes.len();
+        self.index.insert(node.clone(), i);
+        self.nodes.push(node);
+        self.edges.push(Vec::new());
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&'static str, &'static str)]) -> Graph<&'static str> {
+        let mut graph = Graph::new();
+        for &(from, to) in edges {
+            graph.add_edge(from, to);
+        }
+        graph
+    }
+
+    #[test]
+    fn topo_sort_puts_every_edge_forward_ties_in_insertion_order() {
+        let mut graph = graph(&[("config", "db"), ("config", "log"), ("db", "server")]);
+        graph.add_edge("log", "server");
+        graph.add_edge("config", "db"); // a repeat is ignored
+        graph.add_node("metrics");
+
+        let order = graph.topo_sort().unwrap();
+
+        assert_eq!(order, ["config", "metrics", "db", "log", "server"]);
+        assert_eq!(graph.neighbors(&"config").count(), 2);
+        assert_eq!(graph.len(), 5);
+    }
+
+    #[test]
+    fn topo_levels_groups_what_can_run_together() {
+        let graph = graph(&[("a", "b"), ("a", "c"), ("b", "d"), ("c", "d"), ("a", "d")]);
+
+        assert_eq!(
+            graph.topo_levels().unwrap(),
+            [vec!["a"], vec!["b", "c"], vec!["d"]]
+        );
+        assert!(Graph::<u32>::new().topo_levels().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_cycle_is_reported_as_a_closed_path() {
+        let graph = graph(&[
+            ("start", "a"),
+            ("a", "b"),
+            ("b", "c"),
+            ("c", "a"),
+            ("c", "end"),
+        ]);
+
+        let cycle = graph.find_cycle().unwrap();
+        assert_eq!(cycle.path, ["a", "b", "c", "a"]);
+        assert_eq!(cycle.to_string(), "cycle detected: a -> b -> c -> a");
+        assert_eq!(graph.topo_sort().unwrap_err(), cycle);
+        assert!(graph.topo_levels().is_err());
+    }
+
+    #[test]
+    fn a_self_loop_is_a_cycle_and_a_diamond_is_not() {
+        let looped = graph(&[("a", "b"), ("b", "b")]);
+        assert_eq!(looped.find_cycle().unwrap().path, ["b", "b"]);
+
+        let diamond = graph(&[("a", "b"), ("a", "c"), ("b", "d"), ("c", "d")]);
+        assert!(!diamond.has_cycle());
+    }
+
+    #[test]
+    fn a_long_chain_doesnt_overflow_the_stack() {
+        let mut graph = Graph::new();
+        for i in 0..200_000u32 {
+            graph.add_edge(i, i + 1);
+        }
+        assert!(!graph.has_cycle());
+        graph.add_edge(200_000, 0);
+        assert_eq!(graph.find_cycle().unwrap().path.len(), 200_002);
+    }
+}
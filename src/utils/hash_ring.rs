@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::stable_hash::{StableHasher, stable_hash};
+
+// Consistent hashing ring.
+//
+// Each node is placed on a circle of u64 positions many times ("virtual
+// nodes") so keys spread evenly. A key belongs to the first node found
+// walking clockwise from the key's own position. Adding or removing a node
+// only moves the keys in the arcs next to that node's positions, roughly
+// 1/N of all keys, instead of reshuffling everything like `hash % N` would.
+//
+// Positions are `stable_hash`es of bytes, so every node computes the same
+// ring whatever its word size or byte order.
+
+const DEFAULT_VNODES: u32 = 100;
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: u32,
+    ring: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashRing {
+    pub fn new() -> Self {
+        Self::with_vnodes(DEFAULT_VNODES)
+    }
+
+    // More virtual nodes gives a more even spread at the cost of memory.
+    pub fn with_vnodes(vnodes: u32) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    // Returns false if the node was already on the ring.
+    pub fn add_node(&mut self, node: &str) -> bool {
+        if !self.nodes.insert(node.to_string()) {
+            return false;
+        }
+        for replica in 0..self.vnodes {
+            self.ring
+                .insert(vnode_position(node, replica), node.to_string());
+        }
+        true
+    }
+
+    // Returns false if the node wasn't on the ring.
+    pub fn remove_node(&mut self, node: &str) -> bool {
+        if !self.nodes.remove(node) {
+            return false;
+        }
+        // Only drop positions this node still owns; on the (astronomically
+        // unlikely) chance two vnodes collided, the other owner keeps it.
+        self.ring.retain(|_, owner| owner != node);
+        true
+    }
+
+    // The node that owns `key`, or None if the ring is empty.
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let position = stable_hash(key.as_bytes(), 0);
+        self.ring
+            .range(position..)
+            .next()
+            .or_else(|| self.ring.iter().next()) // wrap around the circle
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+fn vnode_position(node: &str, replica: u32) -> u64 {
+    let mut hasher = StableHasher::new(0);
+    hasher.write_field(node.as_bytes());
+    hasher.write_u64(replica as u64);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: usize = 20_000;
+
+    fn ring_of(nodes: usize) -> HashRing {
+        let mut ring = HashRing::new();
+        for i in 0..nodes {
+            ring.add_node(&format!("node-{}", i));
+        }
+        ring
+    }
+
+    fn owners(ring: &HashRing) -> Vec<String> {
+        (0..KEYS)
+            .map(|i| ring.node_for(&format!("topic-{}", i)).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn adding_a_node_moves_about_one_nth_of_the_keys_to_it() {
+        for nodes in [3, 10] {
+            let mut ring = ring_of(nodes);
+            let before = owners(&ring);
+            ring.add_node("newcomer");
+            let after = owners(&ring);
+
+            let moved: Vec<_> = (0..KEYS).filter(|&i| before[i] != after[i]).collect();
+            // Every key that moved went to the new node...
+            assert!(moved.iter().all(|&i| after[i] == "newcomer"));
+            // ...and it took about its fair share.
+            let share = moved.len() as f64 / KEYS as f64;
+            let fair = 1.0 / (nodes + 1) as f64;
+            assert!(
+                (share - fair).abs() < fair * 0.3,
+                "{} nodes: moved {}",
+                nodes,
+                share
+            );
+        }
+    }
+
+    #[test]
+    fn removing_a_node_moves_only_its_keys() {
+        let mut ring = ring_of(8);
+        let before = owners(&ring);
+        ring.remove_node("node-3");
+        let after = owners(&ring);
+
+        for i in 0..KEYS {
+            if before[i] == "node-3" {
+                assert_ne!(after[i], "node-3");
+            } else {
+                assert_eq!(before[i], after[i]);
+            }
+        }
+        let share = before.iter().filter(|n| *n == "node-3").count() as f64 / KEYS as f64;
+        assert!(
+            (share - 1.0 / 8.0).abs() < 0.3 / 8.0,
+            "node-3 owned {}",
+            share
+        );
+    }
+
+    #[test]
+    fn remove_then_add_restores_ownership() {
+        let mut ring = ring_of(5);
+        let before = owners(&ring);
+        assert!(ring.remove_node("node-2"));
+        assert!(!ring.remove_node("node-2"));
+        assert!(ring.add_node("node-2"));
+        assert!(!ring.add_node("node-2"));
+        assert_eq!(owners(&ring), before);
+    }
+
+    #[test]
+    fn empty_and_single_node_rings() {
+        let mut ring = HashRing::with_vnodes(0);
+        assert!(ring.is_empty());
+        assert_eq!(ring.node_for("k"), None);
+        ring.add_node("only");
+        assert_eq!(ring.len(), 1);
+        assert!(owners(&ring).iter().all(|n| n == "only"));
+    }
+
+    #[test]
+    fn positions_are_pinned() {
+        // Nodes on any platform have to agree on these.
+        assert_eq!(vnode_position("node-a", 7), 0x9070_8fa1_d98c_28a3);
+        let ring = ring_of(4);
+        let owners: Vec<_> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|key| ring.node_for(key).unwrap())
+            .collect();
+        assert_eq!(owners, PINNED_OWNERS);
+    }
+
+    const PINNED_OWNERS: [&str; 4] = ["node-1", "node-0", "node-2", "node-0"];
+}
@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+// Health of a program's subsystems, for liveness and readiness checks.
+//
+// Subsystems register once and then report how they're doing whenever
+// they find out (each accept, each storage write, a periodic probe...).
+// A component's reported health only takes effect after it has been seen
+// several times in a row: `fall` reports to get worse, `rise` to get
+// better. One failed probe doesn't take a component out, and a flapping
+// one doesn't bounce readiness up and down with every report.
+//
+// Each component has a role. Liveness components are the program's own
+// machinery (the listener, the scheduler): if one goes Down, restarting
+// the program may help. Readiness components are things it depends on
+// (storage, cluster links): if one is Down the program should get no
+// traffic, but restarting it won't help. Info components are only
+// reported.
+//
+// Live: no liveness component has gone Down. Ready: every liveness and
+// readiness component is Up or Degraded. Components start out Down, so a
+// program isn't ready before it has started, but it is live: only going
+// Down after having been up counts against liveness.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+    Up,
+    Degraded,
+    Down,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Health::Up => "up",
+            Health::Degraded => "degraded",
+            Health::Down => "down",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Liveness,
+    Readiness,
+    Info,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthConfig {
+    // Consecutive reports of a better state needed to switch to it.
+    pub rise: u32,
+    // Consecutive reports of a worse state needed to switch to it.
+    pub fall: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self { rise: 2, fall: 3 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentStatus {
+    pub name: String,
+    pub role: Role,
+    // The state in effect, after hysteresis.
+    pub health: Health,
+    // The detail that came with the latest report, whatever its state.
+    pub detail: String,
+    // How many times `health` has changed.
+    pub changes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub live: bool,
+    pub ready: bool,
+    pub components: Vec<ComponentStatus>,
+}
+
+// "live, ready", then any component that isn't up: "live, not ready;
+// counters down (disk full)".
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let live = if self.live { "live" } else { "not live" };
+        let ready = if self.ready { "ready" } else { "not ready" };
+        write!(f, "{}, {}", live, ready)?;
+        for c in self.components.iter().filter(|c| c.health != Health::Up) {
+            write!(f, "; {} {} ({})", c.name, c.health, c.detail)?;
+        }
+        Ok(())
+    }
+}
+
+struct Component {
+    status: ComponentStatus,
+    // A state reported but not yet in effect, and how many times in a row.
+    pending: Option<(Health, u32)>,
+}
+
+#[derive(Default)]
+pub struct HealthRegistry {
+    config: HealthConfig,
+    components: Mutex<BTreeMap<String, Component>>,
+}
+
+impl HealthRegistry {
+    pub fn new(config: HealthConfig) -> Self {
+        Self {
+            config,
+            components: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Start tracking `name`, as Down until it reports otherwise.
+    // Registering again changes the role and keeps the state.
+    pub fn register(&self, name: &str, role: Role) {
+        let mut components = self.lock();
+        components
+            .entry(name.to_string())
+            .and_modify(|c| c.status.role = role)
+            .or_insert_with(|| Component {
+                status: ComponentStatus {
+                    name: name.to_string(),
+                    role,
+                    health: Health::Down,
+                    detail: "not reported yet".to_string(),
+                    changes: 0,
+                },
+                pending: None,
+            });
+    }
+
+    // Report `name`'s current health. Unregistered names are registered as
+    // `Role::Info`. Returns the state now in effect.
+    pub fn report(&self, name: &str, health: Health, detail: &str) -> Health {
+        self.register_if_missing(name);
+        let mut components = self.lock();
+        let component = components.get_mut(name).expect("registered above");
+        component.status.detail = detail.to_string();
+        if health == component.status.health {
+            component.pending = None;
+            return health;
+        }
+        let seen = match component.pending {
+            Some((pending, count)) if pending == health => count + 1,
+            _ => 1,
+        };
+        let needed = if health < component.status.health {
+            self.config.rise
+        } else {
+            self.config.fall
+        };
+        if seen >= needed {
+            component.status.health = health;
+            component.status.changes += 1;
+            component.pending = None;
+        } else {
+            component.pending = Some((health, seen));
+        }
+        component.status.health
+    }
+
+    // The state in effect for `name`, if it's registered.
+    pub fn health_of(&self, name: &str) -> Option<Health> {
+        self.lock().get(name).map(|c| c.status.health)
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.report_now().live
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.report_now().ready
+    }
+
+    // Everything at once, components sorted by name.
+    pub fn report_now(&self) -> HealthReport {
+        let components: Vec<ComponentStatus> =
+            self.lock().values().map(|c| c.status.clone()).collect();
+        let live = !components
+            .iter()
+            .any(|c| c.role == Role::Liveness && c.health == Health::Down && c.changes > 0);
+        let ready = live
+            && components
+                .iter()
+                .filter(|c| c.role != Role::Info)
+                .all(|c| c.health != Health::Down);
+        HealthReport {
+            live,
+            ready,
+            components,
+        }
+    }
+
+    fn register_if_missing(&self, name: &str) {
+        if !self.lock().contains_key(name) {
+            self.register(name, Role::Info);
+        }
+    }
+
+    // Only plain field updates happen under the lock, so a poisoned one
+    // still guards consistent data.
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Component>> {
+        self.components.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> HealthRegistry {
+        let health = HealthRegistry::new(HealthConfig { rise: 2, fall: 3 });
+        health.register("listener", Role::Liveness);
+        health.register("storage", Role::Readiness);
+        health
+    }
+
+    #[test]
+    fn a_new_state_takes_effect_after_enough_reports_in_a_row() {
+        let health = registry();
+        assert_eq!(health.report("storage", Health::Up, "ok"), Health::Down);
+        assert_eq!(health.report("storage", Health::Up, "ok"), Health::Up);
+
+        // Two failures, a success, then three failures: only the last run
+        // counts.
+        health.report("storage", Health::Down, "full");
+        health.report("storage", Health::Down, "full");
+        assert_eq!(health.report("storage", Health::Up, "ok"), Health::Up);
+        for _ in 0..2 {
+            assert_eq!(health.report("storage", Health::Down, "full"), Health::Up);
+        }
+        assert_eq!(health.report("storage", Health::Down, "full"), Health::Down);
+        assert_eq!(health.health_of("storage"), Some(Health::Down));
+        assert_eq!(health.report_now().components[1].changes, 2);
+    }
+
+    #[test]
+    fn liveness_and_readiness() {
+        let health = registry();
+        // Not started yet: live, but not ready.
+        assert!(health.is_live() && !health.is_ready());
+
+        for _ in 0..2 {
+            health.report("listener", Health::Up, "bound");
+            health.report("storage", Health::Degraded, "slow");
+        }
+        assert!(health.is_live() && health.is_ready());
+        // Info components don't count.
+        health.report("cache", Health::Down, "gone");
+        assert!(health.is_ready());
+
+        for _ in 0..3 {
+            health.report("storage", Health::Down, "full");
+        }
+        assert!(health.is_live() && !health.is_ready());
+        for _ in 0..3 {
+            health.report("listener", Health::Down, "accept failing");
+        }
+        assert!(!health.is_live() && !health.is_ready());
+    }
+
+    #[test]
+    fn the_report_names_what_isnt_up() {
+        let health = registry();
+        for _ in 0..2 {
+            health.report("listener", Health::Up, "bound");
+            health.report("storage", Health::Degraded, "slow");
+        }
+        assert_eq!(
+            health.report_now().to_string(),
+            "live, ready; storage degraded (slow)"
+        );
+        assert_eq!(health.health_of("nothing"), None);
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use super::env_os;
+
+// Message catalogs for user-facing strings.
+//
+// Messages live in one TOML file per locale (`locales/en.toml`,
+// `locales/de.toml`, ...) as `key = "template"` pairs, optionally grouped
+// under `[section]` headers, which prefix the keys (`deleted = ...` under
+// `[dedup]` is `dedup.deleted`). Templates take named parameters: `"Deleted {path}"`.
+// Use `{{` and `}}` for literal braces.
+//
+// The locale comes from RUST_PRACTICE_LOCALE, else the usual LC_ALL /
+// LC_MESSAGES / LANG. A lookup tries the locale itself (`pt-BR`), then its
+// language (`pt`), then the built-in English catalog, so a partial
+// translation still works. A key missing everywhere comes back as-is.
+
+const BUILTIN_MESSAGES: &str = include_str!("../../locales/en.toml");
+
+// Where to look for locale files unless RUST_PRACTICE_LOCALE_DIR says
+// otherwise.
+const DEFAULT_LOCALE_DIR: &str = "locales";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    // Most specific first; the built-in English catalog is always last.
+    chain: Vec<HashMap<String, String>>,
+}
+
+impl Catalog {
+    // Just the built-in English messages.
+    pub fn builtin() -> Self {
+        let messages = parse_messages(BUILTIN_MESSAGES).expect("built-in catalog parses");
+        Self {
+            chain: vec![messages],
+        }
+    }
+
+    // The catalog for `locale`, loading whichever of its fallback files
+    // exist in `dir`. A file that can't be parsed is skipped with a warning
+    // on stderr; a broken translation shouldn't stop the program.
+    pub fn load(dir: &Path, locale: &str) -> Self {
+        let mut catalog = Self::builtin();
+        let mut chain = Vec::new();
+        for tag in fallback_chain(locale) {
+            let path = dir.join(format!("{}.toml", tag));
+            let Ok(source) = fs::read_to_string(&path) else {
+                continue;
+            };
+            match parse_messages(&source) {
+                Ok(messages) => chain.push(messages),
+                Err(e) => eprintln!("warning: ignoring {}: {}", path.display(), e),
+            }
+        }
+        chain.append(&mut catalog.chain);
+        catalog.chain = chain;
+        catalog
+    }
+
+    // The catalog for the locale and directory picked by the environment.
+    pub fn from_env() -> Self {
+        let dir = env_os::var_path("RUST_PRACTICE_LOCALE_DIR")
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_LOCALE_DIR));
+        match locale_from_env() {
+            Some(locale) => Self::load(&dir, &locale),
+            None => Self::builtin(),
+        }
+    }
+
+    pub fn get(&self, key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+        match self.chain.iter().find_map(|messages| messages.get(key)) {
+            Some(template) => render(template, args),
+            None => key.to_string(),
+        }
+    }
+}
+
+static GLOBAL: OnceLock<Catalog> = OnceLock::new();
+
+// Install the catalog used by `tr`. Only the first call has an effect.
+pub fn init(catalog: Catalog) {
+    let _ = GLOBAL.set(catalog);
+}
+
+// Look up `key` in the global catalog (the built-in one if `init` wasn't
+// called).
+pub fn tr(key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    GLOBAL.get_or_init(Catalog::builtin).get(key, args)
+}
+
+// The first of RUST_PRACTICE_LOCALE, LC_ALL, LC_MESSAGES, LANG that's set,
+// ignoring the "C" / "POSIX" locales, which mean "no preference".
+pub fn locale_from_env() -> Option<String> {
+    ["RUST_PRACTICE_LOCALE", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|key| env::var(key).ok())
+        .find(|value| !value.is_empty())
+        .filter(|value| !matches!(value.as_str(), "C" | "POSIX") && !value.starts_with("C."))
+}
+
+// `pt_BR.UTF-8@euro` -> ["pt-BR", "pt"]: the tag with encoding and modifier
+// dropped, then the bare language.
+pub fn fallback_chain(locale: &str) -> Vec<String> {
+    let tag = locale
+        .split(['.', '@'])
+        .next()
+        .unwrap_or_default()
+        .replace('_', "-");
+    let mut chain = Vec::new();
+    if tag.is_empty() {
+        return chain;
+    }
+    if let Some((language, _)) = tag.split_once('-') {
+        chain.push(tag.clone());
+        chain.push(language.to_string());
+    } else {
+        chain.push(tag);
+    }
+    chain
+}
+
+// Parse the subset of TOML the catalogs use: `[section]` headers, `#`
+// comments, and `key = "string"` with the usual escapes.
+pub fn parse_messages(source: &str) -> Result<HashMap<String, String>, CatalogError> {
+    let mut messages = HashMap::new();
+    let mut section = String::new();
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let err = |message: &str| CatalogError {
+            line,
+            message: message.to_string(),
+        };
+        let text = raw.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = text.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .ok_or_else(|| err("unterminated section header"))?
+                .trim();
+            if !is_key(name) {
+                return Err(err("invalid section name"));
+            }
+            section = format!("{}.", name);
+            continue;
+        }
+
+        let (key, value) = text
+            .split_once('=')
+            .ok_or_else(|| err("expected `key = \"value\"`"))?;
+        let key = key.trim();
+        if !is_key(key) {
+            return Err(err("invalid key"));
+        }
+        let value = parse_string(value.trim()).map_err(|m| err(&m))?;
+        if messages
+            .insert(format!("{}{}", section, key), value)
+            .is_some()
+        {
+            return Err(err(&format!("duplicate key '{}{}'", section, key)));
+        }
+    }
+    Ok(messages)
+}
+
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// A basic TOML string, possibly followed by a comment.
+fn parse_string(text: &str) -> Result<String, String> {
+    let mut chars = text
+        .strip_prefix('"')
+        .ok_or("expected a quoted string")?
+        .chars();
+    let mut out = String::new();
+    loop {
+        match chars.next().ok_or("unterminated string")? {
+            '"' => break,
+            '\\' => out.push(match chars.next().ok_or("dangling escape")? {
+                'n' => '\n',
+                't' => '\t',
+                '"' => '"',
+                '\\' => '\\',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or("bad \\u escape")?
+                }
+                other => return Err(format!("unknown escape '\\{}'", other)),
+            }),
+            c => out.push(c),
+        }
+    }
+    let rest = chars.as_str().trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err("unexpected text after string".to_string());
+    }
+    Ok(out)
+}
+
+// Fill in `{name}` parameters. Unknown names are left in place so a typo in
+// a translation shows up instead of silently vanishing.
+fn render(template: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let param = tail
+            .strip_prefix('{')
+            .and_then(|t| t.split_once('}'))
+            .and_then(|(name, after)| {
+                let (_, value) = args.iter().find(|(n, _)| *n == name)?;
+                Some((value, after))
+            });
+        match param {
+            Some((value, after)) => {
+                out.push_str(&value.to_string());
+                rest = after;
+            }
+            None => {
+                out.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("i18n-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn fallback_chain_drops_encoding_and_modifier() {
+        assert_eq!(fallback_chain("pt_BR.UTF-8@euro"), ["pt-BR", "pt"]);
+        assert_eq!(fallback_chain("de"), ["de"]);
+        assert_eq!(fallback_chain("de.UTF-8"), ["de"]);
+        assert!(fallback_chain(".UTF-8").is_empty());
+    }
+
+    #[test]
+    fn a_lookup_falls_back_from_region_to_language_to_english() {
+        let dir = TempDir::new("fallback");
+        fs::write(dir.0.join("pt-BR.toml"), "greeting = \"Oi, {name}\"\n").unwrap();
+        fs::write(
+            dir.0.join("pt.toml"),
+            "greeting = \"Olá, {name}\"\nfarewell = \"Adeus\"\n",
+        )
+        .unwrap();
+
+        let catalog = Catalog::load(&dir.0, "pt_BR.UTF-8");
+
+        assert_eq!(catalog.get("greeting", &[("name", &"Ana")]), "Oi, Ana");
+        assert_eq!(catalog.get("farewell", &[]), "Adeus");
+        assert_eq!(catalog.get("usage", &[("syntax", &"x")]), "usage: x");
+        assert_eq!(catalog.get("no.such.key", &[]), "no.such.key");
+        // A locale with no files at all is plain English.
+        let catalog = Catalog::load(&dir.0, "fr_FR");
+        assert_eq!(catalog.get("greeting", &[]), "greeting");
+    }
+
+    #[test]
+    fn a_broken_translation_is_skipped_not_fatal() {
+        let dir = TempDir::new("broken");
+        fs::write(dir.0.join("pt-BR.toml"), "greeting = Oi\n").unwrap();
+        fs::write(dir.0.join("pt.toml"), "greeting = \"Olá\"\n").unwrap();
+
+        let catalog = Catalog::load(&dir.0, "pt-BR");
+
+        assert_eq!(catalog.get("greeting", &[]), "Olá");
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let source = "# comment\n\n[dedup]\ndeleted = \"x\" # trailing\n[bad\n";
+        assert_eq!(
+            parse_messages(source).unwrap_err(),
+            CatalogError {
+                line: 5,
+                message: "unterminated section header".to_string()
+            }
+        );
+        for (source, message) in [
+            ("a = \"1\"\na = \"2\"", "line 2: duplicate key 'a'"),
+            ("a b = \"1\"", "line 1: invalid key"),
+            ("a = \"\\q\"", "line 1: unknown escape '\\q'"),
+            ("a = \"open", "line 1: unterminated string"),
+            ("a = \"x\" y", "line 1: unexpected text after string"),
+            ("just text", "line 1: expected `key = \"value\"`"),
+        ] {
+            assert_eq!(parse_messages(source).unwrap_err().to_string(), message);
+        }
+
+        let messages = parse_messages("[s]\nk = \"tab\\there \\u00e9 \\\"q\\\"\"").unwrap();
+        assert_eq!(messages["s.k"], "tab\there é \"q\"");
+    }
+
+    #[test]
+    fn render_fills_parameters_and_keeps_the_rest() {
+        let args: &[(&str, &dyn fmt::Display)] = &[("n", &3), ("path", &"a.txt")];
+
+        assert_eq!(render("{n} files in {path}", args), "3 files in a.txt");
+        assert_eq!(render("{{n}} is {n}", args), "{n} is 3");
+        // A typo stays visible.
+        assert_eq!(render("{nn} and {path", args), "{nn} and {path");
+    }
+
+    #[test]
+    fn the_shipped_translations_only_use_english_keys_and_parameters() {
+        let english = parse_messages(BUILTIN_MESSAGES).unwrap();
+        let german = parse_messages(&fs::read_to_string("locales/de.toml").unwrap()).unwrap();
+
+        let params = |template: &str| -> Vec<String> {
+            let mut names: Vec<String> = template
+                .split('{')
+                .skip(1)
+                .filter_map(|s| s.split_once('}').map(|(name, _)| name.to_string()))
+                .collect();
+            names.sort();
+            names.dedup();
+            names
+        };
+        for (key, template) in &german {
+            let original = english
+                .get(key)
+                .unwrap_or_else(|| panic!("{} isn't in en.toml", key));
+            assert_eq!(params(template), params(original), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::lru::LruCache;
+
+// Run each request at most once per idempotency key.
+//
+// A client that times out can't tell whether its request ran, so it
+// retries with the same key. `execute` runs the handler for the first
+// request with a key and remembers the response; a retry within `ttl`
+// gets that response back without running the handler again. A retry
+// that arrives while the first attempt is still running waits for it
+// instead of running alongside it.
+//
+// Responses live in an LRU cache, so under pressure the oldest keys are
+// forgotten early; a retry after that runs the handler again. Whatever
+// the handler returns is cached, errors included: a retry should see the
+// same answer as the original.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdempotencyStats {
+    // Handler runs.
+    pub executed: u64,
+    // Requests answered from the cache.
+    pub replayed: u64,
+    // Requests that had to wait for an in-flight duplicate.
+    pub waited: u64,
+    // Cached responses found past their TTL and dropped.
+    pub expired: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<R> {
+    // The handler ran for this request.
+    Executed(R),
+    // An earlier request with the same key ran it; this is its response.
+    Replayed(R),
+}
+
+impl<R> Outcome<R> {
+    pub fn into_inner(self) -> R {
+        match self {
+            Outcome::Executed(r) | Outcome::Replayed(r) => r,
+        }
+    }
+
+    pub fn was_replayed(&self) -> bool {
+        matches!(self, Outcome::Replayed(_))
+    }
+}
+
+struct Completed<R> {
+    response: R,
+    at: Instant,
+}
+
+struct State<R> {
+    completed: LruCache<String, Completed<R>>,
+    in_flight: HashSet<String>,
+    stats: IdempotencyStats,
+}
+
+pub struct IdempotencyStore<R> {
+    ttl: Duration,
+    state: Mutex<State<R>>,
+    // Signalled whenever a key leaves `in_flight`.
+    finished: Condvar,
+}
+
+impl<R: Clone> IdempotencyStore<R> {
+    // Remember up to `capacity` responses, each for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(State {
+                completed: LruCache::new(capacity),
+                in_flight: HashSet::new(),
+                stats: IdempotencyStats::default(),
+            }),
+            finished: Condvar::new(),
+        }
+    }
+
+    pub fn execute(&self, key: &str, handler: impl FnOnce() -> R) -> Outcome<R> {
+        let mut state = self.lock();
+        let mut waited = false;
+        loop {
+            if let Some(response) = self.cached(&mut state, key) {
+                state.stats.replayed += 1;
+                return Outcome::Replayed(response);
+            }
+            if !state.in_flight.contains(key) {
+                break;
+            }
+            if !waited {
+                state.stats.waited += 1;
+                waited = true;
+            }
+            state = self.finished.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        // Nobody has a response for this key and nobody is working on one:
+        // it's ours. If the handler panics, the guard still releases the key
+        // so waiters can try themselves.
+        state.in_flight.insert(key.to_string());
+        state.stats.executed += 1;
+        drop(state);
+
+        let guard = InFlight { store: self, key };
+        let response = handler();
+        let mut state = self.lock();
+        let _ = state.completed.put(
+            key.to_string(),
+            Completed {
+                response: response.clone(),
+                at: Instant::now(),
+            },
+        );
+        drop(state);
+        drop(guard);
+        Outcome::Executed(response)
+    }
+
+    // Forget `key`, e.g. once the client says it won't retry.
+    pub fn forget(&self, key: &str) {
+        self.lock().completed.remove(&key.to_string());
+    }
+
+    pub fn stats(&self) -> IdempotencyStats {
+        self.lock().stats
+    }
+
+    fn cached(&self, state: &mut State<R>, key: &str) -> Option<R> {
+        let key = key.to_string();
+        let at = state.completed.get(&key)?.at;
+        if at.elapsed() > self.ttl {
+            state.completed.remove(&key);
+            state.stats.expired += 1;
+            return None;
+        }
+        state.completed.peek(&key).map(|c| c.response.clone())
+    }
+
+    // Handlers never run under the lock, so a poisoned one still guards
+    // consistent state.
+    fn lock(&self) -> MutexGuard<'_, State<R>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct InFlight<'a, R: Clone> {
+    store: &'a IdempotencyStore<R>,
+    key: &'a str,
+}
+
+impl<R: Clone> Drop for InFlight<'_, R> {
+    fn drop(&mut self) {
+        self.store.lock().in_flight.remove(self.key);
+        self.store.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Poll `stats` until `done` holds; the threads under test block on a
+    // condvar, so there's nothing better to wait on.
+    fn wait_for(store: &IdempotencyStore<u32>, done: impl Fn(IdempotencyStats) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(store.stats()) {
+            assert!(Instant::now() < deadline, "stuck at {:?}", store.stats());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn duplicates_in_flight_wait_for_the_first() {
+        let store = &IdempotencyStore::new(16, Duration::from_secs(60));
+        let runs = &AtomicUsize::new(0);
+        let (release, hold) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let first = s.spawn(move || {
+                store.execute("k", || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    hold.recv().unwrap();
+                    7
+                })
+            });
+            wait_for(store, |st| st.executed == 1);
+            let retries: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(move || {
+                        store.execute("k", || {
+                            runs.fetch_add(1, Ordering::SeqCst);
+                            0
+                        })
+                    })
+                })
+                .collect();
+            // Every retry is parked on the first attempt before it finishes.
+            wait_for(store, |st| st.waited == 8);
+            release.send(()).unwrap();
+
+            assert_eq!(first.join().unwrap(), Outcome::Executed(7));
+            for retry in retries {
+                assert_eq!(retry.join().unwrap(), Outcome::Replayed(7));
+            }
+        });
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let stats = store.stats();
+        assert_eq!((stats.executed, stats.replayed, stats.waited), (1, 8, 8));
+    }
+
+    #[test]
+    fn racing_duplicates_run_the_handler_once() {
+        let store = IdempotencyStore::new(16, Duration::from_secs(60));
+        let runs = AtomicUsize::new(0);
+        let start = std::sync::Barrier::new(16);
+        let outcomes: Vec<_> = thread::scope(|s| {
+            let threads: Vec<_> = (0..16u32)
+                .map(|i| {
+                    let (store, runs, start) = (&store, &runs, &start);
+                    s.spawn(move || {
+                        start.wait();
+                        store.execute("k", || {
+                            runs.fetch_add(1, Ordering::SeqCst);
+                            i
+                        })
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let winner = outcomes.iter().find(|o| !o.was_replayed()).unwrap().clone();
+        let response = winner.into_inner();
+        assert!(outcomes.iter().all(|o| o.clone().into_inner() == response));
+        assert_eq!(outcomes.iter().filter(|o| o.was_replayed()).count(), 15);
+    }
+
+    #[test]
+    fn different_keys_run_side_by_side() {
+        let store = &IdempotencyStore::new(16, Duration::from_secs(60));
+        let (release, hold) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let a = s.spawn(move || {
+                store.execute("a", || {
+                    hold.recv().unwrap();
+                    1
+                })
+            });
+            wait_for(store, |st| st.executed == 1);
+            // "a" is still running; "b" must not queue behind it.
+            assert_eq!(store.execute("b", || 2), Outcome::Executed(2));
+            release.send(()).unwrap();
+            assert_eq!(a.join().unwrap(), Outcome::Executed(1));
+        });
+        assert_eq!(store.stats().waited, 0);
+    }
+
+    #[test]
+    fn a_panicking_handler_lets_the_waiter_run() {
+        let store = &IdempotencyStore::new(16, Duration::from_secs(60));
+        let (release, hold) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let first = s.spawn(move || {
+                catch_unwind(AssertUnwindSafe(|| {
+                    store.execute("k", || {
+                        hold.recv().unwrap();
+                        panic!("handler failed")
+                    })
+                }))
+            });
+            wait_for(store, |st| st.executed == 1);
+            let retry = s.spawn(move || store.execute("k", || 9));
+            wait_for(store, |st| st.waited == 1);
+            release.send(()).unwrap();
+
+            assert!(first.join().unwrap().is_err());
+            assert_eq!(retry.join().unwrap(), Outcome::Executed(9));
+        });
+        assert_eq!(store.stats().executed, 2);
+    }
+
+    #[test]
+    fn errors_are_replayed_too() {
+        let store: IdempotencyStore<Result<u32, String>> =
+            IdempotencyStore::new(4, Duration::from_secs(60));
+        assert_eq!(
+            store.execute("k", || Err("disk full".into())),
+            Outcome::Executed(Err("disk full".into()))
+        );
+        assert_eq!(
+            store.execute("k", || Ok(1)),
+            Outcome::Replayed(Err("disk full".into()))
+        );
+    }
+
+    #[test]
+    fn expired_and_forgotten_keys_run_again() {
+        let store = IdempotencyStore::new(4, Duration::ZERO);
+        assert_eq!(store.execute("k", || 1), Outcome::Executed(1));
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(store.execute("k", || 2), Outcome::Executed(2));
+        assert_eq!(store.stats().expired, 1);
+
+        let store = IdempotencyStore::new(4, Duration::from_secs(60));
+        store.execute("k", || 1);
+        store.forget("k");
+        assert_eq!(store.execute("k", || 2), Outcome::Executed(2));
+    }
+
+    #[test]
+    fn evicted_keys_run_again() {
+        let store = IdempotencyStore::new(1, Duration::from_secs(60));
+        store.execute("a", || 1);
+        store.execute("b", || 2);
+        assert_eq!(store.execute("a", || 3), Outcome::Executed(3));
+    }
+}
@@ -0,0 +1,211 @@
+// Split a stream of byte chunks (e.g. successive `read` calls) into lines
+// without allocating per line.
+//
+//     let mut splitter = LineSplitter::new();
+//     while let Ok(n) = file.read(&mut buf) {
+//         if n == 0 { break; }
+//         for line in splitter.feed(&buf[..n]) {
+//             ...
+//         }
+//     }
+//     if let Some(last) = splitter.finish() { ... }
+//
+// Lines are yielded without their '\n' (or "\r\n"). Lines that sit entirely
+// inside one chunk are borrowed straight from it. Only a line that spans
+// chunks is copied, into one internal buffer that is reused across calls.
+
+#[derive(Debug, Default)]
+pub struct LineSplitter {
+    // `buf[..consumed]` is the spanning line handed out by the last `feed`;
+    // `buf[consumed..]` is the start of a line still waiting for its '\n'.
+    buf: Vec<u8>,
+    consumed: usize,
+}
+
+pub struct Lines<'a> {
+    first: Option<&'a [u8]>,
+    // Complete lines from the chunk, each still ending in '\n'.
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if let Some(first) = self.first.take() {
+            return Some(first);
+        }
+        let end = self.rest.iter().position(|&b| b == b'\n')?;
+        let line = &self.rest[..end];
+        self.rest = &self.rest[end + 1..];
+        Some(trim_cr(line))
+    }
+}
+
+impl LineSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Feed the next chunk and iterate over the lines it completes.
+    pub fn feed<'a>(&'a mut self, chunk: &'a [u8]) -> Lines<'a> {
+        self.buf.drain(..self.consumed);
+        self.consumed = 0;
+
+        let Some(first_nl) = chunk.iter().position(|&b| b == b'\n') else {
+            self.buf.extend_from_slice(chunk);
+            return Lines {
+                first: None,
+                rest: &[],
+            };
+        };
+        let last_nl = chunk.iter().rposition(|&b| b == b'\n').unwrap_or(first_nl);
+
+        // The first line either continues what we've been holding on to, or
+        // lies entirely inside this chunk.
+        let spans_chunks = !self.buf.is_empty();
+        if spans_chunks {
+            self.buf.extend_from_slice(&chunk[..first_nl]);
+            self.consumed = self.buf.len();
+        }
+        // Keep the unfinished tail for the next call.
+        self.buf.extend_from_slice(&chunk[last_nl + 1..]);
+
+        let first = if spans_chunks {
+            &self.buf[..self.consumed]
+        } else {
+            &chunk[..first_nl]
+        };
+        let rest = if first_nl < last_nl {
+            &chunk[first_nl + 1..last_nl + 1]
+        } else {
+            &[]
+        };
+
+        Lines {
+            first: Some(trim_cr(first)),
+            rest,
+        }
+    }
+
+    // End of input: return the last line if it had no trailing '\n'.
+    pub fn finish(&mut self) -> Option<&[u8]> {
+        self.buf.drain(..self.consumed);
+        self.consumed = 0;
+        if self.buf.is_empty() {
+            return None;
+        }
+        // Hand it out and make sure the next `feed` starts clean.
+        self.consumed = self.buf.len();
+        Some(trim_cr(&self.buf))
+    }
+
+    // Bytes currently held for an unfinished line.
+    pub fn pending(&self) -> usize {
+        self.buf.len() - self.consumed
+    }
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sim::SimRng;
+
+    // What the splitter should yield for `data`, however it's chunked:
+    // `split(b'\n')`, minus a '\r' before each '\n', with an empty remainder
+    // after the last '\n' left out.
+    fn expected(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines: Vec<Vec<u8>> = data
+            .split(|&b| b == b'\n')
+            .map(|line| trim_cr(line).to_vec())
+            .collect();
+        if data.is_empty() || data.ends_with(b"\n") {
+            lines.pop();
+        }
+        lines
+    }
+
+    fn split(splitter: &mut LineSplitter, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        for chunk in chunks {
+            lines.extend(splitter.feed(chunk).map(<[u8]>::to_vec));
+        }
+        lines.extend(splitter.finish().map(<[u8]>::to_vec));
+        lines
+    }
+
+    // Random cuts, empty chunks included.
+    fn chunks<'a>(data: &'a [u8], rng: &mut SimRng) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            let len = rng.range(0, 8) as usize;
+            let end = (at + len).min(data.len());
+            chunks.push(&data[at..end]);
+            at = end;
+        }
+        if rng.range(0, 1) == 1 {
+            chunks.push(&[]);
+        }
+        chunks
+    }
+
+    #[test]
+    fn matches_split_over_random_chunkings() {
+        let mut rng = SimRng::new(469);
+        for _ in 0..2_000 {
+            let len = rng.range(0, 40) as usize;
+            let data: Vec<u8> = (0..len)
+                .map(|_| [b'a', b'b', b'\r', b'\n'][rng.range(0, 3) as usize])
+                .collect();
+            let chunks = chunks(&data, &mut rng);
+            assert_eq!(
+                split(&mut LineSplitter::new(), &chunks),
+                expected(&data),
+                "chunks {:?}",
+                chunks
+            );
+        }
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        let lines = split(&mut LineSplitter::new(), &[b"one\r", b"\ntwo\r", b"\n"]);
+        assert_eq!(lines, vec![b"one".to_vec(), b"two".to_vec()]);
+        // A '\r' that isn't followed by '\n' stays part of the line.
+        let lines = split(&mut LineSplitter::new(), &[b"a\rb\n"]);
+        assert_eq!(lines, vec![b"a\rb".to_vec()]);
+    }
+
+    #[test]
+    fn empty_chunks_change_nothing() {
+        let mut splitter = LineSplitter::new();
+        assert_eq!(splitter.feed(b"").count(), 0);
+        assert_eq!(splitter.feed(b"ab").count(), 0);
+        assert_eq!(splitter.feed(b"").count(), 0);
+        assert_eq!(splitter.pending(), 2);
+        assert_eq!(splitter.feed(b"\n").collect::<Vec<_>>(), vec![b"ab"]);
+        assert_eq!(splitter.pending(), 0);
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn feeding_after_finish_starts_a_new_stream() {
+        let mut rng = SimRng::new(470);
+        let mut splitter = LineSplitter::new();
+        for _ in 0..500 {
+            let len = rng.range(0, 20) as usize;
+            let data: Vec<u8> = (0..len)
+                .map(|_| [b'x', b'\r', b'\n'][rng.range(0, 2) as usize])
+                .collect();
+            let chunks = chunks(&data, &mut rng);
+            assert_eq!(split(&mut splitter, &chunks), expected(&data));
+            assert_eq!(splitter.pending(), 0);
+            assert_eq!(splitter.finish(), None);
+        }
+    }
+}
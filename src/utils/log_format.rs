@@ -0,0 +1,189 @@
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::test_closure::LogLevel;
+
+// How a logger turns a message into a line of text.
+//
+// A `Formatter` gets a `Record`: the level and message, plus when and on
+// which thread it was logged, and the logger's target (the program or
+// subsystem it belongs to, if it was given one). The built-in formats:
+//
+//     bare     level=info: listening
+//     human    2026-10-16T09:30:12.345Z INFO  server [main] listening
+//     compact  2026-10-16T09:30:12Z I server: listening
+//
+// Times are UTC, written as RFC 3339. Any `Fn(&Record) -> String` is a
+// formatter too, for anything else.
+
+// One message as a formatter sees it.
+#[derive(Debug, Clone)]
+pub struct Record<'a> {
+    pub level: LogLevel,
+    pub target: Option<&'a str>,
+    pub message: &'a str,
+    pub time: SystemTime,
+    // The thread's name, or `#N` for an unnamed one.
+    pub thread: String,
+}
+
+impl<'a> Record<'a> {
+    // A record for a message logged right now, on this thread.
+    pub fn now(level: LogLevel, target: Option<&'a str>, message: &'a str) -> Self {
+        let current = thread::current();
+        let thread = match current.name() {
+            Some(name) => name.to_string(),
+            // `ThreadId`'s only stable rendering is its `Debug` one,
+            // `ThreadId(N)`.
+            None => {
+                let id = format!("{:?}", current.id());
+                format!(
+                    "#{}",
+                    id.trim_start_matches("ThreadId(").trim_end_matches(')')
+                )
+            }
+        };
+        Self {
+            level,
+            target,
+            message,
+            time: SystemTime::now(),
+            thread,
+        }
+    }
+}
+
+pub trait Formatter: Send + Sync {
+    // The line for `record`, without a trailing newline.
+    fn format(&self, record: &Record<'_>) -> String;
+}
+
+impl<F: Fn(&Record<'_>) -> String + Send + Sync> Formatter for F {
+    fn format(&self, record: &Record<'_>) -> String {
+        self(record)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    // What `StderrLogger` has always printed: the level and the message.
+    Bare,
+    // Millisecond timestamp, level, target and thread.
+    #[default]
+    Human,
+    // Second timestamp, a one-letter level and the target.
+    Compact,
+}
+
+impl Formatter for LogFormat {
+    fn format(&self, record: &Record<'_>) -> String {
+        match self {
+            LogFormat::Bare => format!("level={}: {}", record.level, record.message),
+            LogFormat::Human => {
+                let mut line = format!(
+                    "{} {:<5} ",
+                    rfc3339(record.time, true),
+                    record.level.as_str().to_ascii_uppercase()
+                );
+                if let Some(target) = record.target {
+                    line.push_str(target);
+                    line.push(' ');
+                }
+                line.push_str(&format!("[{}] {}", record.thread, record.message));
+                line
+            }
+            LogFormat::Compact => {
+                let letter = record.level.as_str()[..1].to_ascii_uppercase();
+                match record.target {
+                    Some(target) => format!(
+                        "{} {} {}: {}",
+                        rfc3339(record.time, false),
+                        letter,
+                        target,
+                        record.message
+                    ),
+                    None => format!(
+                        "{} {} {}",
+                        rfc3339(record.time, false),
+                        letter,
+                        record.message
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl LogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Bare => "bare",
+            LogFormat::Human => "human",
+            LogFormat::Compact => "compact",
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [LogFormat::Bare, LogFormat::Human, LogFormat::Compact]
+            .into_iter()
+            .find(|format| format.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown log format {:?} (expected bare, human or compact)",
+                    s
+                )
+            })
+    }
+}
+
+// `time` in UTC as `2026-10-16T09:30:12Z`, or with `.345` before the `Z`
+// when `millis` is set. Times before 1970 come out as the epoch.
+pub fn rfc3339(time: SystemTime, millis: bool) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rest = secs % 86_400;
+    let mut text = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    );
+    if millis {
+        text.push_str(&format!(".{:03}", since.subsec_millis()));
+    }
+    text.push('Z');
+    text
+}
+
+// The Gregorian date `days` after 1970-01-01, using Howard Hinnant's
+// `civil_from_days`: count in 400-year eras starting on March 1st, so the
+// leap day falls at the end of a year.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+// Least-recently-used cache.
+//
+// Every entry gets a "tick" from a counter that goes up on each access. The
+// `order` map is sorted by tick, so its first entry is always the least
+// recently used one and is the next to be evicted.
+
+// Decides how much of the cache's capacity an entry uses up.
+pub trait Weigher<K, V> {
+    fn weigh(&self, key: &K, value: &V) -> usize;
+}
+
+// Every entry weighs 1, so capacity is a plain entry count.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EntryCount;
+
+impl<K, V> Weigher<K, V> for EntryCount {
+    fn weigh(&self, _key: &K, _value: &V) -> usize {
+        1
+    }
+}
+
+// Any `Fn(&K, &V) -> usize` closure works as a weigher, e.g.
+// `|_k, v: &Vec<u8>| v.len()` to bound the cache by bytes.
+impl<K, V, F> Weigher<K, V> for F
+where
+    F: Fn(&K, &V) -> usize,
+{
+    fn weigh(&self, key: &K, value: &V) -> usize {
+        (self)(key, value)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    tick: u64,
+    weight: usize,
+}
+
+pub struct LruCache<K, V, W = EntryCount> {
+    capacity: usize,
+    weigher: W,
+    entries: HashMap<K, Entry<V>>,
+    order: BTreeMap<u64, K>,
+    next_tick: u64,
+    total_weight: usize,
+    stats: CacheStats,
+}
+
+impl<K, V> LruCache<K, V, EntryCount>
+where
+    K: Hash + Eq + Clone,
+{
+    // A cache holding at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_weigher(capacity, EntryCount)
+    }
+}
+
+impl<K, V, W> LruCache<K, V, W>
+where
+    K: Hash + Eq + Clone,
+    W: Weigher<K, V>,
+{
+    // A cache whose entries' weights (as measured by `weigher`) add up to at
+    // most `capacity`.
+    pub fn with_weigher(capacity: usize, weigher: W) -> Self {
+        Self {
+            capacity,
+            weigher,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0,
+            total_weight: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // Look up `key`, marking it as most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.bump_tick();
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                let old_key = self.order.remove(&entry.tick);
+                entry.tick = tick;
+                if let Some(k) = old_key {
+                    self.order.insert(tick, k);
+                }
+                Some(&entry.value)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Look up `key` without touching its recency or the hit/miss counters.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|e| &e.value)
+    }
+
+    // Insert or replace `key`, evicting old entries until it fits.
+    // An entry heavier than the whole capacity is not stored at all; it is
+    // handed back as `Err` so the caller still owns it.
+    pub fn put(&mut self, key: K, value: V) -> Result<(), V> {
+        let weight = self.weigher.weigh(&key, &value);
+        if weight > self.capacity {
+            return Err(value);
+        }
+
+        self.remove(&key);
+        while self.total_weight + weight > self.capacity {
+            if !self.evict_one() {
+                break;
+            }
+        }
+
+        let tick = self.bump_tick();
+        self.order.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                tick,
+                weight,
+            },
+        );
+        self.total_weight += weight;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.total_weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.total_weight = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Sum of the weights of everything currently cached.
+    pub fn weight(&self) -> usize {
+        self.total_weight
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn bump_tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn evict_one(&mut self) -> bool {
+        let Some((_, key)) = self.order.pop_first() else {
+            return false;
+        };
+        if let Some(entry) = self.entries.remove(&key) {
+            self.total_weight -= entry.weight;
+            self.stats.evictions += 1;
+        }
+        true
+    }
+}
+
+// Thread-safe wrapper: one mutex around the whole cache. `get` returns a
+// clone because a reference can't outlive the lock.
+pub struct SyncLruCache<K, V, W = EntryCount> {
+    inner: Mutex<LruCache<K, V, W>>,
+}
+
+impl<K, V> SyncLruCache<K, V, EntryCount>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self::with_weigher(capacity, EntryCount)
+    }
+}
+
+impl<K, V, W> SyncLruCache<K, V, W>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    W: Weigher<K, V>,
+{
+    pub fn with_weigher(capacity: usize, weigher: W) -> Self {
+        Self {
+            inner: Mutex::new(LruCache::with_weigher(capacity, weigher)),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.lock().get(key).cloned()
+    }
+
+    pub fn put(&self, key: K, value: V) -> Result<(), V> {
+        self.lock().put(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.lock().remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats()
+    }
+
+    // The only user code run under the lock is the weigher, and it runs
+    // before the cache is modified, so a poisoned lock still guards a
+    // consistent cache and can be used as-is.
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<K, V, W>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -1,4 +1,53 @@
+pub mod alerts;
 pub mod array;
+#[cfg(feature = "async")]
+pub mod async_log;
+pub mod bloom;
+pub mod build_info;
 pub mod checktypes;
+pub mod circuit_breaker;
+pub mod client;
+pub mod codec;
+pub mod command;
+pub mod config;
+pub mod compat;
+pub mod conformance;
+pub mod dedup;
+pub mod delta;
+#[cfg(feature = "e2e")]
+pub mod e2e;
+pub mod echo;
+pub mod encoding;
+pub mod env_os;
+pub mod expr;
 pub mod file_handling;
+pub mod fuzz;
+pub mod graph;
+pub mod hash_ring;
+pub mod health;
+pub mod i18n;
+pub mod idempotency;
+pub mod line_splitter;
+pub mod log_format;
+pub mod lru;
+pub mod mutation;
+pub mod output;
+pub mod pattern;
+pub mod proc;
+pub mod prompt;
+pub mod protocol;
+pub mod scope;
+pub mod server_stats;
+pub mod sha256;
+pub mod sim;
+pub mod sniff;
+pub mod stable_hash;
+pub mod startup;
+pub mod supervisor;
+pub mod sync;
 pub mod test_closure;
+pub mod testsupport;
+pub mod text_protocol;
+pub mod trace;
+pub mod transport;
+pub mod vm;
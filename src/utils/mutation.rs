@@ -0,0 +1,298 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::delta::{self, DEFAULT_BLOCK_SIZE, Signature};
+use super::file_handling::{copy_file_with_progress, write_atomic};
+
+// Every change a command makes to the filesystem goes through a
+// `MutationSink` as a `Mutation`, so one switch decides whether changes are
+// made (`ApplyToDisk`) or only recorded for review (`DryRun`).
+//
+// Commands shouldn't call `fs::write`, `fs::remove_file` and friends
+// directly for anything the user asked them to change; add a variant here
+// instead.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    // Make `to` a copy of `from` (creating parent directories) and give it
+    // `from`'s mtime. With `reuse`, an existing `to` is patched with only the
+    // blocks that changed instead of being rewritten.
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        reuse: bool,
+    },
+    Write {
+        path: PathBuf,
+        contents: Vec<u8>,
+    },
+    Remove {
+        path: PathBuf,
+    },
+    // Give `path` the mtime `modified`, leaving its contents alone.
+    SetModified {
+        path: PathBuf,
+        modified: SystemTime,
+    },
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mutation::Copy { from, to, .. } => {
+                write!(f, "copy {} -> {}", from.display(), to.display())
+            }
+            Mutation::Write { path, contents } => {
+                write!(f, "write {} ({} bytes)", path.display(), contents.len())
+            }
+            Mutation::Remove { path } => write!(f, "remove {}", path.display()),
+            Mutation::SetModified { path, .. } => write!(f, "set mtime of {}", path.display()),
+        }
+    }
+}
+
+// What applying a mutation cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Applied {
+    // Bytes read from the source (or written, for `Write`).
+    pub bytes_copied: u64,
+    // Bytes of a patched file that were already there.
+    pub bytes_reused: u64,
+}
+
+pub trait MutationSink {
+    fn apply(&mut self, mutation: Mutation) -> io::Result<Applied>;
+
+    // True if nothing actually changes, so commands can leave out messages
+    // like "Deleted x" that would be untrue.
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+// Carries mutations out.
+#[derive(Debug, Default)]
+pub struct ApplyToDisk;
+
+impl MutationSink for ApplyToDisk {
+    fn apply(&mut self, mutation: Mutation) -> io::Result<Applied> {
+        match mutation {
+            Mutation::Copy { from, to, reuse } => {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let applied = if reuse && to.is_file() {
+                    patch_file(&from, &to)?
+                } else {
+                    Applied {
+                        bytes_copied: copy_file_with_progress(&from, &to, |_| {})?,
+                        bytes_reused: 0,
+                    }
+                };
+                // Carry the mtime over so a later sync can skip the file
+                // without hashing it.
+                let modified = fs::metadata(&from)?.modified()?;
+                fs::File::options()
+                    .write(true)
+                    .open(&to)?
+                    .set_modified(modified)?;
+                Ok(applied)
+            }
+            Mutation::Write { path, contents } => {
+                // Atomic, so a failed write leaves the old contents.
+                write_atomic(&path, &contents)?;
+                Ok(Applied {
+                    bytes_copied: contents.len() as u64,
+                    bytes_reused: 0,
+                })
+            }
+            Mutation::Remove { path } => {
+                fs::remove_file(&path)?;
+                Ok(Applied::default())
+            }
+            Mutation::SetModified { path, modified } => {
+                fs::File::options()
+                    .write(true)
+                    .open(&path)?
+                    .set_modified(modified)?;
+                Ok(Applied::default())
+            }
+        }
+    }
+}
+
+// Records mutations without touching anything.
+#[derive(Debug, Default)]
+pub struct DryRun {
+    pub planned: Vec<Mutation>,
+}
+
+impl MutationSink for DryRun {
+    fn apply(&mut self, mutation: Mutation) -> io::Result<Applied> {
+        self.planned.push(mutation);
+        Ok(Applied::default())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+// Bring `to` up to date with `from` by reusing the blocks `to` already has.
+// The result is written next to `to`, synced, and renamed over it, so `to`
+// is never left half-patched, even by a power cut.
+fn patch_file(from: &Path, to: &Path) -> io::Result<Applied> {
+    let signature = Signature::compute(BufReader::new(fs::File::open(to)?), DEFAULT_BLOCK_SIZE)?;
+    let delta = delta::delta(&signature, BufReader::new(fs::File::open(from)?))?;
+
+    let mut tmp_name = to.as_os_str().to_owned();
+    tmp_name.push(".partial");
+    let tmp = PathBuf::from(tmp_name);
+    let result = (|| -> io::Result<Applied> {
+        let mut out = BufWriter::new(fs::File::create(&tmp)?);
+        let total = delta::apply(fs::File::open(to)?, &delta, &mut out)?;
+        out.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(&tmp, to)?;
+        let literal = delta.literal_bytes();
+        Ok(Applied {
+            bytes_copied: literal,
+            bytes_reused: total - literal,
+        })
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = DEFAULT_BLOCK_SIZE;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("mutation-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Bytes that don't repeat within a block, so every block is distinct.
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u32).wrapping_mul(2_654_435_761).to_le_bytes()[2] ^ seed)
+            .collect()
+    }
+
+    // Patch a file holding `basis` into `new`, returning what it cost.
+    fn patch(name: &str, basis: &[u8], new: &[u8]) -> Applied {
+        let dir = TempDir::new(name);
+        let (from, to) = (dir.0.join("from"), dir.0.join("to"));
+        fs::write(&from, new).unwrap();
+        fs::write(&to, basis).unwrap();
+        let applied = patch_file(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), new, "{}", name);
+        assert!(!dir.0.join("to.partial").exists(), "{}", name);
+        assert_eq!(
+            applied.bytes_copied + applied.bytes_reused,
+            new.len() as u64,
+            "{}",
+            name
+        );
+        applied
+    }
+
+    #[test]
+    fn empty_basis_copies_everything() {
+        let new = data(3 * BLOCK + 17, 1);
+        let applied = patch("empty-basis", &[], &new);
+        assert_eq!(applied.bytes_reused, 0);
+        patch("both-empty", &[], &[]);
+        patch("empty-new", &new, &[]);
+    }
+
+    #[test]
+    fn unchanged_file_reuses_every_block() {
+        let basis = data(4 * BLOCK, 2);
+        assert_eq!(patch("unchanged", &basis, &basis).bytes_copied, 0);
+    }
+
+    #[test]
+    fn short_last_block_is_reused() {
+        let basis = data(2 * BLOCK + 100, 3);
+        assert_eq!(patch("short-last", &basis, &basis).bytes_copied, 0);
+
+        // The short block moved: it's no longer at the end of a block.
+        let mut new = basis.clone();
+        new.splice(0..0, *b"x");
+        let applied = patch("short-last-moved", &basis, &new);
+        assert!(applied.bytes_reused >= 2 * BLOCK as u64);
+    }
+
+    #[test]
+    fn insertion_at_a_block_edge() {
+        let basis = data(3 * BLOCK, 4);
+        for at in [0, BLOCK, 2 * BLOCK, 3 * BLOCK] {
+            let mut new = basis.clone();
+            new.splice(at..at, data(10, 9));
+            let applied = patch(&format!("insert-{}", at), &basis, &new);
+            assert_eq!(applied.bytes_reused, basis.len() as u64, "insert at {}", at);
+        }
+    }
+
+    #[test]
+    fn deletion_at_a_block_edge() {
+        let basis = data(4 * BLOCK, 5);
+        for at in [0, BLOCK, 3 * BLOCK] {
+            let mut new = basis.clone();
+            new.drain(at..at + BLOCK);
+            let applied = patch(&format!("delete-{}", at), &basis, &new);
+            assert_eq!(applied.bytes_copied, 0, "delete block at {}", at);
+        }
+        // A deletion that straddles an edge spoils the two blocks it
+        // touches, and no others.
+        let mut new = basis.clone();
+        new.drain(BLOCK - 5..BLOCK + 5);
+        let applied = patch("delete-straddling", &basis, &new);
+        assert_eq!(applied.bytes_reused, 2 * BLOCK as u64);
+    }
+
+    #[test]
+    fn a_write_replaces_the_file_rather_than_writing_into_it() {
+        let dir = TempDir::new("write");
+        let path = dir.0.join("notes");
+        fs::write(&path, "old").unwrap();
+        // A second name for the old file: an in-place write would show
+        // through it.
+        fs::hard_link(&path, dir.0.join("old")).unwrap();
+
+        let applied = ApplyToDisk
+            .apply(Mutation::Write {
+                path: path.clone(),
+                contents: b"new".to_vec(),
+            })
+            .unwrap();
+
+        assert_eq!(applied.bytes_copied, 3);
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(dir.0.join("old")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+
+use super::expr::Value;
+
+// Where subcommands send their results.
+//
+// By default results are human-readable text on stdout. With `--json`, each
+// result is instead one JSON object per line on stdout, and the human text
+// moves to stderr so it doesn't get in the way of a script parsing stdout.
+//
+// Every JSON result starts with `"schema"` (the subcommand) and `"version"`.
+// A version only goes up when a field is removed or changes meaning; adding
+// fields doesn't count, so consumers should ignore fields they don't know.
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    // A line for people: stdout normally, stderr in JSON mode.
+    pub fn human(&self, line: impl fmt::Display) {
+        if self.json {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    // A result for scripts; does nothing unless in JSON mode.
+    pub fn result(&self, schema: &str, version: u32, fields: Vec<(&str, Json)>) {
+        if self.json {
+            println!("{}", result_object(schema, version, fields));
+        }
+    }
+}
+
+fn result_object(schema: &str, version: u32, fields: Vec<(&str, Json)>) -> Json {
+    let mut object = vec![
+        ("schema".to_string(), Json::from(schema)),
+        ("version".to_string(), Json::from(version)),
+    ];
+    object.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+    Json::Object(object)
+}
+
+// Just enough of a JSON value to write results; there's no parser.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Array(Vec<Json>),
+    // Keys stay in insertion order so output is stable.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no NaN or infinity.
+            Json::Num(n) if !n.is_finite() => write!(f, "null"),
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(s) => write!(f, "{}", quote(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", quote(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Self {
+        Json::Num(n)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(n: u32) -> Self {
+        Json::Num(n as f64)
+    }
+}
+
+// Byte counts and the like; f64 is exact up to 2^53, which is plenty.
+impl From<u64> for Json {
+    fn from(n: u64) -> Self {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::Str(s)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(items: Vec<T>) -> Self {
+        Json::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<&Value> for Json {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => Json::Null,
+            Value::Bool(b) => Json::Bool(*b),
+            Value::Num(n) => Json::Num(*n),
+            Value::Str(s) => Json::Str(s.clone()),
+        }
+    }
+}
+
+// A JSON string literal for `s`, quotes included.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What a consumer would read back, through a real JSON parser.
+    fn parsed(json: &Json) -> serde_json::Value {
+        serde_json::from_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn a_result_starts_with_schema_and_version_in_field_order() {
+        let result = result_object(
+            "dedup-scan",
+            2,
+            vec![
+                ("files_scanned", Json::from(3usize)),
+                ("deleted", Json::from(false)),
+            ],
+        );
+
+        assert_eq!(
+            result.to_string(),
+            r#"{"schema":"dedup-scan","version":2,"files_scanned":3,"deleted":false}"#
+        );
+    }
+
+    #[test]
+    fn nested_values_are_valid_json() {
+        let value = Json::object(vec![
+            ("paths", Json::from(vec!["a b", "c\"d"])),
+            ("missing", Json::from(None::<u64>)),
+            (
+                "sets",
+                Json::Array(vec![Json::object(vec![("size", Json::from(5u64))])]),
+            ),
+            ("empty", Json::Object(Vec::new())),
+        ]);
+
+        assert_eq!(
+            parsed(&value),
+            serde_json::json!({
+                "paths": ["a b", "c\"d"],
+                "missing": null,
+                "sets": [{"size": 5}],
+                "empty": {},
+            })
+        );
+    }
+
+    #[test]
+    fn numbers_are_written_as_json_can_read_them() {
+        assert_eq!(Json::from(3.0).to_string(), "3");
+        assert_eq!(Json::from(0.25).to_string(), "0.25");
+        assert_eq!(Json::from(1u64 << 53).to_string(), "9007199254740992");
+        // JSON has no NaN or infinity.
+        assert_eq!(Json::from(f64::NAN).to_string(), "null");
+        assert_eq!(Json::from(f64::NEG_INFINITY).to_string(), "null");
+        assert_eq!(Json::from(&Value::Num(-1.5)), Json::Num(-1.5));
+    }
+
+    #[test]
+    fn quote_escapes_what_json_needs_and_nothing_else() {
+        let every_char: String = (0u32..0x80)
+            .filter_map(char::from_u32)
+            .chain(['é', '€', '😀'])
+            .collect();
+
+        let quoted = quote(&every_char);
+
+        assert_eq!(serde_json::from_str::<String>(&quoted).unwrap(), every_char);
+        assert!(quoted.contains(r#"\t\n\u000b\u000c\r"#), "{}", quoted);
+        assert!(quoted.ends_with("é€😀\""));
+        assert_eq!(quote("a/b"), r#""a/b""#);
+    }
+}
@@ -0,0 +1,578 @@
+// Pattern matching without a regex crate: shell-style globs, a tiny regex
+// subset, and NO_PROXY-style host matching.
+
+// ---------------------------------------------------------------------------
+// Glob
+// ---------------------------------------------------------------------------
+//
+//   *      any run of characters except '/'
+//   ?      exactly one character except '/'
+//   [a-z]  one character from the set; [!a-z] or [^a-z] negates it
+//   **     any run of characters including '/', so `src/**/*.rs` matches
+//          both `src/main.rs` and `src/utils/array.rs`
+//   \x     a literal x
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GlobToken {
+    Literal(char),
+    AnyChar,
+    AnyRun,
+    AnyPath,
+    Class(CharClass),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<GlobToken>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    i += 2;
+                    // `**/` also matches zero directories: swallow the slash
+                    // so `a/**/b` matches `a/b`.
+                    if chars.get(i) == Some(&'/') {
+                        i += 1;
+                    }
+                    tokens.push(GlobToken::AnyPath);
+                    continue;
+                }
+                '*' => tokens.push(GlobToken::AnyRun),
+                '?' => tokens.push(GlobToken::AnyChar),
+                '[' => {
+                    let (class, len) = CharClass::parse(&chars[i..])?;
+                    tokens.push(GlobToken::Class(class));
+                    i += len;
+                    continue;
+                }
+                '\\' => {
+                    let c = *chars
+                        .get(i + 1)
+                        .ok_or_else(|| format!("trailing '\\' in glob '{}'", pattern))?;
+                    tokens.push(GlobToken::Literal(c));
+                    i += 1;
+                }
+                c => tokens.push(GlobToken::Literal(c)),
+            }
+            i += 1;
+        }
+
+        Ok(Self { tokens })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        glob_match(&self.tokens, &text)
+    }
+}
+
+// Convenience for one-off checks. An invalid pattern never matches.
+pub fn glob_match_str(pattern: &str, text: &str) -> bool {
+    Glob::new(pattern).is_ok_and(|g| g.is_match(text))
+}
+
+// Two pointers, backing up to the last star on a mismatch and letting it
+// cover one more character, as most glob matchers do. Trying every split
+// recursively instead takes exponential time on patterns like `*a*a*a*b`.
+// A `*` can't be stretched over a '/'; then only the last `**` can be, since
+// stretching an earlier `*` couldn't move which '/' comes next.
+fn glob_match(tokens: &[GlobToken], text: &[char]) -> bool {
+    let (mut t, mut i) = (0, 0);
+    // The token after the last star, and how far into `text` it reaches.
+    let mut run: Option<(usize, usize)> = None;
+    let mut path: Option<(usize, usize)> = None;
+    loop {
+        match tokens.get(t) {
+            Some(GlobToken::AnyRun) => {
+                run = Some((t + 1, i));
+                t += 1;
+                continue;
+            }
+            Some(GlobToken::AnyPath) => {
+                path = Some((t + 1, i));
+                run = None;
+                t += 1;
+                continue;
+            }
+            Some(token) if text.get(i).is_some_and(|&c| matches_one(token, c)) => {
+                t += 1;
+                i += 1;
+                continue;
+            }
+            None if i == text.len() => return true,
+            _ => {}
+        }
+        if let Some((next, end)) = run
+            && text.get(end).is_some_and(|&c| c != '/')
+        {
+            run = Some((next, end + 1));
+            (t, i) = (next, end + 1);
+        } else if let Some((next, end)) = path
+            && end < text.len()
+        {
+            path = Some((next, end + 1));
+            run = None;
+            (t, i) = (next, end + 1);
+        } else {
+            return false;
+        }
+    }
+}
+
+fn matches_one(token: &GlobToken, c: char) -> bool {
+    match token {
+        GlobToken::Literal(l) => *l == c,
+        GlobToken::AnyChar => c != '/',
+        GlobToken::Class(class) => c != '/' && class.matches(c),
+        GlobToken::AnyRun | GlobToken::AnyPath => false,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Character classes, shared by globs and regexes
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharClass {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl CharClass {
+    // Parse `[...]` at the start of `chars`, returning the class and how many
+    // characters it used.
+    fn parse(chars: &[char]) -> Result<(Self, usize), String> {
+        let mut i = 1; // skip '['
+        let negated = matches!(chars.get(i), Some('!') | Some('^'));
+        if negated {
+            i += 1;
+        }
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = *chars.get(i).ok_or("unterminated '[' character class")?;
+            // A ']' right after the opening bracket is a literal ']'.
+            if c == ']' && !first {
+                return Ok((Self { negated, ranges }, i + 1));
+            }
+            first = false;
+
+            let c = if c == '\\' {
+                i += 1;
+                *chars.get(i).ok_or("unterminated '[' character class")?
+            } else {
+                c
+            };
+
+            if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&e| e != ']') {
+                let end = chars[i + 2];
+                if end < c {
+                    return Err(format!("invalid range '{}-{}'", c, end));
+                }
+                ranges.push((c, end));
+                i += 3;
+            } else {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        let inside = self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+        inside != self.negated
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Regex-lite
+// ---------------------------------------------------------------------------
+//
+// Supported: literals, `.`, `[...]` classes, `\d` `\w` `\s` (and `\.` etc.
+// for literals), the quantifiers `*` `+` `?` on a single atom, and the
+// anchors `^` / `$`. No groups or alternation. Unanchored patterns match
+// anywhere in the text, like `grep`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Atom {
+    Literal(char),
+    Any,
+    Digit,
+    Word,
+    Space,
+    Class(CharClass),
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Literal(l) => *l == c,
+            Atom::Any => true,
+            Atom::Digit => c.is_ascii_digit(),
+            Atom::Word => c.is_alphanumeric() || c == '_',
+            Atom::Space => c.is_whitespace(),
+            Atom::Class(class) => class.matches(c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    One,
+    ZeroOrOne,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Regex {
+    pieces: Vec<(Atom, Repeat)>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let mut chars: Vec<char> = pattern.chars().collect();
+        let anchored_start = chars.first() == Some(&'^');
+        if anchored_start {
+            chars.remove(0);
+        }
+        // A trailing `$` is an anchor unless it's escaped.
+        let anchored_end =
+            chars.last() == Some(&'$') && !(chars.len() >= 2 && chars[chars.len() - 2] == '\\');
+        if anchored_end {
+            chars.pop();
+        }
+
+        let mut pieces = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let atom = match chars[i] {
+                '.' => Atom::Any,
+                '[' => {
+                    let (class, len) = CharClass::parse(&chars[i..])?;
+                    i += len - 1;
+                    Atom::Class(class)
+                }
+                '\\' => {
+                    i += 1;
+                    match chars.get(i) {
+                        Some('d') => Atom::Digit,
+                        Some('w') => Atom::Word,
+                        Some('s') => Atom::Space,
+                        Some(&c) => Atom::Literal(c),
+                        None => return Err("trailing '\\' in pattern".to_string()),
+                    }
+                }
+                c @ ('*' | '+' | '?') => {
+                    return Err(format!("'{}' has nothing to repeat", c));
+                }
+                c => Atom::Literal(c),
+            };
+            i += 1;
+
+            let repeat = match chars.get(i) {
+                Some('*') => Repeat::ZeroOrMore,
+                Some('+') => Repeat::OneOrMore,
+                Some('?') => Repeat::ZeroOrOne,
+                _ => Repeat::One,
+            };
+            if repeat != Repeat::One {
+                i += 1;
+            }
+            pieces.push((atom, repeat));
+        }
+
+        Ok(Self {
+            pieces,
+            anchored_start,
+            anchored_end,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        if self.anchored_start {
+            return self.match_here(0, &text);
+        }
+        (0..=text.len()).any(|start| self.match_here(0, &text[start..]))
+    }
+
+    fn match_here(&self, piece: usize, text: &[char]) -> bool {
+        let Some((atom, repeat)) = self.pieces.get(piece) else {
+            return !self.anchored_end || text.is_empty();
+        };
+
+        let (min, max) = match repeat {
+            Repeat::One => (1, 1),
+            Repeat::ZeroOrOne => (0, 1),
+            Repeat::ZeroOrMore => (0, usize::MAX),
+            Repeat::OneOrMore => (1, usize::MAX),
+        };
+
+        // Greedy: take as many as possible, then back off one at a time.
+        let available = text.iter().take_while(|&&c| atom.matches(c)).count();
+        let most = available.min(max);
+        if most < min {
+            return false;
+        }
+        (min..=most)
+            .rev()
+            .any(|taken| self.match_here(piece + 1, &text[taken..]))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Host matching
+// ---------------------------------------------------------------------------
+
+// Does `host` match a NO_PROXY-style list such as
+// "localhost,127.0.0.1,.example.com,*.internal"?
+// - `*` alone matches every host;
+// - `.example.com` and `*.example.com` match the domain and its subdomains;
+// - `example.com` matches itself and its subdomains;
+// - other entries may contain glob characters (`10.0.*`).
+// Comparison ignores case, any `:port` on the host and the brackets around
+// an IPv6 address, so `[::1]:8080` matches `::1`.
+pub fn host_matches_list(list: &str, host: &str) -> bool {
+    let host = without_port(host);
+    let host = host.to_ascii_lowercase();
+
+    list.split(',')
+        .map(|entry| without_port(entry.trim()).to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            let domain = entry
+                .strip_prefix("*.")
+                .or_else(|| entry.strip_prefix('.'))
+                .unwrap_or(&entry);
+            if host == domain || host.ends_with(&format!(".{}", domain)) {
+                return true;
+            }
+            glob_match_str(&entry, &host)
+        })
+}
+
+// `host:port` and `[v6]:port` lose the port, `[v6]` its brackets. More than
+// one colon without brackets is a bare IPv6 address, whose last group looks
+// like a port but isn't.
+fn without_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((address, "")) => address,
+            Some((address, port)) if port.strip_prefix(':').is_some_and(is_port) => address,
+            _ => host,
+        };
+    }
+    match host.split_once(':') {
+        Some((name, port)) if is_port(port) => name,
+        _ => host,
+    }
+}
+
+fn is_port(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The obvious recursive matcher: try every split at every star.
+    // Exponential, but plainly right, so the real one is checked against it.
+    fn reference(tokens: &[GlobToken], text: &[char]) -> bool {
+        let Some((first, rest)) = tokens.split_first() else {
+            return text.is_empty();
+        };
+        match first {
+            GlobToken::AnyRun => (0..=text.len())
+                .take_while(|&skip| skip == 0 || text[skip - 1] != '/')
+                .any(|skip| reference(rest, &text[skip..])),
+            GlobToken::AnyPath => (0..=text.len()).any(|skip| reference(rest, &text[skip..])),
+            token => {
+                text.first().is_some_and(|&c| matches_one(token, c)) && reference(rest, &text[1..])
+            }
+        }
+    }
+
+    // Every string of up to `max` characters from `alphabet`.
+    fn strings(alphabet: &[&str], max: usize) -> Vec<String> {
+        let mut all = vec![String::new()];
+        let mut last = vec![String::new()];
+        for _ in 0..max {
+            last = last
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |a| format!("{}{}", s, a)))
+                .collect();
+            all.extend(last.iter().cloned());
+        }
+        all
+    }
+
+    #[test]
+    fn agrees_with_the_reference_exhaustively() {
+        let patterns = strings(&["a", "/", "?", "*", "**", "[!a]"], 4);
+        let texts = strings(&["a", "b", "/"], 6);
+        for pattern in &patterns {
+            let glob = Glob::new(pattern).unwrap();
+            for text in &texts {
+                let chars: Vec<char> = text.chars().collect();
+                assert_eq!(
+                    glob.is_match(text),
+                    reference(&glob.tokens, &chars),
+                    "glob {:?} against {:?}",
+                    pattern,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn glob_table() {
+        let cases = [
+            ("*.rs", "main.rs", true),
+            ("*.rs", "src/main.rs", false),
+            ("src/**/*.rs", "src/main.rs", true),
+            ("src/**/*.rs", "src/utils/array.rs", true),
+            ("src/**/*.rs", "src/a/b/c.rs", true),
+            ("src/**/*.rs", "lib/main.rs", false),
+            ("a/**/b", "a/b", true),
+            ("a/**/b", "a/x/y/b", true),
+            ("**", "", true),
+            ("**", "a/b/c", true),
+            ("*", "", true),
+            ("*", "a/b", false),
+            ("?", "", false),
+            ("?", "/", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("[a-c]x", "bx", true),
+            ("[!a-c]x", "bx", false),
+            ("[^a-c]x", "dx", true),
+            ("[]]", "]", true),
+            ("[a-]", "-", true),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("*a*b*c", "xaybzc", true),
+            ("*a*b*c", "xaybz/c", false),
+            ("ä*ö", "äxyzö", true),
+            ("server.*", "server.stats", true),
+            ("server.*", "client.stats", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match_str(pattern, text),
+                expected,
+                "glob {:?} against {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn many_stars_stay_fast() {
+        let text = "a".repeat(10_000);
+        assert!(!glob_match_str(&format!("{}b", "*a".repeat(20)), &text));
+        assert!(!glob_match_str(&format!("{}b", "**a".repeat(20)), &text));
+        assert!(glob_match_str(&"*a".repeat(20), &text));
+    }
+
+    #[test]
+    fn invalid_globs() {
+        for pattern in ["[abc", "[z-a]", "trailing\\"] {
+            assert!(Glob::new(pattern).is_err(), "{:?}", pattern);
+            assert!(!glob_match_str(pattern, pattern));
+        }
+    }
+
+    #[test]
+    fn host_table() {
+        let list = "localhost,127.0.0.1,.example.com,*.internal,10.0.*,::1,[fe80::1]";
+        let cases = [
+            ("localhost", true),
+            ("LOCALHOST:8080", true),
+            ("127.0.0.1:80", true),
+            ("127.0.0.2", false),
+            ("example.com", true),
+            ("api.example.com:443", true),
+            ("badexample.com", false),
+            ("db.internal", true),
+            ("internal", true),
+            ("10.0.3.4", true),
+            ("10.1.3.4", false),
+            ("::1", true),
+            ("[::1]", true),
+            ("[::1]:8080", true),
+            // A bare address's last group isn't a port.
+            ("::2", false),
+            ("fe80::1", true),
+            ("[fe80::1]:443", true),
+            ("fe80::", false),
+            ("[fe80::1", false),
+        ];
+        for (host, expected) in cases {
+            assert_eq!(host_matches_list(list, host), expected, "{:?}", host);
+        }
+        assert!(host_matches_list(" * ", "anything:1"));
+        assert!(!host_matches_list("", "localhost"));
+        assert!(!host_matches_list(",,", "localhost"));
+    }
+
+    #[test]
+    fn ports_are_stripped_only_when_they_are_ports() {
+        let cases = [
+            ("host", "host"),
+            ("host:80", "host"),
+            ("host:", "host:"),
+            ("host:http", "host:http"),
+            ("::1", "::1"),
+            ("2001:db8::8080", "2001:db8::8080"),
+            ("[2001:db8::1]:8080", "2001:db8::1"),
+            ("[2001:db8::1]", "2001:db8::1"),
+            ("[2001:db8::1]x", "[2001:db8::1]x"),
+        ];
+        for (host, expected) in cases {
+            assert_eq!(without_port(host), expected, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn regex_table() {
+        let cases = [
+            ("^ab*c$", "ac", true),
+            ("^ab*c$", "abbbc", true),
+            ("^ab+c$", "ac", false),
+            ("colou?r", "the color red", true),
+            ("\\d+\\.\\d+", "v1.25", true),
+            ("^\\w+$", "snake_case", true),
+            ("^\\w+$", "two words", false),
+            ("a.c", "a/c", true),
+            ("[0-9]$", "abc1", true),
+            ("cost\\$", "cost$", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                Regex::new(pattern).unwrap().is_match(text),
+                expected,
+                "{:?} against {:?}",
+                pattern,
+                text
+            );
+        }
+        assert!(Regex::new("*a").is_err());
+    }
+}
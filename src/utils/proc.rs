@@ -0,0 +1,268 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::test_closure::{LogLevel, Logger};
+
+// Run a child process, streaming its output line by line into a `Logger`.
+//
+//     let mut cmd = Command::new("rsync");
+//     cmd.args(["-a", "src/", "dst/"]);
+//     let result = proc::run(&mut cmd, RunOptions::default(), &StderrLogger)?;
+//
+// stderr lines are logged at `Warn` and stdout lines at `Info`, prefixed
+// with the program name. The child runs in its own process group (on Unix)
+// so that a timeout or cancel kills everything it started, not just the
+// direct child.
+
+pub const STDERR_LEVEL: LogLevel = LogLevel::Warn;
+pub const STDOUT_LEVEL: LogLevel = LogLevel::Info;
+
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    // Kill the process (group) if it hasn't exited by then.
+    pub timeout: Option<Duration>,
+    // Setting this from another thread kills the process (group).
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    Exited(ExitStatus),
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunResult {
+    pub outcome: RunOutcome,
+    pub stdout_lines: usize,
+    pub stderr_lines: usize,
+    pub elapsed: Duration,
+}
+
+impl RunResult {
+    // Exited on its own with status 0.
+    pub fn success(&self) -> bool {
+        matches!(&self.outcome, RunOutcome::Exited(status) if status.success())
+    }
+}
+
+// How often to check whether the child has exited, timed out or been
+// cancelled. std has no portable "wait with timeout", so we poll.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub fn run<L>(cmd: &mut Command, options: RunOptions, logger: &L) -> io::Result<RunResult>
+where
+    L: Logger + Sync,
+{
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let start = Instant::now();
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    thread::scope(|s| {
+        let out = s.spawn(|| stream_lines(stdout, &name, STDOUT_LEVEL, logger));
+        let err = s.spawn(|| stream_lines(stderr, &name, STDERR_LEVEL, logger));
+
+        let outcome = match wait(&mut child, &options) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // Don't leave the child running (and the readers blocked).
+                kill_group(&mut child);
+                let _ = child.wait();
+                return Err(e);
+            }
+        };
+
+        // The readers finish once every process holding the pipes has gone.
+        let stdout_lines = out.join().expect("stdout reader panicked");
+        let stderr_lines = err.join().expect("stderr reader panicked");
+        Ok(RunResult {
+            outcome,
+            stdout_lines,
+            stderr_lines,
+            elapsed: start.elapsed(),
+        })
+    })
+}
+
+fn wait(child: &mut Child, options: &RunOptions) -> io::Result<RunOutcome> {
+    let deadline = options.timeout.map(|t| Instant::now() + t);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(RunOutcome::Exited(status));
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            kill_group(child);
+            child.wait()?;
+            return Ok(RunOutcome::TimedOut);
+        }
+        if options
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            kill_group(child);
+            child.wait()?;
+            return Ok(RunOutcome::Cancelled);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Kill the child's whole process group. std can only signal the child
+// itself, so on Unix we ask `kill(1)` to do it and fall back to the child
+// alone if that fails.
+fn kill_group(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        let group = format!("-{}", child.id());
+        let killed = Command::new("kill")
+            .args(["-KILL", "--", &group])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success());
+        if killed {
+            return;
+        }
+    }
+    let _ = child.kill();
+}
+
+// Log each line from `pipe`, returning how many there were. Output that
+// isn't UTF-8 is logged lossily; it's only for people to read.
+fn stream_lines<R: Read, L: Logger>(pipe: R, name: &str, level: LogLevel, logger: &L) -> usize {
+    let mut reader = BufReader::new(pipe);
+    let mut line = Vec::new();
+    let mut count = 0;
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end_matches(['\n', '\r']);
+                logger.log(level, &format!("[{}] {}", name, text));
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<(LogLevel, String)>>);
+
+    impl Logger for Collect {
+        fn log(&self, level: LogLevel, message: &str) {
+            self.0.lock().unwrap().push((level, message.to_string()));
+        }
+    }
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn output_is_logged_line_by_line() {
+        let logger = Collect::default();
+        let result = run(
+            &mut sh("echo one; echo oops >&2; printf 'two\\r\\nlast'"),
+            RunOptions::default(),
+            &logger,
+        )
+        .unwrap();
+
+        assert!(result.success());
+        assert_eq!((result.stdout_lines, result.stderr_lines), (3, 1));
+        let mut logged = logger.0.into_inner().unwrap();
+        // The two pipes are read on their own threads.
+        logged.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            logged,
+            [
+                (STDOUT_LEVEL, "[sh] last".to_string()),
+                (STDOUT_LEVEL, "[sh] one".to_string()),
+                (STDERR_LEVEL, "[sh] oops".to_string()),
+                (STDOUT_LEVEL, "[sh] two".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_failing_exit_status_is_reported() {
+        let result = run(
+            &mut sh("exit 3"),
+            RunOptions::default(),
+            &Collect::default(),
+        )
+        .unwrap();
+        assert!(!result.success());
+        assert!(matches!(result.outcome, RunOutcome::Exited(status) if status.code() == Some(3)));
+    }
+
+    #[test]
+    fn a_timeout_kills_the_whole_group() {
+        // The backgrounded sleep holds stdout open too: unless it's killed
+        // as well, reading the output would wait for it.
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(100)),
+            cancel: None,
+        };
+        let start = Instant::now();
+        let result = run(&mut sh("sleep 30 & sleep 30"), options, &Collect::default()).unwrap();
+
+        assert_eq!(result.outcome, RunOutcome::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn cancelling_stops_the_child() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = RunOptions {
+            timeout: None,
+            cancel: Some(cancel.clone()),
+        };
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        let result = run(&mut sh("sleep 30"), options, &Collect::default()).unwrap();
+        canceller.join().unwrap();
+
+        assert_eq!(result.outcome, RunOutcome::Cancelled);
+    }
+
+    #[test]
+    fn a_missing_program_fails_to_start() {
+        let e = run(
+            &mut Command::new("no-such-program-here"),
+            RunOptions::default(),
+            &Collect::default(),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::{self, BufRead, Write};
+
+// Ask a yes/no question on the terminal. The question goes to stderr so it
+// never mixes with a command's results on stdout.
+// Anything other than "y"/"yes" (case-insensitive) counts as "no", so just
+// pressing Enter is always the safe answer.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+// When commands ask before changing something. Set once for the whole
+// command line (`--confirm=...`, or `--yes` / `--force` for `Never`) and
+// passed to every command that changes files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConfirmPolicy {
+    // Ask before every change.
+    Always,
+    // Ask before changes that lose data, like deleting a file or
+    // overwriting one the command wasn't asked to update.
+    #[default]
+    DestructiveOnly,
+    // Never ask; go ahead with everything.
+    Never,
+}
+
+impl ConfirmPolicy {
+    // "always", "destructive" or "never".
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "always" => Some(ConfirmPolicy::Always),
+            "destructive" => Some(ConfirmPolicy::DestructiveOnly),
+            "never" => Some(ConfirmPolicy::Never),
+            _ => None,
+        }
+    }
+
+    // Whether a change may go ahead, asking `question` first if the policy
+    // calls for it.
+    pub fn confirm(self, question: &str, destructive: bool) -> io::Result<bool> {
+        let ask = match self {
+            ConfirmPolicy::Always => true,
+            ConfirmPolicy::DestructiveOnly => destructive,
+            ConfirmPolicy::Never => false,
+        };
+        if ask { confirm(question) } else { Ok(true) }
+    }
+}
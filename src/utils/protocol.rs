@@ -0,0 +1,261 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+// Length-prefixed framing for the client/server protocol.
+//
+// TCP is a byte stream: two writes can arrive as one read and one write
+// as several. So every message goes on the wire as a frame
+//
+//   [len: u32 BE][payload: len bytes]
+//
+// and the receiver splits the stream back into the same messages. Frames
+// longer than the reader's limit (MAX_FRAME_LEN by default) are refused
+// before anything is allocated for them.
+
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+const HEADER_LEN: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    // The header announced more than the limit.
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes is over the {} byte limit", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Write `payload` as one frame. The header and payload go out in a single
+// write so small frames don't turn into two packets.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "payload too large for a frame",
+        ));
+    }
+    writer.write_all(&encode_frame(payload))
+}
+
+// Read the next frame. `Ok(None)` means the stream ended cleanly between
+// frames; ending inside one is an `UnexpectedEof` error.
+pub fn read_frame<R: Read>(reader: &mut R, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_len {
+        return Err(FrameError::TooLarge { len, max: max_len }.into());
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+// Splits bytes fed in arbitrary chunks into frames, for readers that
+// can't block on `read_frame` (async code, simulations).
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_LEN)
+    }
+}
+
+impl FrameDecoder {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // The next complete frame, if one has arrived. After an error the
+    // stream can't be resynchronized; drop the connection.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(header) = self.buf.first_chunk::<HEADER_LEN>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*header) as usize;
+        if len > self.max_len {
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_len,
+            });
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let payload = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+        Ok(Some(payload))
+    }
+
+    // Bytes received that aren't part of a complete frame yet.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sim::SimRng;
+    use std::io::Cursor;
+
+    // Hands out at most one byte per `read`, the worst a socket can do.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    fn payloads() -> Vec<Vec<u8>> {
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"hello".to_vec(),
+            (0..=255).collect(),
+            vec![0xab; 70_000],
+        ]
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for payload in payloads() {
+            let mut wire = Vec::new();
+            write_frame(&mut wire, &payload).unwrap();
+            assert_eq!(wire, encode_frame(&payload));
+            assert_eq!(wire.len(), HEADER_LEN + payload.len());
+
+            let mut reader = Cursor::new(&wire);
+            assert_eq!(
+                read_frame(&mut reader, MAX_FRAME_LEN).unwrap(),
+                Some(payload.clone())
+            );
+            assert_eq!(read_frame(&mut reader, MAX_FRAME_LEN).unwrap(), None);
+
+            let mut decoder = FrameDecoder::default();
+            decoder.push(&wire);
+            assert_eq!(decoder.next_frame(), Ok(Some(payload)));
+            assert_eq!(decoder.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn back_to_back_frames_survive_any_chunking() {
+        let wire: Vec<u8> = payloads().iter().flat_map(|p| encode_frame(p)).collect();
+
+        let mut trickle = Trickle(&wire);
+        for payload in payloads() {
+            assert_eq!(
+                read_frame(&mut trickle, MAX_FRAME_LEN).unwrap(),
+                Some(payload)
+            );
+        }
+        assert_eq!(read_frame(&mut trickle, MAX_FRAME_LEN).unwrap(), None);
+
+        let mut rng = SimRng::new(504);
+        for _ in 0..50 {
+            let mut decoder = FrameDecoder::default();
+            let mut frames = Vec::new();
+            let mut rest = &wire[..];
+            while !rest.is_empty() {
+                let n = (rng.range(0, 9000) as usize).min(rest.len());
+                decoder.push(&rest[..n]);
+                rest = &rest[n..];
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    frames.push(frame);
+                }
+            }
+            assert_eq!(frames, payloads());
+        }
+    }
+
+    #[test]
+    fn limit_is_inclusive() {
+        let wire = encode_frame(&[7; 10]);
+        assert_eq!(
+            read_frame(&mut Cursor::new(&wire), 10).unwrap(),
+            Some(vec![7; 10])
+        );
+        let err = read_frame(&mut Cursor::new(&wire), 9).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut decoder = FrameDecoder::new(9);
+        decoder.push(&wire[..HEADER_LEN]);
+        assert_eq!(
+            decoder.next_frame(),
+            Err(FrameError::TooLarge { len: 10, max: 9 })
+        );
+    }
+
+    #[test]
+    fn oversized_header_is_refused_before_the_payload_arrives() {
+        let header = u32::MAX.to_be_bytes();
+        let err = read_frame(&mut Cursor::new(&header), MAX_FRAME_LEN).unwrap_err();
+        assert!(err.to_string().contains("over the"), "{}", err);
+    }
+
+    #[test]
+    fn stream_ending_inside_a_frame_is_an_error() {
+        let wire = encode_frame(b"hello");
+        for cut in 1..wire.len() {
+            let err = read_frame(&mut Cursor::new(&wire[..cut]), MAX_FRAME_LEN).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "cut at {}", cut);
+
+            let mut decoder = FrameDecoder::default();
+            decoder.push(&wire[..cut]);
+            assert_eq!(decoder.next_frame(), Ok(None));
+            assert_eq!(decoder.buffered(), cut);
+        }
+    }
+}
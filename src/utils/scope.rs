@@ -0,0 +1,266 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Structured concurrency: threads that can't outlive the block that
+// started them.
+//
+//     scope(|s| {
+//         s.spawn(|token| pump(&mut reader, token));
+//         s.spawn(|token| pump(&mut writer, token));
+//         Ok(())
+//     })?;
+//
+// `scope` returns only once every thread spawned in it has finished, like
+// `std::thread::scope`. On top of that, the first worker to return an error
+// or panic cancels the scope's `CancelToken`, so the others can notice and
+// wind down, and that first failure is what `scope` returns. Threads are
+// never killed: a worker that doesn't check its token is waited for.
+
+// A cancellation flag shared by everything working on one task. A child
+// token is cancelled along with its parent, but cancelling the child leaves
+// the parent alone.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn child(&self) -> Self {
+        Self {
+            flag: Arc::new(AtomicBool::new(false)),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeError<E> {
+    // A worker, or the scope's own body, returned this error first.
+    Failed(E),
+    // A worker panicked first, with this message.
+    Panicked(String),
+}
+
+impl<E: fmt::Display> fmt::Display for ScopeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScopeError::Failed(e) => write!(f, "{}", e),
+            ScopeError::Panicked(message) => write!(f, "worker panicked: {}", message),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for ScopeError<E> {}
+
+pub struct Scope<'scope, 'env, E> {
+    inner: &'scope thread::Scope<'scope, 'env>,
+    token: CancelToken,
+    first: Arc<Mutex<Option<ScopeError<E>>>>,
+}
+
+impl<'scope, E: Send + 'scope> Scope<'scope, '_, E> {
+    // Run `work` on a new thread. It gets the scope's token, to return early
+    // once another worker has failed.
+    pub fn spawn<F>(&self, work: F)
+    where
+        F: FnOnce(&CancelToken) -> Result<(), E> + Send + 'scope,
+    {
+        let token = self.token.clone();
+        let first = Arc::clone(&self.first);
+        self.inner.spawn(move || {
+            let failure = match panic::catch_unwind(AssertUnwindSafe(|| work(&token))) {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => ScopeError::Failed(e),
+                Err(payload) => ScopeError::Panicked(panic_message(&*payload)),
+            };
+            record(&first, &token, failure);
+        });
+    }
+
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+// Run `body`, which can spawn workers, and wait for all of them.
+pub fn scope<'env, T, E, F>(body: F) -> Result<T, ScopeError<E>>
+where
+    E: Send,
+    F: for<'scope> FnOnce(&Scope<'scope, 'env, E>) -> Result<T, E>,
+{
+    scope_with(&CancelToken::new(), body)
+}
+
+// `scope`, with a token that's also cancelled when `parent` is.
+pub fn scope_with<'env, T, E, F>(parent: &CancelToken, body: F) -> Result<T, ScopeError<E>>
+where
+    E: Send,
+    F: for<'scope> FnOnce(&Scope<'scope, 'env, E>) -> Result<T, E>,
+{
+    let token = parent.child();
+    let first = Arc::new(Mutex::new(None));
+    let result = thread::scope(|s| {
+        let scope = Scope {
+            inner: s,
+            token: token.clone(),
+            first: Arc::clone(&first),
+        };
+        let result = body(&scope);
+        // Workers are only joined once the body is done, so a failing body
+        // should tell them to stop.
+        result.map_err(|e| record(&first, &token, ScopeError::Failed(e)))
+    });
+    let failure = first.lock().unwrap_or_else(|e| e.into_inner()).take();
+    match failure {
+        Some(failure) => Err(failure),
+        None => Ok(result.expect("a failed body records its error")),
+    }
+}
+
+// Keep `failure` if it's the first, and cancel the scope.
+fn record<E>(first: &Mutex<Option<ScopeError<E>>>, token: &CancelToken, failure: ScopeError<E>) {
+    let mut first = first.lock().unwrap_or_else(|e| e.into_inner());
+    if first.is_none() {
+        *first = Some(failure);
+    }
+    token.cancel();
+}
+
+// The message a panic was raised with, if it was a string.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    // Spin until the token is cancelled; fails rather than hanging.
+    fn wait_cancelled(token: &CancelToken) -> Result<(), String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !token.is_cancelled() {
+            if Instant::now() >= deadline {
+                return Err("never cancelled".to_string());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn every_worker_finishes_before_scope_returns() {
+        let done = AtomicUsize::new(0);
+
+        let value = scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|_| {
+                    thread::sleep(Duration::from_millis(10));
+                    done.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, String>(())
+                });
+            }
+            Ok("body")
+        });
+
+        assert_eq!(value, Ok("body"));
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn the_first_error_cancels_the_rest_and_is_returned() {
+        let result = scope(|s| {
+            s.spawn(|token| {
+                wait_cancelled(token)?;
+                Err("second".to_string())
+            });
+            s.spawn(|_| Err("first".to_string()));
+            Ok(())
+        });
+
+        assert_eq!(result, Err(ScopeError::Failed("first".to_string())));
+    }
+
+    #[test]
+    fn a_panic_is_caught_and_cancels_the_rest() {
+        let noticed = AtomicBool::new(false);
+
+        let result = scope(|s| {
+            s.spawn(|token| {
+                wait_cancelled(token)?;
+                noticed.store(true, Ordering::SeqCst);
+                Ok::<_, String>(())
+            });
+            s.spawn(|_| panic!("worker {} broke", 2));
+            Ok(())
+        });
+
+        let e = result.unwrap_err();
+        assert_eq!(e, ScopeError::Panicked("worker 2 broke".to_string()));
+        assert_eq!(e.to_string(), "worker panicked: worker 2 broke");
+        assert!(noticed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn a_failing_body_cancels_its_workers() {
+        let result: Result<(), _> = scope(|s| {
+            s.spawn(wait_cancelled);
+            Err("body".to_string())
+        });
+
+        assert_eq!(result, Err(ScopeError::Failed("body".to_string())));
+    }
+
+    #[test]
+    fn cancelling_a_parent_reaches_the_scope_but_not_the_other_way() {
+        let parent = CancelToken::new();
+
+        let result = scope_with(&parent, |s| {
+            s.spawn(wait_cancelled);
+            parent.cancel();
+            Ok::<_, String>(())
+        });
+        assert_eq!(result, Ok(()));
+
+        let parent = CancelToken::new();
+        let child = parent.child();
+        child.cancel();
+        assert!(child.is_cancelled() && !parent.is_cancelled());
+    }
+
+    #[test]
+    fn panic_message_reads_either_string_type() {
+        let message = |payload: Box<dyn Any + Send>| panic_message(&*payload);
+        assert_eq!(message(Box::new("static")), "static");
+        assert_eq!(message(Box::new(String::from("owned"))), "owned");
+        assert_eq!(message(Box::new(42)), "unknown panic");
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// Live counts for a running server, shared by every connection handler.
+//
+// Plain atomics, so recording from many threads or tasks costs no locking.
+// The counts are independent of each other, and a snapshot taken while
+// connections are busy can be a message or two out of step between them;
+// for a summary line that's fine.
+//
+// Activity is also kept per client, keyed by IP address: the server has no
+// logins, so every connection from one address counts as the same client.
+// That part takes a lock, once per connection and once per message.
+//
+// Unlike `storage::counters`, nothing here accumulates across restarts:
+// this is what the server has done since it started. `report()` is the
+// whole picture, for writing out where other programs (like the `stats`
+// subcommand) can read it.
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    connections: AtomicU64,
+    active: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    errors: AtomicU64,
+    clients: Mutex<HashMap<String, ClientActivity>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    // Connections accepted since the server started.
+    pub connections: u64,
+    // Connections open right now.
+    pub active: u64,
+    // Payload bytes received and sent; frame headers aren't counted.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Count a new connection from `client`. It stays active until the
+    // guard is dropped.
+    pub fn connection_opened(&self, client: &str) -> ActiveConnection<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.client(client, |activity| activity.connections += 1);
+        ActiveConnection { stats: self }
+    }
+
+    // Count a message of `bytes` payload bytes received from `client`.
+    pub fn message_received(&self, client: &str, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.client(client, |activity| {
+            activity.messages += 1;
+            activity.bytes += bytes as u64;
+        });
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    // Every client seen so far, in no particular order.
+    pub fn clients(&self) -> Vec<ClientActivity> {
+        let clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.values().cloned().collect()
+    }
+
+    pub fn report(&self) -> StatsReport {
+        StatsReport {
+            written_unix: unix_secs(),
+            totals: self.snapshot(),
+            clients: self.clients(),
+        }
+    }
+
+    fn client(&self, client: &str, update: impl FnOnce(&mut ClientActivity)) {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let activity = clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientActivity {
+                client: client.to_string(),
+                ..ClientActivity::default()
+            });
+        update(activity);
+        activity.last_active_unix = unix_secs();
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientActivity {
+    // The client's IP address.
+    pub client: String,
+    pub connections: u64,
+    pub messages: u64,
+    // Payload bytes received from this client.
+    pub bytes: u64,
+    // When the client last connected or sent a message.
+    pub last_active_unix: u64,
+}
+
+// Everything at one moment, as written to a stats file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub written_unix: u64,
+    pub totals: StatsSnapshot,
+    pub clients: Vec<ClientActivity>,
+}
+
+// What to rank clients by, busiest (or most recent) first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientSort {
+    #[default]
+    Messages,
+    Bytes,
+    Connections,
+    LastActive,
+}
+
+impl FromStr for ClientSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "messages" => Ok(ClientSort::Messages),
+            "bytes" => Ok(ClientSort::Bytes),
+            "connections" => Ok(ClientSort::Connections),
+            "last-active" => Ok(ClientSort::LastActive),
+            _ => Err(format!(
+                "unknown sort {:?} (expected messages, bytes, connections or last-active)",
+                s
+            )),
+        }
+    }
+}
+
+// `clients` ranked by `sort`, ties broken by address, keeping the first
+// `top` if given.
+pub fn top_clients(
+    mut clients: Vec<ClientActivity>,
+    sort: ClientSort,
+    top: Option<usize>,
+) -> Vec<ClientActivity> {
+    let key = |c: &ClientActivity| match sort {
+        ClientSort::Messages => c.messages,
+        ClientSort::Bytes => c.bytes,
+        ClientSort::Connections => c.connections,
+        ClientSort::LastActive => c.last_active_unix,
+    };
+    clients.sort_by(|a, b| key(b).cmp(&key(a)).then_with(|| a.client.cmp(&b.client)));
+    if let Some(top) = top {
+        clients.truncate(top);
+    }
+    clients
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Marks one connection as active for as long as it's held.
+#[derive(Debug)]
+pub struct ActiveConnection<'a> {
+    stats: &'a ServerStats,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl StatsSnapshot {
+    // Every count by name, for `alerts::Alerts::evaluate`.
+    pub fn metrics(&self) -> [(&'static str, f64); 5] {
+        [
+            ("connections", self.connections as f64),
+            ("active", self.active as f64),
+            ("bytes_in", self.bytes_in as f64),
+            ("bytes_out", self.bytes_out as f64),
+            ("errors", self.errors as f64),
+        ]
+    }
+}
+
+// One line, for logs: `connections=12 active=3 bytes_in=4096 bytes_out=4096 errors=0`.
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connections={} active={} bytes_in={} bytes_out={} errors={}",
+            self.connections, self.active, self.bytes_in, self.bytes_out, self.errors
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn activity(client: &str, messages: u64, bytes: u64, connections: u64) -> ClientActivity {
+        ClientActivity {
+            client: client.to_string(),
+            connections,
+            messages,
+            bytes,
+            last_active_unix: 0,
+        }
+    }
+
+    #[test]
+    fn a_connection_is_active_while_its_guard_lives() {
+        let stats = ServerStats::new();
+        let first = stats.connection_opened("10.0.0.1");
+        {
+            let _second = stats.connection_opened("10.0.0.1");
+            assert_eq!(stats.snapshot().active, 2);
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.connections, snapshot.active), (2, 1));
+        drop(first);
+        assert_eq!(stats.snapshot().active, 0);
+    }
+
+    #[test]
+    fn messages_count_toward_their_client_and_the_totals() {
+        let stats = ServerStats::new();
+        let _a = stats.connection_opened("10.0.0.1");
+        let _b = stats.connection_opened("10.0.0.2");
+        stats.message_received("10.0.0.1", 5);
+        stats.message_received("10.0.0.1", 7);
+        stats.message_received("10.0.0.2", 1);
+        stats.add_bytes_out(13);
+        stats.record_error();
+
+        assert_eq!(
+            stats.snapshot().to_string(),
+            "connections=2 active=2 bytes_in=13 bytes_out=13 errors=1"
+        );
+        let clients = top_clients(stats.clients(), ClientSort::Messages, None);
+        assert_eq!(
+            clients
+                .iter()
+                .map(|c| (c.client.as_str(), c.connections, c.messages, c.bytes))
+                .collect::<Vec<_>>(),
+            [("10.0.0.1", 1, 2, 12), ("10.0.0.2", 1, 1, 1)]
+        );
+        assert!(clients.iter().all(|c| c.last_active_unix > 0));
+    }
+
+    #[test]
+    fn counts_from_many_threads_all_land() {
+        let stats = ServerStats::new();
+
+        thread::scope(|s| {
+            for t in 0..8 {
+                let stats = &stats;
+                s.spawn(move || {
+                    let client = format!("10.0.0.{}", t % 2);
+                    let _connection = stats.connection_opened(&client);
+                    for _ in 0..1000 {
+                        stats.message_received(&client, 2);
+                    }
+                });
+            }
+        });
+
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.connections, snapshot.active), (8, 0));
+        assert_eq!(snapshot.bytes_in, 8 * 1000 * 2);
+        let messages: Vec<_> = stats.clients().iter().map(|c| c.messages).collect();
+        assert_eq!(messages, [4000, 4000]);
+    }
+
+    #[test]
+    fn top_clients_ranks_then_breaks_ties_by_address() {
+        let clients = vec![
+            activity("10.0.0.3", 5, 100, 1),
+            activity("10.0.0.1", 9, 10, 1),
+            activity("10.0.0.2", 5, 500, 3),
+        ];
+
+        let ranked = |sort, top| -> Vec<String> {
+            top_clients(clients.clone(), sort, top)
+                .into_iter()
+                .map(|c| c.client)
+                .collect()
+        };
+        assert_eq!(
+            ranked(ClientSort::Messages, None),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        assert_eq!(ranked(ClientSort::Bytes, Some(1)), ["10.0.0.2"]);
+        assert_eq!(ranked(ClientSort::Connections, Some(5)).len(), 3);
+        assert_eq!("Last-Active".parse(), Ok(ClientSort::LastActive));
+        assert!("busiest".parse::<ClientSort>().is_err());
+    }
+
+    #[test]
+    fn a_report_round_trips_through_json() {
+        let stats = ServerStats::new();
+        drop(stats.connection_opened("::1"));
+        stats.message_received("::1", 3);
+
+        let report = stats.report();
+        let read: StatsReport =
+            serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+
+        assert_eq!(read, report);
+        assert_eq!(read.totals.metrics()[0], ("connections", 1.0));
+        assert_eq!(read.clients[0].client, "::1");
+    }
+}
@@ -0,0 +1,556 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+use super::transport::{Handler, Transport};
+
+// A deterministic in-memory network for running `Handler`s in one process.
+//
+// Nothing here touches a real socket or clock. A sent message is queued
+// with a delivery time in virtual milliseconds (now plus a latency drawn
+// from a seeded RNG), and `step` jumps the clock to the next delivery and
+// hands the message to its node. The same seed, nodes and sends give the
+// same run every time, so a failure found in simulation can be replayed
+// exactly.
+//
+// Messages between two nodes arrive in the order they were sent unless
+// `reorder` is set, in which case a later message with a shorter latency
+// can overtake an earlier one. While two nodes are partitioned, messages
+// between them are dropped in both directions, including ones already in
+// flight.
+//
+// Everything that happens is recorded in `history`, numbered in the order
+// it ran. Since a run is fixed by its seed, an event's number names the
+// same moment in every run: `run_until` re-executes up to just before it,
+// and the nodes, the messages in flight and the partitions can be looked
+// at as they were then.
+
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub min_latency_ms: u64,
+    pub max_latency_ms: u64,
+    pub reorder: bool,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            min_latency_ms: 1,
+            max_latency_ms: 10,
+            reorder: false,
+        }
+    }
+}
+
+// splitmix64: tiny, and plenty for picking latencies.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in `low..=high` (slightly biased for huge ranges, which
+    // latencies rarely are).
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        match (high - low).checked_add(1) {
+            Some(span) => low + self.next_u64() % span,
+            // `0..=u64::MAX`: every value is in range.
+            None => self.next_u64(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub at_ms: u64,
+    pub from: String,
+    pub to: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: u64,
+    pub delivered: u64,
+    // Lost to a partition or sent to a node that doesn't exist.
+    pub dropped: u64,
+}
+
+// One thing that happened during a run. `id`s count up from 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimEvent {
+    pub id: u64,
+    pub at_ms: u64,
+    pub kind: SimEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimEventKind {
+    Delivered(Delivery),
+    // Lost to a partition or sent to a node that doesn't exist.
+    Dropped(Delivery),
+    Partitioned(String, String),
+    Healed(String, String),
+}
+
+// `#12 [   40 ms] client-0 -> server: hello`, for traces.
+impl fmt::Display for SimEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} [{:>5} ms] ", self.id, self.at_ms)?;
+        match &self.kind {
+            SimEventKind::Delivered(delivery) => write!(f, "{}", delivery),
+            SimEventKind::Dropped(delivery) => write!(f, "dropped {}", delivery),
+            SimEventKind::Partitioned(a, b) => write!(f, "partition {} | {}", a, b),
+            SimEventKind::Healed(a, b) => write!(f, "heal {} | {}", a, b),
+        }
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {}: {}",
+            self.from,
+            self.to,
+            String::from_utf8_lossy(&self.payload)
+        )
+    }
+}
+
+enum Event {
+    Deliver(Delivery),
+    Partition(String, String),
+    Heal(String, String),
+}
+
+pub struct SimNet {
+    config: SimConfig,
+    rng: SimRng,
+    now_ms: u64,
+    next_seq: u64,
+    // Keyed by (time, sequence number), so events due at the same moment
+    // run in the order they were scheduled.
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    events: HashMap<u64, Event>,
+    nodes: HashMap<String, Box<dyn Handler>>,
+    partitions: HashSet<(String, String)>,
+    // When the last message from one node to another arrives, so that
+    // without `reorder` a new one never arrives before it.
+    last_arrival: HashMap<(String, String), u64>,
+    stats: SimStats,
+    trace: Vec<Delivery>,
+    history: Vec<SimEvent>,
+}
+
+impl SimNet {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            config,
+            rng: SimRng::new(config.seed),
+            now_ms: 0,
+            next_seq: 0,
+            queue: BinaryHeap::new(),
+            events: HashMap::new(),
+            nodes: HashMap::new(),
+            partitions: HashSet::new(),
+            last_arrival: HashMap::new(),
+            stats: SimStats::default(),
+            trace: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, name: &str, handler: impl Handler + 'static) {
+        self.nodes.insert(name.to_string(), Box::new(handler));
+    }
+
+    // A transport that sends as `from`, for getting a conversation going
+    // from outside the network.
+    pub fn transport(&mut self, from: &str) -> SimTransport<'_> {
+        SimTransport {
+            net: self,
+            from: from.to_string(),
+        }
+    }
+
+    pub fn partition(&mut self, a: &str, b: &str) {
+        self.partitions.insert(pair(a, b));
+    }
+
+    pub fn heal(&mut self, a: &str, b: &str) {
+        self.partitions.remove(&pair(a, b));
+    }
+
+    pub fn partition_at(&mut self, at_ms: u64, a: &str, b: &str) {
+        self.schedule(at_ms, Event::Partition(a.to_string(), b.to_string()));
+    }
+
+    pub fn heal_at(&mut self, at_ms: u64, a: &str, b: &str) {
+        self.schedule(at_ms, Event::Heal(a.to_string(), b.to_string()));
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.config.seed
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.stats
+    }
+
+    // Every delivered message, in delivery order.
+    pub fn trace(&self) -> &[Delivery] {
+        &self.trace
+    }
+
+    // Every event run so far, in order.
+    pub fn history(&self) -> &[SimEvent] {
+        &self.history
+    }
+
+    // The id the next event to run will get.
+    pub fn next_event_id(&self) -> u64 {
+        self.history.len() as u64
+    }
+
+    // Messages sent but not delivered (or dropped) yet, in the order
+    // they'll arrive.
+    pub fn in_flight(&self) -> Vec<&Delivery> {
+        let mut queued: Vec<_> = self.queue.iter().map(|Reverse(key)| *key).collect();
+        queued.sort();
+        queued
+            .iter()
+            .filter_map(|(_, seq)| match &self.events[seq] {
+                Event::Deliver(delivery) => Some(delivery),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The pairs of nodes cut off from each other right now, sorted.
+    pub fn partitions(&self) -> Vec<(String, String)> {
+        let mut partitions: Vec<_> = self.partitions.iter().cloned().collect();
+        partitions.sort();
+        partitions
+    }
+
+    // Each node's name and `Handler::state`, sorted by name.
+    pub fn node_states(&self) -> Vec<(String, String)> {
+        let mut states: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|(name, node)| (name.clone(), node.state()))
+            .collect();
+        states.sort();
+        states
+    }
+
+    // Run the next event. Returns false once nothing is left to run. If a
+    // handler fails, its delivery is still recorded, so the error belongs
+    // to the last event in `history`.
+    pub fn step(&mut self) -> io::Result<bool> {
+        let Some(Reverse((at_ms, seq))) = self.queue.pop() else {
+            return Ok(false);
+        };
+        self.now_ms = at_ms;
+        match self.events.remove(&seq).expect("queued event exists") {
+            Event::Partition(a, b) => {
+                self.partition(&a, &b);
+                self.record(SimEventKind::Partitioned(a, b));
+            }
+            Event::Heal(a, b) => {
+                self.heal(&a, &b);
+                self.record(SimEventKind::Healed(a, b));
+            }
+            Event::Deliver(delivery) => {
+                let partitioned = self
+                    .partitions
+                    .contains(&pair(&delivery.from, &delivery.to));
+                // Taken out while it runs, so it can send through `self`.
+                match self.nodes.remove(&delivery.to) {
+                    Some(mut node) if !partitioned => {
+                        self.stats.delivered += 1;
+                        let result = node.on_message(
+                            &delivery.from,
+                            &delivery.payload,
+                            &mut self.transport(&delivery.to),
+                        );
+                        self.nodes.insert(delivery.to.clone(), node);
+                        self.trace.push(delivery.clone());
+                        self.record(SimEventKind::Delivered(delivery));
+                        result?;
+                    }
+                    Some(node) => {
+                        self.stats.dropped += 1;
+                        self.nodes.insert(delivery.to.clone(), node);
+                        self.record(SimEventKind::Dropped(delivery));
+                    }
+                    None => {
+                        self.stats.dropped += 1;
+                        self.record(SimEventKind::Dropped(delivery));
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Step until nothing is left or `max_steps` events have run, whichever
+    // comes first (handlers that keep talking to each other never go
+    // quiet). Returns the number of steps taken.
+    pub fn run(&mut self, max_steps: u64) -> io::Result<u64> {
+        let mut steps = 0;
+        while steps < max_steps && self.step()? {
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    // Step until event `event_id` is next, so everything before it has
+    // run and it hasn't, or until nothing is left. Returns the number of
+    // steps taken.
+    pub fn run_until(&mut self, event_id: u64) -> io::Result<u64> {
+        let mut steps = 0;
+        while self.next_event_id() < event_id && self.step()? {
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    fn record(&mut self, kind: SimEventKind) {
+        self.history.push(SimEvent {
+            id: self.next_event_id(),
+            at_ms: self.now_ms,
+            kind,
+        });
+    }
+
+    fn schedule(&mut self, at_ms: u64, event: Event) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.push(Reverse((at_ms, seq)));
+        self.events.insert(seq, event);
+    }
+
+    fn send_from(&mut self, from: &str, to: &str, payload: &[u8]) {
+        self.stats.sent += 1;
+        let latency = self
+            .rng
+            .range(self.config.min_latency_ms, self.config.max_latency_ms);
+        // A huge --latency can't run the clock past the end.
+        let mut at_ms = self.now_ms.saturating_add(latency);
+        if !self.config.reorder {
+            let last = self
+                .last_arrival
+                .entry((from.to_string(), to.to_string()))
+                .or_insert(0);
+            at_ms = at_ms.max(*last);
+            *last = at_ms;
+        }
+        self.schedule(
+            at_ms,
+            Event::Deliver(Delivery {
+                at_ms,
+                from: from.to_string(),
+                to: to.to_string(),
+                payload: payload.to_vec(),
+            }),
+        );
+    }
+}
+
+pub struct SimTransport<'a> {
+    net: &'a mut SimNet,
+    from: String,
+}
+
+impl Transport for SimTransport<'_> {
+    fn send(&mut self, to: &str, payload: &[u8]) -> io::Result<()> {
+        self.net.send_from(&self.from, to, payload);
+        Ok(())
+    }
+}
+
+fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::echo::EchoServer;
+
+    // Remembers what it received, in order.
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Handler for Recorder {
+        fn on_message(
+            &mut self,
+            _from: &str,
+            payload: &[u8],
+            _transport: &mut dyn Transport,
+        ) -> io::Result<()> {
+            self.0.push(String::from_utf8_lossy(payload).into_owned());
+            Ok(())
+        }
+
+        fn state(&self) -> String {
+            self.0.join(",")
+        }
+    }
+
+    // Ten messages from `client` through an echo server; returns the
+    // delivery trace and what came back.
+    fn echo_run(config: SimConfig) -> (Vec<Delivery>, String) {
+        let mut net = SimNet::new(config);
+        net.add_node("server", EchoServer::default());
+        net.add_node("client", Recorder::default());
+        for i in 0..10 {
+            net.transport("client")
+                .send("server", i.to_string().as_bytes())
+                .unwrap();
+        }
+        net.run(1000).unwrap();
+        let received = net.node_states()[0].1.clone();
+        (net.trace().to_vec(), received)
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let config = SimConfig {
+            seed: 42,
+            reorder: true,
+            ..SimConfig::default()
+        };
+        assert_eq!(echo_run(config), echo_run(config));
+        let other = SimConfig { seed: 43, ..config };
+        assert_ne!(echo_run(config).0, echo_run(other).0);
+    }
+
+    #[test]
+    fn without_reorder_messages_arrive_in_order() {
+        for seed in 0..20 {
+            let (_, received) = echo_run(SimConfig {
+                seed,
+                ..SimConfig::default()
+            });
+            assert_eq!(received, "0,1,2,3,4,5,6,7,8,9", "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn with_reorder_some_seed_overtakes() {
+        let reordered = (0..20).any(|seed| {
+            let (_, received) = echo_run(SimConfig {
+                seed,
+                reorder: true,
+                ..SimConfig::default()
+            });
+            received != "0,1,2,3,4,5,6,7,8,9"
+        });
+        assert!(reordered);
+    }
+
+    #[test]
+    fn partition_drops_messages_until_healed() {
+        let mut net = SimNet::new(SimConfig {
+            min_latency_ms: 5,
+            max_latency_ms: 5,
+            ..SimConfig::default()
+        });
+        net.add_node("a", Recorder::default());
+        net.add_node("b", Recorder::default());
+        net.partition_at(0, "a", "b");
+        net.heal_at(100, "a", "b");
+        net.step().unwrap();
+        net.transport("a").send("b", b"lost").unwrap();
+        net.run(10).unwrap();
+        net.transport("a").send("b", b"kept").unwrap();
+        net.run(10).unwrap();
+
+        assert_eq!(net.node_states()[1], ("b".to_string(), "kept".to_string()));
+        assert_eq!(
+            net.stats(),
+            SimStats {
+                sent: 2,
+                delivered: 1,
+                dropped: 1
+            }
+        );
+        assert_eq!(net.now_ms(), 105);
+    }
+
+    #[test]
+    fn run_until_stops_before_the_event() {
+        let config = SimConfig {
+            seed: 7,
+            ..SimConfig::default()
+        };
+        let mut full = SimNet::new(config);
+        full.add_node("server", EchoServer::default());
+        full.add_node("client", Recorder::default());
+        full.transport("client").send("server", b"x").unwrap();
+        full.run(10).unwrap();
+
+        let mut partial = SimNet::new(config);
+        partial.add_node("server", EchoServer::default());
+        partial.add_node("client", Recorder::default());
+        partial.transport("client").send("server", b"x").unwrap();
+        partial.run_until(1).unwrap();
+
+        assert_eq!(partial.history(), &full.history()[..1]);
+        assert_eq!(partial.in_flight().len(), 1);
+    }
+
+    #[test]
+    fn range_covers_extremes_without_overflow() {
+        let mut rng = SimRng::new(1);
+        for _ in 0..100 {
+            rng.range(0, u64::MAX);
+            assert!((u64::MAX - 1..=u64::MAX).contains(&rng.range(u64::MAX - 1, u64::MAX)));
+            assert_eq!(rng.range(5, 5), 5);
+            assert_eq!(rng.range(9, 3), 9);
+        }
+    }
+
+    #[test]
+    fn huge_latency_saturates_the_clock() {
+        let mut net = SimNet::new(SimConfig {
+            min_latency_ms: u64::MAX,
+            max_latency_ms: u64::MAX,
+            ..SimConfig::default()
+        });
+        net.add_node("b", Recorder::default());
+        net.transport("a").send("b", b"1").unwrap();
+        net.run(10).unwrap();
+        net.transport("a").send("b", b"2").unwrap();
+        net.run(10).unwrap();
+        assert_eq!(net.now_ms(), u64::MAX);
+        assert_eq!(net.stats().delivered, 2);
+    }
+}
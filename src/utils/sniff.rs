@@ -0,0 +1,274 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Guess what kind of content a byte buffer holds from its first few hundred
+// bytes: magic numbers for the binary formats we care about, otherwise a
+// UTF-8 / control-character check to tell text from binary.
+//
+// This is a heuristic for choosing how to show or handle data, not
+// validation: a file sniffed as JSON may still fail to parse.
+
+// How many leading bytes `sniff` looks at.
+pub const SNIFF_LEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Empty,
+    // UTF-8 text (with or without a BOM).
+    Text,
+    // Text that starts like a JSON object or array.
+    Json,
+    Gzip,
+    Png,
+    // Anything else.
+    Binary,
+}
+
+impl ContentKind {
+    // Safe to print to a terminal as-is.
+    pub fn is_text(self) -> bool {
+        matches!(
+            self,
+            ContentKind::Empty | ContentKind::Text | ContentKind::Json
+        )
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ContentKind::Empty | ContentKind::Text => "text/plain; charset=utf-8",
+            ContentKind::Json => "application/json",
+            ContentKind::Gzip => "application/gzip",
+            ContentKind::Png => "image/png",
+            ContentKind::Binary => "application/octet-stream",
+        }
+    }
+}
+
+impl fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ContentKind::Empty => "empty",
+            ContentKind::Text => "text",
+            ContentKind::Json => "json",
+            ContentKind::Gzip => "gzip",
+            ContentKind::Png => "png",
+            ContentKind::Binary => "binary",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+pub fn sniff(bytes: &[u8]) -> ContentKind {
+    let head = &bytes[..bytes.len().min(SNIFF_LEN)];
+    if head.is_empty() {
+        return ContentKind::Empty;
+    }
+    if head.starts_with(PNG_MAGIC) {
+        return ContentKind::Png;
+    }
+    if head.starts_with(GZIP_MAGIC) {
+        return ContentKind::Gzip;
+    }
+
+    let head = head.strip_prefix(UTF8_BOM).unwrap_or(head);
+    // `head` may cut a multi-byte character in half; that's still text.
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return ContentKind::Binary,
+    };
+    if !looks_like_text(text) {
+        return ContentKind::Binary;
+    }
+    if looks_like_json(text) {
+        ContentKind::Json
+    } else {
+        ContentKind::Text
+    }
+}
+
+// Sniff the start of a file without reading the whole thing.
+pub fn sniff_file(path: &Path) -> io::Result<ContentKind> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)?;
+    Ok(sniff(&head))
+}
+
+// Render `bytes` for a terminal: as text if it sniffs as text, otherwise as a
+// hex dump, so binary data can't garble the display.
+pub fn display_safe(bytes: &[u8]) -> String {
+    if sniff(bytes).is_text()
+        && let Ok(text) = std::str::from_utf8(bytes)
+    {
+        return text.to_string();
+    }
+    hex_dump(bytes)
+}
+
+// `xxd`-style dump: offset, 16 bytes in hex, then the printable ASCII.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, row) in bytes.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x}  ", i * 16));
+        for col in 0..16 {
+            match row.get(col) {
+                Some(b) => out.push_str(&format!("{:02x} ", b)),
+                None => out.push_str("   "),
+            }
+            if col == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        out.extend(row.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+// Text has no NULs and few control characters besides whitespace and the
+// escape that starts ANSI color codes.
+fn looks_like_text(text: &str) -> bool {
+    if text.contains('\0') {
+        return false;
+    }
+    let controls = text
+        .chars()
+        .filter(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+        .count();
+    // Allow the odd stray control character, but not a run of them.
+    controls * 100 <= text.chars().count()
+}
+
+fn looks_like_json(text: &str) -> bool {
+    let mut chars = text.trim_start().chars();
+    let open = chars.next();
+    let next = chars.find(|c| !c.is_whitespace());
+    match (open, next) {
+        (Some('{'), Some(c)) => c == '"' || c == '}',
+        (Some('['), Some(c)) => {
+            matches!(c, '"' | '{' | '[' | ']' | '-' | 't' | 'f' | 'n') || c.is_ascii_digit()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("sniff-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn magic_numbers_win_over_everything_else() {
+        assert_eq!(sniff(b""), ContentKind::Empty);
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), ContentKind::Png);
+        assert_eq!(sniff(&[0x1f, 0x8b, 0x08, 0x00]), ContentKind::Gzip);
+        assert_eq!(sniff(b"\x89PNG but not quite"), ContentKind::Binary);
+        assert_eq!(ContentKind::Gzip.mime_type(), "application/gzip");
+    }
+
+    #[test]
+    fn text_is_utf8_without_nuls_or_a_run_of_controls() {
+        assert_eq!(
+            sniff("grüße\r\n\tund \x1b[1mfett\x1b[0m".as_bytes()),
+            ContentKind::Text
+        );
+        assert_eq!(sniff(b"\xef\xbb\xbfwith a BOM"), ContentKind::Text);
+        assert_eq!(sniff(b"nul\0inside"), ContentKind::Binary);
+        assert_eq!(sniff(b"not \xff utf-8"), ContentKind::Binary);
+
+        // One stray control character in a hundred is let through.
+        let mut text = "x".repeat(99);
+        text.push('\x07');
+        assert_eq!(sniff(text.as_bytes()), ContentKind::Text);
+        text.push('\x07');
+        assert_eq!(sniff(text.as_bytes()), ContentKind::Binary);
+    }
+
+    #[test]
+    fn only_the_head_is_looked_at() {
+        // A character cut in half at the end of the head is still text...
+        let mut bytes = "a".repeat(SNIFF_LEN - 1).into_bytes();
+        bytes.extend_from_slice("é".as_bytes());
+        assert_eq!(sniff(&bytes), ContentKind::Text);
+        // ...and whatever comes after the head doesn't count.
+        bytes.extend_from_slice(b"\0\0\0\xff");
+        assert_eq!(sniff(&bytes), ContentKind::Text);
+
+        let dir = TempDir::new("file");
+        let path = dir.0.join("data");
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(sniff_file(&path).unwrap(), ContentKind::Text);
+        fs::write(&path, b"\x1f\x8b\x08").unwrap();
+        assert_eq!(sniff_file(&path).unwrap(), ContentKind::Gzip);
+        assert!(sniff_file(&dir.0.join("missing")).is_err());
+    }
+
+    #[test]
+    fn json_is_text_that_opens_like_an_object_or_array() {
+        for json in [
+            "{\"a\": 1}",
+            "  {}",
+            "[1, 2]",
+            "[\n  {\"a\": 1}]",
+            "[-1]",
+            "[]",
+            "[null]",
+        ] {
+            assert_eq!(sniff(json.as_bytes()), ContentKind::Json, "{}", json);
+        }
+        for text in ["{ not json }", "[section]", "[", "plain"] {
+            assert_eq!(sniff(text.as_bytes()), ContentKind::Text, "{}", text);
+        }
+    }
+
+    #[test]
+    fn display_safe_dumps_anything_that_isnt_text() {
+        assert_eq!(display_safe(b"hello"), "hello");
+        assert_eq!(
+            display_safe(b"\0\x01AB"),
+            "00000000  00 01 41 42                                       |..AB|\n"
+        );
+
+        let dump = hex_dump(&(0u8..20).collect::<Vec<_>>());
+        let lines: Vec<_> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("00000000  00 01 02 03 04 05 06 07  08 09"));
+        assert!(lines[1].starts_with("00000010  10 11 12 13 "));
+        assert!(lines[1].ends_with("|....|"));
+    }
+}
@@ -0,0 +1,95 @@
+// A hash of bytes whose output never changes between runs, machines, or
+// Rust versions. `DefaultHasher` makes no such promise, so anything that
+// gets persisted or compared across processes (Bloom filters on disk, hash
+// ring positions shared by several nodes) should hash with this instead.
+//
+// It takes bytes rather than any `T: Hash` on purpose: `Hash` impls feed
+// integers to the hasher in native byte order and lengths as `usize`, and
+// std doesn't promise to keep them the same, so the same value could hash
+// differently on a 32-bit or big-endian node, or after an upgrade. Callers
+// that hash more than one field lay them out with `StableHasher`, whose
+// integers are always little-endian and 64-bit.
+//
+// It's FNV-1a seeded by its offset basis, finished with the MurmurHash3
+// avalanche step because plain FNV mixes the last few bytes poorly.
+pub fn stable_hash(bytes: &[u8], seed: u64) -> u64 {
+    let mut hasher = StableHasher::new(seed);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[derive(Debug, Clone)]
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+
+    // Length first, so ("ab", "c") and ("a", "bc") hash differently.
+    pub fn write_field(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.write(bytes);
+    }
+
+    pub fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pinned outputs: if one of these changes, every persisted filter and
+    // every ring shared with an older node breaks.
+    #[test]
+    fn outputs_are_pinned() {
+        assert_eq!(stable_hash(b"", 0), 0);
+        assert_eq!(stable_hash(b"hello", 0), PINNED_HELLO);
+        assert_eq!(
+            stable_hash(b"hello", 0xcbf2_9ce4_8422_2325),
+            PINNED_HELLO_SEEDED
+        );
+        let mut fields = StableHasher::new(0);
+        fields.write_field(b"node-a");
+        fields.write_u64(7);
+        assert_eq!(fields.finish(), PINNED_FIELDS);
+    }
+
+    #[test]
+    fn fields_are_delimited() {
+        let hash = |a: &[u8], b: &[u8]| {
+            let mut hasher = StableHasher::new(1);
+            hasher.write_field(a);
+            hasher.write_field(b);
+            hasher.finish()
+        };
+        assert_ne!(hash(b"ab", b"c"), hash(b"a", b"bc"));
+    }
+
+    #[test]
+    fn seed_changes_the_hash() {
+        assert_ne!(stable_hash(b"x", 1), stable_hash(b"x", 2));
+    }
+
+    const PINNED_HELLO: u64 = 0xd821_31e9_637c_0108;
+    const PINNED_HELLO_SEEDED: u64 = 0xe9c5_62c0_fdb2_3244;
+    const PINNED_FIELDS: u64 = 0x9070_8fa1_d98c_28a3;
+}
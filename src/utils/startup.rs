@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::graph::Graph;
+
+// Starting a program's subsystems without letting an optional one take the
+// whole program down.
+//
+// Each subsystem is started through `Startup::optional` or
+// `Startup::critical`. A critical one failing is always fatal. An optional
+// one failing is fatal under `Strictness::Strict`; under
+// `Strictness::Degrade` its fallback (usually a no-op or a simpler
+// stand-in) is used instead and the failure is recorded, so the program
+// can report what it's running without.
+//
+// `StartupPlan` puts the steps in order: each step names the steps it needs
+// to run after, and steps that don't depend on each other run at the same
+// time on their own threads. `run` returns a timeline of when each step
+// started and how long it took.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    // Any subsystem failing to start stops the program.
+    Strict,
+    // Optional subsystems that fail are replaced by their fallback.
+    #[default]
+    Degrade,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Strictness::Strict),
+            "degrade" => Ok(Strictness::Degrade),
+            _ => Err(format!(
+                "unknown strictness {:?} (expected strict or degrade)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strictness::Strict => "strict",
+            Strictness::Degrade => "degrade",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Degraded {
+    pub component: String,
+    pub error: String,
+}
+
+impl fmt::Display for Degraded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} degraded: {}", self.component, self.error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartupError {
+    pub component: String,
+    pub error: String,
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed to start: {}", self.component, self.error)
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+// Shared by reference between steps running in parallel, hence the mutex.
+#[derive(Debug, Default)]
+pub struct Startup {
+    strictness: Strictness,
+    degraded: Mutex<Vec<Degraded>>,
+}
+
+impl Startup {
+    pub fn new(strictness: Strictness) -> Self {
+        Self {
+            strictness,
+            degraded: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn critical<T, E: fmt::Display>(
+        &self,
+        component: &str,
+        init: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, StartupError> {
+        init().map_err(|e| StartupError {
+            component: component.to_string(),
+            error: e.to_string(),
+        })
+    }
+
+    pub fn optional<T, E: fmt::Display>(
+        &self,
+        component: &str,
+        init: impl FnOnce() -> Result<T, E>,
+        fallback: impl FnOnce() -> T,
+    ) -> Result<T, StartupError> {
+        match (init(), self.strictness) {
+            (Ok(value), _) => Ok(value),
+            (Err(e), Strictness::Strict) => Err(StartupError {
+                component: component.to_string(),
+                error: e.to_string(),
+            }),
+            (Err(e), Strictness::Degrade) => {
+                self.lock().push(Degraded {
+                    component: component.to_string(),
+                    error: e.to_string(),
+                });
+                Ok(fallback())
+            }
+        }
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
+
+    // The optional subsystems running on their fallback, in the order they
+    // failed.
+    pub fn degraded(&self) -> Vec<Degraded> {
+        self.lock().clone()
+    }
+
+    // Only pushes happen under the lock, so a poisoned one still guards
+    // consistent data.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Degraded>> {
+        self.degraded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+type StepFn<'a> = Box<dyn FnOnce() -> Result<(), String> + Send + 'a>;
+
+#[derive(Default)]
+pub struct StartupPlan<'a> {
+    graph: Graph<&'static str>,
+    steps: HashMap<&'static str, StepFn<'a>>,
+    // (step, dependency) pairs, to check every dependency is a step.
+    after: Vec<(&'static str, &'static str)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepTiming {
+    pub name: &'static str,
+    // Steps in the same wave ran in parallel.
+    pub wave: usize,
+    // From the start of `run`.
+    pub started: Duration,
+    pub elapsed: Duration,
+    // `None` if the step succeeded.
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeline {
+    pub steps: Vec<StepTiming>,
+    pub total: Duration,
+}
+
+impl Timeline {
+    pub fn failures(&self) -> impl Iterator<Item = &StepTiming> {
+        self.steps.iter().filter(|s| s.failure.is_some())
+    }
+}
+
+impl fmt::Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "startup took {} ms", self.total.as_millis())?;
+        for step in &self.steps {
+            write!(
+                f,
+                "\n  [{}] {:>6} ms +{:<6} ms {}",
+                step.wave,
+                step.started.as_millis(),
+                step.elapsed.as_millis(),
+                step.name
+            )?;
+            if let Some(failure) = &step.failure {
+                write!(f, " FAILED: {}", failure)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanError {
+    // A step runs after one that was never added.
+    UnknownStep {
+        step: &'static str,
+        after: &'static str,
+    },
+    Cycle(Vec<&'static str>),
+    // A step failed; steps that depended on it (and everything in later
+    // waves) didn't run. The timeline shows what did.
+    Failed(Timeline),
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::UnknownStep { step, after } => {
+                write!(f, "{} runs after {}, which isn't a step", step, after)
+            }
+            PlanError::Cycle(path) => {
+                write!(f, "startup steps form a cycle: {}", path.join(" -> "))
+            }
+            PlanError::Failed(timeline) => {
+                let names: Vec<_> = timeline.failures().map(|s| s.name).collect();
+                write!(f, "startup failed at {}\n{}", names.join(", "), timeline)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+impl<'a> StartupPlan<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add step `name`, to run once every step in `after` has succeeded.
+    pub fn step(
+        mut self,
+        name: &'static str,
+        after: &[&'static str],
+        run: impl FnOnce() -> Result<(), String> + Send + 'a,
+    ) -> Self {
+        self.graph.add_node(name);
+        for &dependency in after {
+            self.graph.add_edge(dependency, name);
+            self.after.push((name, dependency));
+        }
+        self.steps.insert(name, Box::new(run));
+        self
+    }
+
+    // Run the steps wave by wave: each wave is every step whose
+    // dependencies ran in earlier waves, run in parallel. Stops after the
+    // first wave with a failure.
+    pub fn run(mut self) -> Result<Timeline, PlanError> {
+        if let Some(&(step, after)) = self
+            .after
+            .iter()
+            .find(|(_, dependency)| !self.steps.contains_key(dependency))
+        {
+            return Err(PlanError::UnknownStep { step, after });
+        }
+        let waves = self
+            .graph
+            .topo_levels()
+            .map_err(|cycle| PlanError::Cycle(cycle.path))?;
+
+        let start = Instant::now();
+        let mut timings = Vec::new();
+        for (wave, names) in waves.into_iter().enumerate() {
+            let steps: Vec<_> = names
+                .into_iter()
+                .map(|name| (name, self.steps.remove(name).expect("checked above")))
+                .collect();
+            let results: Vec<StepTiming> = thread::scope(|scope| {
+                let handles: Vec<_> = steps
+                    .into_iter()
+                    .map(|(name, run)| {
+                        let handle = scope.spawn(move || {
+                            let started = start.elapsed();
+                            let result = run();
+                            (started, start.elapsed() - started, result)
+                        });
+                        (name, handle)
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|(name, handle)| {
+                        let (started, elapsed, result) = handle.join().unwrap_or_else(|_| {
+                            (Duration::ZERO, Duration::ZERO, Err("panicked".to_string()))
+                        });
+                        StepTiming {
+                            name,
+                            wave,
+                            started,
+                            elapsed,
+                            failure: result.err(),
+                        }
+                    })
+                    .collect()
+            });
+            let failed = results.iter().any(|s| s.failure.is_some());
+            timings.extend(results);
+            if failed {
+                return Err(PlanError::Failed(Timeline {
+                    steps: timings,
+                    total: start.elapsed(),
+                }));
+            }
+        }
+        Ok(Timeline {
+            steps: timings,
+            total: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn strictness_parses_either_case() {
+        assert_eq!("STRICT".parse(), Ok(Strictness::Strict));
+        assert_eq!("degrade".parse(), Ok(Strictness::Degrade));
+        assert!(
+            "lenient"
+                .parse::<Strictness>()
+                .unwrap_err()
+                .contains("lenient")
+        );
+        assert_eq!(Strictness::default(), Strictness::Degrade);
+    }
+
+    #[test]
+    fn an_optional_failure_degrades_unless_strict() {
+        let failing = || Err::<&str, _>("disk full");
+
+        let startup = Startup::new(Strictness::Degrade);
+        assert_eq!(
+            startup.optional("log file", failing, || "stderr"),
+            Ok("stderr")
+        );
+        assert_eq!(
+            startup.optional("counters", || Ok::<_, String>("on"), || "off"),
+            Ok("on")
+        );
+        assert_eq!(
+            startup.degraded(),
+            [Degraded {
+                component: "log file".to_string(),
+                error: "disk full".to_string()
+            }]
+        );
+        assert_eq!(
+            startup.degraded()[0].to_string(),
+            "log file degraded: disk full"
+        );
+
+        let startup = Startup::new(Strictness::Strict);
+        let e = startup
+            .optional("log file", failing, || "stderr")
+            .unwrap_err();
+        assert_eq!(e.to_string(), "log file failed to start: disk full");
+        assert!(startup.degraded().is_empty());
+    }
+
+    #[test]
+    fn a_critical_failure_is_always_fatal() {
+        let startup = Startup::new(Strictness::Degrade);
+        let e = startup
+            .critical("listener", || Err::<(), _>("address in use"))
+            .unwrap_err();
+        assert_eq!(e.component, "listener");
+        assert!(startup.degraded().is_empty());
+    }
+
+    #[test]
+    fn steps_run_in_waves_and_a_wave_runs_in_parallel() {
+        let (tx, rx) = mpsc::channel();
+
+        let timeline = StartupPlan::new()
+            .step("config", &[], || Ok(()))
+            // These two only finish if they run at the same time.
+            .step("log file", &["config"], move || {
+                tx.send(()).map_err(|e| e.to_string())
+            })
+            .step("counters", &["config"], move || {
+                rx.recv_timeout(Duration::from_secs(5))
+                    .map_err(|e| e.to_string())
+            })
+            .step("listener", &["log file", "counters"], || Ok(()))
+            .run()
+            .unwrap();
+
+        let waves: Vec<_> = timeline.steps.iter().map(|s| (s.name, s.wave)).collect();
+        assert_eq!(
+            waves,
+            [
+                ("config", 0),
+                ("log file", 1),
+                ("counters", 1),
+                ("listener", 2)
+            ]
+        );
+        let shown = timeline.to_string();
+        assert!(shown.starts_with("startup took "), "{}", shown);
+        assert_eq!(shown.lines().count(), 5);
+    }
+
+    #[test]
+    fn a_failure_stops_the_later_waves() {
+        let listened = AtomicBool::new(false);
+
+        let e = StartupPlan::new()
+            .step("config", &[], || Ok(()))
+            .step("log file", &["config"], || {
+                Err("no such directory".to_string())
+            })
+            .step("counters", &["config"], || panic!("bug"))
+            .step("listener", &["log file"], || {
+                listened.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+            .run()
+            .unwrap_err();
+
+        let PlanError::Failed(timeline) = &e else {
+            panic!("{}", e);
+        };
+        let failures: Vec<_> = timeline
+            .failures()
+            .map(|s| (s.name, s.failure.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            failures,
+            [("log file", "no such directory"), ("counters", "panicked")]
+        );
+        assert!(!listened.load(Ordering::SeqCst));
+        assert!(
+            e.to_string()
+                .starts_with("startup failed at log file, counters\n")
+        );
+    }
+
+    #[test]
+    fn a_plan_that_cant_be_ordered_runs_nothing() {
+        let ran = AtomicBool::new(false);
+        let e = StartupPlan::new()
+            .step("a", &["b"], || Ok(()))
+            .step("b", &["a"], || Ok(()))
+            .step("c", &[], || {
+                ran.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+            .run()
+            .unwrap_err();
+        assert_eq!(e, PlanError::Cycle(vec!["a", "b", "a"]));
+        assert_eq!(e.to_string(), "startup steps form a cycle: a -> b -> a");
+
+        let e = StartupPlan::new()
+            .step("listener", &["config"], || Ok(()))
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "listener runs after config, which isn't a step"
+        );
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,539 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::scope::panic_message;
+use super::test_closure::{LogLevel, Logger, StderrLogger};
+
+// Keeps long-lived background threads running.
+//
+// Each worker is a function the supervisor runs on its own thread. The
+// worker calls `Heartbeat::beat` regularly and returns once
+// `Heartbeat::should_stop` says so. If it panics, returns on its own, or
+// stops beating for `heartbeat_timeout`, the supervisor logs it and starts
+// the function again on a fresh thread, waiting `backoff_base * 2^n`
+// (capped at `backoff_max`) after the nth failure in a row. A worker that
+// stays up for `stable_after` has its failure streak forgotten.
+//
+// A hung thread can't be killed, only abandoned: its `should_stop` starts
+// returning true, so one that's merely slow will notice and exit, and its
+// beats no longer count.
+
+#[derive(Debug, Clone, Copy)]
+pub struct SupervisorConfig {
+    pub heartbeat_timeout: Duration,
+    // How often workers are checked.
+    pub check_interval: Duration,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    pub stable_after: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_timeout: Duration::from_secs(10),
+            check_interval: Duration::from_millis(500),
+            backoff_base: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(30),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerState {
+    Running,
+    // Failed; waiting out the backoff before starting again.
+    Restarting,
+    Stopped,
+}
+
+impl fmt::Display for WorkerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WorkerState::Running => "running",
+            WorkerState::Restarting => "restarting",
+            WorkerState::Stopped => "stopped",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub state: WorkerState,
+    pub restarts: u64,
+    pub panics: u64,
+    pub hangs: u64,
+    // Returned without being asked to stop.
+    pub exits: u64,
+    pub last_failure: Option<String>,
+}
+
+struct Beat {
+    last: Mutex<Instant>,
+    // Set when this run was given up on as hung.
+    abandoned: AtomicBool,
+}
+
+// Handed to each run of a worker.
+#[derive(Clone)]
+pub struct Heartbeat {
+    beat: Arc<Beat>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.beat.last.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    // True once the supervisor is shutting down or has replaced this run.
+    pub fn should_stop(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst) || self.beat.abandoned.load(Ordering::SeqCst)
+    }
+
+    // Sleep up to `duration`, waking early to stop. Returns `should_stop`.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+        while !self.should_stop() {
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            thread::sleep((until - now).min(Duration::from_millis(20)));
+        }
+        self.should_stop()
+    }
+
+    fn last(&self) -> Instant {
+        *self.beat.last.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+type WorkerFn = Arc<dyn Fn(&Heartbeat) + Send + Sync>;
+
+struct Worker {
+    run: WorkerFn,
+    status: WorkerStatus,
+    handle: Option<JoinHandle<()>>,
+    heartbeat: Heartbeat,
+    started: Instant,
+    // Failures since the worker was last stable.
+    streak: u32,
+    restart_at: Option<Instant>,
+}
+
+struct Shared {
+    config: SupervisorConfig,
+    logger: Arc<dyn Logger + Send + Sync>,
+    workers: Mutex<Vec<Worker>>,
+    stopping: Arc<AtomicBool>,
+}
+
+pub struct Supervisor {
+    shared: Arc<Shared>,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig) -> Self {
+        Self::with_logger(config, Arc::new(StderrLogger))
+    }
+
+    pub fn with_logger(config: SupervisorConfig, logger: Arc<dyn Logger + Send + Sync>) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            logger,
+            workers: Mutex::new(Vec::new()),
+            stopping: Arc::new(AtomicBool::new(false)),
+        });
+        let monitor = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                while !shared.stopping.load(Ordering::SeqCst) {
+                    shared.check(Instant::now());
+                    thread::sleep(shared.config.check_interval);
+                }
+            })
+        };
+        Self {
+            shared,
+            monitor: Some(monitor),
+        }
+    }
+
+    // Start `run` as worker `name` and keep it running until `shutdown`.
+    pub fn spawn(&self, name: &str, run: impl Fn(&Heartbeat) + Send + Sync + 'static) {
+        let run: WorkerFn = Arc::new(run);
+        let heartbeat = self.shared.heartbeat();
+        let handle = start(name, &run, &heartbeat);
+        self.shared.lock().push(Worker {
+            run,
+            status: WorkerStatus {
+                name: name.to_string(),
+                state: WorkerState::Running,
+                restarts: 0,
+                panics: 0,
+                hangs: 0,
+                exits: 0,
+                last_failure: None,
+            },
+            handle: Some(handle),
+            heartbeat,
+            started: Instant::now(),
+            streak: 0,
+            restart_at: None,
+        });
+    }
+
+    // Workers in the order they were spawned.
+    pub fn statuses(&self) -> Vec<WorkerStatus> {
+        self.shared
+            .lock()
+            .iter()
+            .map(|w| w.status.clone())
+            .collect()
+    }
+
+    pub fn status(&self, name: &str) -> Option<WorkerStatus> {
+        self.shared
+            .lock()
+            .iter()
+            .find(|w| w.status.name == name)
+            .map(|w| w.status.clone())
+    }
+
+    // Ask every worker to stop and wait for those that aren't hung.
+    pub fn shutdown(mut self) -> Vec<WorkerStatus> {
+        self.stop();
+        self.statuses()
+    }
+
+    fn stop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        if let Some(monitor) = self.monitor.take() {
+            let _ = monitor.join();
+        }
+        let handles: Vec<_> = self
+            .shared
+            .lock()
+            .iter_mut()
+            .filter_map(|w| {
+                w.status.state = WorkerState::Stopped;
+                if w.heartbeat.beat.abandoned.load(Ordering::SeqCst) {
+                    None
+                } else {
+                    w.handle.take()
+                }
+            })
+            .collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Shared {
+    fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            beat: Arc::new(Beat {
+                last: Mutex::new(Instant::now()),
+                abandoned: AtomicBool::new(false),
+            }),
+            stopping: Arc::clone(&self.stopping),
+        }
+    }
+
+    fn check(&self, now: Instant) {
+        let mut workers = self.lock();
+        for worker in workers.iter_mut() {
+            match worker.status.state {
+                WorkerState::Running => self.check_running(worker, now),
+                WorkerState::Restarting if worker.restart_at.is_some_and(|at| now >= at) => {
+                    worker.heartbeat = self.heartbeat();
+                    worker.handle =
+                        Some(start(&worker.status.name, &worker.run, &worker.heartbeat));
+                    worker.started = now;
+                    worker.restart_at = None;
+                    worker.status.state = WorkerState::Running;
+                    worker.status.restarts += 1;
+                    self.logger.log(
+                        LogLevel::Info,
+                        &format!(
+                            "worker {} restarted ({} so far)",
+                            worker.status.name, worker.status.restarts
+                        ),
+                    );
+                }
+                WorkerState::Restarting | WorkerState::Stopped => {}
+            }
+        }
+    }
+
+    fn check_running(&self, worker: &mut Worker, now: Instant) {
+        let failure = if worker.handle.as_ref().is_some_and(|h| h.is_finished()) {
+            let handle = worker.handle.take().expect("checked above");
+            match handle.join() {
+                Ok(()) => {
+                    worker.status.exits += 1;
+                    "exited".to_string()
+                }
+                Err(payload) => {
+                    worker.status.panics += 1;
+                    format!("panicked: {}", panic_message(&*payload))
+                }
+            }
+        } else if now.duration_since(worker.heartbeat.last()) >= self.config.heartbeat_timeout {
+            worker
+                .heartbeat
+                .beat
+                .abandoned
+                .store(true, Ordering::SeqCst);
+            // Dropping the handle detaches the thread.
+            worker.handle = None;
+            worker.status.hangs += 1;
+            format!(
+                "no heartbeat for {} ms",
+                self.config.heartbeat_timeout.as_millis()
+            )
+        } else {
+            if now.duration_since(worker.started) >= self.config.stable_after {
+                worker.streak = 0;
+            }
+            return;
+        };
+
+        let delay = backoff(&self.config, worker.streak);
+        worker.streak += 1;
+        worker.status.state = WorkerState::Restarting;
+        worker.restart_at = Some(now + delay);
+        self.logger.log(
+            LogLevel::Warn,
+            &format!(
+                "worker {} {}; restarting in {} ms",
+                worker.status.name,
+                failure,
+                delay.as_millis()
+            ),
+        );
+        worker.status.last_failure = Some(failure);
+    }
+
+    // Worker code never runs under the lock, so a poisoned one still guards
+    // consistent data.
+    fn lock(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn start(name: &str, run: &WorkerFn, heartbeat: &Heartbeat) -> JoinHandle<()> {
+    let run = Arc::clone(run);
+    let heartbeat = heartbeat.clone();
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || run(&heartbeat))
+        .expect("failed to spawn worker thread")
+}
+
+fn backoff(config: &SupervisorConfig, streak: u32) -> Duration {
+    config
+        .backoff_base
+        .saturating_mul(2u32.saturating_pow(streak))
+        .min(config.backoff_max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<String>>);
+
+    impl Logger for Collect {
+        fn log(&self, _level: LogLevel, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    impl Collect {
+        fn lines(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn fast() -> SupervisorConfig {
+        SupervisorConfig {
+            heartbeat_timeout: Duration::from_secs(10),
+            check_interval: Duration::from_millis(2),
+            backoff_base: Duration::from_millis(10),
+            backoff_max: Duration::from_millis(40),
+            stable_after: Duration::from_secs(10),
+        }
+    }
+
+    // Poll until `done` holds, failing the test after a few seconds.
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    fn delays(logger: &Collect) -> Vec<String> {
+        logger
+            .lines()
+            .iter()
+            .filter_map(|line| {
+                line.split_once("restarting in ")
+                    .map(|(_, d)| d.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let config = fast();
+        let delays: Vec<u128> = (0..5).map(|n| backoff(&config, n).as_millis()).collect();
+        assert_eq!(delays, [10, 20, 40, 40, 40]);
+        assert_eq!(backoff(&config, u32::MAX), config.backoff_max);
+    }
+
+    #[test]
+    fn a_panicking_worker_is_restarted_with_growing_delays() {
+        let logger = Arc::new(Collect::default());
+        let supervisor = Supervisor::with_logger(fast(), logger.clone());
+        supervisor.spawn("flaky", |_| panic!("boom"));
+
+        wait_for("three restarts", || {
+            supervisor.status("flaky").unwrap().restarts >= 3
+        });
+
+        let status = supervisor.shutdown()[0].clone();
+        assert!(status.panics >= 3);
+        assert_eq!(status.last_failure.as_deref(), Some("panicked: boom"));
+        assert_eq!(delays(&logger)[..3], ["10 ms", "20 ms", "40 ms"]);
+        assert!(
+            logger
+                .lines()
+                .contains(&"worker flaky panicked: boom; restarting in 10 ms".to_string())
+        );
+    }
+
+    #[test]
+    fn a_worker_that_stops_beating_is_abandoned_and_replaced() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let noticed = Arc::new(AtomicBool::new(false));
+        let supervisor = Supervisor::with_logger(
+            SupervisorConfig {
+                heartbeat_timeout: Duration::from_millis(30),
+                ..fast()
+            },
+            Arc::new(Collect::default()),
+        );
+        let (r, n) = (runs.clone(), noticed.clone());
+        supervisor.spawn("stuck", move |heartbeat| {
+            let first = r.fetch_add(1, Ordering::SeqCst) == 0;
+            while !heartbeat.sleep(Duration::from_millis(2)) {
+                if !first {
+                    heartbeat.beat();
+                }
+            }
+            if first {
+                n.store(true, Ordering::SeqCst);
+            }
+        });
+
+        wait_for("the stuck run to be replaced", || {
+            noticed.load(Ordering::SeqCst) && runs.load(Ordering::SeqCst) == 2
+        });
+
+        let status = supervisor.shutdown()[0].clone();
+        assert_eq!((status.hangs, status.restarts, status.panics), (1, 1, 0));
+        assert_eq!(
+            status.last_failure.as_deref(),
+            Some("no heartbeat for 30 ms")
+        );
+        assert_eq!(status.state, WorkerState::Stopped);
+    }
+
+    #[test]
+    fn staying_up_long_enough_forgets_the_failure_streak() {
+        let logger = Arc::new(Collect::default());
+        let supervisor = Supervisor::with_logger(
+            SupervisorConfig {
+                stable_after: Duration::from_millis(20),
+                ..fast()
+            },
+            logger.clone(),
+        );
+        let runs = AtomicU32::new(0);
+        supervisor.spawn("settles", move |heartbeat| {
+            match runs.fetch_add(1, Ordering::SeqCst) {
+                0 => panic!("early"),
+                // Up well past `stable_after`, then a second failure.
+                1 => {
+                    for _ in 0..30 {
+                        heartbeat.beat();
+                        thread::sleep(Duration::from_millis(2));
+                    }
+                    panic!("later")
+                }
+                _ => {
+                    while !heartbeat.sleep(Duration::from_millis(2)) {
+                        heartbeat.beat();
+                    }
+                }
+            }
+        });
+
+        wait_for("two restarts", || {
+            supervisor.status("settles").unwrap().restarts >= 2
+        });
+
+        supervisor.shutdown();
+        // Without the reset the second delay would have doubled.
+        assert_eq!(delays(&logger), ["10 ms", "10 ms"]);
+    }
+
+    #[test]
+    fn shutdown_waits_for_workers_to_finish() {
+        let finished = Arc::new(AtomicBool::new(false));
+        let supervisor = Supervisor::with_logger(fast(), Arc::new(Collect::default()));
+        let f = finished.clone();
+        supervisor.spawn("steady", move |heartbeat| {
+            while !heartbeat.sleep(Duration::from_millis(2)) {
+                heartbeat.beat();
+            }
+            f.store(true, Ordering::SeqCst);
+        });
+        thread::sleep(Duration::from_millis(20));
+
+        let statuses = supervisor.shutdown();
+
+        assert!(finished.load(Ordering::SeqCst));
+        assert_eq!(
+            statuses,
+            [WorkerStatus {
+                name: "steady".to_string(),
+                state: WorkerState::Stopped,
+                restarts: 0,
+                panics: 0,
+                hangs: 0,
+                exits: 0,
+                last_failure: None,
+            }]
+        );
+    }
+}
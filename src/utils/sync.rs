@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::dedup::hash_file;
+use super::file_handling::walk_files;
+use super::mutation::{Mutation, MutationSink};
+
+// One-way directory sync ("rsync-lite"): make `dst` look like `src`.
+// Only files are compared; directories are created on demand.
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncOptions {
+    // Remove files in `dst` that don't exist in `src`.
+    pub delete_extraneous: bool,
+    // Patch an existing copy with only the blocks that changed instead of
+    // copying the whole file. Both files are read in full either way, so
+    // this only pays off once the two sides are on different machines; until
+    // there's a transport for that, it's off by default.
+    pub delta: bool,
+}
+
+// Paths are relative to the sync roots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    Copy { path: PathBuf, size: u64 },
+    Delete { path: PathBuf },
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub actions: Vec<SyncAction>,
+    // Actions the `confirm` callback turned down.
+    pub declined: Vec<SyncAction>,
+    pub unchanged: usize,
+    pub bytes_copied: u64,
+    // Bytes of changed files that were taken from the existing copy in `dst`
+    // rather than copied from `src`.
+    pub bytes_reused: u64,
+}
+
+// What `plan` found.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub actions: Vec<SyncAction>,
+    pub unchanged: usize,
+    // Unchanged files whose mtime differs from the source's, so they had to
+    // be hashed. Giving them the source's mtime spares the next sync that.
+    pub retime: Vec<PathBuf>,
+}
+
+// Passed to the progress callback before each action is carried out.
+pub struct SyncProgress<'a> {
+    pub index: usize,
+    pub total: usize,
+    pub action: &'a SyncAction,
+}
+
+// Compare the two trees and decide what needs to change.
+// A file is copied when it's missing from `dst` or its size differs. When the
+// sizes match but the mtimes don't, the contents are hashed to decide, so a
+// `touch` alone doesn't trigger a copy.
+pub fn plan(src: &Path, dst: &Path, options: SyncOptions) -> io::Result<Plan> {
+    let mut plan = Plan::default();
+    let mut seen = HashSet::new();
+
+    for src_path in walk_files(src)? {
+        let rel = relative(src, &src_path);
+        let dst_path = dst.join(&rel);
+        let src_meta = fs::metadata(&src_path)?;
+        seen.insert(rel.clone());
+
+        let needs_copy = match fs::metadata(&dst_path) {
+            Err(_) => true,
+            Ok(dst_meta) if dst_meta.len() != src_meta.len() => true,
+            Ok(dst_meta) if dst_meta.modified().ok() == src_meta.modified().ok() => false,
+            Ok(_) if hash_file(&src_path)? == hash_file(&dst_path)? => {
+                plan.retime.push(rel.clone());
+                false
+            }
+            Ok(_) => true,
+        };
+
+        if needs_copy {
+            plan.actions.push(SyncAction::Copy {
+                path: rel,
+                size: src_meta.len(),
+            });
+        } else {
+            plan.unchanged += 1;
+        }
+    }
+
+    if options.delete_extraneous && dst.is_dir() {
+        for dst_path in walk_files(dst)? {
+            let rel = relative(dst, &dst_path);
+            if !seen.contains(&rel) {
+                plan.actions.push(SyncAction::Delete { path: rel });
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+// Plan the sync and hand each change to `sink`, skipping any that
+// `confirm` turns down. With a dry-run sink the returned report lists the
+// planned actions but nothing is written.
+pub fn sync_dirs<C, F>(
+    src: &Path,
+    dst: &Path,
+    options: SyncOptions,
+    sink: &mut dyn MutationSink,
+    mut confirm: C,
+    mut on_progress: F,
+) -> io::Result<SyncReport>
+where
+    C: FnMut(&SyncAction) -> io::Result<bool>,
+    F: FnMut(&SyncProgress),
+{
+    if !src.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("source is not a directory: {}", src.display()),
+        ));
+    }
+
+    let plan = plan(src, dst, options)?;
+    let mut actions = Vec::new();
+    let mut declined = Vec::new();
+    for action in plan.actions {
+        if confirm(&action)? {
+            actions.push(action);
+        } else {
+            declined.push(action);
+        }
+    }
+    let mut bytes_copied = 0;
+    let mut bytes_reused = 0;
+
+    for (index, action) in actions.iter().enumerate() {
+        on_progress(&SyncProgress {
+            index,
+            total: actions.len(),
+            action,
+        });
+
+        let mutation = match action {
+            SyncAction::Copy { path, .. } => Mutation::Copy {
+                from: src.join(path),
+                to: dst.join(path),
+                reuse: options.delta,
+            },
+            SyncAction::Delete { path } => Mutation::Remove {
+                path: dst.join(path),
+            },
+        };
+        let applied = sink.apply(mutation)?;
+        bytes_copied += applied.bytes_copied;
+        bytes_reused += applied.bytes_reused;
+    }
+    for path in &plan.retime {
+        sink.apply(Mutation::SetModified {
+            path: dst.join(path),
+            modified: fs::metadata(src.join(path))?.modified()?,
+        })?;
+    }
+
+    Ok(SyncReport {
+        actions,
+        declined,
+        unchanged: plan.unchanged,
+        bytes_copied,
+        bytes_reused,
+    })
+}
+
+fn relative(root: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(root).unwrap_or(path).to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::mutation::{ApplyToDisk, DryRun};
+    use std::time::{Duration, SystemTime};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("sync-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::create_dir_all(dir.join("dst")).unwrap();
+            Self(dir)
+        }
+
+        fn src(&self) -> PathBuf {
+            self.0.join("src")
+        }
+
+        fn dst(&self) -> PathBuf {
+            self.0.join("dst")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn set_modified(path: &Path, at: SystemTime) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(at)
+            .unwrap();
+    }
+
+    fn sync(dir: &TempDir, sink: &mut dyn MutationSink) -> SyncReport {
+        sync_dirs(
+            &dir.src(),
+            &dir.dst(),
+            SyncOptions::default(),
+            sink,
+            |_| Ok(true),
+            |_| {},
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn matching_contents_get_the_source_mtime() {
+        let dir = TempDir::new("retime");
+        fs::write(dir.src().join("a"), "same").unwrap();
+        fs::write(dir.dst().join("a"), "same").unwrap();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        set_modified(&dir.src().join("a"), then);
+
+        let planned = plan(&dir.src(), &dir.dst(), SyncOptions::default()).unwrap();
+        assert_eq!(planned.retime, vec![PathBuf::from("a")]);
+        assert!(planned.actions.is_empty());
+
+        let mut dry_run = DryRun::default();
+        let report = sync(&dir, &mut dry_run);
+        assert_eq!((report.actions.len(), report.unchanged), (0, 1));
+        assert_eq!(dry_run.planned.len(), 1);
+        assert_ne!(
+            fs::metadata(dir.dst().join("a"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            then
+        );
+
+        sync(&dir, &mut ApplyToDisk);
+        assert_eq!(
+            fs::metadata(dir.dst().join("a"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            then
+        );
+        let planned = plan(&dir.src(), &dir.dst(), SyncOptions::default()).unwrap();
+        assert!(planned.retime.is_empty());
+    }
+
+    #[test]
+    fn differing_contents_are_copied() {
+        let dir = TempDir::new("copy");
+        fs::write(dir.src().join("a"), "new!").unwrap();
+        fs::write(dir.dst().join("a"), "old!").unwrap();
+        fs::write(dir.dst().join("extra"), "x").unwrap();
+        // Same size, so only the differing mtime gets it hashed.
+        set_modified(&dir.dst().join("a"), SystemTime::UNIX_EPOCH);
+
+        let report = sync(&dir, &mut ApplyToDisk);
+        assert_eq!(
+            report.actions,
+            vec![SyncAction::Copy {
+                path: PathBuf::from("a"),
+                size: 4
+            }]
+        );
+        assert_eq!(fs::read(dir.dst().join("a")).unwrap(), b"new!");
+        assert!(dir.dst().join("extra").exists());
+        assert!(!dir.dst().join("a.partial").exists());
+    }
+}
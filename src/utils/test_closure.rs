@@ -1,14 +1,82 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::file_handling::{open_append, rotate_numbered};
+use super::log_format::{Formatter, LogFormat, Record};
+
+// How important a log message is. The variants are declared from most to least
+// severe, and `#[derive(PartialOrd, Ord)]` orders an enum by declaration order,
+// so `LogLevel::Error < LogLevel::Trace`. "More verbose" means "greater", which
+// is what lets `LevelFilter` below compare levels with a plain `<=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 5] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Implementing `FromStr` is what makes `"warn".parse::<LogLevel>()` work, e.g.
+// for a level taken from an environment variable. Case doesn't matter.
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogLevel::ALL
+            .into_iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown log level {:?} (expected error, warn, info, debug or trace)",
+                    s
+                )
+            })
+    }
+}
+
 // A 'trait' is a collection of methods that a type can implement. It's similar to an
 // interface in other languages. Here, we define a `Logger` trait that requires any
 // implementing type to have a `log` method. This allows us to write code that works
 // with any kind of logger, as long as it adheres to this contract.
 pub trait Logger {
     /// This is a documentation comment for the `log` method.
-    /// It specifies that the method should log a message at a given verbosity level.
+    /// It specifies that the method should log a message at a given level.
     // '&self' means the method borrows the instance it's called on, so it can't modify it.
-    // 'verbosity: u8' is an 8-bit unsigned integer representing the log level.
+    // 'level: LogLevel' says how important the message is (see `LogLevel` above).
     // 'message: &str' is a borrowed string slice for the log message.
-    fn log(&self, verbosity: u8, message: &str);
+    fn log(&self, level: LogLevel, message: &str);
 }
 
 // This is a simple struct that will act as our concrete logger.
@@ -19,11 +87,41 @@ pub struct StderrLogger;
 // `StderrLogger` struct. This is how we fulfill the contract defined by the trait.
 impl Logger for StderrLogger {
     // We provide the concrete implementation for the `log` method.
-    fn log(&self, verbosity: u8, message: &str) {
+    fn log(&self, level: LogLevel, message: &str) {
         // `eprintln!` is a macro that prints the formatted string to the standard error stream.
         // This is a common practice for logging. The curly braces `{}` are placeholders
         // for the variables that follow.
-        eprintln!("verbosity={verbosity}: {message}");
+        eprintln!("level={level}: {message}");
+    }
+}
+
+// `ConsoleLogger` also writes to stderr, but lays each line out with a
+// `Formatter` (see `log_format`): `ConsoleLogger::new(LogFormat::Human)`
+// adds a timestamp and the thread, `.with_target("server")` says which
+// program the line came from. `LogFormat::Bare` gives `StderrLogger`'s lines.
+pub struct ConsoleLogger<F = LogFormat> {
+    format: F,
+    target: Option<String>,
+}
+
+impl<F: Formatter> ConsoleLogger<F> {
+    pub fn new(format: F) -> Self {
+        Self {
+            format,
+            target: None,
+        }
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+}
+
+impl<F: Formatter> Logger for ConsoleLogger<F> {
+    fn log(&self, level: LogLevel, message: &str) {
+        let record = Record::now(level, self.target.as_deref(), message);
+        eprintln!("{}", self.format.format(&record));
     }
 }
 
@@ -53,10 +151,10 @@ where
     // This is necessary because we need to call `self.inner.log(...)` later.
     L: Logger,
     // This constraint says "The type `P` must be a closure that can be called (`Fn`),
-    // takes a `u8` and a `&str` as arguments, and returns a `bool`".
+    // takes a `LogLevel` and a `&str` as arguments, and returns a `bool`".
     // `Fn` is one of the three closure traits in Rust (`Fn`, `FnMut`, `FnOnce`).
     // `Fn` means the closure can be called multiple times without changing its captured state.
-    P: Fn(u8, &str) -> bool,
+    P: Fn(LogLevel, &str) -> bool,
 {
     // This is a "constructor" function for our `Filter`. It's a common Rust convention
     // to have a `new` function to create instances of a struct.
@@ -81,19 +179,248 @@ impl<L, P> Logger for Filter<L, P>
 // to be able to implement the `log` method.
 where
     L: Logger,
-    P: Fn(u8, &str) -> bool,
+    P: Fn(LogLevel, &str) -> bool,
 {
     // Here we implement the `log` method required by the `Logger` trait.
-    fn log(&self, verbosity: u8, message: &str) {
+    fn log(&self, level: LogLevel, message: &str) {
         // The core logic of the filter.
         // We call the closure stored in `self.predicate`.
         // The syntax `(self.predicate)(...)` is used to invoke a closure that is stored in a field.
-        if (self.predicate)(verbosity, message) {
+        if (self.predicate)(level, message) {
             // If the predicate closure returns `true`, we then call the `log` method
             // on our inner logger, `self.inner`. We know `self.inner` has a `.log()`
             // method because of the `where L: Logger` constraint.
-            self.inner.log(verbosity, message);
+            self.inner.log(level, message);
         }
         // If the predicate returns `false`, we do nothing, effectively filtering out the message.
     }
 }
+
+// `LevelFilter` is a `Filter` for the most common case: drop everything less
+// severe than a threshold. It could be written as
+// `Filter::new(inner, move |level, _| level <= max)`, but a named type is
+// easier to store in a struct field, because a closure's type can't be written
+// down.
+pub struct LevelFilter<L> {
+    inner: L,
+    max: LogLevel,
+}
+
+impl<L: Logger> LevelFilter<L> {
+    // Messages at `max` or more severe get through: with `LogLevel::Info`,
+    // that's errors, warnings and info, but not debug or trace.
+    pub fn new(inner: L, max: LogLevel) -> Self {
+        Self { inner, max }
+    }
+
+    pub fn max_level(&self) -> LogLevel {
+        self.max
+    }
+}
+
+impl<L: Logger> Logger for LevelFilter<L> {
+    fn log(&self, level: LogLevel, message: &str) {
+        // Thanks to the derived `Ord`, "at most as verbose as `max`" is just `<=`.
+        if level <= self.max {
+            self.inner.log(level, message);
+        }
+    }
+}
+
+// `FileLogger` appends one line per message to a file and rotates it once it
+// grows past `max_bytes`: `app.log` becomes `app.log.1`, the old `app.log.1`
+// becomes `app.log.2`, and so on, keeping at most `keep` old files.
+//
+// `Logger::log` only gets `&self`, but writing changes the file and the size
+// count. A `Mutex` provides that "interior mutability" and also makes the
+// logger safe to share between threads (it is `Sync`), so one `FileLogger` in
+// an `Arc` can serve every connection of a server.
+//
+// Lines are `<unix seconds> <level> <message>` unless `with_format` picks a
+// `Formatter`.
+pub struct FileLogger {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    format: Option<Box<dyn Formatter>>,
+    target: Option<String>,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    file: File,
+    // Bytes in the current file, including whatever was there when we opened it.
+    size: u64,
+}
+
+impl FileLogger {
+    // Opens (or creates) `path` for appending. Errors here are returned; errors
+    // while logging can't be, so they are reported on stderr instead.
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            format: None,
+            target: None,
+            state: Mutex::new(FileState { file, size }),
+        })
+    }
+
+    pub fn with_format(mut self, format: impl Formatter + 'static) -> Self {
+        self.format = Some(Box::new(format));
+        self
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_line(&self, state: &mut FileState, line: &str) -> io::Result<()> {
+        // Rotate before writing rather than after, so a file is never left
+        // empty, and a single huge line still gets written (to a fresh file).
+        if state.size > 0 && state.size + line.len() as u64 > self.max_bytes {
+            state.file.flush()?;
+            rotate_numbered(&self.path, self.keep)?;
+            state.file = open_append(&self.path)?;
+            state.size = 0;
+        }
+        state.file.write_all(line.as_bytes())?;
+        state.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Logger for FileLogger {
+    fn log(&self, level: LogLevel, message: &str) {
+        let line = match &self.format {
+            Some(format) => {
+                let record = Record::now(level, self.target.as_deref(), message);
+                format!("{}\n", format.format(&record))
+            }
+            None => {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                format!("{} {} {}\n", secs, level, message)
+            }
+        };
+        // A poisoned lock only means another thread panicked mid-write; the file
+        // is still usable, so carry on with it.
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = self.write_line(&mut state, &line) {
+            eprintln!("log file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Loggers that are shared (an `Arc` handed to every connection thread) or
+// boxed (picked at runtime) are loggers too, so they can be wrapped and
+// combined like any other.
+impl<L: Logger + ?Sized> Logger for &L {
+    fn log(&self, level: LogLevel, message: &str) {
+        (**self).log(level, message);
+    }
+}
+
+impl<L: Logger + ?Sized> Logger for Box<L> {
+    fn log(&self, level: LogLevel, message: &str) {
+        (**self).log(level, message);
+    }
+}
+
+impl<L: Logger + ?Sized> Logger for Arc<L> {
+    fn log(&self, level: LogLevel, message: &str) {
+        (**self).log(level, message);
+    }
+}
+
+// `Tee` sends every message to two loggers, `first` and then `second`, e.g.
+// stderr and a file. Each one still applies its own filtering, so
+// `LevelFilter::new(StderrLogger, LogLevel::Warn).and(file_logger)` shows
+// only warnings on the terminal but keeps everything in the file.
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Logger, B: Logger> Tee<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: Logger, B: Logger> Logger for Tee<A, B> {
+    fn log(&self, level: LogLevel, message: &str) {
+        self.first.log(level, message);
+        self.second.log(level, message);
+    }
+}
+
+// `MultiLogger` is `Tee` for a list only known at runtime (say, one logger
+// per `--log-file` flag), at the cost of boxing each one.
+#[derive(Default)]
+pub struct MultiLogger {
+    loggers: Vec<Box<dyn Logger + Send + Sync>>,
+}
+
+impl MultiLogger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, logger: impl Logger + Send + Sync + 'static) -> Self {
+        self.push(logger);
+        self
+    }
+
+    pub fn push(&mut self, logger: impl Logger + Send + Sync + 'static) {
+        self.loggers.push(Box::new(logger));
+    }
+
+    pub fn len(&self) -> usize {
+        self.loggers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loggers.is_empty()
+    }
+}
+
+impl Logger for MultiLogger {
+    fn log(&self, level: LogLevel, message: &str) {
+        for logger in &self.loggers {
+            logger.log(level, message);
+        }
+    }
+}
+
+// Builder-style helpers for every logger, so a logging setup reads left to
+// right: `StderrLogger.up_to(LogLevel::Warn).and(file_logger)`.
+pub trait LoggerExt: Logger + Sized {
+    // Log to `self` and to `other`.
+    fn and<O: Logger>(self, other: O) -> Tee<Self, O> {
+        Tee::new(self, other)
+    }
+
+    // Only log messages `predicate` accepts.
+    fn filter<P: Fn(LogLevel, &str) -> bool>(self, predicate: P) -> Filter<Self, P> {
+        Filter::new(self, predicate)
+    }
+
+    // Only log messages at `max` or more severe.
+    fn up_to(self, max: LogLevel) -> LevelFilter<Self> {
+        LevelFilter::new(self, max)
+    }
+}
+
+impl<L: Logger> LoggerExt for L {}
@@ -0,0 +1,178 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::protocol::{MAX_FRAME_LEN, read_frame, write_frame};
+
+// A scripted stand-in for the example server, for testing code built on
+// `Client` without running the real thing:
+//
+//   let server = MockServer::new()
+//       .on("echo", |req| MockReply::frame(req).after(Duration::from_millis(50)))
+//       .start()?;
+//   let mut client = Client::connect(server.addr())?;
+//
+// It listens on an ephemeral port on 127.0.0.1 and answers every request
+// frame with whatever the handler returns, so replies can be wrong, slow,
+// malformed or missing on purpose. It stops when the handle is dropped.
+
+// The calls the protocol has. Requests carry no method name yet, so every
+// frame is routed to the one there is.
+pub const METHODS: &[&str] = &["echo"];
+
+type ReplyFn = Box<dyn FnMut(&[u8]) -> MockReply + Send>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockReply {
+    // Send this payload back as one frame.
+    Frame(Vec<u8>),
+    // Write these bytes as they are, framed or not.
+    Raw(Vec<u8>),
+    // Close the connection without replying.
+    Hangup,
+    // Wait, then reply.
+    Delayed(Duration, Box<MockReply>),
+}
+
+impl MockReply {
+    pub fn frame(payload: &[u8]) -> Self {
+        MockReply::Frame(payload.to_vec())
+    }
+
+    pub fn after(self, delay: Duration) -> Self {
+        MockReply::Delayed(delay, Box::new(self))
+    }
+}
+
+#[derive(Default)]
+pub struct MockServer {
+    // With a single method there's a single handler; this becomes a map
+    // once requests name their method.
+    handler: Option<ReplyFn>,
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Answer `method` with `reply`, replacing any earlier handler. Panics on a method the protocol
+    // doesn't have, since no request could ever reach it.
+    pub fn on(
+        mut self,
+        method: &'static str,
+        reply: impl FnMut(&[u8]) -> MockReply + Send + 'static,
+    ) -> Self {
+        assert!(
+            METHODS.contains(&method),
+            "unknown method {:?}; the protocol has {:?}",
+            method,
+            METHODS
+        );
+        self.handler = Some(Box::new(reply));
+        self
+    }
+
+    pub fn start(self) -> io::Result<RunningMock> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(MockState {
+            handler: Mutex::new(self.handler),
+            requests: Mutex::new(Vec::new()),
+            stopping: AtomicBool::new(false),
+        });
+        let accepting = Arc::clone(&state);
+        let acceptor = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = Arc::clone(&accepting);
+                    thread::spawn(move || serve(stream, &state));
+                }
+            }
+        });
+        Ok(RunningMock {
+            addr,
+            state,
+            acceptor: Some(acceptor),
+        })
+    }
+}
+
+struct MockState {
+    // `None` if nothing was registered: every request gets a hangup.
+    handler: Mutex<Option<ReplyFn>>,
+    requests: Mutex<Vec<Vec<u8>>>,
+    stopping: AtomicBool,
+}
+
+pub struct RunningMock {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    acceptor: Option<thread::JoinHandle<()>>,
+}
+
+impl RunningMock {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Every request received so far, across all connections, in arrival
+    // order.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.state
+            .requests
+            .lock()
+            .expect("mock state poisoned")
+            .clone()
+    }
+}
+
+impl Drop for RunningMock {
+    fn drop(&mut self) {
+        self.state.stopping.store(true, Ordering::SeqCst);
+        // `incoming` blocks in accept; a connection of our own wakes it to
+        // see the flag. Connections already open finish on their own.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, state: &MockState) {
+    while let Ok(Some(request)) = read_frame(&mut stream, MAX_FRAME_LEN) {
+        state
+            .requests
+            .lock()
+            .expect("mock state poisoned")
+            .push(request.clone());
+        // Decided under the lock, sent outside it, so one slow reply
+        // doesn't hold up other connections.
+        let reply = match state.handler.lock().expect("mock state poisoned").as_mut() {
+            Some(handler) => handler(&request),
+            None => MockReply::Hangup,
+        };
+        if send(&mut stream, reply).is_err() {
+            break;
+        }
+    }
+}
+
+// An `Err` means the connection is done, deliberately or not.
+fn send(stream: &mut TcpStream, reply: MockReply) -> io::Result<()> {
+    match reply {
+        MockReply::Frame(payload) => write_frame(stream, &payload),
+        MockReply::Raw(bytes) => stream.write_all(&bytes),
+        MockReply::Hangup => Err(io::ErrorKind::ConnectionAborted.into()),
+        MockReply::Delayed(delay, reply) => {
+            thread::sleep(delay);
+            send(stream, *reply)
+        }
+    }
+}
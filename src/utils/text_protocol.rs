@@ -0,0 +1,167 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::SystemTime;
+
+use super::log_format::rfc3339;
+
+// A line protocol for people at a terminal (`nc`, `telnet`, or `client
+// --interactive`), next to the framed one programs use:
+//
+//     ECHO <text>   replies with <text>
+//     PING          replies PONG
+//     TIME          replies with the server's clock, RFC 3339 in UTC
+//     QUIT          replies BYE and closes the connection
+//     AUTH <token>  replies OK; a server started with `--tokens` checks the
+//                   token, and then every later command against its scopes
+//
+// One command per line, ended by '\n' (a '\r' before it is dropped);
+// command names are case-insensitive. Anything else gets `ERR <reason>`
+// and the connection stays open.
+//
+// A server can tell which protocol a client speaks from its first byte: a
+// frame starts with the high byte of its length, which is 0 or 1 for
+// anything under `protocol::MAX_FRAME_LEN`, while a command starts with a
+// letter.
+
+// Longer lines are refused and the connection is closed, the way an
+// oversized frame is.
+pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextCommand {
+    Auth(String),
+    Echo(String),
+    Ping,
+    Time,
+    Quit,
+}
+
+impl TextCommand {
+    // Parse one line, without its line ending.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let (name, rest) = match line.split_once(' ') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (line, None),
+        };
+        let command = match name.to_ascii_uppercase().as_str() {
+            "AUTH" => {
+                return match rest.map(str::trim) {
+                    Some(token) if !token.is_empty() => Ok(TextCommand::Auth(token.to_string())),
+                    _ => Err("AUTH needs a token".to_string()),
+                };
+            }
+            "ECHO" => return Ok(TextCommand::Echo(rest.unwrap_or("").to_string())),
+            "PING" => TextCommand::Ping,
+            "TIME" => TextCommand::Time,
+            "QUIT" => TextCommand::Quit,
+            "" => return Err("empty command".to_string()),
+            _ => return Err(format!("unknown command '{}'", name)),
+        };
+        match rest {
+            Some(rest) if !rest.trim().is_empty() => {
+                Err(format!("{} takes no arguments", name.to_ascii_uppercase()))
+            }
+            _ => Ok(command),
+        }
+    }
+
+    // The name a token's scopes are matched against.
+    pub fn method(&self) -> &'static str {
+        match self {
+            TextCommand::Auth(_) => "auth",
+            TextCommand::Echo(_) => "echo",
+            TextCommand::Ping => "ping",
+            TextCommand::Time => "time",
+            TextCommand::Quit => "quit",
+        }
+    }
+
+    // The line a client sends for this command. Unlike `Display`, it keeps
+    // AUTH's token.
+    pub fn line(&self) -> String {
+        match self {
+            TextCommand::Auth(token) => format!("AUTH {}", token),
+            command => command.to_string(),
+        }
+    }
+
+    // The reply line, with `now` for TIME.
+    pub fn reply(&self, now: SystemTime) -> String {
+        match self {
+            TextCommand::Auth(_) => "OK".to_string(),
+            TextCommand::Echo(text) => text.clone(),
+            TextCommand::Ping => "PONG".to_string(),
+            TextCommand::Time => rfc3339(now, true),
+            TextCommand::Quit => "BYE".to_string(),
+        }
+    }
+}
+
+// The token in AUTH is left out, so commands can be logged.
+impl fmt::Display for TextCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextCommand::Auth(_) => f.write_str("AUTH <token>"),
+            TextCommand::Echo(text) => write!(f, "ECHO {}", text),
+            TextCommand::Ping => f.write_str("PING"),
+            TextCommand::Time => f.write_str("TIME"),
+            TextCommand::Quit => f.write_str("QUIT"),
+        }
+    }
+}
+
+// Whether a connection starting with `first` speaks this protocol rather
+// than frames.
+pub fn is_text_start(first: u8) -> bool {
+    first.is_ascii_alphabetic()
+}
+
+// Read one line, without its line ending. Ok(None) means the connection
+// closed cleanly between lines; a line over `MAX_LINE_LEN` or cut off by
+// the connection closing is an error.
+pub fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LEN as u64 + 1;
+    reader.take(limit).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        let message = if line.len() >= MAX_LINE_LEN {
+            format!("line is over the {} byte limit", MAX_LINE_LEN)
+        } else {
+            "connection closed mid-line".to_string()
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// The client side: send a command line, read the reply line.
+#[derive(Debug)]
+pub struct TextClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl TextClient {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    // Send `line` and return the reply, or None if the server hung up
+    // instead of answering.
+    pub fn request(&mut self, line: &str) -> io::Result<Option<String>> {
+        self.writer.write_all(format!("{}\n", line).as_bytes())?;
+        read_line(&mut self.reader)
+    }
+}
@@ -0,0 +1,278 @@
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+// A tiny per-thread profiler.
+//
+//     let _s = trace_span!("handle_frame");
+//     {
+//         let _p = trace_span!("parse");
+//         ...
+//     }
+//
+// Each span records how long it was alive. Spans opened while another is
+// alive become its children, so a thread builds up a call tree. Spans with
+// the same name under the same parent are merged (count + total time),
+// which keeps loops from blowing up the tree. `take_report` hands back the
+// tree and starts a new one, e.g. once per connection.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanNode {
+    pub name: &'static str,
+    pub count: u64,
+    pub total: Duration,
+    pub children: Vec<SpanNode>,
+}
+
+impl SpanNode {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: 0,
+            total: Duration::ZERO,
+            children: Vec::new(),
+        }
+    }
+
+    // Time spent in this span but not in any of its children.
+    pub fn self_time(&self) -> Duration {
+        let children: Duration = self.children.iter().map(|c| c.total).sum();
+        self.total.saturating_sub(children)
+    }
+
+    fn child(&mut self, name: &'static str) -> usize {
+        match self.children.iter().position(|c| c.name == name) {
+            Some(i) => i,
+            None => {
+                self.children.push(SpanNode::new(name));
+                self.children.len() - 1
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceReport {
+    // Top-level spans, in the order they were first opened.
+    pub roots: Vec<SpanNode>,
+}
+
+impl TraceReport {
+    // Render as an indented tree, one span per line:
+    //
+    //   handle_frame      1x   2.104ms  100.0%
+    //     parse           1x   0.310ms   14.7%
+    //     transform       1x   1.502ms   71.4%
+    //
+    // Percentages are relative to the enclosing top-level span.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for root in &self.roots {
+            render_node(&mut out, root, 0, root.total);
+        }
+        out
+    }
+}
+
+fn render_node(out: &mut String, node: &SpanNode, depth: usize, root_total: Duration) {
+    let percent = if root_total.is_zero() {
+        100.0
+    } else {
+        node.total.as_secs_f64() / root_total.as_secs_f64() * 100.0
+    };
+    let label = format!("{}{}", "  ".repeat(depth), node.name);
+    let _ = writeln!(
+        out,
+        "{:<30} {:>5}x {:>10.3}ms {:>6.1}%",
+        label,
+        node.count,
+        node.total.as_secs_f64() * 1000.0,
+        percent
+    );
+    for child in &node.children {
+        render_node(out, child, depth + 1, root_total);
+    }
+}
+
+// Per-thread state: the tree so far, plus the path (as child indices from
+// the root list) of the spans that are currently open.
+#[derive(Default)]
+struct Tracer {
+    roots: Vec<SpanNode>,
+    open: Vec<usize>,
+}
+
+impl Tracer {
+    fn node_at(&mut self, path: &[usize]) -> &mut SpanNode {
+        let (first, rest) = path.split_first().expect("path is never empty");
+        let mut node = &mut self.roots[*first];
+        for &i in rest {
+            node = &mut node.children[i];
+        }
+        node
+    }
+}
+
+thread_local! {
+    static TRACER: RefCell<Tracer> = RefCell::new(Tracer::default());
+}
+
+// Guard returned by `Span::enter`; the span ends when it's dropped.
+// Guards must be dropped in reverse order of creation, which is what
+// happens naturally with `let _s = ...` bindings.
+#[must_use = "the span ends as soon as the guard is dropped"]
+pub struct Span {
+    start: Instant,
+    depth: usize,
+}
+
+impl Span {
+    pub fn enter(name: &'static str) -> Span {
+        let depth = TRACER.with(|t| {
+            let mut t = t.borrow_mut();
+            let index = if t.open.is_empty() {
+                match t.roots.iter().position(|r| r.name == name) {
+                    Some(i) => i,
+                    None => {
+                        t.roots.push(SpanNode::new(name));
+                        t.roots.len() - 1
+                    }
+                }
+            } else {
+                let path = t.open.clone();
+                t.node_at(&path).child(name)
+            };
+            t.open.push(index);
+            t.open.len()
+        });
+        Span {
+            start: Instant::now(),
+            depth,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        TRACER.with(|t| {
+            let mut t = t.borrow_mut();
+            // `take_report` may have reset the tree while this span was open;
+            // in that case there's nothing left to record into.
+            if t.open.len() != self.depth {
+                return;
+            }
+            let path = t.open.clone();
+            let node = t.node_at(&path);
+            node.count += 1;
+            node.total += elapsed;
+            t.open.pop();
+        });
+    }
+}
+
+// Take everything recorded on this thread so far and start over.
+pub fn take_report() -> TraceReport {
+    TRACER.with(|t| {
+        let mut t = t.borrow_mut();
+        t.open.clear();
+        TraceReport {
+            roots: std::mem::take(&mut t.roots),
+        }
+    })
+}
+
+// `let _s = trace_span!("name");` opens a span until the end of the scope.
+#[macro_export]
+macro_rules! trace_span {
+    ($name:expr) => {
+        $crate::utils::trace::Span::enter($name)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_spans_build_a_tree_and_repeats_are_merged() {
+        take_report();
+        {
+            let _connection = Span::enter("connection");
+            for _ in 0..3 {
+                let _read = Span::enter("read");
+            }
+            let _echo = Span::enter("echo");
+        }
+
+        let report = take_report();
+        assert_eq!(report.roots.len(), 1);
+        let connection = &report.roots[0];
+        assert_eq!((connection.name, connection.count), ("connection", 1));
+        let children: Vec<_> = connection
+            .children
+            .iter()
+            .map(|c| (c.name, c.count))
+            .collect();
+        assert_eq!(children, [("read", 3), ("echo", 1)]);
+    }
+
+    #[test]
+    fn take_report_starts_over() {
+        take_report();
+        drop(Span::enter("first"));
+
+        assert_eq!(take_report().roots.len(), 1);
+        assert!(take_report().roots.is_empty());
+        drop(Span::enter("second"));
+        assert_eq!(take_report().roots[0].name, "second");
+    }
+
+    #[test]
+    fn a_span_still_open_when_the_report_is_taken_records_nothing() {
+        take_report();
+        let open = Span::enter("open");
+        let report = take_report();
+        drop(open);
+
+        assert_eq!(report.roots[0].count, 0);
+        assert!(take_report().roots.is_empty());
+    }
+
+    #[test]
+    fn self_time_leaves_out_the_children() {
+        let mut parent = SpanNode::new("parent");
+        parent.total = Duration::from_millis(10);
+        let mut child = SpanNode::new("child");
+        child.total = Duration::from_millis(4);
+        parent.children.push(child);
+
+        assert_eq!(parent.self_time(), Duration::from_millis(6));
+    }
+
+    #[test]
+    fn render_indents_children_with_their_share_of_the_root() {
+        let mut root = SpanNode::new("connection");
+        root.count = 1;
+        root.total = Duration::from_millis(8);
+        let mut read = SpanNode::new("read");
+        read.count = 2;
+        read.total = Duration::from_millis(2);
+        root.children.push(read);
+
+        let rendered = TraceReport { roots: vec![root] }.render();
+        let lines: Vec<_> = rendered
+            .lines()
+            .map(str::split_whitespace)
+            .map(Vec::from_iter)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                vec!["connection", "1x", "8.000ms", "100.0%"],
+                vec!["read", "2x", "2.000ms", "25.0%"],
+            ]
+        );
+        assert!(rendered.lines().nth(1).unwrap().starts_with("  read"));
+    }
+}
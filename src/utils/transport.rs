@@ -0,0 +1,91 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::{TcpStream, UdpSocket};
+use std::rc::Rc;
+
+use super::protocol::{encode_frame, write_frame};
+
+// The seam between protocol logic and the network.
+//
+// Servers and clients are written as `Handler`s: they react to a message
+// by sending others through a `Transport`, and never touch sockets
+// themselves. The binaries drive them over TCP; `sim::SimNet` drives them
+// in memory with a virtual clock, so a whole conversation can be replayed
+// exactly from a seed.
+
+// Where a node's outgoing messages go.
+pub trait Transport {
+    fn send(&mut self, to: &str, payload: &[u8]) -> io::Result<()>;
+}
+
+// Protocol logic for one node.
+pub trait Handler {
+    // `from` names the sender: a node name in a simulation, the peer
+    // address over TCP.
+    fn on_message(
+        &mut self,
+        from: &str,
+        payload: &[u8],
+        transport: &mut dyn Transport,
+    ) -> io::Result<()>;
+
+    // A one-line summary of what the node is holding, for debugging
+    // dumps like `SimNet::node_states`.
+    fn state(&self) -> String {
+        String::new()
+    }
+}
+
+// A TCP connection only reaches its peer, so `to` is ignored. Each
+// message goes out as one frame (see `protocol`).
+impl Transport for TcpStream {
+    fn send(&mut self, _to: &str, payload: &[u8]) -> io::Result<()> {
+        write_frame(self, payload)
+    }
+}
+
+// Over UDP every message is one datagram, so there's no framing, and `to`
+// is the peer address to send it to.
+impl Transport for UdpSocket {
+    fn send(&mut self, to: &str, payload: &[u8]) -> io::Result<()> {
+        let sent = self.send_to(payload, to)?;
+        if sent < payload.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!(
+                    "datagram truncated: sent {} of {} bytes",
+                    sent,
+                    payload.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Lets a caller hand a handler to a driver and still look at its state
+// afterwards.
+impl<H: Handler> Handler for Rc<RefCell<H>> {
+    fn on_message(
+        &mut self,
+        from: &str,
+        payload: &[u8],
+        transport: &mut dyn Transport,
+    ) -> io::Result<()> {
+        self.borrow_mut().on_message(from, payload, transport)
+    }
+
+    fn state(&self) -> String {
+        self.borrow().state()
+    }
+}
+
+// Collects what's sent as encoded frames, so a caller that can't write
+// synchronously (async code, say) can send the bytes itself afterwards.
+// `to` is ignored.
+impl Transport for Vec<u8> {
+    fn send(&mut self, _to: &str, payload: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(&encode_frame(payload));
+        Ok(())
+    }
+}
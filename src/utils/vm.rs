@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::expr::Value;
+
+// A tiny stack-based virtual machine for user-defined transforms.
+//
+// Programs are written in a line-based assembly:
+//
+//     # Upper-case the first input and repeat it N times
+//         push ""
+//     loop:
+//         load 1          # remaining count
+//         jz done
+//         load 0
+//         call upper
+//         add             # strings concatenate
+//         load 1
+//         push 1
+//         sub
+//         store 1
+//         jmp loop
+//     done:
+//         halt
+//
+// `Program::assemble` turns the text into instructions, resolving labels,
+// and `Program::run` executes them against a list of input values (which
+// `load`/`store` address by index). The value on top of the stack when the
+// program halts (or runs off the end) is the result. A step limit stops
+// runaway loops, and limits on slots, stack depth and string length stop a
+// script from using up memory before the step limit is reached.
+//
+// `Program::transform` runs a script on one string (input 0), which is how
+// the server's `--transform` rewrites echo replies.
+//
+// Instructions:
+//   push <lit>   push a number, "string", true, false or null
+//   load <n>     push input n            store <n>  pop into input n
+//   pop  dup  swap  over
+//   add sub mul div rem                  (add also joins strings)
+//   eq ne lt le gt ge not
+//   jmp <label>  jz <label> (jump if falsy)  jnz <label> (if truthy)
+//   call <builtin>                       see `call_builtin`
+//   halt
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Push(Value),
+    Load(usize),
+    Store(usize),
+    Pop,
+    Dup,
+    Swap,
+    Over,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Not,
+    Jmp(usize),
+    Jz(usize),
+    Jnz(usize),
+    Call(Builtin),
+    Halt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Upper,
+    Lower,
+    Trim,
+    Len,
+    Reverse,
+    ToStr,
+    ToNum,
+    Round,
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Option<Self> {
+        let builtin = match name {
+            "upper" => Builtin::Upper,
+            "lower" => Builtin::Lower,
+            "trim" => Builtin::Trim,
+            "len" => Builtin::Len,
+            "reverse" => Builtin::Reverse,
+            "str" => Builtin::ToStr,
+            "num" => Builtin::ToNum,
+            "round" => Builtin::Round,
+            _ => return None,
+        };
+        Some(builtin)
+    }
+}
+
+// Assembly error, pointing at a 1-based source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Runtime error, pointing at the instruction that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmError {
+    pub pc: usize,
+    // Source line of the failing instruction (1-based).
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    code: Vec<Instr>,
+    // Source line for each instruction, for error messages.
+    lines: Vec<usize>,
+    step_limit: u64,
+}
+
+const DEFAULT_STEP_LIMIT: u64 = 1_000_000;
+
+// `load` and `store` address slots below this.
+pub const MAX_SLOTS: usize = 256;
+// Values on the stack at once.
+pub const MAX_STACK: usize = 1024;
+// Bytes in a string an instruction produces. Inputs may be longer.
+pub const MAX_STRING_LEN: usize = 64 * 1024;
+
+impl Program {
+    pub fn assemble(source: &str) -> Result<Program, AsmError> {
+        // First pass: find where each label points.
+        let mut labels = HashMap::new();
+        let mut count = 0;
+        for (index, raw) in source.lines().enumerate() {
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(label) = line.strip_suffix(':') {
+                if labels.insert(label.to_string(), count).is_some() {
+                    return Err(AsmError {
+                        line: index + 1,
+                        message: format!("label '{}' defined twice", label),
+                    });
+                }
+            } else {
+                count += 1;
+            }
+        }
+
+        // Second pass: parse instructions.
+        let mut code = Vec::with_capacity(count);
+        let mut lines = Vec::with_capacity(count);
+        for (index, raw) in source.lines().enumerate() {
+            let line = strip_comment(raw).trim();
+            if line.is_empty() || line.ends_with(':') {
+                continue;
+            }
+            let err = |message: String| AsmError {
+                line: index + 1,
+                message,
+            };
+            let (op, arg) = match line.split_once(char::is_whitespace) {
+                Some((op, arg)) => (op, Some(arg.trim())),
+                None => (line, None),
+            };
+            let need_arg = || arg.ok_or_else(|| err(format!("'{}' needs an argument", op)));
+            let index_arg = || {
+                let index = need_arg()?
+                    .parse::<usize>()
+                    .map_err(|_| err(format!("'{}' needs an input index", op)))?;
+                if index >= MAX_SLOTS {
+                    return Err(err(format!(
+                        "input index {} is over the limit of {}",
+                        index,
+                        MAX_SLOTS - 1
+                    )));
+                }
+                Ok(index)
+            };
+            let label_arg = || {
+                let name = need_arg()?;
+                labels
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| err(format!("unknown label '{}'", name)))
+            };
+
+            let instr = match op {
+                "push" => Instr::Push(parse_literal(need_arg()?).map_err(err)?),
+                "load" => Instr::Load(index_arg()?),
+                "store" => Instr::Store(index_arg()?),
+                "jmp" => Instr::Jmp(label_arg()?),
+                "jz" => Instr::Jz(label_arg()?),
+                "jnz" => Instr::Jnz(label_arg()?),
+                "call" => {
+                    let name = need_arg()?;
+                    Instr::Call(
+                        Builtin::from_name(name)
+                            .ok_or_else(|| err(format!("unknown builtin '{}'", name)))?,
+                    )
+                }
+                _ => {
+                    if arg.is_some() {
+                        return Err(err(format!("'{}' takes no argument", op)));
+                    }
+                    match op {
+                        "pop" => Instr::Pop,
+                        "dup" => Instr::Dup,
+                        "swap" => Instr::Swap,
+                        "over" => Instr::Over,
+                        "add" => Instr::Add,
+                        "sub" => Instr::Sub,
+                        "mul" => Instr::Mul,
+                        "div" => Instr::Div,
+                        "rem" => Instr::Rem,
+                        "eq" => Instr::Eq,
+                        "ne" => Instr::Ne,
+                        "lt" => Instr::Lt,
+                        "le" => Instr::Le,
+                        "gt" => Instr::Gt,
+                        "ge" => Instr::Ge,
+                        "not" => Instr::Not,
+                        "halt" => Instr::Halt,
+                        _ => return Err(err(format!("unknown instruction '{}'", op))),
+                    }
+                }
+            };
+            code.push(instr);
+            lines.push(index + 1);
+        }
+
+        Ok(Program {
+            code,
+            lines,
+            step_limit: DEFAULT_STEP_LIMIT,
+        })
+    }
+
+    pub fn with_step_limit(mut self, limit: u64) -> Self {
+        self.step_limit = limit;
+        self
+    }
+
+    pub fn instructions(&self) -> &[Instr] {
+        &self.code
+    }
+
+    // Run the program with `inputs` as its numbered slots. Returns the top
+    // of the stack at the end, or null if the stack is empty.
+    pub fn run(&self, inputs: &[Value]) -> Result<Value, VmError> {
+        let mut slots = inputs.to_vec();
+        let mut stack: Vec<Value> = Vec::new();
+        let mut pc = 0;
+        let mut steps = 0u64;
+
+        while let Some(instr) = self.code.get(pc) {
+            let fail = |message: String| VmError {
+                pc,
+                line: self.lines[pc],
+                message,
+            };
+            steps += 1;
+            if steps > self.step_limit {
+                return Err(fail(format!("step limit of {} exceeded", self.step_limit)));
+            }
+
+            let mut pop = || stack.pop().ok_or_else(|| fail("stack underflow".into()));
+            let mut next = pc + 1;
+            match instr {
+                Instr::Push(v) => stack.push(v.clone()),
+                Instr::Load(i) => {
+                    let v = slots
+                        .get(*i)
+                        .cloned()
+                        .ok_or_else(|| fail(format!("no input {}", i)))?;
+                    stack.push(v);
+                }
+                Instr::Store(i) => {
+                    let v = pop()?;
+                    // The assembler keeps indexes under `MAX_SLOTS`.
+                    let len = i
+                        .checked_add(1)
+                        .ok_or_else(|| fail(format!("no slot {}", i)))?;
+                    if len > slots.len() {
+                        slots.resize(len, Value::Null);
+                    }
+                    slots[*i] = v;
+                }
+                Instr::Pop => {
+                    pop()?;
+                }
+                Instr::Dup => {
+                    let v = pop()?;
+                    stack.push(v.clone());
+                    stack.push(v);
+                }
+                Instr::Swap => {
+                    let b = pop()?;
+                    let a = pop()?;
+                    stack.push(b);
+                    stack.push(a);
+                }
+                Instr::Over => {
+                    let b = pop()?;
+                    let a = pop()?;
+                    stack.push(a.clone());
+                    stack.push(b);
+                    stack.push(a);
+                }
+                Instr::Not => {
+                    let v = pop()?;
+                    stack.push(Value::Bool(!v.is_truthy()));
+                }
+                Instr::Jmp(target) => next = *target,
+                Instr::Jz(target) => {
+                    if !pop()?.is_truthy() {
+                        next = *target;
+                    }
+                }
+                Instr::Jnz(target) => {
+                    if pop()?.is_truthy() {
+                        next = *target;
+                    }
+                }
+                Instr::Call(builtin) => {
+                    let v = pop()?;
+                    stack.push(
+                        call_builtin(*builtin, v)
+                            .and_then(within_limits)
+                            .map_err(fail)?,
+                    );
+                }
+                Instr::Halt => break,
+                op => {
+                    let b = pop()?;
+                    let a = pop()?;
+                    stack.push(binary(op, a, b).and_then(within_limits).map_err(fail)?);
+                }
+            }
+            if stack.len() > MAX_STACK {
+                return Err(fail(format!("stack overflow: over {} values", MAX_STACK)));
+            }
+            pc = next;
+        }
+
+        Ok(stack.pop().unwrap_or(Value::Null))
+    }
+
+    // Run the program on one string and give back its result as text.
+    pub fn transform(&self, text: &str) -> Result<String, VmError> {
+        Ok(self.run(&[Value::Str(text.to_string())])?.to_string())
+    }
+}
+
+fn within_limits(v: Value) -> Result<Value, String> {
+    match &v {
+        Value::Str(s) if s.len() > MAX_STRING_LEN => Err(format!(
+            "string of {} bytes is over the limit of {}",
+            s.len(),
+            MAX_STRING_LEN
+        )),
+        _ => Ok(v),
+    }
+}
+
+fn binary(op: &Instr, a: Value, b: Value) -> Result<Value, String> {
+    use Value::{Num, Str};
+
+    let result = match (op, &a, &b) {
+        (Instr::Add, Str(x), _) => Str(format!("{}{}", x, b)),
+        (Instr::Add, _, Str(y)) => Str(format!("{}{}", a, y)),
+        (Instr::Add, Num(x), Num(y)) => Num(x + y),
+        (Instr::Sub, Num(x), Num(y)) => Num(x - y),
+        (Instr::Mul, Num(x), Num(y)) => Num(x * y),
+        (Instr::Div | Instr::Rem, Num(_), Num(y)) if *y == 0.0 => {
+            return Err("division by zero".to_string());
+        }
+        (Instr::Div, Num(x), Num(y)) => Num(x / y),
+        (Instr::Rem, Num(x), Num(y)) => Num(x % y),
+        (Instr::Eq, _, _) => Value::Bool(a == b),
+        (Instr::Ne, _, _) => Value::Bool(a != b),
+        (Instr::Lt | Instr::Le | Instr::Gt | Instr::Ge, Num(x), Num(y)) => {
+            Value::Bool(compare(op, x.partial_cmp(y)))
+        }
+        (Instr::Lt | Instr::Le | Instr::Gt | Instr::Ge, Str(x), Str(y)) => {
+            Value::Bool(compare(op, Some(x.cmp(y))))
+        }
+        _ => {
+            return Err(format!(
+                "can't apply {:?} to {} and {}",
+                op,
+                a.type_name(),
+                b.type_name()
+            ));
+        }
+    };
+    Ok(result)
+}
+
+fn compare(op: &Instr, ordering: Option<std::cmp::Ordering>) -> bool {
+    use std::cmp::Ordering::*;
+    matches!(
+        (op, ordering),
+        (Instr::Lt, Some(Less))
+            | (Instr::Le, Some(Less | Equal))
+            | (Instr::Gt, Some(Greater))
+            | (Instr::Ge, Some(Greater | Equal))
+    )
+}
+
+// upper/lower/trim/reverse work on strings, len gives a string's length in
+// characters, str/num convert, round rounds a number to the nearest integer.
+fn call_builtin(builtin: Builtin, v: Value) -> Result<Value, String> {
+    let result = match (builtin, &v) {
+        (Builtin::Upper, Value::Str(s)) => Value::Str(s.to_uppercase()),
+        (Builtin::Lower, Value::Str(s)) => Value::Str(s.to_lowercase()),
+        (Builtin::Trim, Value::Str(s)) => Value::Str(s.trim().to_string()),
+        (Builtin::Reverse, Value::Str(s)) => Value::Str(s.chars().rev().collect()),
+        (Builtin::Len, Value::Str(s)) => Value::Num(s.chars().count() as f64),
+        (Builtin::ToStr, _) => Value::Str(v.to_string()),
+        (Builtin::ToNum, Value::Num(_)) => v,
+        (Builtin::ToNum, Value::Str(s)) => s
+            .trim()
+            .parse()
+            .map(Value::Num)
+            .map_err(|_| format!("'{}' is not a number", s))?,
+        (Builtin::Round, Value::Num(n)) => Value::Num(n.round()),
+        _ => {
+            return Err(format!(
+                "builtin {:?} doesn't take a {}",
+                builtin,
+                v.type_name()
+            ));
+        }
+    };
+    Ok(result)
+}
+
+// Strip a `#` comment, ignoring any `#` inside a string literal.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_literal(text: &str) -> Result<Value, String> {
+    match text {
+        "null" => return Ok(Value::Null),
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(inner) = text.strip_prefix('"') {
+        let inner = inner
+            .strip_suffix('"')
+            .ok_or_else(|| format!("unterminated string {}", text))?;
+        let mut out = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                other => return Err(format!("bad escape in {}: \\{:?}", text, other)),
+            }
+        }
+        return Ok(Value::Str(out));
+    }
+    text.parse::<f64>()
+        .map(Value::Num)
+        .map_err(|_| format!("'{}' is not a number, string, bool or null", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, inputs: &[Value]) -> Result<Value, VmError> {
+        Program::assemble(source).expect("assembles").run(inputs)
+    }
+
+    #[test]
+    fn assembles_labels_literals_and_comments() {
+        let program = Program::assemble(
+            "# comment\n  push \"a # b\"\nstart:\n  jmp start  # back\n  push -1.5\n  call upper\n",
+        )
+        .unwrap();
+        assert_eq!(
+            program.instructions(),
+            &[
+                Instr::Push(Value::Str("a # b".into())),
+                Instr::Jmp(1),
+                Instr::Push(Value::Num(-1.5)),
+                Instr::Call(Builtin::Upper),
+            ]
+        );
+    }
+
+    #[test]
+    fn assembler_errors_name_the_line() {
+        let cases = [
+            ("push 1\nfrob", 2, "unknown instruction 'frob'"),
+            ("jmp nowhere", 1, "unknown label 'nowhere'"),
+            ("a:\na:", 2, "label 'a' defined twice"),
+            ("push", 1, "'push' needs an argument"),
+            ("dup 3", 1, "'dup' takes no argument"),
+            ("load x", 1, "'load' needs an input index"),
+            ("call frob", 1, "unknown builtin 'frob'"),
+            ("push \"open", 1, "unterminated string \"open"),
+        ];
+        for (source, line, message) in cases {
+            let err = Program::assemble(source).unwrap_err();
+            assert_eq!(
+                (err.line, err.message.as_str()),
+                (line, message),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn assembler_caps_slot_indexes() {
+        for source in ["push 1\nstore 18446744073709551615", "store 100000000000"] {
+            let err = Program::assemble(source).unwrap_err();
+            assert!(err.message.contains("over the limit"), "{}", err);
+        }
+        assert!(Program::assemble(&format!("load {}", MAX_SLOTS - 1)).is_ok());
+    }
+
+    #[test]
+    fn runs_the_doc_example() {
+        let source = "push \"\"\nloop:\nload 1\njz done\nload 0\ncall upper\nadd\n\
+                      load 1\npush 1\nsub\nstore 1\njmp loop\ndone:\nhalt";
+        let result = run(source, &[Value::Str("ab".into()), Value::Num(3.0)]);
+        assert_eq!(result, Ok(Value::Str("ABABAB".into())));
+    }
+
+    #[test]
+    fn store_grows_the_slots() {
+        assert_eq!(run("push 7\nstore 5\nload 5", &[]), Ok(Value::Num(7.0)));
+        assert_eq!(run("load 4", &[]).unwrap_err().message, "no input 4");
+    }
+
+    #[test]
+    fn runtime_errors_name_the_line() {
+        let cases = [
+            ("push 1\npop\npop", 3, "stack underflow"),
+            ("push 1\npush 0\ndiv", 3, "division by zero"),
+            (
+                "push true\npush 1\nadd",
+                3,
+                "can't apply Add to bool and number",
+            ),
+            (
+                "push 1\ncall upper",
+                2,
+                "builtin Upper doesn't take a number",
+            ),
+        ];
+        for (source, line, message) in cases {
+            let err = run(source, &[]).unwrap_err();
+            assert_eq!(
+                (err.line, err.message.as_str()),
+                (line, message),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn step_limit_stops_a_loop() {
+        let program = Program::assemble("top:\njmp top")
+            .unwrap()
+            .with_step_limit(50);
+        let err = program.run(&[]).unwrap_err();
+        assert_eq!(err.message, "step limit of 50 exceeded");
+    }
+
+    #[test]
+    fn doubling_a_string_hits_the_size_limit() {
+        let err = run("push \"x\"\ntop:\ndup\nadd\njmp top", &[]).unwrap_err();
+        assert!(err.message.contains("over the limit"), "{}", err);
+    }
+
+    #[test]
+    fn pushing_forever_overflows_the_stack() {
+        let err = run("top:\npush 1\njmp top", &[]).unwrap_err();
+        assert!(err.message.starts_with("stack overflow"), "{}", err);
+    }
+
+    #[test]
+    fn transform_rewrites_text() {
+        let program = Program::assemble("load 0\ncall reverse").unwrap();
+        assert_eq!(program.transform("abc").unwrap(), "cba");
+    }
+}
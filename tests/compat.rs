@@ -0,0 +1,37 @@
+// Every released wire format still round-trips: the fixtures `compat`
+// ships with all pass against today's code. The matrix is printed, as
+// `rust-practice compat` shows it; `cargo test --test compat --
+// --nocapture` shows it even when the test passes.
+
+use std::error::Error;
+
+use rust_practice::utils::compat::{fixtures, matrix, render_matrix, run};
+
+#[test]
+fn released_formats_stay_compatible() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let fixtures = fixtures();
+
+    // Act
+    let results = run(&fixtures);
+    let cells = matrix(&results);
+    println!("{}", render_matrix(&cells));
+
+    // Assert
+    let failures: Vec<String> = results
+        .iter()
+        .filter_map(|r| {
+            let failure = r.failure.as_ref()?;
+            Some(format!(
+                "{} v{} {} {}: {}",
+                r.format, r.version, r.name, r.direction, failure
+            ))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    let formats: Vec<_> = cells.iter().map(|c| c.format).collect();
+    for format in ["frame", "json", "binary", "text"] {
+        assert!(formats.contains(&format), "no {} fixtures", format);
+    }
+    Ok(())
+}
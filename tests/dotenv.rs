@@ -0,0 +1,54 @@
+// `ConfigLoader::with_dotenv` reads a `.env` file only when built with the
+// `dotenv` feature; without it, it's `from_env` and the file is ignored.
+//
+// The loader looks in the current directory, so the test changes it. That
+// and the variable it loads are process-wide, which is why this is the
+// only test in this file: nothing else in this binary runs alongside it.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use rust_practice::utils::config::ConfigLoader;
+
+// A scratch directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> std::io::Result<Self> {
+        let path = env::temp_dir().join(format!("dotenv-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+const KEY: &str = "RUST_PRACTICE_DOTENV_TEST_GREETING";
+
+#[test]
+fn with_dotenv_reads_the_file_only_with_the_feature() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let dir = TempDir::new("with-dotenv")?;
+    fs::write(dir.0.join(".env"), format!("{}=from-the-file\n", KEY))?;
+    let before = env::current_dir()?;
+    env::set_current_dir(&dir.0)?;
+
+    // Act
+    let mut loader = ConfigLoader::with_dotenv();
+    let greeting: Option<String> = loader.maybe(KEY);
+    env::set_current_dir(before)?;
+
+    // Assert
+    if cfg!(feature = "dotenv") {
+        assert_eq!(greeting.as_deref(), Some("from-the-file"));
+    } else {
+        assert_eq!(greeting, None);
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+// What each cargo feature adds, seen from outside the crate. Every test
+// here builds in any feature set: the ones for an optional subsystem are
+// compiled only when it's on, and the rest check that the build says
+// which features it has. `tests/dotenv.rs` and `tests/server_async.rs`
+// cover `dotenv` and `async`.
+
+use std::error::Error;
+
+use rust_practice::utils::build_info::BUILD_INFO;
+
+#[test]
+fn build_info_lists_exactly_the_enabled_features() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let expected = [
+        ("async", cfg!(feature = "async")),
+        ("dotenv", cfg!(feature = "dotenv")),
+        ("e2e", cfg!(feature = "e2e")),
+        ("full", cfg!(feature = "full")),
+    ];
+
+    // Act
+    let listed: Vec<&str> = BUILD_INFO.features().collect();
+
+    // Assert
+    for (feature, enabled) in expected {
+        assert_eq!(
+            listed.contains(&feature),
+            enabled,
+            "{} in {:?}",
+            feature,
+            listed
+        );
+    }
+    assert!(!listed.contains(&"default"), "{:?}", listed);
+    Ok(())
+}
+
+#[cfg(feature = "e2e")]
+mod e2e {
+    use std::error::Error;
+
+    use rust_practice::utils::e2e::{Handshake, fingerprint, fingerprint_matches, parse_hello};
+
+    #[test]
+    fn two_clients_agree_and_exchange_messages() -> Result<(), Box<dyn Error>> {
+        // Arrange: each side sends its hello through the relay.
+        let alice = Handshake::new()?;
+        let bob = Handshake::new()?;
+        let (alice_hello, bob_hello) = (alice.hello(), bob.hello());
+        let bob_key = bob.public_key();
+
+        // Act
+        let mut alice = alice.finish(&bob_hello)?;
+        let mut bob = bob.finish(&alice_hello)?;
+        let sealed = alice.seal("meet at noon".as_bytes())?;
+        let opened = bob.open(&sealed)?;
+        let reply = alice.open(&bob.seal(b"ok")?)?;
+
+        // Assert
+        assert_eq!(opened, b"meet at noon");
+        assert_eq!(reply, b"ok");
+        assert!(!sealed.contains("noon"));
+        assert_eq!(alice.safety_number(), bob.safety_number());
+        assert_eq!(parse_hello(&bob_hello)?, bob_key);
+        let read_aloud = fingerprint(&bob_key).to_uppercase();
+        assert!(fingerprint_matches(&alice.peer_key(), &read_aloud));
+        // The relay sending a message back doesn't open.
+        assert!(bob.open(&sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn a_relay_in_the_middle_shows_up_in_the_safety_number() -> Result<(), Box<dyn Error>> {
+        // Arrange: the relay answers each side with its own key.
+        let alice = Handshake::new()?;
+        let bob = Handshake::new()?;
+        let (relay_for_alice, relay_for_bob) = (Handshake::new()?, Handshake::new()?);
+        let (to_alice, to_bob) = (relay_for_alice.hello(), relay_for_bob.hello());
+
+        // Act
+        let alice_sees = alice.finish(&to_alice)?;
+        let bob_sees = bob.finish(&to_bob)?;
+
+        // Assert
+        assert_ne!(alice_sees.safety_number(), bob_sees.safety_number());
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+// Frames over a real socket: what `Client` sends, a peer using only the
+// public framing functions reads back intact, and the reply it frames
+// reaches the client the same way.
+
+use std::error::Error;
+use std::net::TcpListener;
+use std::thread;
+
+use rust_practice::Client;
+use rust_practice::utils::protocol::{MAX_FRAME_LEN, read_frame, write_frame};
+
+// Echo every frame on one connection until the client hangs up.
+fn echo_once(listener: TcpListener) -> thread::JoinHandle<std::io::Result<usize>> {
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept()?;
+        let mut frames = 0;
+        while let Some(payload) = read_frame(&mut stream, MAX_FRAME_LEN)? {
+            write_frame(&mut stream, &payload)?;
+            frames += 1;
+        }
+        Ok(frames)
+    })
+}
+
+#[test]
+fn messages_round_trip_through_a_socket() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let peer = echo_once(listener);
+    let messages = [
+        "hello",
+        "",
+        "ünïcödé",
+        "line\nbreaks\r\n",
+        &"x".repeat(100_000),
+    ];
+    let binary: Vec<u8> = (0..=255).collect();
+
+    // Act
+    let mut client = Client::connect(addr)?;
+    let mut replies = Vec::new();
+    for message in messages {
+        replies.push(client.echo(message)?);
+    }
+    let binary_reply = client.echo_bytes(&binary)?;
+    drop(client);
+    let frames = peer.join().expect("echo thread panicked")?;
+
+    // Assert
+    assert_eq!(replies, messages);
+    assert_eq!(binary_reply, binary);
+    assert_eq!(frames, messages.len() + 1);
+    Ok(())
+}
@@ -0,0 +1,42 @@
+// Every saved fuzz input, replayed as the fuzzer's first step does, so a
+// regression on a case it once found fails `cargo test` rather than waiting
+// for someone to run `fuzz_frames`.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use rust_practice::utils::fuzz;
+
+#[test]
+fn corpus_replays_without_failures() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+    let mut names = Vec::new();
+    let mut inputs = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            names.push(path.display().to_string());
+            inputs.push(fs::read(&path)?);
+        }
+    }
+    assert!(!inputs.is_empty(), "no corpus files in {}", dir.display());
+
+    // Act
+    let report = fuzz::run(0, 0, &inputs);
+
+    // Assert
+    assert_eq!(report.executed, inputs.len() as u64);
+    let failures: Vec<String> = report
+        .failures
+        .iter()
+        .map(|failure| {
+            let i = inputs.iter().position(|input| *input == failure.input);
+            let name = i.map_or("?", |i| names[i].as_str());
+            format!("{}: {}", name, failure.reason)
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    Ok(())
+}
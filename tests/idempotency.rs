@@ -0,0 +1,51 @@
+// Retries of one request from many threads, through the public API: the
+// handler runs once and every caller gets its response.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use rust_practice::utils::idempotency::IdempotencyStore;
+
+#[test]
+fn concurrent_retries_share_one_response() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let store = Arc::new(IdempotencyStore::new(64, Duration::from_secs(60)));
+    let runs = Arc::new(AtomicUsize::new(0));
+    let start = Arc::new(Barrier::new(32));
+
+    // Act: 4 keys, 8 threads racing on each.
+    let threads: Vec<_> = (0..32)
+        .map(|i| {
+            let (store, runs, start) = (store.clone(), runs.clone(), start.clone());
+            thread::spawn(move || {
+                let key = format!("order-{}", i % 4);
+                start.wait();
+                let outcome = store.execute(&key, || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    format!("{}-by-{}", key, i)
+                });
+                (key, outcome)
+            })
+        })
+        .collect();
+    let mut outcomes = Vec::new();
+    for t in threads {
+        outcomes.push(t.join().map_err(|_| "worker panicked")?);
+    }
+
+    // Assert
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+    for (key, outcome) in &outcomes {
+        let winner = outcomes
+            .iter()
+            .find(|(k, o)| k == key && !o.was_replayed())
+            .ok_or("no thread ran the handler")?;
+        assert_eq!(outcome.clone().into_inner(), winner.1.clone().into_inner());
+    }
+    let stats = store.stats();
+    assert_eq!((stats.executed, stats.replayed), (4, 28));
+    Ok(())
+}
@@ -0,0 +1,97 @@
+// `Client` against a scripted `MockServer`, the way code built on the
+// client would test itself without the real server.
+
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+use rust_practice::Client;
+use rust_practice::utils::testsupport::{MockReply, MockServer};
+
+#[test]
+fn echo_round_trips_and_requests_are_recorded() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let server = MockServer::new().on("echo", MockReply::frame).start()?;
+    let mut client = Client::connect(server.addr())?;
+
+    // Act
+    let first = client.echo("hello")?;
+    let second = client.echo("again")?;
+
+    // Assert
+    assert_eq!((first.as_str(), second.as_str()), ("hello", "again"));
+    assert_eq!(server.requests(), [b"hello".to_vec(), b"again".to_vec()]);
+    Ok(())
+}
+
+#[test]
+fn a_wrong_reply_is_invalid_data() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let server = MockServer::new()
+        .on("echo", |_| MockReply::frame(b"something else"))
+        .start()?;
+    let mut client = Client::connect(server.addr())?;
+
+    // Act
+    let e = client.echo("hello").unwrap_err();
+
+    // Assert
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn a_malformed_frame_is_refused() -> Result<(), Box<dyn Error>> {
+    // Arrange: a length prefix far over the frame limit.
+    let server = MockServer::new()
+        .on("echo", |_| MockReply::Raw(u32::MAX.to_be_bytes().to_vec()))
+        .start()?;
+    let mut client = Client::connect(server.addr())?;
+
+    // Act
+    let e = client.echo("hello").unwrap_err();
+
+    // Assert
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn a_hangup_before_the_reply_is_unexpected_eof() -> Result<(), Box<dyn Error>> {
+    // Arrange: no handler, so every request is hung up on.
+    let server = MockServer::new().start()?;
+    let mut client = Client::connect(server.addr())?;
+
+    // Act
+    let e = client.echo("hello").unwrap_err();
+
+    // Assert
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(server.requests(), [b"hello".to_vec()]);
+    Ok(())
+}
+
+#[test]
+fn a_slow_reply_times_out() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let server = MockServer::new()
+        .on("echo", |req| {
+            MockReply::frame(req).after(Duration::from_millis(500))
+        })
+        .start()?;
+    let mut client = Client::connect_timeout(server.addr(), Duration::from_millis(50))?;
+
+    // Act
+    let e = client.echo("hello").unwrap_err();
+
+    // Assert: which kind a read timeout is depends on the platform.
+    assert!(
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        "{:?}",
+        e
+    );
+    Ok(())
+}
@@ -0,0 +1,73 @@
+// The tokio echo server, run as its binary: a client using the blocking
+// `Client` gets its frames echoed back, from several connections at once.
+// Built only with the `async` feature (see `[[test]]` in Cargo.toml).
+
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+use rust_practice::Client;
+
+// The server process, killed when dropped so a failed assert doesn't leave
+// it running.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Start `server_async` on a free port and wait until it's listening.
+fn start() -> Result<(Server, String), Box<dyn Error>> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+    let mut server = Server(
+        Command::new(env!("CARGO_BIN_EXE_server_async"))
+            .args([addr.as_str(), "--stats-interval", "0"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?,
+    );
+    let stdout = server.0.stdout.take().ok_or("no stdout")?;
+    let mut line = String::new();
+    BufReader::new(stdout).read_line(&mut line)?;
+    if !line.starts_with("server listening on") {
+        return Err(format!("server didn't start: {:?}", line).into());
+    }
+    Ok((server, addr))
+}
+
+#[test]
+fn echoes_frames_on_concurrent_connections() -> Result<(), Box<dyn Error>> {
+    // Arrange
+    let (_server, addr) = start()?;
+
+    // Act
+    let clients: Vec<_> = (0..8)
+        .map(|i| {
+            let addr = addr.clone();
+            thread::spawn(move || -> std::io::Result<Vec<String>> {
+                let mut client = Client::connect(addr.as_str())?;
+                (0..20)
+                    .map(|n| client.echo(&format!("client {} message {}", i, n)))
+                    .collect()
+            })
+        })
+        .collect();
+    let mut replies = Vec::new();
+    for client in clients {
+        replies.push(client.join().map_err(|_| "client panicked")??);
+    }
+
+    // Assert
+    for (i, replies) in replies.iter().enumerate() {
+        let expected: Vec<String> = (0..20)
+            .map(|n| format!("client {} message {}", i, n))
+            .collect();
+        assert_eq!(replies, &expected);
+    }
+    Ok(())
+}